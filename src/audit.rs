@@ -0,0 +1,177 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use ethereum_types::H256;
+
+use crate::codec::{NodeCodec, RlpCodec};
+use crate::db::{Meta, DB};
+use crate::errors::TrieError;
+use crate::hasher::{Hasher, KeccakHasher};
+use crate::trie::{EthTrie, TrieResult};
+use crate::versions::be_u64;
+
+// The number of records in the log.
+const AUDIT_LEN_KEY: &[u8] = b"audit-len";
+
+// A record is stored as the timestamp, the root, a flags byte telling which value
+// hashes follow, the hashes, and the key.
+const HAS_OLD: u8 = 1;
+const HAS_NEW: u8 = 2;
+
+// Changed keys with the hashes of their old and new values.
+pub(crate) type ValueHashes = Vec<(Vec<u8>, Option<H256>, Option<H256>)>;
+
+fn record_key(index: u64) -> Vec<u8> {
+    [b"audit:".as_slice(), &index.to_be_bytes()].concat()
+}
+
+/// A change to one key, as recorded in the audit log by the commit that made it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct AuditRecord {
+    pub key: Vec<u8>,
+    /// The hash of the value before the commit, or `None` if the key was absent.
+    pub old_value_hash: Option<H256>,
+    /// The hash of the value after the commit, or `None` if the key was deleted.
+    pub new_value_hash: Option<H256>,
+    /// The root committed.
+    pub root: H256,
+    /// When the commit happened, in milliseconds since the Unix epoch.
+    pub timestamp: u64,
+}
+
+impl<D, H, C> EthTrie<D, H, C>
+where
+    D: DB,
+    H: Hasher,
+    C: NodeCodec,
+{
+    /// Chooses whether `commit` appends a record of every key it changes to an audit
+    /// log kept in the trie's database: the key, the hashes of its old and new value
+    /// with the trie's hasher, the new root and the time. Records are only ever
+    /// appended. The changes are found by comparing the trie with the last committed
+    /// root, so the cost follows the size of the change. `build_from_sorted` doesn't
+    /// commit and isn't recorded.
+    pub fn with_audit_log(mut self, audit: bool) -> Self {
+        self.audit = audit;
+        self
+    }
+
+    /// Returns the number of records in the audit log.
+    pub fn audit_len(&self) -> TrieResult<u64> {
+        self.get_meta(AUDIT_LEN_KEY)?
+            .map_or(Ok(0), |len| be_u64(&len))
+    }
+
+    /// Returns the records of the audit log from position `from` on, in the order
+    /// they were written, to inspect them or replay them into another system. Records
+    /// appended while iterating are not returned.
+    pub fn audit_log(&self, from: u64) -> TrieResult<AuditLog<'_, D, H, C>> {
+        Ok(AuditLog {
+            trie: self,
+            next: from,
+            len: self.audit_len()?,
+        })
+    }
+
+    // The keys changed since the last commit, with the hashes of their old and new
+    // values. They must be found before `commit` prunes the nodes of the last root.
+    pub(crate) fn audit_changes(&self) -> TrieResult<ValueHashes> {
+        let mut changes = vec![];
+        self.diff_since(self.root_hash, &mut |key, old, new| {
+            let old = old.map(|v| self.load_value(v)).transpose()?;
+            let new = new.map(|v| self.load_value(v)).transpose()?;
+            changes.push((key, old.map(|v| H::hash(&v)), new.map(|v| H::hash(&v))));
+            Ok(())
+        })?;
+        Ok(changes)
+    }
+}
+
+// The entries appending the `changes` committed with `root` to the audit log, which
+// holds `len` records, to be written with the commit.
+pub(crate) fn audit_meta(len: u64, root: H256, changes: ValueHashes) -> Meta {
+    let mut meta = vec![];
+    if changes.is_empty() {
+        return meta;
+    }
+
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_millis() as u64);
+    let mut len = len;
+    for (key, old, new) in changes {
+        let mut record = [timestamp.to_be_bytes().as_slice(), root.as_bytes()].concat();
+        record.push(0);
+        if let Some(old) = old {
+            record[40] |= HAS_OLD;
+            record.extend_from_slice(old.as_bytes());
+        }
+        if let Some(new) = new {
+            record[40] |= HAS_NEW;
+            record.extend_from_slice(new.as_bytes());
+        }
+        record.extend_from_slice(&key);
+        meta.push((record_key(len), record));
+        len += 1;
+    }
+    meta.push((AUDIT_LEN_KEY.to_vec(), len.to_be_bytes().to_vec()));
+    meta
+}
+
+/// Iterator over the records of an audit log, created with `EthTrie::audit_log`.
+pub struct AuditLog<'a, D, H = KeccakHasher, C = RlpCodec>
+where
+    D: DB,
+    H: Hasher,
+    C: NodeCodec,
+{
+    trie: &'a EthTrie<D, H, C>,
+    next: u64,
+    len: u64,
+}
+
+impl<D, H, C> Iterator for AuditLog<'_, D, H, C>
+where
+    D: DB,
+    H: Hasher,
+    C: NodeCodec,
+{
+    type Item = TrieResult<AuditRecord>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.next >= self.len {
+            return None;
+        }
+        let record = self
+            .trie
+            .get_meta(&record_key(self.next))
+            .and_then(|record| decode_record(&record.ok_or(TrieError::InvalidData)?));
+        self.next += 1;
+        Some(record)
+    }
+}
+
+fn decode_record(record: &[u8]) -> TrieResult<AuditRecord> {
+    if record.len() < 41 {
+        return Err(TrieError::InvalidData);
+    }
+    let (timestamp, root, flags) = (be_u64(&record[..8])?, &record[8..40], record[40]);
+    let mut rest = &record[41..];
+    let mut hash = |present: bool| -> TrieResult<Option<H256>> {
+        if !present {
+            return Ok(None);
+        }
+        let hash = rest.get(..32).ok_or(TrieError::InvalidData)?;
+        rest = &rest[32..];
+        Ok(Some(H256::from_slice(hash)))
+    };
+    let old_value_hash = hash(flags & HAS_OLD != 0)?;
+    let new_value_hash = hash(flags & HAS_NEW != 0)?;
+    Ok(AuditRecord {
+        key: rest.to_vec(),
+        old_value_hash,
+        new_value_hash,
+        root: H256::from_slice(root),
+        timestamp,
+    })
+}
@@ -0,0 +1,582 @@
+use std::marker::PhantomData;
+use std::sync::Arc;
+
+use ethereum_types::H256;
+use hashbrown::{HashMap, HashSet};
+
+use crate::db::{CommitBatch, DB};
+use crate::errors::{with_context, TrieError, TrieOp};
+use crate::hasher::{Hasher, KeccakHasher};
+use crate::trie::{ITrie, TrieResult};
+
+const LEAF_NODE: u8 = 0;
+const BRANCH_NODE: u8 = 1;
+
+// Nodes are paths of bits (one per byte, 0 or 1). Keys are turned into bits with
+// `key_to_bits`, which makes them prefix-free, so a key always ends in a leaf and
+// branches never hold values.
+#[derive(Debug, Clone)]
+enum BinaryNode {
+    Empty,
+    Leaf {
+        path: Vec<u8>,
+        value: Vec<u8>,
+    },
+    // The bits shared by both children come first, so there are no extension nodes.
+    // Both children are always non-empty.
+    Branch {
+        prefix: Vec<u8>,
+        children: [Arc<BinaryNode>; 2],
+    },
+    Hash(H256),
+}
+
+/// A binary Merkle-Patricia trie: every branch has two children, so a proof carries
+/// one sibling hash per level instead of up to fifteen. It stores its nodes in the
+/// same `DB` backends as `EthTrie` and implements `ITrie`, but its roots and proofs
+/// are not compatible with Ethereum's hexary trie.
+///
+/// Paths are compressed as in `EthTrie`, and every node is stored under its hash
+/// whatever its size, so a proof is simply the encoded nodes from the root down to
+/// the key, which `BinaryTrie::verify_proof` checks.
+#[derive(Debug)]
+pub struct BinaryTrie<D, H = KeccakHasher>
+where
+    D: DB,
+    H: Hasher,
+{
+    root: BinaryNode,
+    root_hash: H256,
+
+    db: Arc<D>,
+
+    // Hashes of stored nodes replaced since the last commit.
+    passing_keys: HashSet<Vec<u8>>,
+
+    hasher: PhantomData<H>,
+}
+
+impl<D> BinaryTrie<D>
+where
+    D: DB,
+{
+    pub fn new(db: Arc<D>) -> Self {
+        Self::new_with_hasher(db)
+    }
+}
+
+impl<D, H> BinaryTrie<D, H>
+where
+    D: DB,
+    H: Hasher,
+{
+    /// Creates an empty binary trie hashing its nodes with `H`.
+    pub fn new_with_hasher(db: Arc<D>) -> Self {
+        Self {
+            root: BinaryNode::Empty,
+            root_hash: H::hash(&[]),
+            db,
+            passing_keys: HashSet::new(),
+            hasher: PhantomData,
+        }
+    }
+
+    pub fn at_root(&self, root_hash: H256) -> Self {
+        let root = if root_hash == H::hash(&[]) {
+            BinaryNode::Empty
+        } else {
+            BinaryNode::Hash(root_hash)
+        };
+        Self {
+            root,
+            root_hash,
+            db: self.db.clone(),
+            passing_keys: HashSet::new(),
+            hasher: PhantomData,
+        }
+    }
+
+    /// Checks a proof produced by `proof` against `root_hash`. Returns the value
+    /// stored under `key`, or `None` if the proof shows the key is absent.
+    pub fn verify_proof(
+        root_hash: H256,
+        key: &[u8],
+        proof: Vec<Vec<u8>>,
+    ) -> TrieResult<Option<Vec<u8>>> {
+        if root_hash == H::hash(&[]) {
+            return Ok(None);
+        }
+
+        let bits = key_to_bits(key);
+        let mut path = &bits[..];
+        let mut expected = root_hash;
+        for encoded in proof.iter() {
+            if H::hash(encoded) != expected {
+                return Err(TrieError::InvalidProof);
+            }
+            match decode_node(encoded)? {
+                BinaryNode::Leaf {
+                    path: leaf_path,
+                    value,
+                } => {
+                    return Ok((leaf_path == path).then_some(value));
+                }
+                BinaryNode::Branch { prefix, children } => {
+                    let rest = match path.strip_prefix(prefix.as_slice()) {
+                        Some(rest) if !rest.is_empty() => rest,
+                        _ => return Ok(None),
+                    };
+                    expected = match &*children[rest[0] as usize] {
+                        BinaryNode::Hash(hash) => *hash,
+                        _ => return Err(TrieError::InvalidProof),
+                    };
+                    path = &rest[1..];
+                }
+                _ => return Err(TrieError::InvalidProof),
+            }
+        }
+        Err(TrieError::InvalidProof)
+    }
+
+    fn get_at(&self, node: &BinaryNode, path: &[u8]) -> TrieResult<Option<Vec<u8>>> {
+        match node {
+            BinaryNode::Empty => Ok(None),
+            BinaryNode::Leaf {
+                path: leaf_path,
+                value,
+            } => Ok((leaf_path == path).then(|| value.clone())),
+            BinaryNode::Branch { prefix, children } => match path.strip_prefix(prefix.as_slice()) {
+                Some(rest) if !rest.is_empty() => {
+                    self.get_at(&children[rest[0] as usize], &rest[1..])
+                }
+                _ => Ok(None),
+            },
+            BinaryNode::Hash(hash) => {
+                let node = self.recover_from_db(*hash)?;
+                self.get_at(&node, path)
+            }
+        }
+    }
+
+    fn insert_at(
+        &mut self,
+        node: &BinaryNode,
+        path: &[u8],
+        value: Vec<u8>,
+    ) -> TrieResult<BinaryNode> {
+        match node {
+            BinaryNode::Empty => Ok(BinaryNode::Leaf {
+                path: path.to_vec(),
+                value,
+            }),
+            BinaryNode::Leaf {
+                path: leaf_path,
+                value: leaf_value,
+            } => {
+                if leaf_path == path {
+                    return Ok(BinaryNode::Leaf {
+                        path: path.to_vec(),
+                        value,
+                    });
+                }
+
+                // Paths are prefix-free, so both still have a bit where they differ.
+                let common = common_prefix(leaf_path, path);
+                let old = BinaryNode::Leaf {
+                    path: leaf_path[common + 1..].to_vec(),
+                    value: leaf_value.clone(),
+                };
+                let new = BinaryNode::Leaf {
+                    path: path[common + 1..].to_vec(),
+                    value,
+                };
+                Ok(split(&path[..common], path[common], new, old))
+            }
+            BinaryNode::Branch { prefix, children } => {
+                let common = common_prefix(prefix, path);
+                if common == prefix.len() {
+                    let bit = path[common] as usize;
+                    let child = self.insert_at(&children[bit], &path[common + 1..], value)?;
+                    let mut children = children.clone();
+                    children[bit] = Arc::new(child);
+                    return Ok(BinaryNode::Branch {
+                        prefix: prefix.clone(),
+                        children,
+                    });
+                }
+
+                let old = BinaryNode::Branch {
+                    prefix: prefix[common + 1..].to_vec(),
+                    children: children.clone(),
+                };
+                let new = BinaryNode::Leaf {
+                    path: path[common + 1..].to_vec(),
+                    value,
+                };
+                Ok(split(&path[..common], path[common], new, old))
+            }
+            BinaryNode::Hash(hash) => {
+                self.passing_keys.insert(hash.as_bytes().to_vec());
+                let node = self.recover_from_db(*hash)?;
+                self.insert_at(&node, path, value)
+            }
+        }
+    }
+
+    // Returns `None` if the key is absent, so untouched nodes stay as they are.
+    fn delete_at(&mut self, node: &BinaryNode, path: &[u8]) -> TrieResult<Option<BinaryNode>> {
+        match node {
+            BinaryNode::Empty => Ok(None),
+            BinaryNode::Leaf {
+                path: leaf_path, ..
+            } => Ok((leaf_path == path).then_some(BinaryNode::Empty)),
+            BinaryNode::Branch { prefix, children } => {
+                let rest = match path.strip_prefix(prefix.as_slice()) {
+                    Some(rest) if !rest.is_empty() => rest,
+                    _ => return Ok(None),
+                };
+                let bit = rest[0] as usize;
+                let child = match self.delete_at(&children[bit], &rest[1..])? {
+                    Some(child) => child,
+                    None => return Ok(None),
+                };
+
+                if let BinaryNode::Empty = child {
+                    // The sibling takes the branch's place, absorbing its prefix.
+                    let mut joined = prefix.clone();
+                    joined.push(1 - bit as u8);
+                    let sibling = match &*children[1 - bit] {
+                        BinaryNode::Hash(hash) => {
+                            self.passing_keys.insert(hash.as_bytes().to_vec());
+                            self.recover_from_db(*hash)?
+                        }
+                        sibling => sibling.clone(),
+                    };
+                    return match sibling {
+                        BinaryNode::Leaf { path, value } => Ok(Some(BinaryNode::Leaf {
+                            path: [joined, path].concat(),
+                            value,
+                        })),
+                        BinaryNode::Branch { prefix, children } => Ok(Some(BinaryNode::Branch {
+                            prefix: [joined, prefix].concat(),
+                            children,
+                        })),
+                        _ => Err(TrieError::InvalidData),
+                    };
+                }
+
+                let mut children = children.clone();
+                children[bit] = Arc::new(child);
+                Ok(Some(BinaryNode::Branch {
+                    prefix: prefix.clone(),
+                    children,
+                }))
+            }
+            BinaryNode::Hash(hash) => {
+                let node = self.recover_from_db(*hash)?;
+                let result = self.delete_at(&node, path)?;
+                if result.is_some() {
+                    self.passing_keys.insert(hash.as_bytes().to_vec());
+                }
+                Ok(result)
+            }
+        }
+    }
+
+    // Collects the encoded nodes along the key, from the root down.
+    fn get_path_at(
+        &self,
+        node: &BinaryNode,
+        path: &[u8],
+        proof: &mut Vec<Vec<u8>>,
+    ) -> TrieResult<()> {
+        let node = match node {
+            BinaryNode::Empty => return Ok(()),
+            BinaryNode::Hash(hash) => self.recover_from_db(*hash)?,
+            node => node.clone(),
+        };
+        proof.push(encode_node::<H>(&node, &mut HashMap::new()));
+
+        if let BinaryNode::Branch { prefix, children } = &node {
+            if let Some(rest) = path.strip_prefix(prefix.as_slice()) {
+                if !rest.is_empty() {
+                    return self.get_path_at(&children[rest[0] as usize], &rest[1..], proof);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn recover_from_db(&self, hash: H256) -> TrieResult<BinaryNode> {
+        let data = self
+            .db
+            .get(hash.as_bytes())
+            .map_err(TrieError::backend)?
+            .ok_or(TrieError::MissingTrieNode {
+                node_hash: hash,
+                traversed: None,
+                root_hash: Some(self.root_hash),
+                err_key: None,
+            })?;
+        decode_node(&data).map_err(|e| TrieError::DecodeAtNode {
+            hash,
+            source: Box::new(e),
+        })
+    }
+}
+
+impl<D, H> ITrie<D> for BinaryTrie<D, H>
+where
+    D: DB,
+    H: Hasher,
+{
+    fn get(&self, key: &[u8]) -> TrieResult<Option<Vec<u8>>> {
+        let result = self.get_at(&self.root, &key_to_bits(key));
+        with_context(result, TrieOp::Get, key)
+    }
+
+    /// Inserts value into trie and modifies it if it exists.
+    /// An empty value removes the key.
+    fn put(&mut self, key: &[u8], value: &[u8]) -> TrieResult<()> {
+        if value.is_empty() {
+            return self.del(key);
+        }
+
+        let root = self.root.clone();
+        let result = self.insert_at(&root, &key_to_bits(key), value.to_vec());
+        self.root = with_context(result, TrieOp::Put, key)?;
+        Ok(())
+    }
+
+    fn del(&mut self, key: &[u8]) -> TrieResult<()> {
+        let root = self.root.clone();
+        let result = self.delete_at(&root, &key_to_bits(key));
+        if let Some(root) = with_context(result, TrieOp::Delete, key)? {
+            self.root = root;
+        }
+        Ok(())
+    }
+
+    /// Saves all the new nodes in the db, prunes the ones they replace and returns
+    /// the root hash of the trie.
+    fn commit(&mut self) -> TrieResult<H256> {
+        let mut batch = HashMap::new();
+        let root_hash = match &self.root {
+            BinaryNode::Empty => H::hash(&[]),
+            root => write_node::<H>(root, &mut batch),
+        };
+
+        let removed: Vec<Vec<u8>> = self
+            .passing_keys
+            .iter()
+            .filter(|key| !batch.contains_key(*key))
+            .cloned()
+            .collect();
+
+        let (keys, values) = batch.into_iter().unzip();
+        self.db
+            .commit_batch(CommitBatch {
+                root: root_hash,
+                keys,
+                values,
+                removed,
+                meta: vec![],
+                removed_meta: vec![],
+            })
+            .map_err(TrieError::backend)?;
+        self.passing_keys.clear();
+
+        self.root_hash = root_hash;
+        if !matches!(self.root, BinaryNode::Empty) {
+            self.root = BinaryNode::Hash(root_hash);
+        }
+        Ok(root_hash)
+    }
+
+    /// Returns the encoded nodes from the root down to the leaf holding key, or down
+    /// to the node showing that it is absent. Nodes are encoded from memory, so
+    /// uncommitted changes are proven too.
+    fn proof(&mut self, key: &[u8]) -> TrieResult<Vec<Vec<u8>>> {
+        let mut proof = vec![];
+        let result = self.get_path_at(&self.root, &key_to_bits(key), &mut proof);
+        with_context(result, TrieOp::Proof, key)?;
+        Ok(proof)
+    }
+}
+
+// Every byte becomes a 1 followed by its eight bits and the key ends with a 0, so no
+// key is a prefix of another and bit paths sort like the keys.
+fn key_to_bits(key: &[u8]) -> Vec<u8> {
+    let mut bits = Vec::with_capacity(key.len() * 9 + 1);
+    for byte in key {
+        bits.push(1);
+        bits.extend((0..8).rev().map(|i| (byte >> i) & 1));
+    }
+    bits.push(0);
+    bits
+}
+
+fn common_prefix(a: &[u8], b: &[u8]) -> usize {
+    a.iter().zip(b).take_while(|(a, b)| a == b).count()
+}
+
+// Builds a branch under `prefix` holding `new` at `bit` and `old` on the other side.
+fn split(prefix: &[u8], bit: u8, new: BinaryNode, old: BinaryNode) -> BinaryNode {
+    let (new, old) = (Arc::new(new), Arc::new(old));
+    let children = if bit == 0 { [new, old] } else { [old, new] };
+    BinaryNode::Branch {
+        prefix: prefix.to_vec(),
+        children,
+    }
+}
+
+// Hashes a node, adding it and the new nodes below it to `batch`.
+fn write_node<H: Hasher>(node: &BinaryNode, batch: &mut HashMap<Vec<u8>, Vec<u8>>) -> H256 {
+    if let BinaryNode::Hash(hash) = node {
+        return *hash;
+    }
+    let data = encode_node::<H>(node, batch);
+    let hash = H::hash(&data);
+    batch.insert(hash.as_bytes().to_vec(), data);
+    hash
+}
+
+fn encode_node<H: Hasher>(node: &BinaryNode, batch: &mut HashMap<Vec<u8>, Vec<u8>>) -> Vec<u8> {
+    match node {
+        BinaryNode::Leaf { path, value } => {
+            let mut data = vec![LEAF_NODE];
+            encode_bits(path, &mut data);
+            data.extend_from_slice(value);
+            data
+        }
+        BinaryNode::Branch { prefix, children } => {
+            let mut data = vec![BRANCH_NODE];
+            encode_bits(prefix, &mut data);
+            for child in children {
+                data.extend_from_slice(write_node::<H>(child, batch).as_bytes());
+            }
+            data
+        }
+        BinaryNode::Empty | BinaryNode::Hash(_) => unreachable!(),
+    }
+}
+
+// A path is stored as its length in bits followed by the bits packed MSB first.
+fn encode_bits(bits: &[u8], data: &mut Vec<u8>) {
+    data.extend_from_slice(&(bits.len() as u32).to_be_bytes());
+    data.extend(bits.chunks(8).map(|chunk| {
+        chunk
+            .iter()
+            .enumerate()
+            .fold(0, |byte, (i, bit)| byte | bit << (7 - i))
+    }));
+}
+
+fn decode_bits(data: &[u8]) -> TrieResult<(Vec<u8>, &[u8])> {
+    if data.len() < 4 {
+        return Err(TrieError::InvalidData);
+    }
+    let len = u32::from_be_bytes([data[0], data[1], data[2], data[3]]) as usize;
+    let packed_len = len.div_ceil(8);
+    let packed = data[4..].get(..packed_len).ok_or(TrieError::InvalidData)?;
+    let bits = (0..len)
+        .map(|i| (packed[i / 8] >> (7 - i % 8)) & 1)
+        .collect();
+    Ok((bits, &data[4 + packed_len..]))
+}
+
+fn decode_node(data: &[u8]) -> TrieResult<BinaryNode> {
+    match data.split_first() {
+        Some((&LEAF_NODE, rest)) => {
+            let (path, value) = decode_bits(rest)?;
+            Ok(BinaryNode::Leaf {
+                path,
+                value: value.to_vec(),
+            })
+        }
+        Some((&BRANCH_NODE, rest)) => {
+            let (prefix, hashes) = decode_bits(rest)?;
+            if hashes.len() != 64 {
+                return Err(TrieError::InvalidData);
+            }
+            let children = [
+                Arc::new(BinaryNode::Hash(H256::from_slice(&hashes[..32]))),
+                Arc::new(BinaryNode::Hash(H256::from_slice(&hashes[32..]))),
+            ];
+            Ok(BinaryNode::Branch { prefix, children })
+        }
+        _ => Err(TrieError::InvalidData),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{random_entries, Entries, FaultyDB, MemoryDB};
+
+    fn filled<D: DB>(db: Arc<D>, entries: &Entries) -> BinaryTrie<D> {
+        let mut trie = BinaryTrie::new(db);
+        for (key, value) in entries {
+            trie.put(key, value).unwrap();
+        }
+        trie
+    }
+
+    #[test]
+    fn commit_stores_the_trie_and_prunes_what_it_replaced() {
+        let entries = random_entries(1, 200);
+        let db = Arc::new(MemoryDB::new());
+        let mut trie = filled(db.clone(), &entries);
+        let root = trie.commit().unwrap();
+        let stored = db.len();
+
+        let mut trie = trie.at_root(root);
+        for (key, value) in &entries {
+            assert_eq!(trie.get(key).unwrap().as_ref(), Some(value));
+            let proof = trie.proof(key).unwrap();
+            assert_eq!(
+                BinaryTrie::<MemoryDB>::verify_proof(root, key, proof).unwrap().as_ref(),
+                Some(value)
+            );
+        }
+
+        // Writing the same values back replaces no node.
+        for (key, value) in &entries {
+            trie.put(key, value).unwrap();
+        }
+        assert_eq!(trie.commit().unwrap(), root);
+        assert_eq!(db.len(), stored);
+
+        for key in entries.keys() {
+            trie.del(key).unwrap();
+        }
+        assert_eq!(trie.commit().unwrap(), KeccakHasher::hash(&[]));
+        assert!(db.is_empty());
+    }
+
+    #[test]
+    fn failed_commit_keeps_the_changes_pending() {
+        let entries = random_entries(2, 200);
+        let db = Arc::new(FaultyDB::new());
+        let mut trie = filled(db.clone(), &entries);
+        let root = trie.commit().unwrap();
+        let stored = db.inner().len();
+
+        for key in entries.keys().step_by(2) {
+            trie.del(key).unwrap();
+        }
+        db.fail_writes(1);
+        assert!(trie.commit().is_err());
+        assert_eq!(db.inner().len(), stored);
+
+        let new_root = trie.commit().unwrap();
+        assert_ne!(new_root, root);
+        assert!(db.inner().len() < stored);
+
+        let trie = trie.at_root(new_root);
+        for (i, (key, value)) in entries.iter().enumerate() {
+            let expected = (i % 2 == 1).then_some(value);
+            assert_eq!(trie.get(key).unwrap().as_ref(), expected);
+        }
+    }
+}
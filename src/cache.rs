@@ -0,0 +1,47 @@
+use std::num::NonZeroUsize;
+
+use ethereum_types::H256;
+use lru::LruCache;
+use parking_lot::Mutex;
+
+use crate::node::Node;
+
+/// Default number of decoded nodes kept in memory by `EthTrie`.
+pub const DEFAULT_NODE_CACHE_CAPACITY: usize = 4096;
+
+/// LRU of nodes decoded from the database, keyed by hash, and a second one of the
+/// encodings of stored nodes built for proofs, so proving many keys encodes the
+/// nodes they share once.
+#[derive(Debug)]
+pub(crate) struct NodeCache {
+    // This is the only lock left on the read path, nodes themselves being immutable.
+    // `parking_lot` locks are not poisoned, so a thread panicking while holding it
+    // doesn't make every later lookup panic. It is only held for the LRU update.
+    nodes: Mutex<LruCache<H256, Node>>,
+    encodings: Mutex<LruCache<H256, Vec<u8>>>,
+}
+
+impl NodeCache {
+    pub fn new(capacity: NonZeroUsize) -> Self {
+        NodeCache {
+            nodes: Mutex::new(LruCache::new(capacity)),
+            encodings: Mutex::new(LruCache::new(capacity)),
+        }
+    }
+
+    pub fn get(&self, hash: &H256) -> Option<Node> {
+        self.nodes.lock().get(hash).cloned()
+    }
+
+    pub fn insert(&self, hash: H256, node: &Node) {
+        self.nodes.lock().put(hash, node.clone());
+    }
+
+    pub fn encoding(&self, hash: &H256) -> Option<Vec<u8>> {
+        self.encodings.lock().get(hash).cloned()
+    }
+
+    pub fn insert_encoding(&self, hash: H256, encoded: &[u8]) {
+        self.encodings.lock().put(hash, encoded.to_vec());
+    }
+}
@@ -0,0 +1,366 @@
+use ethereum_types::H256;
+
+use crate::codec::NodeCodec;
+use crate::db::DB;
+use crate::errors::TrieError;
+use crate::hasher::Hasher;
+use crate::node::{empty_children, Node};
+use crate::page::from_nibbles;
+use crate::trie::{EthTrie, ITrie, TrieResult};
+
+/// The keys written and deleted between two roots of a trie, as computed by
+/// `EthTrie::export_changeset`. Applying it with `apply_changeset` to a trie at
+/// `from`, in any database, brings that trie to `to`, so a replica can follow a
+/// primary without copying the whole trie again. With the `serde` feature it can be
+/// serialized to ship it between processes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ChangeSet {
+    pub from: H256,
+    pub to: H256,
+    /// Every key whose value differs, with its value at `to`, or `None` if it was
+    /// deleted, in key order.
+    pub changes: Vec<(Vec<u8>, Option<Vec<u8>>)>,
+}
+
+// Keys in order, with their new value or `None` if deleted.
+pub(crate) type Changes = Vec<(Vec<u8>, Option<Vec<u8>>)>;
+
+// Receives a changed key with its stored values before and after.
+pub(crate) type DiffFn<'a> =
+    dyn FnMut(Vec<u8>, Option<Vec<u8>>, Option<Vec<u8>>) -> TrieResult<()> + 'a;
+
+// The value stored at a subtree's own path and its subtrees one nibble further down.
+type Expanded = (Option<Vec<u8>>, [Subtree; 16]);
+
+impl ChangeSet {
+    /// Returns true if both roots hold the same entries.
+    pub fn is_empty(&self) -> bool {
+        self.changes.is_empty()
+    }
+}
+
+// A subtree reached `skip` nibbles into the key of `node`, when that key is the
+// path of a leaf or the prefix of an extension partly walked already.
+#[derive(Clone)]
+struct Subtree {
+    node: Node,
+    skip: usize,
+}
+
+impl Subtree {
+    fn new(node: Node) -> Self {
+        Subtree { node, skip: 0 }
+    }
+
+    // Stored subtrees are the same if they are stored under the same hash.
+    fn same_as(&self, other: &Subtree) -> bool {
+        match (&self.node, &other.node) {
+            (Node::Empty, Node::Empty) => true,
+            (a, b) => {
+                let hash = stored_hash(a);
+                self.skip == other.skip && hash.is_some() && hash == stored_hash(b)
+            }
+        }
+    }
+}
+
+fn stored_hash(node: &Node) -> Option<H256> {
+    match node {
+        Node::Hash(hash_node) => Some(hash_node.hash),
+        node => node.cached_hash(),
+    }
+}
+
+impl<D, H, C> EthTrie<D, H, C>
+where
+    D: DB,
+    H: Hasher,
+    C: NodeCodec,
+{
+    /// Computes the changes between the committed roots `from` and `to` of this
+    /// trie's database. Both tries are walked side by side and subtrees stored under
+    /// the same hash in both are skipped, so the cost follows the size of the change
+    /// rather than the size of the trie.
+    ///
+    /// Commits prune the nodes they replace, so the trie writing to the database must
+    /// be created `with_pruning(false)` for earlier roots to stay readable; otherwise
+    /// this fails with `TrieError::MissingTrieNode`.
+    pub fn export_changeset(&self, from: H256, to: H256) -> TrieResult<ChangeSet> {
+        let changes = self.at_root(to).changes_since(from)?;
+        Ok(ChangeSet { from, to, changes })
+    }
+
+    // The changes from the committed root `from` to this trie, including the ones
+    // not committed yet, in key order.
+    pub(crate) fn changes_since(&self, from: H256) -> TrieResult<Changes> {
+        let mut changes = vec![];
+        self.diff_since(from, &mut |key, _, new| {
+            let value = new.map(|v| self.load_value(v)).transpose()?;
+            changes.push((key, value));
+            Ok(())
+        })?;
+        Ok(changes)
+    }
+
+    // Calls `f` with every key whose value differs between the committed root `from`
+    // and this trie, in key order, along with its stored values in both.
+    pub(crate) fn diff_since(&self, from: H256, f: &mut DiffFn<'_>) -> TrieResult<()> {
+        let mut path = vec![];
+        self.diff_at(
+            Subtree::new(self.at_root(from).root),
+            Subtree::new(self.root.clone()),
+            &mut path,
+            f,
+        )
+    }
+
+    /// Applies `changeset` to this trie, commits, and returns the new root. The trie
+    /// must be at `changeset.from` with nothing pending, and ends at `changeset.to`;
+    /// either root differing fails with `TrieError::RootMismatch`. The root reached is
+    /// checked before committing, so on a mismatch, meaning the two tries don't encode
+    /// values the same way, the trie is left at `changeset.from` and no node is
+    /// written; only value blobs, stored as values are put, may remain.
+    pub fn apply_changeset(&mut self, changeset: &ChangeSet) -> TrieResult<H256> {
+        // Pending changes would be committed along with the changeset.
+        for found in [self.root_hash, self.current_root()] {
+            if found != changeset.from {
+                return Err(TrieError::RootMismatch {
+                    expected: changeset.from,
+                    found,
+                });
+            }
+        }
+
+        let root = self.root.clone();
+        // The changes stay in memory until the root they reach is checked.
+        let budget = self.memory_budget.take();
+        let applied = self.apply_changes(&changeset.changes).and_then(|()| {
+            let found = self.current_root();
+            if found != changeset.to {
+                return Err(TrieError::RootMismatch {
+                    expected: changeset.to,
+                    found,
+                });
+            }
+            Ok(())
+        });
+        self.memory_budget = budget;
+        if let Err(error) = applied {
+            self.discard_changes(root);
+            return Err(error);
+        }
+        self.commit()
+    }
+
+    fn apply_changes(&mut self, changes: &Changes) -> TrieResult<()> {
+        for (key, value) in changes {
+            match value {
+                Some(value) => self.put(key, value)?,
+                None => self.del(key)?,
+            }
+        }
+        Ok(())
+    }
+
+    // Reports the changes from `old` to `new`, both found at `path`, in key order.
+    fn diff_at(
+        &self,
+        old: Subtree,
+        new: Subtree,
+        path: &mut Vec<u8>,
+        f: &mut DiffFn<'_>,
+    ) -> TrieResult<()> {
+        if old.same_as(&new) {
+            return Ok(());
+        }
+
+        // A subtree left out by a lenient traversal can't be compared.
+        let Some((old_value, old_children)) = self.expand(old, path)? else {
+            return Ok(());
+        };
+        let Some((new_value, new_children)) = self.expand(new, path)? else {
+            return Ok(());
+        };
+        if old_value != new_value {
+            f(from_nibbles(path), old_value, new_value)?;
+        }
+
+        for (i, (old, new)) in old_children.into_iter().zip(new_children).enumerate() {
+            path.push(i as u8);
+            self.diff_at(old, new, path, f)?;
+            path.pop();
+        }
+        Ok(())
+    }
+
+    // Splits a subtree found at `path` into the value stored at its own path, if any,
+    // and the subtrees one nibble further down; `None` if a lenient traversal skips it.
+    fn expand(&self, subtree: Subtree, path: &[u8]) -> TrieResult<Option<Expanded>> {
+        let mut children = empty_children().map(Subtree::new);
+        let Subtree { node, skip } = subtree;
+        let value = match &node {
+            Node::Empty => None,
+            Node::Leaf(leaf) => match leaf.key.get_data()[skip..] {
+                // Only the terminator is left.
+                [_] => Some(leaf.value.clone()),
+                [nibble, ..] => {
+                    children[nibble as usize] = Subtree {
+                        node: node.clone(),
+                        skip: skip + 1,
+                    };
+                    None
+                }
+                [] => return Err(TrieError::InvalidData),
+            },
+            Node::Extension(ext) => match ext.prefix.get_data()[skip..] {
+                [] => return self.expand(Subtree::new(ext.node.clone()), path),
+                [nibble, ..] => {
+                    children[nibble as usize] = Subtree {
+                        node: node.clone(),
+                        skip: skip + 1,
+                    };
+                    None
+                }
+            },
+            Node::Branch(branch) => {
+                for (child, node) in children.iter_mut().zip(&branch.children) {
+                    child.node = node.clone();
+                }
+                branch.value.clone()
+            }
+            Node::Hash(hash_node) => {
+                return match self.load_or_skip(hash_node.hash, path)? {
+                    Some(node) => self.expand(Subtree { node, skip }, path),
+                    None => Ok(None),
+                };
+            }
+        };
+        Ok(Some((value, children)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::test_utils::{random_entries, reference_root, Entries, FaultyDB, MemoryDB};
+
+    // A trie keeping every root, at the root of `entries`, which it returns.
+    fn primary(entries: &Entries) -> (EthTrie<MemoryDB>, H256) {
+        let mut trie = EthTrie::new(Arc::new(MemoryDB::new())).with_pruning(false);
+        for (key, value) in entries {
+            trie.put(key, value).unwrap();
+        }
+        let root = trie.commit().unwrap();
+        (trie, root)
+    }
+
+    fn replica<D: DB>(db: Arc<D>, entries: &Entries) -> EthTrie<D> {
+        let mut trie = EthTrie::new(db);
+        for (key, value) in entries {
+            trie.put(key, value).unwrap();
+        }
+        trie.commit().unwrap();
+        trie
+    }
+
+    // Changes `entries` as a new block would: updates, deletions and new keys.
+    fn next_block(entries: &Entries) -> Entries {
+        let mut next: Entries = entries
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| i % 5 != 0)
+            .map(|(i, (key, value))| {
+                let value = if i % 3 == 0 {
+                    vec![i as u8; 40]
+                } else {
+                    value.clone()
+                };
+                (key.clone(), value)
+            })
+            .collect();
+        next.extend(random_entries(99, 50));
+        next
+    }
+
+    fn changeset(entries: &Entries) -> ChangeSet {
+        let (mut trie, from) = primary(entries);
+        let next = next_block(entries);
+        for key in entries.keys().filter(|key| !next.contains_key(*key)) {
+            trie.del(key).unwrap();
+        }
+        for (key, value) in &next {
+            trie.put(key, value).unwrap();
+        }
+        let to = trie.commit().unwrap();
+        trie.export_changeset(from, to).unwrap()
+    }
+
+    #[test]
+    fn apply_brings_a_replica_to_the_new_root() {
+        let entries = random_entries(1, 300);
+        let changeset = changeset(&entries);
+        assert_eq!(changeset.to, reference_root(&next_block(&entries)));
+
+        let mut trie = replica(Arc::new(MemoryDB::new()), &entries);
+        assert_eq!(trie.apply_changeset(&changeset).unwrap(), changeset.to);
+        for (key, value) in next_block(&entries) {
+            assert_eq!(trie.get(&key).unwrap(), Some(value));
+        }
+    }
+
+    #[test]
+    fn apply_at_another_root_fails_without_changes() {
+        let entries = random_entries(2, 200);
+        let changeset = changeset(&entries);
+
+        let mut trie = replica(Arc::new(MemoryDB::new()), &random_entries(3, 200));
+        let root = trie.root_hash;
+        assert!(matches!(
+            trie.apply_changeset(&changeset),
+            Err(TrieError::RootMismatch { .. })
+        ));
+        assert_eq!(trie.current_root(), root);
+    }
+
+    #[test]
+    fn apply_reaching_another_root_rolls_back() {
+        let entries = random_entries(4, 200);
+        let mut changeset = changeset(&entries);
+        let expected = changeset.to;
+        changeset.to = H256::repeat_byte(1);
+
+        let db = Arc::new(MemoryDB::new());
+        let mut trie = replica(db.clone(), &entries);
+        let stored = db.len();
+        assert!(matches!(
+            trie.apply_changeset(&changeset),
+            Err(TrieError::RootMismatch { found, .. }) if found == expected
+        ));
+        assert_eq!(trie.current_root(), changeset.from);
+        assert_eq!(db.len(), stored);
+
+        // Nothing is left pending, so the right changeset still applies.
+        changeset.to = expected;
+        assert_eq!(trie.apply_changeset(&changeset).unwrap(), expected);
+    }
+
+    #[test]
+    fn failed_commit_keeps_the_changes_pending() {
+        let entries = random_entries(5, 200);
+        let changeset = changeset(&entries);
+
+        let db = Arc::new(FaultyDB::new());
+        let mut trie = replica(db.clone(), &entries);
+        db.fail_writes(1);
+        assert!(trie.apply_changeset(&changeset).is_err());
+        assert_eq!(trie.commit().unwrap(), changeset.to);
+
+        let trie = EthTrie::new(db).at_root(changeset.to);
+        for (key, value) in next_block(&entries) {
+            assert_eq!(trie.get(&key).unwrap(), Some(value));
+        }
+    }
+}
@@ -0,0 +1,448 @@
+use alloc::vec;
+use alloc::vec::Vec;
+
+use ethereum_types::H256;
+use rlp::{Prototype, Rlp, RlpStream};
+
+use crate::errors::{TrieError, TrieResult};
+use crate::nibbles::Nibbles;
+
+/// How a child is referenced from its parent's encoding: by the hash it is stored
+/// under, or embedded as its own encoding when that is shorter than a hash.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChildRef<'a> {
+    Hash(H256),
+    Inline(&'a [u8]),
+}
+
+/// A node as decoded by a `NodeCodec`. Keys are given as nibbles (values 0 to 15)
+/// and children are left encoded; the trie decodes them again with the same codec,
+/// which must turn a hash reference into `DecodedNode::Hash`.
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DecodedNode<'a> {
+    Empty,
+    Leaf {
+        key: Vec<u8>,
+        value: Vec<u8>,
+    },
+    Extension {
+        prefix: Vec<u8>,
+        child: &'a [u8],
+    },
+    Branch {
+        children: [&'a [u8]; 16],
+        value: Option<Vec<u8>>,
+    },
+    Hash(H256),
+}
+
+/// The wire encoding of trie nodes. The trie hashes and stores whatever the codec
+/// produces, so roots, proofs and the database contents all depend on it.
+///
+/// `EthTrie` uses `RlpCodec`, Ethereum's encoding, by default.
+pub trait NodeCodec {
+    /// Nodes whose encoding is shorter than this many bytes are embedded in their
+    /// parent instead of being stored under their hash. Ethereum uses 32, the width
+    /// of a hash; 0 stores every node on its own. The root is always stored, and
+    /// empty slots are always embedded.
+    const INLINE_THRESHOLD: usize = 32;
+
+    /// The encoding of the empty trie, which is also used for empty branch slots.
+    fn encode_empty() -> Vec<u8>;
+
+    fn encode_leaf(key: &[u8], value: &[u8]) -> Vec<u8>;
+
+    fn encode_extension(prefix: &[u8], child: ChildRef<'_>) -> Vec<u8>;
+
+    fn encode_branch(children: &[ChildRef<'_>; 16], value: Option<&[u8]>) -> Vec<u8>;
+
+    /// Decodes a node, or a child reference found inside another node.
+    fn decode(data: &[u8]) -> TrieResult<DecodedNode<'_>>;
+}
+
+/// Bounds on the nodes `EthTrie` decodes, so corrupt or malicious data is rejected
+/// with `TrieError::InvalidData` instead of exhausting the stack or memory.
+///
+/// Children shorter than the codec's inline threshold are embedded in their parent and
+/// decoded along with it; `max_depth` bounds how deeply they may nest. `max_node_size` bounds the size
+/// of an encoded node, values included.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DecodeLimits {
+    pub max_depth: usize,
+    pub max_node_size: usize,
+}
+
+impl Default for DecodeLimits {
+    fn default() -> Self {
+        // With the default inline threshold, embedded nodes are shorter than 32 bytes,
+        // so valid nesting stays far below this depth. Large values are usually stored outside the trie.
+        DecodeLimits {
+            max_depth: 32,
+            max_node_size: 16 << 20,
+        }
+    }
+}
+
+/// Ethereum's RLP node encoding, with hex-prefix encoded keys.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RlpCodec;
+
+impl RlpCodec {
+    fn append_child(stream: &mut RlpStream, child: ChildRef<'_>) {
+        match child {
+            ChildRef::Hash(hash) => stream.append(&hash.as_bytes()),
+            ChildRef::Inline(data) => stream.append_raw(data, 1),
+        };
+    }
+}
+
+impl NodeCodec for RlpCodec {
+    fn encode_empty() -> Vec<u8> {
+        rlp::NULL_RLP.to_vec()
+    }
+
+    fn encode_leaf(key: &[u8], value: &[u8]) -> Vec<u8> {
+        let key = Nibbles::from_hex(&[key, &[16]].concat());
+        let mut stream = RlpStream::new_list(2);
+        stream.append(&key.encode_compact());
+        stream.append(&value);
+        stream.out().to_vec()
+    }
+
+    fn encode_extension(prefix: &[u8], child: ChildRef<'_>) -> Vec<u8> {
+        let mut stream = RlpStream::new_list(2);
+        stream.append(&Nibbles::from_hex(prefix).encode_compact());
+        Self::append_child(&mut stream, child);
+        stream.out().to_vec()
+    }
+
+    fn encode_branch(children: &[ChildRef<'_>; 16], value: Option<&[u8]>) -> Vec<u8> {
+        let mut stream = RlpStream::new_list(17);
+        for child in children {
+            Self::append_child(&mut stream, *child);
+        }
+
+        match value {
+            Some(v) => stream.append(&v),
+            None => stream.append_empty_data(),
+        };
+        stream.out().to_vec()
+    }
+
+    fn decode(data: &[u8]) -> TrieResult<DecodedNode<'_>> {
+        let r = Rlp::new(data);
+
+        match r.prototype()? {
+            Prototype::Data(0) => Ok(DecodedNode::Empty),
+            Prototype::List(2) => {
+                let key = Nibbles::try_from_compact(r.at(0)?.data()?)?;
+
+                if key.is_leaf() {
+                    let nibbles = key.get_data();
+                    Ok(DecodedNode::Leaf {
+                        key: nibbles[..nibbles.len() - 1].to_vec(),
+                        value: r.at(1)?.data()?.to_vec(),
+                    })
+                } else {
+                    Ok(DecodedNode::Extension {
+                        prefix: key.get_data().to_vec(),
+                        child: r.at(1)?.as_raw(),
+                    })
+                }
+            }
+            Prototype::List(17) => {
+                let mut children: [&[u8]; 16] = [&[]; 16];
+                for (i, child) in children.iter_mut().enumerate() {
+                    *child = r.at(i)?.as_raw();
+                }
+
+                // The last element is a value node.
+                let value_rlp = r.at(16)?;
+                let value = if value_rlp.is_empty() {
+                    None
+                } else {
+                    Some(value_rlp.data()?.to_vec())
+                };
+
+                Ok(DecodedNode::Branch { children, value })
+            }
+            _ => {
+                if r.is_data() && r.size() == 32 {
+                    Ok(DecodedNode::Hash(H256::from_slice(r.data()?)))
+                } else {
+                    Err(TrieError::InvalidData)
+                }
+            }
+        }
+    }
+}
+
+/// A node encoding made of field elements, to pair with `PoseidonHasher` so nodes can
+/// be hashed inside zk circuits without parsing RLP.
+///
+/// A node is a sequence of 31-byte big-endian words, the chunks `PoseidonHasher` packs
+/// into field elements, so each word is absorbed as exactly one element. The first
+/// word is a header: the node kind in its first byte, then the key length in nibbles
+/// and the value length as big-endian `u32`s, and a byte telling whether a branch has
+/// a value. The key follows, two nibbles to a byte, then the value, each padded with
+/// zeros to whole words. A branch gives its 16 children before its value, and an
+/// extension its child after its key, each as two words: a hash reference has the
+/// first byte of the hash in the last byte of its header and the other 31 bytes in the
+/// next word, and an empty child is the empty node followed by a zero word.
+///
+/// Every node but the empty one is stored under its hash, as the inline threshold is 0.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FieldCodec;
+
+impl FieldCodec {
+    const WORD: usize = 31;
+    // The width of a child in a branch or extension.
+    const CHILD: usize = 2 * Self::WORD;
+
+    const EMPTY: u8 = 0;
+    const LEAF: u8 = 1;
+    const EXTENSION: u8 = 2;
+    const BRANCH: u8 = 3;
+    const HASH: u8 = 4;
+
+    fn header(kind: u8, nibbles: usize, value: Option<&[u8]>) -> Vec<u8> {
+        let mut header = vec![0; Self::WORD];
+        header[0] = kind;
+        header[1..5].copy_from_slice(&(nibbles as u32).to_be_bytes());
+        if let Some(value) = value {
+            header[5..9].copy_from_slice(&(value.len() as u32).to_be_bytes());
+            header[9] = 1;
+        }
+        header
+    }
+
+    fn append_padded(out: &mut Vec<u8>, data: &[u8]) {
+        let padded = out.len() + data.len().div_ceil(Self::WORD) * Self::WORD;
+        out.extend_from_slice(data);
+        out.resize(padded, 0);
+    }
+
+    fn append_key(out: &mut Vec<u8>, nibbles: &[u8]) {
+        let packed: Vec<u8> = nibbles
+            .chunks(2)
+            .map(|pair| pair[0] << 4 | pair.get(1).copied().unwrap_or(0))
+            .collect();
+        Self::append_padded(out, &packed);
+    }
+
+    fn append_child(out: &mut Vec<u8>, child: ChildRef<'_>) {
+        match child {
+            ChildRef::Hash(hash) => {
+                let mut header = Self::header(Self::HASH, 0, None);
+                header[Self::WORD - 1] = hash[0];
+                out.extend_from_slice(&header);
+                out.extend_from_slice(&hash[1..]);
+            }
+            // Only the empty node is embedded, as it is shorter than a child.
+            ChildRef::Inline(data) => {
+                out.extend_from_slice(data);
+                out.resize(out.len() + Self::CHILD.saturating_sub(data.len()), 0);
+            }
+        }
+    }
+
+    // Splits `len` bytes, padded to whole words with zeros, off the front of `data`.
+    fn take_padded(data: &[u8], len: usize) -> TrieResult<(&[u8], &[u8])> {
+        let padded = len.div_ceil(Self::WORD) * Self::WORD;
+        if data.len() < padded || data[len..padded].iter().any(|b| *b != 0) {
+            return Err(TrieError::InvalidData);
+        }
+        Ok((&data[..len], &data[padded..]))
+    }
+
+    fn take_key(data: &[u8], nibbles: usize) -> TrieResult<(Vec<u8>, &[u8])> {
+        let (packed, rest) = Self::take_padded(data, nibbles.div_ceil(2))?;
+        let key = packed
+            .iter()
+            .flat_map(|byte| [byte >> 4, byte & 0x0f])
+            .take(nibbles)
+            .collect();
+        Ok((key, rest))
+    }
+}
+
+impl NodeCodec for FieldCodec {
+    const INLINE_THRESHOLD: usize = 0;
+
+    fn encode_empty() -> Vec<u8> {
+        Self::header(Self::EMPTY, 0, None)
+    }
+
+    fn encode_leaf(key: &[u8], value: &[u8]) -> Vec<u8> {
+        let mut out = Self::header(Self::LEAF, key.len(), Some(value));
+        Self::append_key(&mut out, key);
+        Self::append_padded(&mut out, value);
+        out
+    }
+
+    fn encode_extension(prefix: &[u8], child: ChildRef<'_>) -> Vec<u8> {
+        let mut out = Self::header(Self::EXTENSION, prefix.len(), None);
+        Self::append_key(&mut out, prefix);
+        Self::append_child(&mut out, child);
+        out
+    }
+
+    fn encode_branch(children: &[ChildRef<'_>; 16], value: Option<&[u8]>) -> Vec<u8> {
+        let mut out = Self::header(Self::BRANCH, 0, value);
+        for child in children {
+            Self::append_child(&mut out, *child);
+        }
+        if let Some(value) = value {
+            Self::append_padded(&mut out, value);
+        }
+        out
+    }
+
+    fn decode(data: &[u8]) -> TrieResult<DecodedNode<'_>> {
+        if data.len() < Self::WORD || !data.len().is_multiple_of(Self::WORD) {
+            return Err(TrieError::InvalidData);
+        }
+        let (header, body) = data.split_at(Self::WORD);
+        let be_u32 = |at: usize| {
+            u32::from_be_bytes([header[at], header[at + 1], header[at + 2], header[at + 3]])
+                as usize
+        };
+        let nibbles = be_u32(1);
+        let value_len = be_u32(5);
+        let has_value = header[9] == 1;
+
+        let node = match header[0] {
+            Self::EMPTY if data.len() <= Self::CHILD && data.iter().all(|b| *b == 0) => {
+                DecodedNode::Empty
+            }
+            Self::HASH if body.len() == Self::WORD => {
+                let mut hash = [0; 32];
+                hash[0] = header[Self::WORD - 1];
+                hash[1..].copy_from_slice(body);
+                DecodedNode::Hash(H256(hash))
+            }
+            Self::LEAF => {
+                let (key, rest) = Self::take_key(body, nibbles)?;
+                let (value, rest) = Self::take_padded(rest, value_len)?;
+                if !rest.is_empty() {
+                    return Err(TrieError::InvalidData);
+                }
+                DecodedNode::Leaf {
+                    key,
+                    value: value.to_vec(),
+                }
+            }
+            Self::EXTENSION => {
+                let (prefix, child) = Self::take_key(body, nibbles)?;
+                if child.len() != Self::CHILD {
+                    return Err(TrieError::InvalidData);
+                }
+                DecodedNode::Extension { prefix, child }
+            }
+            Self::BRANCH if body.len() >= 16 * Self::CHILD => {
+                let (slots, rest) = body.split_at(16 * Self::CHILD);
+                let mut children: [&[u8]; 16] = [&[]; 16];
+                for (child, slot) in children.iter_mut().zip(slots.chunks(Self::CHILD)) {
+                    *child = slot;
+                }
+                let (value, rest) = Self::take_padded(rest, value_len)?;
+                if !rest.is_empty() || (!has_value && !value.is_empty()) {
+                    return Err(TrieError::InvalidData);
+                }
+                let value = has_value.then(|| value.to_vec());
+                DecodedNode::Branch { children, value }
+            }
+            _ => return Err(TrieError::InvalidData),
+        };
+        Ok(node)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::hasher::{Hasher, KeccakHasher};
+    use crate::test_utils::{check_root, random_entries, MemoryDB};
+    use crate::trie::{EthTrie, ITrie};
+    use crate::verify::verify_proof_with;
+
+    #[test]
+    fn field_nodes_decode_to_what_was_encoded() {
+        let empty = FieldCodec::encode_empty();
+        let hash = ChildRef::Hash(KeccakHasher::hash(b"child"));
+
+        let leaf = FieldCodec::encode_leaf(&[1, 2, 3], &[7; 40]);
+        let extension = FieldCodec::encode_extension(&[4, 5], hash);
+        let mut children = [ChildRef::Inline(&empty); 16];
+        children[3] = hash;
+        let branch = FieldCodec::encode_branch(&children, Some(b"value"));
+
+        for node in [&empty, &leaf, &extension, &branch] {
+            assert!(node.len().is_multiple_of(FieldCodec::WORD));
+        }
+        assert_eq!(FieldCodec::decode(&empty).unwrap(), DecodedNode::Empty);
+        assert_eq!(
+            FieldCodec::decode(&leaf).unwrap(),
+            DecodedNode::Leaf {
+                key: vec![1, 2, 3],
+                value: vec![7; 40],
+            }
+        );
+        match FieldCodec::decode(&extension).unwrap() {
+            DecodedNode::Extension { prefix, child } => {
+                assert_eq!(prefix, vec![4, 5]);
+                assert_eq!(
+                    FieldCodec::decode(child).unwrap(),
+                    DecodedNode::Hash(KeccakHasher::hash(b"child"))
+                );
+            }
+            node => panic!("decoded {:?}", node),
+        }
+        match FieldCodec::decode(&branch).unwrap() {
+            DecodedNode::Branch { children, value } => {
+                assert_eq!(value.as_deref(), Some(&b"value"[..]));
+                for (i, child) in children.iter().enumerate() {
+                    let expected = match i {
+                        3 => DecodedNode::Hash(KeccakHasher::hash(b"child")),
+                        _ => DecodedNode::Empty,
+                    };
+                    assert_eq!(FieldCodec::decode(child).unwrap(), expected);
+                }
+            }
+            node => panic!("decoded {:?}", node),
+        }
+
+        let mut damaged = leaf.clone();
+        damaged.pop();
+        assert!(FieldCodec::decode(&damaged).is_err());
+    }
+
+    fn check_field_trie<H: Hasher>(count: usize) {
+        let entries = random_entries(1, count);
+        let mut trie = EthTrie::<_, H, FieldCodec>::new_with_hasher(Arc::new(MemoryDB::new()));
+        for (key, value) in &entries {
+            trie.put(key, value).unwrap();
+        }
+        let root = check_root(&mut trie, &entries).unwrap();
+        for (key, value) in &entries {
+            let proof = trie.proof(key).unwrap();
+            let proven = verify_proof_with::<H, FieldCodec>(root, key, &proof).unwrap();
+            assert_eq!(proven.as_ref(), Some(value));
+        }
+    }
+
+    #[test]
+    fn field_tries_match_the_reference_with_keccak() {
+        check_field_trie::<KeccakHasher>(200);
+    }
+
+    #[cfg(feature = "poseidon")]
+    #[test]
+    fn field_tries_match_the_reference_with_poseidon() {
+        // Poseidon is slow outside a circuit, so fewer entries.
+        check_field_trie::<crate::hasher::PoseidonHasher>(20);
+    }
+}
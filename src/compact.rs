@@ -0,0 +1,49 @@
+use std::collections::HashSet;
+use std::fs;
+use std::io;
+
+use ethereum_types::H256;
+
+use crate::codec::NodeCodec;
+use crate::db::SqliteDB;
+use crate::errors::TrieError;
+use crate::hasher::Hasher;
+use crate::trie::{EthTrie, TrieResult};
+
+// The file next to the database that the nodes to keep are copied to.
+const COMPACTION_SUFFIX: &str = ".compact";
+
+impl<H, C> EthTrie<SqliteDB, H, C>
+where
+    H: Hasher,
+    C: NodeCodec,
+{
+    /// Rewrites the database keeping only the nodes reachable from `roots`, and the
+    /// value blobs they refer to, to reclaim the space of every other node, however
+    /// it was left behind: commits made without pruning, roots dropped from the
+    /// version index, or subtrees pruning missed. No reference counts are needed.
+    ///
+    /// The nodes to keep are first copied to a fresh database in the file next to
+    /// this one with `.compact` appended to its name, then swapped in for the nodes
+    /// and blobs of this one in a single transaction, and the file is vacuumed so it
+    /// shrinks. Metadata, and rows of the trie table that aren't nodes, are kept.
+    /// Nodes committed by another trie while compacting, and not reachable from
+    /// `roots`, are lost, so nothing else should commit meanwhile.
+    pub fn compact(&self, roots: &[H256]) -> TrieResult<()> {
+        let compacted = self.db.sibling(COMPACTION_SUFFIX);
+        remove_file(compacted.path())?;
+        let (mut nodes, mut blobs) = (HashSet::new(), HashSet::new());
+        for &root in roots {
+            self.copy_reachable(&compacted, root, &mut nodes, &mut blobs)?;
+        }
+        self.db.swap_in(&compacted).map_err(TrieError::backend)?;
+        remove_file(compacted.path())
+    }
+}
+
+fn remove_file(path: &str) -> TrieResult<()> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
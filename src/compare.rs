@@ -0,0 +1,152 @@
+use std::collections::HashSet;
+
+use ethereum_types::H256;
+
+use crate::codec::NodeCodec;
+use crate::db::DB;
+use crate::errors::TrieError;
+use crate::hasher::Hasher;
+use crate::trie::{EthTrie, TrieResult};
+
+// Entries are compared by `equivalent` a page at a time.
+const EQUIVALENT_PAGE_SIZE: usize = 1024;
+
+/// A node or value blob reachable from a root that two databases don't agree on, as
+/// found by `EthTrie::compare_with`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Mismatch {
+    /// The hash the node or blob is stored under.
+    pub hash: H256,
+    /// Whether it is a value blob rather than a node.
+    pub blob: bool,
+    pub kind: MismatchKind,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MismatchKind {
+    /// Stored in the trie's database but not in the other one.
+    MissingInOther,
+    /// Stored in the other database but not in the trie's.
+    MissingHere,
+    /// Stored in both, with different data.
+    Differs,
+}
+
+impl<D, H, C> EthTrie<D, H, C>
+where
+    D: DB,
+    H: Hasher,
+    C: NodeCodec,
+{
+    /// Whether the trie holds the same keys and values as `other`, changes not
+    /// committed yet included. Unlike `==`, which compares roots, it works between
+    /// tries hashing or encoding their nodes differently, by reading both in key order
+    /// a page at a time, up to the first difference.
+    pub fn equivalent<D2, H2, C2>(&self, other: &EthTrie<D2, H2, C2>) -> TrieResult<bool>
+    where
+        D2: DB,
+        H2: Hasher,
+        C2: NodeCodec,
+    {
+        let (mut mine, mut theirs) = (None, None);
+        loop {
+            let page = self.iter_page(mine.as_ref(), EQUIVALENT_PAGE_SIZE)?;
+            let other_page = other.iter_page(theirs.as_ref(), EQUIVALENT_PAGE_SIZE)?;
+            if page.entries != other_page.entries {
+                return Ok(false);
+            }
+            match (page.cursor, other_page.cursor) {
+                (Some(next), Some(other_next)) => {
+                    mine = Some(next);
+                    theirs = Some(other_next);
+                }
+                (None, None) => return Ok(true),
+                _ => return Ok(false),
+            }
+        }
+    }
+
+    /// Walks every node and value blob reachable from the last committed root in both
+    /// the trie's database and `other`, and returns those that are missing from one
+    /// of them or stored differently, for instance to check that a migration to
+    /// another backend lost nothing. The subtree below a node stored in only one of
+    /// them is walked as it is stored there, and below a node stored differently, as
+    /// the trie's database stores it.
+    ///
+    /// Fails with `TrieError::MissingTrieNode`, or `TrieError::NotFound` for a blob,
+    /// if something reachable is stored in neither database.
+    pub fn compare_with<O: DB>(&self, other: &O) -> TrieResult<Vec<Mismatch>> {
+        let mut mismatches = vec![];
+        let blobs = self.walk_stored(
+            self.root_hash,
+            &mut HashSet::new(),
+            |hash| {
+                let here = self.stored_node(hash)?;
+                let there = other.get(hash.as_bytes()).map_err(TrieError::backend)?;
+                let kind = match (&here, &there) {
+                    (Some(here), Some(there)) if here == there => None,
+                    (Some(_), Some(_)) => Some(MismatchKind::Differs),
+                    (Some(_), None) => Some(MismatchKind::MissingInOther),
+                    (None, Some(_)) => Some(MismatchKind::MissingHere),
+                    (None, None) => None,
+                };
+                if let Some(kind) = kind {
+                    mismatches.push(Mismatch {
+                        hash,
+                        blob: false,
+                        kind,
+                    });
+                }
+                Ok(here.or(there))
+            },
+            |_, _| Ok(()),
+        )?;
+
+        for hash in blobs {
+            self.counters.db_read();
+            let here = self
+                .db
+                .get_blob(hash.as_bytes())
+                .map_err(TrieError::backend)?;
+            let there = other
+                .get_blob(hash.as_bytes())
+                .map_err(TrieError::backend)?;
+            let kind = match (here, there) {
+                (Some(here), Some(there)) if here == there => continue,
+                (Some(_), Some(_)) => MismatchKind::Differs,
+                (Some(_), None) => MismatchKind::MissingInOther,
+                (None, Some(_)) => MismatchKind::MissingHere,
+                (None, None) => return Err(TrieError::NotFound { hash }),
+            };
+            mismatches.push(Mismatch {
+                hash,
+                blob: true,
+                kind,
+            });
+        }
+        Ok(mismatches)
+    }
+}
+
+/// Tries are equal when their roots are, taken as they stand: changes not committed
+/// yet are hashed without being committed, so tries built independently compare
+/// equal once they hold the same keys and values, whatever databases they use.
+impl<D, D2, H, C> PartialEq<EthTrie<D2, H, C>> for EthTrie<D, H, C>
+where
+    D: DB,
+    D2: DB,
+    H: Hasher,
+    C: NodeCodec,
+{
+    fn eq(&self, other: &EthTrie<D2, H, C>) -> bool {
+        self.current_root() == other.current_root()
+    }
+}
+
+impl<D, H, C> Eq for EthTrie<D, H, C>
+where
+    D: DB,
+    H: Hasher,
+    C: NodeCodec,
+{
+}
@@ -0,0 +1,172 @@
+use std::collections::HashSet;
+use std::mem;
+use std::sync::Arc;
+
+use ethereum_types::H256;
+
+use crate::codec::NodeCodec;
+use crate::db::DB;
+use crate::errors::TrieError;
+use crate::hasher::Hasher;
+use crate::trie::{EthTrie, TrieResult};
+
+// Nodes are written to the target in batches of this many.
+const COPY_BATCH_SIZE: usize = 1024;
+
+impl<D, H, C> EthTrie<D, H, C>
+where
+    D: DB,
+    H: Hasher,
+    C: NodeCodec,
+{
+    /// Copies every node reachable from the committed root `root`, and the value
+    /// blobs they refer to, from this trie's database to `target`, and returns the
+    /// trie at `root` in `target`, with this trie's settings. Nodes are written in
+    /// batches as they are read, so the trie never has to fit in memory.
+    ///
+    /// Only committed nodes are read, so the trie can keep changing meanwhile, for a
+    /// migration to another backend while it stays in use, provided the nodes of
+    /// `root` stay stored: with pruning on, the default, a commit made during the copy
+    /// removes the nodes it replaces and the copy fails with
+    /// `TrieError::MissingTrieNode`. Copy from a trie built `with_pruning(false)`, or
+    /// make sure no commit meanwhile builds on `root`.
+    ///
+    /// The copy is then checked by reading every node and blob back from `target`
+    /// and hashing it: one missing fails with `TrieError::MissingTrieNode`, or
+    /// `TrieError::NotFound` for a blob, and one stored differently with
+    /// `TrieError::Corrupted`.
+    pub fn copy_to<D2: DB>(&self, target: Arc<D2>, root: H256) -> TrieResult<EthTrie<D2, H, C>> {
+        self.copy_reachable(&*target, root, &mut HashSet::new(), &mut HashSet::new())?;
+        target.flush().map_err(TrieError::backend)?;
+
+        let trie = self.at_root_in(target, root);
+        trie.check_stored(root)?;
+        Ok(trie)
+    }
+
+    // Copies the nodes and blobs reachable from `root` to `target`, skipping the
+    // ones already copied, as recorded in `nodes_copied` and `blobs_copied`.
+    pub(crate) fn copy_reachable<D2: DB>(
+        &self,
+        target: &D2,
+        root: H256,
+        nodes_copied: &mut HashSet<H256>,
+        blobs_copied: &mut HashSet<H256>,
+    ) -> TrieResult<()> {
+        let (mut keys, mut values) = (vec![], vec![]);
+        let blobs = self.walk_stored(
+            root,
+            nodes_copied,
+            |hash| self.stored_node(hash),
+            |hash, node| {
+                keys.push(hash.as_bytes().to_vec());
+                values.push(node);
+                if keys.len() == COPY_BATCH_SIZE {
+                    target
+                        .insert_batch(mem::take(&mut keys), mem::take(&mut values))
+                        .map_err(TrieError::backend)?;
+                }
+                Ok(())
+            },
+        )?;
+        if !keys.is_empty() {
+            target
+                .insert_batch(keys, values)
+                .map_err(TrieError::backend)?;
+        }
+
+        for hash in blobs {
+            if !blobs_copied.insert(hash) {
+                continue;
+            }
+            self.counters.db_read();
+            let blob = self
+                .db
+                .get_blob(hash.as_bytes())
+                .map_err(TrieError::backend)?
+                .ok_or(TrieError::NotFound { hash })?;
+            target
+                .insert_blob(hash.as_bytes(), blob)
+                .map_err(TrieError::backend)?;
+        }
+        Ok(())
+    }
+
+    // Reads every node and blob reachable from `root` and checks that each is
+    // stored under its own hash.
+    fn check_stored(&self, root: H256) -> TrieResult<()> {
+        let blobs = self.walk_stored(
+            root,
+            &mut HashSet::new(),
+            |hash| self.stored_node(hash),
+            |hash, node| {
+                self.counters.hashed();
+                if H::hash(&node) != hash {
+                    return Err(TrieError::Corrupted {
+                        hash,
+                        reason: "node does not match its hash",
+                    });
+                }
+                Ok(())
+            },
+        )?;
+
+        for hash in blobs {
+            self.counters.db_read();
+            let blob = self
+                .db
+                .get_blob(hash.as_bytes())
+                .map_err(TrieError::backend)?
+                .ok_or(TrieError::NotFound { hash })?;
+            self.counters.hashed();
+            if H::hash(&blob) != hash {
+                return Err(TrieError::Corrupted {
+                    hash,
+                    reason: "value blob does not match its hash",
+                });
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{random_entries, MemoryDB};
+    use crate::trie::ITrie;
+
+    #[test]
+    fn copy_holds_the_same_nodes() {
+        let entries = random_entries(1, 300);
+        let mut trie = EthTrie::new(Arc::new(MemoryDB::new())).with_large_values(16);
+        for (key, value) in &entries {
+            trie.put(key, value).unwrap();
+        }
+        let root = trie.commit().unwrap();
+
+        let target = Arc::new(MemoryDB::new());
+        let copy = trie.copy_to(target.clone(), root).unwrap();
+        assert!(trie.compare_with(&*target).unwrap().is_empty());
+        for (key, value) in &entries {
+            assert_eq!(copy.get(key).unwrap().as_ref(), Some(value));
+        }
+    }
+
+    #[test]
+    fn copy_of_a_pruned_root_fails() {
+        let entries = random_entries(2, 100);
+        let mut trie = EthTrie::new(Arc::new(MemoryDB::new()));
+        for (key, value) in &entries {
+            trie.put(key, value).unwrap();
+        }
+        let root = trie.commit().unwrap();
+        trie.put(b"key", b"value").unwrap();
+        trie.commit().unwrap();
+
+        assert!(matches!(
+            trie.copy_to(Arc::new(MemoryDB::new()), root),
+            Err(TrieError::MissingTrieNode { .. })
+        ));
+    }
+}
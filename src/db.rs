@@ -1,15 +1,30 @@
+use std::collections::HashMap;
 use std::error::Error;
-use rusqlite::{params, Connection, Result};
-use crate::errors::TrieError;
+use std::thread;
+use std::time::Duration;
+
+use ethereum_types::H256;
+use rusqlite::{params_from_iter, Connection, ErrorCode, OptionalExtension, Result};
 
 /// "DB" defines the "trait" of trie and database interaction.
 /// You should first write the data to the cache and write the data
 /// to the database in bulk after the end of a set of operations.
 pub trait DB: Send + Sync {
-    type Error: Error;
+    type Error: Error + Send + Sync + 'static;
 
     fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Self::Error>;
 
+    /// Get the data stored under each of `keys`, in the same order. Backends that
+    /// can should read them all in one round trip.
+    fn multi_get(&self, keys: &[Vec<u8>]) -> Result<Vec<Option<Vec<u8>>>, Self::Error> {
+        keys.iter().map(|key| self.get(key)).collect()
+    }
+
+    /// Whether data is stored under `key`, without reading it.
+    fn contains(&self, key: &[u8]) -> Result<bool, Self::Error> {
+        self.get(key).map(|value| value.is_some())
+    }
+
     /// Insert data into the cache.
     fn insert(&self, key: &[u8], value: Vec<u8>) -> Result<(), Self::Error>;
 
@@ -37,96 +52,449 @@ pub trait DB: Send + Sync {
     /// Flush data to the DB from the cache.
     fn flush(&self) -> Result<(), Self::Error>;
 
+    /// Get a large value stored outside of the trie nodes by its hash.
+    /// By default blobs share the node keyspace under a `blob:` prefix.
+    fn get_blob(&self, hash: &[u8]) -> Result<Option<Vec<u8>>, Self::Error> {
+        self.get(&[BLOB_KEY_PREFIX, hash].concat())
+    }
+
+    /// Store a large value outside of the trie nodes under its hash.
+    fn insert_blob(&self, hash: &[u8], value: Vec<u8>) -> Result<(), Self::Error> {
+        self.insert(&[BLOB_KEY_PREFIX, hash].concat(), value)
+    }
+
+    /// Get metadata kept next to the trie, such as the version index. By default
+    /// metadata shares the node keyspace under a `meta:` prefix.
+    fn get_meta(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Self::Error> {
+        self.get(&[META_KEY_PREFIX, key].concat())
+    }
+
+    /// Store metadata, replacing any value already stored under `key`.
+    fn insert_meta(&self, key: &[u8], value: Vec<u8>) -> Result<(), Self::Error> {
+        self.insert(&[META_KEY_PREFIX, key].concat(), value)
+    }
+
+    /// Remove the metadata stored under `key`.
+    fn remove_meta(&self, key: &[u8]) -> Result<(), Self::Error> {
+        self.remove(&[META_KEY_PREFIX, key].concat())
+    }
+
+    /// Write everything a commit stores: its new nodes, the removal of the nodes it
+    /// pruned and the metadata it changes, such as what pre-commit hooks add. Backends
+    /// that can should write them in a single transaction.
+    fn commit_batch(&self, batch: CommitBatch) -> Result<(), Self::Error> {
+        self.insert_batch(batch.keys, batch.values)?;
+        self.remove_batch(&batch.removed)?;
+        for key in batch.removed_meta {
+            self.remove_meta(&key)?;
+        }
+        for (key, value) in batch.meta {
+            self.insert_meta(&key, value)?;
+        }
+        Ok(())
+    }
+
+    /// How much the database holds, or `None` if the backend doesn't keep track.
+    fn stats(&self) -> Result<Option<StorageStats>, Self::Error> {
+        Ok(None)
+    }
+
+    /// Calls `f` with the key and data of every node stored, in no particular order,
+    /// for tools scanning the whole database such as `EthTrie::recover_from_leaves`.
+    /// Blobs and metadata are left out. Returns false without calling `f` if the
+    /// backend can't list what it holds, which is the default.
+    fn iter_nodes(&self, f: &mut dyn FnMut(&[u8], &[u8])) -> Result<bool, Self::Error> {
+        let _ = f;
+        Ok(false)
+    }
+}
+
+/// Everything a commit writes, as handed to pre-commit hooks and `DB::commit_batch`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CommitBatch {
+    /// The root being committed.
+    pub root: H256,
+    /// The new nodes, with `values`, under their hashes.
+    pub keys: Vec<Vec<u8>>,
+    pub values: Vec<Vec<u8>>,
+    /// The nodes pruned.
+    pub removed: Vec<Vec<u8>>,
+    /// Metadata to store with the nodes, replacing any value already stored under
+    /// the same key.
+    pub meta: Vec<(Vec<u8>, Vec<u8>)>,
+    /// Metadata to remove with the nodes. Removals are applied before `meta`, so a
+    /// key in both ends up with the value in `meta`.
+    pub removed_meta: Vec<Vec<u8>>,
 }
 
-#[derive(Default, Debug)]
+// Metadata written with a commit, as it goes in `CommitBatch::meta`.
+pub(crate) type Meta = Vec<(Vec<u8>, Vec<u8>)>;
+
+/// How much a `DB` holds, as returned by `DB::stats`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct StorageStats {
+    /// The number of trie nodes stored.
+    pub nodes: u64,
+    /// The size of the keys and data stored, counting blobs and metadata too.
+    pub bytes: u64,
+    /// The pages of the database file, for backends storing data in pages.
+    pub pages: Option<PageStats>,
+}
+
+/// The pages of a database file, as reported by SQLite.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct PageStats {
+    pub page_size: u64,
+    pub page_count: u64,
+    /// Pages left unused by deletions, which SQLite reuses before growing the file.
+    pub freelist_count: u64,
+}
+
+pub(crate) const BLOB_KEY_PREFIX: &[u8] = b"blob:";
+pub(crate) const META_KEY_PREFIX: &[u8] = b"meta:";
+
+// SQLite waits this long on a locked database before reporting it busy.
+const DEFAULT_BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+// Then operations are retried this many times, first after this long.
+pub(crate) const DEFAULT_RETRIES: u32 = 3;
+pub(crate) const DEFAULT_RETRY_BACKOFF: Duration = Duration::from_millis(50);
+// Keys read by one query of `multi_get`, well below SQLite's limit on parameters.
+const MULTI_GET_CHUNK_SIZE: usize = 500;
+
+#[derive(Debug)]
 pub struct SqliteDB {
     db_name: String,
+    busy_timeout: Duration,
+    retries: u32,
+    retry_backoff: Duration,
 }
 
-#[derive(Debug)]
-struct NodeDB {
-    key: Vec<u8>,
-    data: Option<Vec<u8>>,
+impl Default for SqliteDB {
+    fn default() -> Self {
+        SqliteDB::new(String::new())
+    }
 }
 
 impl SqliteDB {
     pub fn new(db_name: String) -> Self {
-        return SqliteDB {
-            db_name: String::from(db_name)
+        SqliteDB {
+            db_name,
+            busy_timeout: DEFAULT_BUSY_TIMEOUT,
+            retries: DEFAULT_RETRIES,
+            retry_backoff: DEFAULT_RETRY_BACKOFF,
+        }
+    }
+
+    /// Sets how long SQLite waits for another connection, possibly in another process,
+    /// to release a locked database before giving up, 5 seconds by default.
+    pub fn with_busy_timeout(mut self, timeout: Duration) -> Self {
+        self.busy_timeout = timeout;
+        self
+    }
+
+    /// Sets how many times an operation that found the database locked is retried,
+    /// waiting `backoff` before the first retry and twice as long before each next
+    /// one. Once they are exhausted the trie fails with `TrieError::Busy`. By default
+    /// operations are retried 3 times, from 50 milliseconds.
+    pub fn with_retries(mut self, retries: u32, backoff: Duration) -> Self {
+        self.retries = retries;
+        self.retry_backoff = backoff;
+        self
+    }
+
+    // Runs `f` on a new connection, and again while the database is locked, as long
+    // as retries are left.
+    fn with_connection<T>(&self, mut f: impl FnMut(&mut Connection) -> Result<T>) -> Result<T> {
+        let mut backoff = self.retry_backoff;
+        let mut retries = 0;
+        loop {
+            match self.open().and_then(|mut conn| f(&mut conn)) {
+                Err(error) if is_busy(&error) && retries < self.retries => {
+                    thread::sleep(backoff);
+                    backoff = backoff.saturating_mul(2);
+                    retries += 1;
+                }
+                result => return result,
+            }
         }
     }
+
+    pub(crate) fn path(&self) -> &str {
+        &self.db_name
+    }
+
+    // A database in the file named like this one's followed by `suffix`, with the
+    // same settings.
+    pub(crate) fn sibling(&self, suffix: &str) -> SqliteDB {
+        SqliteDB {
+            db_name: format!("{}{suffix}", self.db_name),
+            ..*self
+        }
+    }
+
+    // Replaces the nodes and blobs of this database with those of `compacted`, in
+    // one transaction, and vacuums the file to give the space freed back. Rows of
+    // the trie table whose key isn't a hash aren't nodes and are kept.
+    pub(crate) fn swap_in(&self, compacted: &SqliteDB) -> Result<()> {
+        self.with_connection(|conn| {
+            create_table(conn, "trie", "key")?;
+            create_table(conn, "blob", "hash")?;
+            conn.execute("ATTACH DATABASE ?1 AS compacted", [&compacted.db_name])?;
+            create_table(conn, "compacted.trie", "key")?;
+            create_table(conn, "compacted.blob", "hash")?;
+
+            let tx = conn.transaction()?;
+            tx.execute("DELETE FROM trie WHERE LENGTH(key) = 32", ())?;
+            tx.execute("INSERT OR REPLACE INTO trie SELECT key, data FROM compacted.trie", ())?;
+            tx.execute("DELETE FROM blob", ())?;
+            tx.execute("INSERT INTO blob SELECT hash, data FROM compacted.blob", ())?;
+            tx.commit()?;
+
+            conn.execute("DETACH DATABASE compacted", ())?;
+            conn.execute("VACUUM", ())?;
+            Ok(())
+        })
+    }
+
+    fn open(&self) -> Result<Connection> {
+        let conn = Connection::open(&self.db_name)?;
+        conn.busy_timeout(self.busy_timeout)?;
+        Ok(conn)
+    }
 }
 
-// TODO catch all errors
 impl DB for SqliteDB {
-    type Error = TrieError;
+    type Error = rusqlite::Error;
 
     fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Self::Error> {
-        let conn = Connection::open(self.db_name.clone()).unwrap();
-
-        _ = conn.execute(
-            "CREATE TABLE trie (
-                key BLOB PRIMARY KEY,
-                data BLOB
-            )",
-            (), // empty list of parameters.
-        );
-
-        // let mut stmt = conn.prepare("SELECT key, data FROM trie").unwrap();
-        // let node_iter = stmt.query_map([], |row| {
-        //     Ok(NodeDB {
-        //         key: row.get(0)?,
-        //         data: row.get(1)?,
-        //     })
-        // }).unwrap();
-
-        let mut stmt = conn.prepare("SELECT key, data FROM trie WHERE key=?1").unwrap();
-        let node_iter = stmt.query_map([key], |row| {
-            Ok(NodeDB {
-                key: row.get(0)?,
-                data: row.get(1)?,
-            })
-        }).unwrap();
-        
-        for node in node_iter {
-            return Ok(node.unwrap().data.clone());
+        self.with_connection(|conn| {
+            create_table(conn, "trie", "key")?;
+            conn.query_row("SELECT data FROM trie WHERE key=?1", [key], |row| row.get(0))
+                .optional()
+                .map(Option::flatten)
+        })
+    }
+
+    /// Reads the keys with one `SELECT ... IN` query per chunk of keys.
+    fn multi_get(&self, keys: &[Vec<u8>]) -> Result<Vec<Option<Vec<u8>>>, Self::Error> {
+        if keys.is_empty() {
+            return Ok(vec![]);
         }
+        self.with_connection(|conn| {
+            create_table(conn, "trie", "key")?;
 
-        return Err(TrieError::SqliteDB{0:String::from("db error")});
+            let mut found = HashMap::new();
+            for chunk in keys.chunks(MULTI_GET_CHUNK_SIZE) {
+                let params = vec!["?"; chunk.len()].join(", ");
+                let mut stmt =
+                    conn.prepare(&format!("SELECT key, data FROM trie WHERE key IN ({params})"))?;
+                let rows = stmt.query_map(params_from_iter(chunk), |row| {
+                    Ok((row.get::<_, Vec<u8>>(0)?, row.get::<_, Option<Vec<u8>>>(1)?))
+                })?;
+                for row in rows {
+                    let (key, data) = row?;
+                    found.insert(key, data);
+                }
+            }
+            Ok(keys.iter().map(|key| found.get(key).cloned().flatten()).collect())
+        })
     }
 
+    fn contains(&self, key: &[u8]) -> Result<bool, Self::Error> {
+        self.with_connection(|conn| {
+            create_table(conn, "trie", "key")?;
+            conn.query_row("SELECT 1 FROM trie WHERE key=?1", [key], |_| Ok(()))
+                .optional()
+                .map(|row| row.is_some())
+        })
+    }
+
+    /// Existing rows are kept, nodes never changing under their hash.
     fn insert(&self, key: &[u8], value: Vec<u8>) -> Result<(), Self::Error> {
-        let conn = Connection::open(self.db_name.clone()).unwrap();
-
-        _ = conn.execute(
-            "CREATE TABLE trie (
-                key BLOB PRIMARY KEY,
-                data BLOB
-            )",
-            (), // empty list of parameters.
-        );
-        let node_to_add = NodeDB {
-            key: key.to_vec(),
-            data: Some(value),
-        };
-        _ = conn.execute(
-            "INSERT INTO trie (key, data) VALUES (?1, ?2)",
-            (&node_to_add.key, &node_to_add.data),
-        );
-        Ok(())
+        self.with_connection(|conn| {
+            create_table(conn, "trie", "key")?;
+            conn.execute(
+                "INSERT OR IGNORE INTO trie (key, data) VALUES (?1, ?2)",
+                (key, &value),
+            )?;
+            Ok(())
+        })
     }
 
     fn remove(&self, key: &[u8]) -> Result<(), Self::Error> {
-        let conn = Connection::open(self.db_name.clone()).unwrap();
+        self.with_connection(|conn| {
+            create_table(conn, "trie", "key")?;
+            conn.execute("DELETE FROM trie WHERE key=?1", [key])?;
+            Ok(())
+        })
+    }
 
-        let mut stmt = conn.prepare("DELETE FROM trie WHERE key=?1").unwrap();
-        stmt.execute([key]);
-    
-        Ok(())
+    /// Inserts every node in a single transaction, so committing a large trie doesn't
+    /// open a connection per node.
+    fn insert_batch(&self, keys: Vec<Vec<u8>>, values: Vec<Vec<u8>>) -> Result<(), Self::Error> {
+        self.with_connection(|conn| {
+            create_table(conn, "trie", "key")?;
+
+            let tx = conn.transaction()?;
+            {
+                // Like `insert`, existing rows are kept.
+                let mut stmt =
+                    tx.prepare("INSERT OR IGNORE INTO trie (key, data) VALUES (?1, ?2)")?;
+                for (key, value) in keys.iter().zip(values.iter()) {
+                    stmt.execute((key, value))?;
+                }
+            }
+            tx.commit()
+        })
+    }
+
+    /// Writes the nodes, removals and metadata in a single transaction, so metadata
+    /// added by pre-commit hooks is stored if and only if the nodes are.
+    fn commit_batch(&self, batch: CommitBatch) -> Result<(), Self::Error> {
+        self.with_connection(|conn| {
+            create_table(conn, "trie", "key")?;
+            create_table(conn, "meta", "key")?;
+
+            let tx = conn.transaction()?;
+            {
+                let mut stmt =
+                    tx.prepare("INSERT OR IGNORE INTO trie (key, data) VALUES (?1, ?2)")?;
+                for (key, value) in batch.keys.iter().zip(batch.values.iter()) {
+                    stmt.execute((key, value))?;
+                }
+                let mut stmt = tx.prepare("DELETE FROM trie WHERE key=?1")?;
+                for key in &batch.removed {
+                    stmt.execute([key])?;
+                }
+                let mut stmt = tx.prepare("DELETE FROM meta WHERE key=?1")?;
+                for key in &batch.removed_meta {
+                    stmt.execute([key])?;
+                }
+                let mut stmt =
+                    tx.prepare("INSERT OR REPLACE INTO meta (key, data) VALUES (?1, ?2)")?;
+                for (key, value) in &batch.meta {
+                    stmt.execute((key, value))?;
+                }
+            }
+            tx.commit()
+        })
     }
 
     fn flush(&self) -> Result<(),  Self::Error> {
         Ok(())
     }
-}
\ No newline at end of file
+
+    fn get_blob(&self, hash: &[u8]) -> Result<Option<Vec<u8>>, Self::Error> {
+        self.with_connection(|conn| {
+            create_table(conn, "blob", "hash")?;
+            conn.query_row("SELECT data FROM blob WHERE hash=?1", [hash], |row| row.get(0))
+                .optional()
+                .map(Option::flatten)
+        })
+    }
+
+    fn insert_blob(&self, hash: &[u8], value: Vec<u8>) -> Result<(), Self::Error> {
+        self.with_connection(|conn| {
+            create_table(conn, "blob", "hash")?;
+            conn.execute(
+                "INSERT OR IGNORE INTO blob (hash, data) VALUES (?1, ?2)",
+                (hash, &value),
+            )?;
+            Ok(())
+        })
+    }
+
+    fn get_meta(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Self::Error> {
+        self.with_connection(|conn| {
+            create_table(conn, "meta", "key")?;
+            conn.query_row("SELECT data FROM meta WHERE key=?1", [key], |row| row.get(0))
+                .optional()
+                .map(Option::flatten)
+        })
+    }
+
+    /// Unlike nodes and blobs, which never change under their hash, metadata is
+    /// overwritten.
+    fn insert_meta(&self, key: &[u8], value: Vec<u8>) -> Result<(), Self::Error> {
+        self.with_connection(|conn| {
+            create_table(conn, "meta", "key")?;
+            conn.execute(
+                "INSERT OR REPLACE INTO meta (key, data) VALUES (?1, ?2)",
+                (key, &value),
+            )?;
+            Ok(())
+        })
+    }
+
+    fn remove_meta(&self, key: &[u8]) -> Result<(), Self::Error> {
+        self.with_connection(|conn| {
+            create_table(conn, "meta", "key")?;
+            conn.execute("DELETE FROM meta WHERE key=?1", [key])?;
+            Ok(())
+        })
+    }
+
+    /// Reads the rows of the trie table keyed by a hash, the others not being nodes.
+    fn iter_nodes(&self, f: &mut dyn FnMut(&[u8], &[u8])) -> Result<bool, Self::Error> {
+        self.with_connection(|conn| {
+            create_table(conn, "trie", "key")?;
+            let mut stmt = conn.prepare("SELECT key, data FROM trie WHERE LENGTH(key) = 32")?;
+            let mut rows = stmt.query(())?;
+            while let Some(row) = rows.next()? {
+                let key: Vec<u8> = row.get(0)?;
+                if let Some(data) = row.get::<_, Option<Vec<u8>>>(1)? {
+                    f(&key, &data);
+                }
+            }
+            Ok(true)
+        })
+    }
+
+    /// Counts the rows of every table, and reads the page counts SQLite keeps.
+    fn stats(&self) -> Result<Option<StorageStats>, Self::Error> {
+        self.with_connection(|conn| {
+            let mut stats = StorageStats::default();
+            for (table, key) in [("trie", "key"), ("blob", "hash"), ("meta", "key")] {
+                create_table(conn, table, key)?;
+                let (rows, bytes): (u64, u64) = conn.query_row(
+                    &format!(
+                        "SELECT COUNT(*), IFNULL(SUM(LENGTH({key}) + IFNULL(LENGTH(data), 0)), 0) \
+                         FROM {table}"
+                    ),
+                    (),
+                    |row| Ok((row.get(0)?, row.get(1)?)),
+                )?;
+                if table == "trie" {
+                    stats.nodes = rows;
+                }
+                stats.bytes += bytes;
+            }
+            let pragma =
+                |name: &str| conn.query_row(&format!("PRAGMA {name}"), (), |row| row.get(0));
+            stats.pages = Some(PageStats {
+                page_size: pragma("page_size")?,
+                page_count: pragma("page_count")?,
+                freelist_count: pragma("freelist_count")?,
+            });
+            Ok(Some(stats))
+        })
+    }
+}
+
+fn create_table(conn: &Connection, table: &str, key: &str) -> Result<()> {
+    conn.execute(
+        &format!("CREATE TABLE IF NOT EXISTS {table} ({key} BLOB PRIMARY KEY, data BLOB)"),
+        (),
+    )?;
+    Ok(())
+}
+
+// Whether another connection held a lock SQLite couldn't wait out.
+pub(crate) fn is_busy(error: &rusqlite::Error) -> bool {
+    matches!(
+        error.sqlite_error_code(),
+        Some(ErrorCode::DatabaseBusy | ErrorCode::DatabaseLocked)
+    )
+}
@@ -0,0 +1,214 @@
+use std::fmt::Write;
+
+use crate::codec::{ChildRef, NodeCodec};
+use crate::db::DB;
+use crate::errors::TrieError;
+use crate::hasher::Hasher;
+use crate::nibbles::Nibbles;
+use crate::node::Node;
+use crate::trie::{EthTrie, TrieResult};
+
+impl<D, H, C> EthTrie<D, H, C>
+where
+    D: DB,
+    H: Hasher,
+    C: NodeCodec,
+{
+    /// Renders the trie as a Graphviz DOT graph, for debugging its structure.
+    ///
+    /// Each node is labelled with its type, the first bytes of its hash and its key or
+    /// prefix as hex nibbles, `$` being the leaf terminator. Nodes small enough to be
+    /// embedded in their parent are shown as `inline`, and nodes changed since the last
+    /// commit are marked `dirty`. Branch edges are labelled with their nibble.
+    ///
+    /// The whole trie is loaded, so this is only meant for small tries.
+    pub fn to_dot(&self) -> TrieResult<String> {
+        let (view, _) = self.view_at(&self.root)?;
+        let mut out = String::from("digraph trie {\n    node [shape=box, fontname=monospace];\n");
+        let mut next_id = 0;
+        dot_node(&view, &mut next_id, &mut out);
+        out.push_str("}\n");
+        Ok(out)
+    }
+
+    /// Renders the trie as an indented tree, one node per line under its parent, for
+    /// eyeballing its structure in a terminal, say after deletions. Nodes are labelled
+    /// as in `to_dot`, and each child line starts with its nibble in the branch above.
+    ///
+    /// The whole trie is loaded, so this is only meant for small tries.
+    pub fn format_tree(&self) -> TrieResult<String> {
+        let (view, _) = self.view_at(&self.root)?;
+        let mut out = String::new();
+        tree_node(&view, "", "", &mut out);
+        Ok(out)
+    }
+
+    // Describes `node` and its subtree, returning the description and the node's
+    // encoding. Hashes are computed from the children's encodings as the walk comes
+    // back up, so the trie and its caches are left untouched.
+    fn view_at(&self, node: &Node) -> TrieResult<(NodeView, Vec<u8>)> {
+        let (node, loaded) = match node {
+            Node::Hash(hash_node) => {
+                let node = self.recover_from_db(hash_node.hash)?.ok_or_else(|| {
+                    TrieError::MissingTrieNode {
+                        node_hash: hash_node.hash,
+                        traversed: None,
+                        root_hash: Some(self.root_hash),
+                        err_key: None,
+                    }
+                })?;
+                (node, true)
+            }
+            _ => (node.clone(), false),
+        };
+
+        let mut children = vec![];
+        let (kind, details, encoded) = match &node {
+            Node::Empty => {
+                let view = NodeView {
+                    label: "empty".to_owned(),
+                    children,
+                };
+                return Ok((view, C::encode_empty()));
+            }
+            Node::Leaf(leaf) => {
+                let key = leaf.key.get_data();
+                let encoded = C::encode_leaf(key.strip_suffix(&[16]).unwrap_or(key), &leaf.value);
+                let details = vec![
+                    format!("key {}", nibble_label(&leaf.key)),
+                    format!("value {} bytes", leaf.value.len()),
+                ];
+                ("leaf", details, encoded)
+            }
+            Node::Extension(ext) => {
+                let (child, child_encoded) = self.view_at(&ext.node)?;
+                children.push((None, child));
+                let encoded =
+                    C::encode_extension(ext.prefix.get_data(), child_ref::<H, C>(&child_encoded));
+                (
+                    "extension",
+                    vec![format!("prefix {}", nibble_label(&ext.prefix))],
+                    encoded,
+                )
+            }
+            Node::Branch(branch) => {
+                let mut children_encoded: [Vec<u8>; 16] = Default::default();
+                for (i, child) in branch.children.iter().enumerate() {
+                    if let Node::Empty = child {
+                        children_encoded[i] = C::encode_empty();
+                        continue;
+                    }
+                    let (child, child_encoded) = self.view_at(child)?;
+                    children.push((Some(i as u8), child));
+                    children_encoded[i] = child_encoded;
+                }
+                let refs = std::array::from_fn(|i| match &branch.children[i] {
+                    Node::Empty => ChildRef::Inline(&children_encoded[i]),
+                    _ => child_ref::<H, C>(&children_encoded[i]),
+                });
+                let details = match &branch.value {
+                    Some(v) => vec![format!("value {} bytes", v.len())],
+                    None => vec![],
+                };
+                let encoded = C::encode_branch(&refs, branch.value.as_deref());
+                ("branch", details, encoded)
+            }
+            Node::Hash(_) => unreachable!(),
+        };
+
+        let hash = if encoded.len() < C::INLINE_THRESHOLD {
+            "inline".to_owned()
+        } else {
+            let hash = hex::encode(&H::hash(&encoded)[..4]);
+            if loaded || node.cached_hash().is_some() {
+                hash
+            } else {
+                format!("{} dirty", hash)
+            }
+        };
+        let label = [format!("{} {}", kind, hash)]
+            .into_iter()
+            .chain(details)
+            .collect::<Vec<_>>()
+            .join("\n");
+        Ok((NodeView { label, children }, encoded))
+    }
+}
+
+// A node as shown by `to_dot` and `format_tree`: its label, one line per detail, and
+// its children, with their nibble when under a branch.
+struct NodeView {
+    label: String,
+    children: Vec<(Option<u8>, NodeView)>,
+}
+
+// Writes `view` and its subtree as DOT, returning the id given to `view`.
+fn dot_node(view: &NodeView, next_id: &mut usize, out: &mut String) -> usize {
+    let id = *next_id;
+    *next_id += 1;
+    for (nibble, child) in &view.children {
+        let child = dot_node(child, next_id, out);
+        match nibble {
+            Some(nibble) => {
+                let _ = writeln!(out, "    n{} -> n{} [label=\"{:x}\"];", id, child, nibble);
+            }
+            None => {
+                let _ = writeln!(out, "    n{} -> n{};", id, child);
+            }
+        }
+    }
+    let label = view.label.replace('\n', "\\n");
+    let _ = writeln!(out, "    n{} [label=\"{}\"];", id, label);
+    id
+}
+
+// Writes `view` and its subtree as indented lines, `first` leading the node's first
+// line and `rest` its other lines and its children's.
+fn tree_node(view: &NodeView, first: &str, rest: &str, out: &mut String) {
+    let mut lines = view.label.lines();
+    let _ = writeln!(out, "{}{}", first, lines.next().unwrap_or_default());
+    let more = if view.children.is_empty() {
+        "  "
+    } else {
+        "│ "
+    };
+    for line in lines {
+        let _ = writeln!(out, "{}{}{}", rest, more, line);
+    }
+    for (i, (nibble, child)) in view.children.iter().enumerate() {
+        let last = i + 1 == view.children.len();
+        let nibble = nibble.map_or(String::new(), |n| format!("{:x}: ", n));
+        let (branch, indent) = if last {
+            ("└─ ", "   ")
+        } else {
+            ("├─ ", "│  ")
+        };
+        tree_node(
+            child,
+            &format!("{}{}{}", rest, branch, nibble),
+            &format!("{}{}", rest, indent),
+            out,
+        );
+    }
+}
+
+// How a child with the given encoding is referenced from its parent.
+fn child_ref<H: Hasher, C: NodeCodec>(encoded: &[u8]) -> ChildRef<'_> {
+    if encoded.len() >= C::INLINE_THRESHOLD {
+        ChildRef::Hash(H::hash(encoded))
+    } else {
+        ChildRef::Inline(encoded)
+    }
+}
+
+// Nibbles as hex digits, with `$` for the leaf terminator.
+fn nibble_label(nibbles: &Nibbles) -> String {
+    nibbles
+        .get_data()
+        .iter()
+        .map(|&n| match n {
+            16 => '$',
+            n => char::from_digit(n as u32, 16).unwrap_or('?'),
+        })
+        .collect()
+}
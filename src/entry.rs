@@ -0,0 +1,185 @@
+use crate::codec::{NodeCodec, RlpCodec};
+use crate::db::DB;
+use crate::errors::{TrieError, TrieOp};
+use crate::hasher::{Hasher, KeccakHasher};
+use crate::trie::{Change, EthTrie, ITrie, TrieResult};
+
+/// A view into a single key of the trie, which may or may not hold a value.
+/// It is obtained through `EthTrie::entry` and allows read-modify-write updates
+/// without the caller juggling separate `get` and `put` calls: each update reads
+/// the value and writes the new one in a single walk down the trie.
+///
+/// The entry remembers the value its last walk found, so `and_modify` followed by
+/// `or_insert` walks the trie once when the key is occupied.
+pub struct Entry<'a, D, H = KeccakHasher, C = RlpCodec>
+where
+    D: DB,
+    H: Hasher,
+    C: NodeCodec,
+{
+    trie: &'a mut EthTrie<D, H, C>,
+    key: Vec<u8>,
+    // The value under the key, once a walk has found it.
+    value: Option<Option<Vec<u8>>>,
+}
+
+impl<'a, D, H, C> Entry<'a, D, H, C>
+where
+    D: DB,
+    H: Hasher,
+    C: NodeCodec,
+{
+    /// Returns the key of this entry.
+    pub fn key(&self) -> &[u8] {
+        &self.key
+    }
+
+    /// Returns the value currently stored under the key, if any, reading it from the
+    /// trie unless an update through the entry already has.
+    pub fn get(&mut self) -> TrieResult<Option<&[u8]>> {
+        if self.value.is_none() {
+            self.value = Some(self.trie.get(&self.key)?);
+        }
+        Ok(self.value.as_ref().and_then(|value| value.as_deref()))
+    }
+
+    /// Returns true if the key currently holds a value.
+    pub fn is_occupied(&mut self) -> TrieResult<bool> {
+        Ok(self.get()?.is_some())
+    }
+
+    /// Inserts `default` if the entry is vacant.
+    /// Returns the value stored under the key afterwards.
+    pub fn or_insert(self, default: &[u8]) -> TrieResult<Vec<u8>> {
+        self.or_insert_with(|| default.to_vec())
+    }
+
+    /// Inserts the result of `default` if the entry is vacant. The closure is only
+    /// evaluated when no value is present.
+    /// Returns the value stored under the key afterwards.
+    ///
+    /// An empty value would leave the key vacant unless the trie stores empty values,
+    /// so it fails with `TrieError::EmptyValue` without writing anything.
+    pub fn or_insert_with<F>(self, default: F) -> TrieResult<Vec<u8>>
+    where
+        F: FnOnce() -> Vec<u8>,
+    {
+        if let Some(Some(value)) = self.value {
+            return Ok(value);
+        }
+
+        let values = self.trie.values;
+        let mut stored = None;
+        self.trie.change(&self.key, TrieOp::Update, |old| {
+            if let Some(value) = old {
+                stored = Some(value);
+                return Ok(Change::Keep);
+            }
+            let value = default();
+            if values.removes(&value) {
+                return Ok(Change::Keep);
+            }
+            stored = Some(value.clone());
+            Ok(Change::Put(value))
+        })?;
+        stored.ok_or(TrieError::EmptyValue { key: self.key })
+    }
+
+    /// Applies `f` to the value of an occupied entry and writes the result back to
+    /// the trie. Vacant entries are left untouched, so it can be chained with
+    /// `or_insert` for counter-like updates.
+    ///
+    /// As with `put`, modifying the value to an empty one removes the key unless the
+    /// trie stores empty values.
+    pub fn and_modify<F>(mut self, f: F) -> TrieResult<Self>
+    where
+        F: FnOnce(&mut Vec<u8>),
+    {
+        if let Some(None) = self.value {
+            return Ok(self);
+        }
+
+        let mut modified = None;
+        self.trie.change(&self.key, TrieOp::Update, |old| {
+            let Some(mut value) = old else {
+                return Ok(Change::Keep);
+            };
+            f(&mut value);
+            modified = Some(value.clone());
+            Ok(Change::Put(value))
+        })?;
+        let values = self.trie.values;
+        self.value = Some(modified.filter(|value| !values.removes(value)));
+        Ok(self)
+    }
+}
+
+impl<D, H, C> EthTrie<D, H, C>
+where
+    D: DB,
+    H: Hasher,
+    C: NodeCodec,
+{
+    /// Gets the entry for the given key for in-place manipulation. Nothing is read
+    /// until the entry is used.
+    pub fn entry(&mut self, key: &[u8]) -> Entry<'_, D, H, C> {
+        Entry {
+            trie: self,
+            key: key.to_vec(),
+            value: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::test_utils::MemoryDB;
+
+    #[test]
+    fn counters_start_at_the_default_and_are_modified_in_place() {
+        let mut trie = EthTrie::new(Arc::new(MemoryDB::new()));
+        for expected in 1..=3 {
+            let entry = trie
+                .entry(b"counter")
+                .and_modify(|value| value[0] += 1)
+                .unwrap();
+            assert_eq!(entry.or_insert(&[1]).unwrap(), vec![expected]);
+        }
+        assert_eq!(trie.get(b"counter").unwrap(), Some(vec![3]));
+
+        let mut entry = trie.entry(b"other");
+        assert!(!entry.is_occupied().unwrap());
+        assert_eq!(entry.or_insert(b"value").unwrap(), b"value");
+    }
+
+    #[test]
+    fn occupied_entries_are_left_as_they_are() {
+        let mut trie = EthTrie::new(Arc::new(MemoryDB::new()));
+        trie.put(b"key", b"value").unwrap();
+        let root = trie.commit().unwrap();
+
+        let mut trie = trie.at_root(root);
+        let value = trie
+            .entry(b"key")
+            .or_insert_with(|| panic!("the entry is occupied"));
+        assert_eq!(value.unwrap(), b"value");
+        assert_eq!(trie.commit().unwrap(), root);
+    }
+
+    #[test]
+    fn empty_values_are_not_inserted() {
+        let mut trie = EthTrie::new(Arc::new(MemoryDB::new()));
+        assert!(matches!(
+            trie.entry(b"key").or_insert(&[]),
+            Err(TrieError::EmptyValue { .. })
+        ));
+        assert_eq!(trie.get(b"key").unwrap(), None);
+
+        trie.put(b"key", b"value").unwrap();
+        let entry = trie.entry(b"key").and_modify(Vec::clear).unwrap();
+        assert_eq!(entry.or_insert(b"new").unwrap(), b"new");
+    }
+}
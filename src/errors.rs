@@ -1,42 +1,247 @@
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::fmt;
+#[cfg(feature = "std")]
 use std::error::Error;
-use std::fmt;
 
 use ethereum_types::H256;
 use rlp::DecoderError;
 
 use crate::nibbles::Nibbles;
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug)]
+#[cfg_attr(feature = "std", derive(thiserror::Error))]
 pub enum TrieError {
-    SqliteDB(String),
-    Decoder(DecoderError),
+    /// A node, value blob or key preimage referenced by hash is not in the database.
+    #[cfg_attr(
+        feature = "std",
+        error("trie error: {hash:?} not found in the database")
+    )]
+    NotFound { hash: H256 },
+    /// Data read from the database under `hash` decodes but is not what the trie
+    /// stores there.
+    #[cfg_attr(feature = "std", error("trie error: {hash:?} is corrupted: {reason}"))]
+    Corrupted { hash: H256, reason: &'static str },
+    /// The database failed.
+    #[cfg(feature = "std")]
+    #[error("trie error: database backend failed")]
+    Backend {
+        #[source]
+        source: Box<dyn Error + Send + Sync>,
+    },
+    /// The database stayed locked by another connection, or process, through every
+    /// retry the backend allows.
+    #[cfg(feature = "std")]
+    #[error("trie error: database busy")]
+    Busy {
+        #[source]
+        source: Box<dyn Error + Send + Sync>,
+    },
+    /// Writing an export or reading an import failed.
+    #[cfg(feature = "std")]
+    #[error("trie error: i/o failed")]
+    Io {
+        #[from]
+        source: std::io::Error,
+    },
+    /// The node stored under `hash` could not be decoded.
+    #[cfg_attr(feature = "std", error("trie error: cannot decode node {hash:?}"))]
+    DecodeAtNode {
+        hash: H256,
+        #[cfg_attr(feature = "std", source)]
+        source: Box<TrieError>,
+    },
+    /// Returned by backends guarding their state with a lock that another thread
+    /// poisoned by panicking while holding it.
+    #[cfg_attr(feature = "std", error("trie error: lock poisoned"))]
+    LockPoisoned,
+    #[cfg_attr(feature = "std", error("trie error: {0:?}"))]
+    Decoder(#[cfg_attr(feature = "std", from)] DecoderError),
+    #[cfg_attr(feature = "std", error("trie error: invalid data"))]
     InvalidData,
+    #[cfg_attr(feature = "std", error("trie error: invalid proof"))]
     InvalidProof,
+    #[cfg_attr(
+        feature = "std",
+        error("trie error: root mismatch, expected {expected:?}, found {found:?}")
+    )]
+    RootMismatch { expected: H256, found: H256 },
+    /// `EthTrie::put_cas` found another value under `key` than the one expected:
+    /// `found`, or `None` if the key had no value.
+    #[cfg_attr(feature = "std", error("trie error: value of key {key:?} changed"))]
+    ValueMismatch {
+        key: Vec<u8>,
+        found: Option<Vec<u8>>,
+    },
+    /// `Entry::or_insert` was given an empty value for `key`, which the trie would
+    /// remove instead of inserting, as it doesn't store empty values.
+    #[cfg_attr(
+        feature = "std",
+        error("trie error: empty value inserted for key {key:?}")
+    )]
+    EmptyValue { key: Vec<u8> },
+    /// The database backend can't do `operation`, such as listing the nodes it holds
+    /// for `EthTrie::recover_from_leaves`.
+    #[cfg_attr(
+        feature = "std",
+        error("trie error: {operation} is not supported by the database backend")
+    )]
+    Unsupported { operation: &'static str },
+    /// A node referenced by hash is missing from the database, so the trie is
+    /// corrupted or was pruned. `traversed` holds the nibbles walked before reaching
+    /// it. Keys that are simply absent are reported as `Ok(None)`, never as this.
+    #[cfg_attr(feature = "std", error("trie error: missing node {node_hash:?}"))]
     MissingTrieNode {
         node_hash: H256,
-        traversed: Option<Nibbles>,
+        // Boxed to keep `TrieResult` small, `Nibbles` being stored inline.
+        traversed: Option<Box<Nibbles>>,
         root_hash: Option<H256>,
         err_key: Option<Vec<u8>>,
     },
+    /// `source` ended `op` on `key`. Errors of `get`, `put`, `del`, proofs and the
+    /// like come wrapped in this, so that logging one is enough to replay the failing
+    /// call. `traversed` holds the nibbles of the key walked before the failure, when
+    /// it happened down in the trie. `root_cause` returns the error underneath.
+    #[cfg_attr(
+        feature = "std",
+        error("trie error: {op} of key {} failed at {}: {source}", Hex(.key), Path(.traversed))
+    )]
+    Operation {
+        op: TrieOp,
+        key: Vec<u8>,
+        traversed: Option<Box<Nibbles>>,
+        #[cfg_attr(feature = "std", source)]
+        source: Box<TrieError>,
+    },
+}
+
+/// The trie operation an error ended, as recorded by `TrieError::Operation`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum TrieOp {
+    Get,
+    Put,
+    Delete,
+    Update,
+    DeletePrefix,
+    Proof,
+}
+
+impl fmt::Display for TrieOp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            TrieOp::Get => "get",
+            TrieOp::Put => "put",
+            TrieOp::Delete => "delete",
+            TrieOp::Update => "update",
+            TrieOp::DeletePrefix => "delete_prefix",
+            TrieOp::Proof => "proof",
+        })
+    }
+}
+
+pub type TrieResult<T> = Result<T, TrieError>;
+
+impl TrieError {
+    /// Wraps an error returned by a `DB`. `SqliteDB` errors meaning the database is
+    /// locked become `TrieError::Busy`, so callers can tell them apart and retry.
+    #[cfg(feature = "std")]
+    pub fn backend<E: Error + Send + Sync + 'static>(error: E) -> Self {
+        let source: Box<dyn Error + Send + Sync> = Box::new(error);
+        match source.downcast_ref::<rusqlite::Error>() {
+            Some(error) if crate::db::is_busy(error) => TrieError::Busy { source },
+            _ => TrieError::Backend { source },
+        }
+    }
+
+    /// The error underneath any `TrieError::Operation` context, to match on what
+    /// went wrong rather than where.
+    pub fn root_cause(&self) -> &TrieError {
+        match self {
+            TrieError::Operation { source, .. } => source.root_cause(),
+            error => error,
+        }
+    }
+}
+
+// Wraps the error of `op` on `key` in `TrieError::Operation`, taking the nibbles walked
+// from a missing node error, which also gets the key.
+#[cfg(feature = "std")]
+pub(crate) fn with_context<T>(result: TrieResult<T>, op: TrieOp, key: &[u8]) -> TrieResult<T> {
+    let error = match result {
+        Ok(value) => return Ok(value),
+        Err(error @ TrieError::Operation { .. }) => return Err(error),
+        Err(error) => error,
+    };
+    let (traversed, source) = match error {
+        TrieError::MissingTrieNode {
+            node_hash,
+            traversed,
+            root_hash,
+            err_key: _,
+        } => (
+            traversed.clone(),
+            TrieError::MissingTrieNode {
+                node_hash,
+                traversed,
+                root_hash,
+                err_key: Some(key.to_vec()),
+            },
+        ),
+        error => (None, error),
+    };
+    Err(TrieError::Operation {
+        op,
+        key: key.to_vec(),
+        traversed,
+        source: Box::new(source),
+    })
 }
 
-impl Error for TrieError {}
+// Formats bytes as 0x-prefixed hex in error messages.
+#[cfg(feature = "std")]
+struct Hex<'a>(&'a [u8]);
 
+#[cfg(feature = "std")]
+impl fmt::Display for Hex<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("0x")?;
+        self.0.iter().try_for_each(|byte| write!(f, "{:02x}", byte))
+    }
+}
+
+// Formats the nibbles walked before an error, one hex digit each.
+#[cfg(feature = "std")]
+struct Path<'a>(&'a Option<Box<Nibbles>>);
+
+#[cfg(feature = "std")]
+impl fmt::Display for Path<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.0 {
+            Some(path) if !path.is_empty() => {
+                f.write_str("path ")?;
+                path.get_data()
+                    .iter()
+                    .try_for_each(|nibble| write!(f, "{:x}", nibble))
+            }
+            Some(_) => f.write_str("the root"),
+            None => f.write_str("an unknown depth"),
+        }
+    }
+}
+
+// Without `std` there is no `Error` trait to derive from, so the `Debug` output is
+// shown instead.
+#[cfg(not(feature = "std"))]
 impl fmt::Display for TrieError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        let printable = match *self {
-            TrieError::SqliteDB(ref err) => format!("trie error: {:?}", err),
-            TrieError::Decoder(ref err) => format!("trie error: {:?}", err),
-            TrieError::InvalidData => "trie error: invalid data".to_owned(),
-            TrieError::InvalidProof => "trie error: invalid proof".to_owned(),
-            TrieError::MissingTrieNode { .. } => "trie error: missing node".to_owned(),
-        };
-        write!(f, "{}", printable)
+        write!(f, "trie error: {:?}", self)
     }
 }
 
+#[cfg(not(feature = "std"))]
 impl From<DecoderError> for TrieError {
     fn from(error: DecoderError) -> Self {
         TrieError::Decoder(error)
     }
-}
\ No newline at end of file
+}
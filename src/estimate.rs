@@ -0,0 +1,70 @@
+use rand::Rng;
+
+use crate::codec::NodeCodec;
+use crate::db::DB;
+use crate::errors::TrieError;
+use crate::hasher::Hasher;
+use crate::node::Node;
+use crate::trie::{EthTrie, TrieResult};
+
+impl<D, H, C> EthTrie<D, H, C>
+where
+    D: DB,
+    H: Hasher,
+    C: NodeCodec,
+{
+    /// Estimates the number of keys in the trie from `sample_budget` random walks
+    /// from the root to a value, for tries too large to count by iterating, as on
+    /// dashboards. Each walk picks one of the children or the value of every branch
+    /// it meets at random, and estimates the count as the product of the choices it
+    /// had; the average over the walks is unbiased, and gets closer as the budget
+    /// grows. Every walk reads one path of nodes; a budget of zero makes one walk.
+    pub fn estimate_len(&self, sample_budget: usize) -> TrieResult<u64> {
+        let mut rng = rand::thread_rng();
+        let walks = sample_budget.max(1);
+        let mut total = 0.0;
+        for _ in 0..walks {
+            total += self.sample_walk(&mut rng)?;
+        }
+        Ok((total / walks as f64).round() as u64)
+    }
+
+    // The product of the choices met on one random walk down to a value.
+    fn sample_walk(&self, rng: &mut impl Rng) -> TrieResult<f64> {
+        let mut node = self.root.clone();
+        let mut weight = 1.0;
+        loop {
+            node = match &node {
+                Node::Empty => return Ok(0.0),
+                Node::Leaf(_) => return Ok(weight),
+                Node::Extension(ext) => ext.node.clone(),
+                Node::Branch(branch) => {
+                    let children: Vec<&Node> = branch
+                        .children
+                        .iter()
+                        .filter(|child| !matches!(child, Node::Empty))
+                        .collect();
+                    let choices = children.len() + usize::from(branch.value.is_some());
+                    if choices == 0 {
+                        return Ok(0.0);
+                    }
+                    weight *= choices as f64;
+                    match children.get(rng.gen_range(0..choices)) {
+                        Some(child) => (*child).clone(),
+                        None => return Ok(weight),
+                    }
+                }
+                Node::Hash(hash_node) => {
+                    let hash = hash_node.hash;
+                    self.recover_from_db(hash)?
+                        .ok_or(TrieError::MissingTrieNode {
+                            node_hash: hash,
+                            traversed: None,
+                            root_hash: Some(self.root_hash),
+                            err_key: None,
+                        })?
+                }
+            };
+        }
+    }
+}
@@ -0,0 +1,84 @@
+use ethereum_types::{H160, H256};
+
+use crate::codec::NodeCodec;
+use crate::db::DB;
+use crate::hasher::Hasher;
+use crate::nibbles::Nibbles;
+use crate::trie::{EthTrie, TrieResult};
+
+/// A key of a fixed width, like the 32-byte hashes keying Ethereum's state and
+/// storage tries or a 20-byte address. Its path is built by `Nibbles::from_fixed`.
+pub trait FixedKey {
+    fn key_bytes(&self) -> &[u8];
+
+    fn path(&self) -> Nibbles;
+}
+
+impl<const N: usize> FixedKey for [u8; N] {
+    fn key_bytes(&self) -> &[u8] {
+        self
+    }
+
+    fn path(&self) -> Nibbles {
+        Nibbles::from_fixed(self)
+    }
+}
+
+impl FixedKey for H256 {
+    fn key_bytes(&self) -> &[u8] {
+        self.as_bytes()
+    }
+
+    fn path(&self) -> Nibbles {
+        Nibbles::from_fixed(&self.0)
+    }
+}
+
+impl FixedKey for H160 {
+    fn key_bytes(&self) -> &[u8] {
+        self.as_bytes()
+    }
+
+    fn path(&self) -> Nibbles {
+        Nibbles::from_fixed(&self.0)
+    }
+}
+
+impl<D, H, C> EthTrie<D, H, C>
+where
+    D: DB,
+    H: Hasher,
+    C: NodeCodec,
+{
+    /// `get` for a fixed-width key, skipping the generic handling of byte slices.
+    pub fn get_fixed<K: FixedKey>(&self, key: &K) -> TrieResult<Option<Vec<u8>>> {
+        self.get_path(key.key_bytes(), &key.path())
+    }
+
+    /// `put` for a fixed-width key.
+    pub fn put_fixed<K: FixedKey>(&mut self, key: &K, value: &[u8]) -> TrieResult<()> {
+        self.put_path(key.key_bytes(), &key.path(), value)
+    }
+
+    // `get_fixed` passing the value to `read` where it is stored.
+    pub(crate) fn read_fixed<K: FixedKey, R, F>(&self, key: &K, read: F) -> TrieResult<Option<R>>
+    where
+        F: FnOnce(&[u8]) -> TrieResult<R>,
+    {
+        self.read_path(key.key_bytes(), &key.path(), read)
+    }
+
+    // `put_fixed` taking ownership of the value.
+    pub(crate) fn put_fixed_owned<K: FixedKey>(
+        &mut self,
+        key: &K,
+        value: Vec<u8>,
+    ) -> TrieResult<()> {
+        self.put_path_owned(key.key_bytes(), &key.path(), value)
+    }
+
+    /// `del` for a fixed-width key.
+    pub fn del_fixed<K: FixedKey>(&mut self, key: &K) -> TrieResult<()> {
+        self.del_path(key.key_bytes(), &key.path())
+    }
+}
@@ -0,0 +1,348 @@
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use memmap2::Mmap;
+use parking_lot::Mutex;
+
+use crate::db::{StorageStats, DB};
+
+const LOG_FILE: &str = "nodes.log";
+const INDEX_FILE: &str = "nodes.idx";
+const INDEX_MAGIC: &[u8; 8] = b"MPTIDX\0\x01";
+
+// Every record in the log is a tag, the key and value lengths as big-endian u32s,
+// the key and the value. Records are only appended: a later record for the same
+// key replaces the earlier one, and a removal is a record of its own.
+const NODE: u8 = 1;
+const BLOB: u8 = 2;
+const META: u8 = 3;
+const REMOVED: u8 = 0x80;
+const HEADER_LEN: u64 = 9;
+
+// Where the value of each tagged key starts in the log, and its length.
+type Index = HashMap<Vec<u8>, (u64, u32)>;
+
+/// A `DB` appending everything to a single log file, for write-once archival tries
+/// that don't need a database engine. Reads go through a memory map of the log,
+/// found with an index of every key held in memory.
+///
+/// The index is saved next to the log by `flush` and when the database is dropped,
+/// so opening doesn't read the whole log again, only what was appended after the
+/// index was saved. A record cut short by a crash is dropped on open.
+///
+/// Space is never reclaimed: removed and replaced values stay in the log. The files
+/// must not be written by another process while open.
+pub struct FlatFileDB {
+    dir: PathBuf,
+    inner: Mutex<Inner>,
+}
+
+struct Inner {
+    writer: BufWriter<File>,
+    // The length of the log, including what is still buffered.
+    len: u64,
+    index: Index,
+    // A map of the log as of the last write that reached the file.
+    map: Option<Mmap>,
+    // Whether the index changed since it was saved.
+    dirty: bool,
+}
+
+impl FlatFileDB {
+    /// Opens the database in directory `dir`, creating it if needed.
+    pub fn open<P: AsRef<Path>>(dir: P) -> io::Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+        let mut file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(dir.join(LOG_FILE))?;
+        let file_len = file.metadata()?.len();
+
+        let (mut index, indexed) = match read_index(&dir.join(INDEX_FILE)) {
+            Ok(Some((index, indexed))) if indexed <= file_len => (index, indexed),
+            // Missing, unreadable or ahead of the log: rebuilt from the whole log.
+            _ => (HashMap::new(), 0),
+        };
+        let len = scan(&mut file, indexed, file_len, &mut index)?;
+        if len < file_len {
+            file.set_len(len)?;
+        }
+
+        let inner = Inner {
+            writer: BufWriter::new(file),
+            len,
+            index,
+            map: None,
+            dirty: len > indexed,
+        };
+        let db = FlatFileDB {
+            dir,
+            inner: Mutex::new(inner),
+        };
+        db.inner.lock().remap()?;
+        Ok(db)
+    }
+
+    /// The size of the log in bytes, including values removed or replaced since.
+    pub fn log_size(&self) -> u64 {
+        self.inner.lock().len
+    }
+
+    fn read(&self, tag: u8, key: &[u8]) -> io::Result<Option<Vec<u8>>> {
+        let mut inner = self.inner.lock();
+        let Some(&(offset, len)) = inner.index.get(&tagged(tag, key)) else {
+            return Ok(None);
+        };
+        let end = offset + len as u64;
+        if inner.map.as_ref().map_or(0, |map| map.len() as u64) < end {
+            inner.writer.flush()?;
+            inner.remap()?;
+        }
+        let map = inner.map.as_ref().expect("the log holds the record");
+        Ok(Some(map[offset as usize..end as usize].to_vec()))
+    }
+
+    fn append(&self, tag: u8, key: &[u8], value: &[u8]) -> io::Result<()> {
+        self.inner.lock().append(tag, key, value)
+    }
+
+    fn save_index(&self, inner: &mut Inner) -> io::Result<()> {
+        if !inner.dirty {
+            return Ok(());
+        }
+        let tmp = self.dir.join(format!("{INDEX_FILE}.tmp"));
+        let mut out = BufWriter::new(File::create(&tmp)?);
+        out.write_all(INDEX_MAGIC)?;
+        out.write_all(&inner.len.to_be_bytes())?;
+        for (key, (offset, len)) in &inner.index {
+            out.write_all(&(key.len() as u32).to_be_bytes())?;
+            out.write_all(key)?;
+            out.write_all(&offset.to_be_bytes())?;
+            out.write_all(&len.to_be_bytes())?;
+        }
+        out.into_inner()?.sync_all()?;
+        fs::rename(tmp, self.dir.join(INDEX_FILE))?;
+        inner.dirty = false;
+        Ok(())
+    }
+}
+
+impl Inner {
+    fn append(&mut self, tag: u8, key: &[u8], value: &[u8]) -> io::Result<()> {
+        let (key_len, value_len) = (len_u32(key)?, len_u32(value)?);
+        self.writer.write_all(&[tag])?;
+        self.writer.write_all(&key_len.to_be_bytes())?;
+        self.writer.write_all(&value_len.to_be_bytes())?;
+        self.writer.write_all(key)?;
+        self.writer.write_all(value)?;
+
+        let offset = self.len + HEADER_LEN + key_len as u64;
+        self.len = offset + value_len as u64;
+        index_record(&mut self.index, tag, key, offset, value_len);
+        self.dirty = true;
+        Ok(())
+    }
+
+    fn remap(&mut self) -> io::Result<()> {
+        let file = self.writer.get_ref();
+        self.map = match file.metadata()?.len() {
+            // Empty files can't be mapped everywhere.
+            0 => None,
+            // SAFETY: the log is only ever appended to, by this process alone, so the
+            // mapped bytes don't change while mapped.
+            _ => Some(unsafe { Mmap::map(file)? }),
+        };
+        Ok(())
+    }
+}
+
+impl Drop for FlatFileDB {
+    fn drop(&mut self) {
+        let mut inner = self.inner.lock();
+        if inner.writer.flush().is_ok() {
+            let _ = self.save_index(&mut inner);
+        }
+    }
+}
+
+impl DB for FlatFileDB {
+    type Error = io::Error;
+
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Self::Error> {
+        self.read(NODE, key)
+    }
+
+    fn contains(&self, key: &[u8]) -> Result<bool, Self::Error> {
+        Ok(self.inner.lock().index.contains_key(&tagged(NODE, key)))
+    }
+
+    /// Nodes never change under their hash, so a key already stored isn't written
+    /// again.
+    fn insert(&self, key: &[u8], value: Vec<u8>) -> Result<(), Self::Error> {
+        let mut inner = self.inner.lock();
+        if !inner.index.contains_key(&tagged(NODE, key)) {
+            inner.append(NODE, key, &value)?;
+        }
+        inner.writer.flush()
+    }
+
+    fn remove(&self, key: &[u8]) -> Result<(), Self::Error> {
+        let mut inner = self.inner.lock();
+        if inner.index.contains_key(&tagged(NODE, key)) {
+            inner.append(NODE | REMOVED, key, &[])?;
+        }
+        Ok(())
+    }
+
+    /// Appends the nodes not stored yet, and hands them to the file system at once.
+    fn insert_batch(&self, keys: Vec<Vec<u8>>, values: Vec<Vec<u8>>) -> Result<(), Self::Error> {
+        let mut inner = self.inner.lock();
+        for (key, value) in keys.iter().zip(values.iter()) {
+            if !inner.index.contains_key(&tagged(NODE, key)) {
+                inner.append(NODE, key, value)?;
+            }
+        }
+        inner.writer.flush()
+    }
+
+    /// Writes what is buffered to disk and saves the index.
+    fn flush(&self) -> Result<(), Self::Error> {
+        let mut inner = self.inner.lock();
+        inner.writer.flush()?;
+        inner.writer.get_ref().sync_data()?;
+        self.save_index(&mut inner)
+    }
+
+    fn get_blob(&self, hash: &[u8]) -> Result<Option<Vec<u8>>, Self::Error> {
+        self.read(BLOB, hash)
+    }
+
+    fn insert_blob(&self, hash: &[u8], value: Vec<u8>) -> Result<(), Self::Error> {
+        let mut inner = self.inner.lock();
+        if !inner.index.contains_key(&tagged(BLOB, hash)) {
+            inner.append(BLOB, hash, &value)?;
+        }
+        Ok(())
+    }
+
+    fn get_meta(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Self::Error> {
+        self.read(META, key)
+    }
+
+    fn insert_meta(&self, key: &[u8], value: Vec<u8>) -> Result<(), Self::Error> {
+        self.append(META, key, &value)
+    }
+
+    fn remove_meta(&self, key: &[u8]) -> Result<(), Self::Error> {
+        self.append(META | REMOVED, key, &[])
+    }
+
+    /// Counts what is live in the index; `log_size` includes what was removed or
+    /// replaced.
+    fn stats(&self) -> Result<Option<StorageStats>, Self::Error> {
+        let inner = self.inner.lock();
+        let mut stats = StorageStats::default();
+        for (key, (_, len)) in &inner.index {
+            if key[0] == NODE {
+                stats.nodes += 1;
+            }
+            stats.bytes += (key.len() - 1) as u64 + *len as u64;
+        }
+        Ok(Some(stats))
+    }
+
+    fn iter_nodes(&self, f: &mut dyn FnMut(&[u8], &[u8])) -> Result<bool, Self::Error> {
+        let mut inner = self.inner.lock();
+        inner.writer.flush()?;
+        inner.remap()?;
+        let Some(map) = &inner.map else {
+            return Ok(true);
+        };
+        for (key, &(offset, len)) in &inner.index {
+            if key[0] == NODE {
+                f(&key[1..], &map[offset as usize..offset as usize + len as usize]);
+            }
+        }
+        Ok(true)
+    }
+}
+
+fn tagged(tag: u8, key: &[u8]) -> Vec<u8> {
+    [&[tag], key].concat()
+}
+
+fn index_record(index: &mut Index, tag: u8, key: &[u8], offset: u64, len: u32) {
+    if tag & REMOVED == 0 {
+        index.insert(tagged(tag, key), (offset, len));
+    } else {
+        index.remove(&tagged(tag & !REMOVED, key));
+    }
+}
+
+fn len_u32(data: &[u8]) -> io::Result<u32> {
+    u32::try_from(data.len())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "record too large"))
+}
+
+// Adds the records of the log from `from` to `end` to `index`, and returns where the
+// last complete record ends.
+fn scan(file: &mut File, from: u64, end: u64, index: &mut Index) -> io::Result<u64> {
+    file.seek(SeekFrom::Start(from))?;
+    let mut reader = io::BufReader::new(file.take(end - from));
+    let mut pos = from;
+    let mut header = [0; HEADER_LEN as usize];
+    loop {
+        if pos + HEADER_LEN > end {
+            return Ok(pos);
+        }
+        reader.read_exact(&mut header)?;
+        let tag = header[0];
+        let key_len = u32::from_be_bytes(header[1..5].try_into().unwrap()) as u64;
+        let value_len = u32::from_be_bytes(header[5..9].try_into().unwrap());
+        let record_end = pos + HEADER_LEN + key_len + value_len as u64;
+        if record_end > end {
+            return Ok(pos);
+        }
+        let mut key = vec![0; key_len as usize];
+        reader.read_exact(&mut key)?;
+        io::copy(&mut (&mut reader).take(value_len as u64), &mut io::sink())?;
+        index_record(index, tag, &key, pos + HEADER_LEN + key_len, value_len);
+        pos = record_end;
+    }
+}
+
+fn read_index(path: &Path) -> io::Result<Option<(Index, u64)>> {
+    let data = match fs::read(path) {
+        Ok(data) => data,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    let Some(rest) = data.strip_prefix(INDEX_MAGIC.as_slice()) else {
+        return Ok(None);
+    };
+    let mut rest = rest;
+    let mut take = |n: usize| -> Option<&[u8]> {
+        let (head, tail) = rest.split_at_checked(n)?;
+        rest = tail;
+        Some(head)
+    };
+    let Some(indexed) = take(8) else {
+        return Ok(None);
+    };
+    let indexed = u64::from_be_bytes(indexed.try_into().unwrap());
+    let mut index = HashMap::new();
+    while let Some(key_len) = take(4) {
+        let key_len = u32::from_be_bytes(key_len.try_into().unwrap()) as usize;
+        let (Some(key), Some(offset), Some(len)) = (take(key_len), take(8), take(4)) else {
+            return Ok(None);
+        };
+        let offset = u64::from_be_bytes(offset.try_into().unwrap());
+        let len = u32::from_be_bytes(len.try_into().unwrap());
+        index.insert(key.to_vec(), (offset, len));
+    }
+    Ok(Some((index, indexed)))
+}
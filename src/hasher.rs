@@ -0,0 +1,116 @@
+use ethereum_types::H256;
+
+/// The digest used to hash trie nodes, and the values stored as blobs.
+/// `EthTrie` uses keccak256 by default, as Ethereum does; other chains and
+/// applications can plug in their own 32-byte digest. Roots, proofs and node keys in
+/// the database all depend on it, so a database must always be opened with the same
+/// hasher.
+pub trait Hasher {
+    /// Identifies the digest in files that record which one a trie uses, such as
+    /// snapshots, so they aren't loaded into a trie using another.
+    const NAME: &'static str;
+
+    fn hash(data: &[u8]) -> H256;
+}
+
+/// keccak256, as used by Ethereum.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct KeccakHasher;
+
+impl Hasher for KeccakHasher {
+    const NAME: &'static str = "keccak256";
+
+    fn hash(data: &[u8]) -> H256 {
+        keccak_hash::keccak(data)
+    }
+}
+
+/// The standardised SHA3-256, which pads differently from Ethereum's keccak256.
+#[cfg(feature = "sha3")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Sha3Hasher;
+
+#[cfg(feature = "sha3")]
+impl Hasher for Sha3Hasher {
+    const NAME: &'static str = "sha3-256";
+
+    fn hash(data: &[u8]) -> H256 {
+        use sha3::Digest;
+        H256::from_slice(&sha3::Sha3_256::digest(data))
+    }
+}
+
+/// BLAKE3 with its default 32-byte output.
+#[cfg(feature = "blake3")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Blake3Hasher;
+
+#[cfg(feature = "blake3")]
+impl Hasher for Blake3Hasher {
+    const NAME: &'static str = "blake3";
+
+    fn hash(data: &[u8]) -> H256 {
+        H256::from(blake3::hash(data).as_bytes())
+    }
+}
+
+/// Poseidon over the BN254 scalar field with circom's parameters, so roots and proofs
+/// can be checked cheaply inside zk circuits.
+///
+/// Node encodings are packed into field elements as big-endian 31-byte chunks, which
+/// always fit below the modulus, and absorbed 11 at a time: the first permutation
+/// takes the input length and the first chunks, every following one takes the
+/// previous output and the next chunks, padding the last group with zeros. The digest
+/// is the final field element in big-endian order, so it is always below the modulus
+/// and can be fed back in as a single element. Paired with `FieldCodec`, whose nodes are
+/// made of 31-byte words, each word of a node is absorbed as one element.
+#[cfg(feature = "poseidon")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PoseidonHasher;
+
+#[cfg(feature = "poseidon")]
+impl Hasher for PoseidonHasher {
+    const NAME: &'static str = "poseidon-bn254";
+
+    fn hash(data: &[u8]) -> H256 {
+        use std::cell::RefCell;
+
+        use ark_bn254::Fr;
+        use light_poseidon::{Poseidon, PoseidonBytesHasher};
+
+        const CHUNK_LEN: usize = 31;
+        const CHUNKS_PER_ROUND: usize = 11;
+        const ZERO: &[u8] = &[0];
+
+        thread_local! {
+            // Building the round constants is far more expensive than hashing.
+            static POSEIDON: RefCell<Poseidon<Fr>> = RefCell::new(
+                Poseidon::<Fr>::new_circom(CHUNKS_PER_ROUND + 1).expect("width is supported"),
+            );
+        }
+
+        // Starts from the length, as a 32-byte big-endian element.
+        let mut acc = [0u8; 32];
+        acc[24..].copy_from_slice(&(data.len() as u64).to_be_bytes());
+        let chunks: Vec<&[u8]> = data.chunks(CHUNK_LEN).collect();
+        let groups: Vec<&[&[u8]]> = if chunks.is_empty() {
+            vec![&[]]
+        } else {
+            chunks.chunks(CHUNKS_PER_ROUND).collect()
+        };
+        for group in groups {
+            let mut inputs: Vec<&[u8]> = Vec::with_capacity(CHUNKS_PER_ROUND + 1);
+            inputs.push(&acc);
+            inputs.extend_from_slice(group);
+            inputs.resize(CHUNKS_PER_ROUND + 1, ZERO);
+
+            acc = POSEIDON.with(|poseidon| {
+                poseidon
+                    .borrow_mut()
+                    .hash_bytes_be(&inputs)
+                    .expect("inputs are below the field modulus")
+            });
+        }
+        H256::from(acc)
+    }
+}
@@ -0,0 +1,144 @@
+use crate::changeset::Changes;
+use crate::codec::NodeCodec;
+use crate::db::{Meta, DB};
+use crate::errors::TrieError;
+use crate::hasher::Hasher;
+use crate::trie::{EthTrie, TrieResult};
+use crate::versions::be_u64;
+
+// Each key's history is a list of entries, one per version at which it changed,
+// linked from the latest one. An entry is a flags byte, the previous version if
+// there is one, and the value unless the key was deleted.
+const HAS_PREVIOUS: u8 = 1;
+const HAS_VALUE: u8 = 2;
+
+fn head_key(key: &[u8]) -> Vec<u8> {
+    [b"history-head:".as_slice(), key].concat()
+}
+
+fn entry_key(key: &[u8], version: u64) -> Vec<u8> {
+    [b"history:".as_slice(), key, &version.to_be_bytes()].concat()
+}
+
+// The keys that changed at a version, so their entries can be dropped with it.
+fn changed_key(version: u64) -> Vec<u8> {
+    [b"changed:".as_slice(), &version.to_be_bytes()].concat()
+}
+
+impl<D, H, C> EthTrie<D, H, C>
+where
+    D: DB,
+    H: Hasher,
+    C: NodeCodec,
+{
+    /// Chooses whether `commit_version` records, for every key it changes, the
+    /// version and the new value, so `history` can answer when a key changed without
+    /// replaying every version. The changes are found by comparing the trie with the
+    /// root of the previous version, so the first version records every key.
+    ///
+    /// With pruning, as by default, every commit must go through `commit_version`:
+    /// a plain `commit` in between removes nodes of the previous version, and the next
+    /// `commit_version` then fails with `TrieError::MissingTrieNode`.
+    pub fn with_history(mut self, history: bool) -> Self {
+        self.history = history;
+        self
+    }
+
+    /// Returns the versions at which `key` changed, oldest first, with its value from
+    /// then on, or `None` where it was deleted. Versions dropped from the index by
+    /// `with_version_retention` are dropped from the history too.
+    pub fn history(&self, key: &[u8]) -> TrieResult<Vec<(u64, Option<Vec<u8>>)>> {
+        let mut history = vec![];
+        let mut version = self
+            .get_meta(&head_key(key))?
+            .map(|v| be_u64(&v))
+            .transpose()?;
+        while let Some(at) = version {
+            // The older entries have been pruned.
+            let Some(entry) = self.get_meta(&entry_key(key, at))? else {
+                break;
+            };
+            let (previous, value) = decode_entry(&entry)?;
+            history.push((at, value));
+            version = previous;
+        }
+        history.reverse();
+        Ok(history)
+    }
+
+    // The entries appending the `changes` made at `version` to the history of their
+    // keys, to be written with the commit of that version.
+    pub(crate) fn history_meta(&self, version: u64, changes: Changes) -> TrieResult<Meta> {
+        let mut meta = vec![];
+        if changes.is_empty() {
+            return Ok(meta);
+        }
+
+        let mut changed = vec![];
+        for (key, value) in changes {
+            let previous = self.get_meta(&head_key(&key))?;
+            let mut entry = vec![0];
+            if let Some(previous) = previous {
+                entry[0] |= HAS_PREVIOUS;
+                entry.extend_from_slice(&previous);
+            }
+            if let Some(value) = value {
+                entry[0] |= HAS_VALUE;
+                entry.extend_from_slice(&value);
+            }
+            meta.push((entry_key(&key, version), entry));
+            meta.push((head_key(&key), version.to_be_bytes().to_vec()));
+
+            changed.extend_from_slice(&(key.len() as u32).to_be_bytes());
+            changed.extend_from_slice(&key);
+        }
+        meta.push((changed_key(version), changed));
+        Ok(meta)
+    }
+
+    // The keys of the history entries of `version`, which is leaving the index, to
+    // be removed by the commit of a later version.
+    pub(crate) fn history_removals(&self, version: u64) -> TrieResult<Vec<Vec<u8>>> {
+        let Some(changed) = self.get_meta(&changed_key(version))? else {
+            return Ok(vec![]);
+        };
+
+        let mut removals = vec![];
+        let mut rest = &changed[..];
+        while !rest.is_empty() {
+            let len = be_u32(rest.get(..4))? as usize;
+            let key = rest.get(4..4 + len).ok_or(TrieError::InvalidData)?;
+            rest = &rest[4 + len..];
+
+            removals.push(entry_key(key, version));
+            // A head the same commit moves to its own version is written after the
+            // removals, so it is kept.
+            let head = self.get_meta(&head_key(key))?;
+            if head.is_some_and(|head| head == version.to_be_bytes()) {
+                removals.push(head_key(key));
+            }
+        }
+        removals.push(changed_key(version));
+        Ok(removals)
+    }
+}
+
+fn decode_entry(entry: &[u8]) -> TrieResult<(Option<u64>, Option<Vec<u8>>)> {
+    let (&flags, mut rest) = entry.split_first().ok_or(TrieError::InvalidData)?;
+    let previous = if flags & HAS_PREVIOUS != 0 {
+        let previous = be_u64(rest.get(..8).ok_or(TrieError::InvalidData)?)?;
+        rest = &rest[8..];
+        Some(previous)
+    } else {
+        None
+    };
+    let value = (flags & HAS_VALUE != 0).then(|| rest.to_vec());
+    Ok((previous, value))
+}
+
+fn be_u32(bytes: Option<&[u8]>) -> TrieResult<u32> {
+    bytes
+        .and_then(|bytes| bytes.try_into().ok())
+        .map(u32::from_be_bytes)
+        .ok_or(TrieError::InvalidData)
+}
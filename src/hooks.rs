@@ -0,0 +1,65 @@
+use std::fmt;
+use std::sync::Arc;
+
+use ethereum_types::H256;
+
+use crate::codec::NodeCodec;
+use crate::db::{CommitBatch, DB};
+use crate::hasher::Hasher;
+use crate::trie::{EthTrie, TrieResult};
+
+type PreCommitHook = Arc<dyn Fn(&mut CommitBatch) -> TrieResult<()> + Send + Sync>;
+type PostCommitHook = Arc<dyn Fn(H256) + Send + Sync>;
+
+// The hooks registered on a trie, kept by the tries created from it with `at_root`.
+#[derive(Clone, Default)]
+pub(crate) struct CommitHooks {
+    pre: Vec<PreCommitHook>,
+    post: Vec<PostCommitHook>,
+}
+
+impl fmt::Debug for CommitHooks {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CommitHooks")
+            .field("pre", &self.pre.len())
+            .field("post", &self.post.len())
+            .finish()
+    }
+}
+
+impl CommitHooks {
+    pub(crate) fn pre_commit(&self, batch: &mut CommitBatch) -> TrieResult<()> {
+        self.pre.iter().try_for_each(|hook| hook(batch))
+    }
+
+    pub(crate) fn post_commit(&self, root: H256) {
+        self.post.iter().for_each(|hook| hook(root))
+    }
+}
+
+impl<D, H, C> EthTrie<D, H, C>
+where
+    D: DB,
+    H: Hasher,
+    C: NodeCodec,
+{
+    /// Registers `hook` to run on every commit once its batch is ready, before it is
+    /// written. The hook sees the root being committed and the nodes written and
+    /// pruned, and may add metadata to `CommitBatch::meta`, which `DB::commit_batch`
+    /// writes with the nodes, in the same transaction with `SqliteDB`. An error from
+    /// the hook aborts the commit before anything is written. Hooks run in the order
+    /// they were registered, and carry over to tries created with `at_root`.
+    pub fn with_pre_commit_hook(
+        mut self,
+        hook: impl Fn(&mut CommitBatch) -> TrieResult<()> + Send + Sync + 'static,
+    ) -> Self {
+        self.hooks.pre.push(Arc::new(hook));
+        self
+    }
+
+    /// Registers `hook` to run with the new root once a commit has been written.
+    pub fn with_post_commit_hook(mut self, hook: impl Fn(H256) + Send + Sync + 'static) -> Self {
+        self.hooks.post.push(Arc::new(hook));
+        self
+    }
+}
@@ -0,0 +1,409 @@
+use std::collections::{HashMap, HashSet};
+use std::mem;
+
+use ethereum_types::H256;
+use parking_lot::Mutex;
+
+use crate::db::{CommitBatch, StorageStats, DB};
+
+/// A `DB` keeping the nodes of recent commits in memory, one layer per root, and
+/// writing a layer to the database below only once enough commits have been sealed
+/// on top of it, as geth does. Until then a reorganization costs nothing: the trie
+/// at an earlier root is still readable, and layers on the abandoned branch are
+/// dropped without ever reaching the disk.
+///
+/// Nodes written by the trie go to a pending layer, which `seal` turns into the
+/// layer of the root just committed. Node removals, as made by a pruning commit, are
+/// held back too and only applied when their layer is written out, so pruning
+/// happens automatically once the roots that still needed the nodes are final.
+/// Metadata written along with the nodes of a commit, such as the version index, is
+/// journaled with them, so it reaches the database below in the same `commit_batch`
+/// and is dropped with them. Blobs and other metadata go straight to the database
+/// below.
+pub struct JournalDB<D> {
+    backing: D,
+    confirmations: usize,
+    journal: Mutex<Journal>,
+}
+
+#[derive(Default)]
+struct Journal {
+    // Writes since the last `seal`.
+    pending: Layer,
+    layers: HashMap<H256, Layer>,
+    // Layers sealed so far, numbering them in order.
+    sealed: u64,
+}
+
+#[derive(Default)]
+struct Layer {
+    parent: H256,
+    // When the layer was sealed, for the latest metadata to win.
+    seq: u64,
+    inserts: HashMap<Vec<u8>, Vec<u8>>,
+    removes: Vec<Vec<u8>>,
+    // `None` for metadata the commit removes.
+    meta: HashMap<Vec<u8>, Option<Vec<u8>>>,
+}
+
+impl<D: DB> JournalDB<D> {
+    /// Layers are written to `backing` once `confirmations` roots have been sealed on
+    /// top of them. With none, every layer is written as soon as it's sealed.
+    pub fn new(backing: D, confirmations: usize) -> Self {
+        JournalDB {
+            backing,
+            confirmations,
+            journal: Mutex::new(Journal::default()),
+        }
+    }
+
+    pub fn backing(&self) -> &D {
+        &self.backing
+    }
+
+    /// The roots whose layers are held in memory.
+    pub fn layer_roots(&self) -> Vec<H256> {
+        self.journal.lock().layers.keys().copied().collect()
+    }
+
+    /// Turns the writes since the last call into the layer of `root`, committed on
+    /// top of `parent`, and writes out the layers that now have enough confirmations.
+    /// Sibling layers of one written out, and the layers built on them, are on a
+    /// branch that can no longer become final and are dropped.
+    pub fn seal(&self, parent: H256, root: H256) -> Result<(), D::Error> {
+        let mut journal = self.journal.lock();
+        let mut pending = mem::take(&mut journal.pending);
+        journal.sealed += 1;
+        pending.parent = parent;
+        pending.seq = journal.sealed;
+        if let Some(layer) = journal.layers.get_mut(&root) {
+            // The same root committed again, on this branch or another.
+            layer.seq = pending.seq;
+            layer.inserts.extend(pending.inserts);
+            layer.removes.extend(pending.removes);
+            layer.meta.extend(pending.meta);
+            return Ok(());
+        }
+        if root == parent {
+            // Nothing changed, but writes made without a new root still belong below.
+            // They stay pending if that fails.
+            let written = self.write(root, &pending);
+            if written.is_err() {
+                journal.pending = pending;
+            }
+            return written;
+        }
+        journal.layers.insert(root, pending);
+
+        // The branch of `root` back to its oldest layer in memory, newest first.
+        let mut branch = vec![root];
+        while let Some(layer) = journal.layers.get(branch.last().unwrap()) {
+            if !journal.layers.contains_key(&layer.parent) {
+                break;
+            }
+            branch.push(layer.parent);
+        }
+        while branch.len() > self.confirmations {
+            // A layer that fails to be written stays in memory, for the next seal to
+            // write again.
+            let final_root = branch.pop().unwrap();
+            self.write(final_root, &journal.layers[&final_root])?;
+            let layer = journal.layers.remove(&final_root).unwrap();
+            drop_siblings(&mut journal.layers, layer.parent);
+        }
+        Ok(())
+    }
+
+    // Writes the layer of `root` to the database below in a single `commit_batch`.
+    fn write(&self, root: H256, layer: &Layer) -> Result<(), D::Error> {
+        // A node removed and written again ends up stored.
+        let removed = layer
+            .removes
+            .iter()
+            .filter(|key| !layer.inserts.contains_key(*key))
+            .cloned()
+            .collect();
+        let (keys, values) = layer
+            .inserts
+            .iter()
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .unzip();
+        let (mut meta, mut removed_meta) = (vec![], vec![]);
+        for (key, value) in &layer.meta {
+            match value {
+                Some(value) => meta.push((key.clone(), value.clone())),
+                None => removed_meta.push(key.clone()),
+            }
+        }
+        self.backing.commit_batch(CommitBatch {
+            root,
+            keys,
+            values,
+            removed,
+            meta,
+            removed_meta,
+        })
+    }
+
+    // The node stored under `key` in memory, if any.
+    fn journaled(&self, key: &[u8]) -> Option<Vec<u8>> {
+        let journal = self.journal.lock();
+        journal
+            .pending
+            .inserts
+            .get(key)
+            .or_else(|| {
+                journal
+                    .layers
+                    .values()
+                    .find_map(|layer| layer.inserts.get(key))
+            })
+            .cloned()
+    }
+
+    // The metadata stored under `key` in memory, by the latest commit setting or
+    // removing it, or `None` if no commit in memory does.
+    fn journaled_meta(&self, key: &[u8]) -> Option<Option<Vec<u8>>> {
+        let journal = self.journal.lock();
+        journal.pending.meta.get(key).cloned().or_else(|| {
+            journal
+                .layers
+                .values()
+                .filter(|layer| layer.meta.contains_key(key))
+                .max_by_key(|layer| layer.seq)
+                .map(|layer| layer.meta[key].clone())
+        })
+    }
+
+    // Drops the metadata journaled under `key`.
+    fn forget_meta(&self, key: &[u8]) {
+        let mut journal = self.journal.lock();
+        journal.pending.meta.remove(key);
+        for layer in journal.layers.values_mut() {
+            layer.meta.remove(key);
+        }
+    }
+}
+
+// Drops the layers built on `parent`, which has just had a child made final, and
+// everything built on them.
+fn drop_siblings(layers: &mut HashMap<H256, Layer>, parent: H256) {
+    let mut dropped = HashSet::from([parent]);
+    loop {
+        let stale: Vec<H256> = layers
+            .iter()
+            .filter(|(_, layer)| dropped.contains(&layer.parent))
+            .map(|(root, _)| *root)
+            .collect();
+        if stale.is_empty() {
+            return;
+        }
+        for root in stale {
+            layers.remove(&root);
+            dropped.insert(root);
+        }
+    }
+}
+
+impl<D: DB> DB for JournalDB<D> {
+    type Error = D::Error;
+
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Self::Error> {
+        match self.journaled(key) {
+            Some(value) => Ok(Some(value)),
+            None => self.backing.get(key),
+        }
+    }
+
+    fn contains(&self, key: &[u8]) -> Result<bool, Self::Error> {
+        if self.journaled(key).is_some() {
+            return Ok(true);
+        }
+        self.backing.contains(key)
+    }
+
+    fn insert(&self, key: &[u8], value: Vec<u8>) -> Result<(), Self::Error> {
+        self.journal
+            .lock()
+            .pending
+            .inserts
+            .insert(key.to_vec(), value);
+        Ok(())
+    }
+
+    fn remove(&self, key: &[u8]) -> Result<(), Self::Error> {
+        self.journal.lock().pending.removes.push(key.to_vec());
+        Ok(())
+    }
+
+    fn insert_batch(&self, keys: Vec<Vec<u8>>, values: Vec<Vec<u8>>) -> Result<(), Self::Error> {
+        self.journal
+            .lock()
+            .pending
+            .inserts
+            .extend(keys.into_iter().zip(values));
+        Ok(())
+    }
+
+    fn remove_batch(&self, keys: &[Vec<u8>]) -> Result<(), Self::Error> {
+        self.journal.lock().pending.removes.extend_from_slice(keys);
+        Ok(())
+    }
+
+    /// Journals the nodes, removals and metadata of a commit together, for `seal` to
+    /// turn into a layer.
+    fn commit_batch(&self, batch: CommitBatch) -> Result<(), Self::Error> {
+        let mut journal = self.journal.lock();
+        let pending = &mut journal.pending;
+        pending
+            .inserts
+            .extend(batch.keys.into_iter().zip(batch.values));
+        pending.removes.extend(batch.removed);
+        pending
+            .meta
+            .extend(batch.removed_meta.into_iter().map(|key| (key, None)));
+        pending.meta.extend(
+            batch
+                .meta
+                .into_iter()
+                .map(|(key, value)| (key, Some(value))),
+        );
+        Ok(())
+    }
+
+    /// Flushes the database below. Layers without enough confirmations stay in
+    /// memory.
+    fn flush(&self) -> Result<(), Self::Error> {
+        self.backing.flush()
+    }
+
+    fn get_blob(&self, hash: &[u8]) -> Result<Option<Vec<u8>>, Self::Error> {
+        self.backing.get_blob(hash)
+    }
+
+    fn insert_blob(&self, hash: &[u8], value: Vec<u8>) -> Result<(), Self::Error> {
+        self.backing.insert_blob(hash, value)
+    }
+
+    fn get_meta(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Self::Error> {
+        match self.journaled_meta(key) {
+            Some(value) => Ok(value),
+            None => self.backing.get_meta(key),
+        }
+    }
+
+    /// Written straight to the database below, replacing any value journaled under
+    /// `key`, which would otherwise be read first and written over it later.
+    fn insert_meta(&self, key: &[u8], value: Vec<u8>) -> Result<(), Self::Error> {
+        self.forget_meta(key);
+        self.backing.insert_meta(key, value)
+    }
+
+    fn remove_meta(&self, key: &[u8]) -> Result<(), Self::Error> {
+        self.forget_meta(key);
+        self.backing.remove_meta(key)
+    }
+
+    /// The stats of the database below, without the layers held in memory.
+    fn stats(&self) -> Result<Option<StorageStats>, Self::Error> {
+        self.backing.stats()
+    }
+
+    /// The nodes of the database below, without the layers held in memory.
+    fn iter_nodes(&self, f: &mut dyn FnMut(&[u8], &[u8])) -> Result<bool, Self::Error> {
+        self.backing.iter_nodes(f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::test_utils::{random_entries, Entries, FaultyDB, MemoryDB};
+    use crate::trie::{EthTrie, ITrie};
+
+    // The entries block `seed` adds, under keys of their own.
+    fn entries(seed: u64) -> Entries {
+        random_entries(seed, 20)
+            .into_iter()
+            .map(|(key, value)| ([&seed.to_be_bytes()[..], &key].concat(), value))
+            .collect()
+    }
+
+    // Commits the entries of block `seed` on top of `trie` and seals the new root.
+    fn block<D: DB>(db: &JournalDB<D>, trie: &mut EthTrie<JournalDB<D>>, seed: u64) -> H256 {
+        let parent = trie.root_hash;
+        for (key, value) in entries(seed) {
+            trie.put(&key, &value).unwrap();
+        }
+        let root = trie.commit().unwrap();
+        db.seal(parent, root).unwrap();
+        root
+    }
+
+    #[test]
+    fn layers_are_written_once_confirmed() {
+        let db = Arc::new(JournalDB::new(MemoryDB::new(), 2));
+        let mut trie = EthTrie::new(db.clone());
+        let first = block(&db, &mut trie, 1);
+        let second = block(&db, &mut trie, 2);
+        assert!(db.backing().is_empty());
+        assert_eq!(db.layer_roots().len(), 2);
+
+        block(&db, &mut trie, 3);
+        assert!(!db.layer_roots().contains(&first));
+        assert!(db.backing().get(first.as_bytes()).unwrap().is_some());
+        assert!(db.backing().get(second.as_bytes()).unwrap().is_none());
+        for seed in 1..=3 {
+            for (key, value) in entries(seed) {
+                assert_eq!(trie.get(&key).unwrap(), Some(value));
+            }
+        }
+    }
+
+    #[test]
+    fn forks_are_dropped_once_a_sibling_is_final() {
+        let db = Arc::new(JournalDB::new(MemoryDB::new(), 1));
+        let mut trie = EthTrie::new(db.clone());
+        let base = block(&db, &mut trie, 1);
+        let mut fork = trie.at_root(base);
+        let dropped = block(&db, &mut fork, 2);
+        let kept = block(&db, &mut trie, 3);
+
+        // Both branches are readable until one of them is final.
+        let (key, value) = entries(2).pop_first().unwrap();
+        let fork = trie.at_root(dropped);
+        assert_eq!(fork.get(&key).unwrap(), Some(value));
+        assert!(db.layer_roots().contains(&dropped));
+
+        block(&db, &mut trie, 4);
+        assert!(!db.layer_roots().contains(&dropped));
+        assert!(db.backing().get(kept.as_bytes()).unwrap().is_some());
+        assert!(db.backing().get(dropped.as_bytes()).unwrap().is_none());
+    }
+
+    #[test]
+    fn layer_failing_to_be_written_is_kept() {
+        let db = Arc::new(JournalDB::new(FaultyDB::new(), 0));
+        // Pruning would remove nodes of the first root once the next one is written.
+        let mut trie = EthTrie::new(db.clone()).with_pruning(false);
+        db.backing().fail_writes(1);
+        let parent = trie.root_hash;
+        for (key, value) in entries(1) {
+            trie.put(&key, &value).unwrap();
+        }
+        let root = trie.commit().unwrap();
+        assert!(db.seal(parent, root).is_err());
+        assert_eq!(db.layer_roots(), vec![root]);
+
+        // The next seal writes it out along with the new layer.
+        let next = block(&db, &mut trie, 2);
+        assert!(db.layer_roots().is_empty());
+        assert!(db.backing().get(next.as_bytes()).unwrap().is_some());
+        let stored = EthTrie::new(db.clone()).at_root(root);
+        for (key, value) in entries(1) {
+            assert_eq!(stored.get(&key).unwrap(), Some(value));
+        }
+    }
+}
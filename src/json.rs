@@ -0,0 +1,133 @@
+#[cfg(feature = "json")]
+use std::collections::BTreeMap;
+#[cfg(feature = "json")]
+use std::io::Read;
+use std::io::Write;
+
+use ethereum_types::H256;
+
+use crate::codec::NodeCodec;
+use crate::db::DB;
+#[cfg(feature = "json")]
+use crate::errors::TrieError;
+use crate::hasher::Hasher;
+#[cfg(feature = "json")]
+use crate::node::Node;
+use crate::page::PageCursor;
+use crate::trie::{EthTrie, ITrie, TrieResult};
+
+#[cfg(feature = "json")]
+#[derive(serde::Deserialize)]
+struct JsonTrie {
+    root: String,
+    entries: Vec<JsonEntry>,
+}
+
+#[cfg(feature = "json")]
+#[derive(serde::Deserialize)]
+struct JsonEntry {
+    key: String,
+    value: String,
+}
+
+// Entries are read from the trie a page at a time, so exporting never holds more
+// than a page in memory.
+const EXPORT_PAGE_SIZE: usize = 1024;
+
+impl<D, H, C> EthTrie<D, H, C>
+where
+    D: DB,
+    H: Hasher,
+    C: NodeCodec,
+{
+    /// Writes every key/value pair to `writer` as JSON, in key order, and returns the
+    /// root hash. Pending changes are committed first so the recorded root matches
+    /// the entries. Keys, values and the root are hex encoded:
+    ///
+    /// ```json
+    /// {"root": "56e8...b421", "entries": [
+    /// {"key": "6b6579", "value": "76616c7565"}
+    /// ]}
+    /// ```
+    ///
+    /// Entries are written one per line so exports can be diffed line by line.
+    pub fn export_json<W: Write>(&mut self, mut writer: W) -> TrieResult<H256> {
+        let root_hash = self.commit()?;
+        write!(
+            writer,
+            "{{\"root\": \"{}\", \"entries\": [",
+            hex::encode(root_hash)
+        )?;
+
+        let mut cursor: Option<PageCursor> = None;
+        let mut first = true;
+        loop {
+            let page = self.iter_page(cursor.as_ref(), EXPORT_PAGE_SIZE)?;
+            for (key, value) in page.entries {
+                let separator = if first { "" } else { "," };
+                first = false;
+                write!(
+                    writer,
+                    "{}\n{{\"key\": \"{}\", \"value\": \"{}\"}}",
+                    separator,
+                    hex::encode(key),
+                    hex::encode(value)
+                )?;
+            }
+            match page.cursor {
+                Some(next) => cursor = Some(next),
+                None => break,
+            }
+        }
+
+        writeln!(writer, "\n]}}")?;
+        writer.flush()?;
+        Ok(root_hash)
+    }
+
+    /// Reads entries written by `export_json`, inserts them, commits, and checks the
+    /// resulting root against the one recorded in the export.
+    ///
+    /// A committed empty trie is filled with `build_from_sorted`, after sorting the
+    /// entries and keeping the last value of a repeated key, so each node is encoded
+    /// and written once; like the build, this runs no commit hooks. Otherwise the
+    /// entries are added to what the trie holds with `put` and committed. Malformed
+    /// input fails with `InvalidData` before anything is inserted. On `RootMismatch`
+    /// the entries have already been written.
+    #[cfg(feature = "json")]
+    pub fn import_json<R: Read>(&mut self, reader: R) -> TrieResult<H256> {
+        let export: JsonTrie =
+            serde_json::from_reader(reader).map_err(|_| TrieError::InvalidData)?;
+        let expected = decode_hex(&export.root)?;
+        if expected.len() != 32 {
+            return Err(TrieError::InvalidData);
+        }
+        let expected = H256::from_slice(&expected);
+
+        let entries = export
+            .entries
+            .iter()
+            .map(|entry| Ok((decode_hex(&entry.key)?, decode_hex(&entry.value)?)))
+            .collect::<TrieResult<BTreeMap<_, _>>>()?;
+        // Only a committed empty trie, with no removals pending, is built afresh.
+        let empty =
+            matches!(self.root, Node::Empty) && self.root_hash == H::hash(&C::encode_empty());
+        let found = if empty {
+            self.build_from_sorted(&entries)?
+        } else {
+            for (key, value) in &entries {
+                self.put(key, value)?;
+            }
+            self.commit()?
+        };
+        if found != expected {
+            return Err(TrieError::RootMismatch { expected, found });
+        }
+        Ok(found)
+    }
+}
+
+#[cfg(feature = "json")]
+fn decode_hex(s: &str) -> TrieResult<Vec<u8>> {
+    hex::decode(s).map_err(|_| TrieError::InvalidData)
+}
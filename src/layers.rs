@@ -0,0 +1,233 @@
+use std::collections::{HashMap, HashSet};
+
+use ethereum_types::H256;
+use parking_lot::Mutex;
+
+use crate::codec::NodeCodec;
+use crate::db::{CommitBatch, DB};
+use crate::errors::TrieError;
+use crate::hasher::Hasher;
+use crate::trie::{EthTrie, TrieResult};
+
+// The layers of the virtual commits of a trie and of the tries created from it with
+// `at_root`, by root.
+#[derive(Debug, Default)]
+pub(crate) struct Layers {
+    layers: Mutex<HashMap<H256, Layer>>,
+}
+
+#[derive(Debug)]
+struct Layer {
+    parent: H256,
+    nodes: HashMap<Vec<u8>, Vec<u8>>,
+    removed: Vec<Vec<u8>>,
+}
+
+impl Layers {
+    // The node stored under `hash` in a layer, if any.
+    pub(crate) fn get(&self, hash: H256) -> Option<Vec<u8>> {
+        let layers = self.layers.lock();
+        layers
+            .values()
+            .find_map(|layer| layer.nodes.get(hash.as_bytes()))
+            .cloned()
+    }
+
+    // Whether a layer holds the node stored under `hash`.
+    pub(crate) fn holds(&self, hash: H256) -> bool {
+        let layers = self.layers.lock();
+        layers
+            .values()
+            .any(|layer| layer.nodes.contains_key(hash.as_bytes()))
+    }
+
+    pub(crate) fn contains(&self, root: H256) -> bool {
+        self.layers.lock().contains_key(&root)
+    }
+}
+
+impl<D, H, C> EthTrie<D, H, C>
+where
+    D: DB,
+    H: Hasher,
+    C: NodeCodec,
+{
+    /// Commits like `commit`, but into an in-memory layer keyed by the new root
+    /// instead of the database, and returns the root. Nothing is written until the
+    /// layer is flushed with `flush`, so several blocks can be committed and the
+    /// branch that becomes final chosen afterwards, as execution clients do.
+    ///
+    /// Layers are shared with the tries created with `at_root`, which can open any
+    /// virtual root. Commit hooks run when a layer is flushed, and a plain `commit`
+    /// on top of a virtual root flushes it first.
+    pub fn commit_virtual(&mut self) -> TrieResult<H256> {
+        let parent = self.root_hash;
+        let batch = self.commit_batch();
+        let root = batch.root;
+        let mut layers = self.layers.layers.lock();
+        match layers.get_mut(&root) {
+            // The same root committed again, from this parent or another.
+            Some(layer) => {
+                layer.nodes.extend(batch.keys.into_iter().zip(batch.values));
+                layer.removed.extend(batch.removed);
+            }
+            None if root == parent => {}
+            None => {
+                let layer = Layer {
+                    parent,
+                    nodes: batch.keys.into_iter().zip(batch.values).collect(),
+                    removed: batch.removed,
+                };
+                layers.insert(root, layer);
+            }
+        }
+        drop(layers);
+        self.finish_commit(root);
+        Ok(root)
+    }
+
+    /// Writes the layer of the virtual root `root`, and the layers below it not
+    /// written yet, to the database as a single commit. Layers built on top of
+    /// `root` stay in memory; every other layer is on a branch that was not chosen
+    /// and is dropped. Roots without a layer, such as ones already written, are left
+    /// as they are.
+    pub fn flush(&self, root: H256) -> TrieResult<()> {
+        let mut layers = self.layers.layers.lock();
+        let mut branch = vec![];
+        let mut at = root;
+        while let Some(layer) = layers.get(&at) {
+            branch.push(at);
+            at = layer.parent;
+        }
+        if branch.is_empty() {
+            return Ok(());
+        }
+
+        // Applied oldest first, a node written again after being removed is kept,
+        // and one removed after being written is dropped.
+        let mut nodes = HashMap::new();
+        let mut removed = HashSet::new();
+        for at in branch.iter().rev() {
+            let layer = &layers[at];
+            for key in &layer.removed {
+                nodes.remove(key);
+                removed.insert(key.clone());
+            }
+            for (key, value) in &layer.nodes {
+                removed.remove(key);
+                nodes.insert(key.clone(), value.clone());
+            }
+        }
+        let (keys, values) = nodes.into_iter().unzip();
+        let mut batch = CommitBatch {
+            root,
+            keys,
+            values,
+            removed: removed.into_iter().collect(),
+            meta: vec![],
+            removed_meta: vec![],
+        };
+        self.hooks.pre_commit(&mut batch)?;
+        self.db.commit_batch(batch).map_err(TrieError::backend)?;
+
+        // Keep the layers descending from `root`.
+        let mut kept = HashSet::from([root]);
+        loop {
+            let children: Vec<H256> = layers
+                .iter()
+                .filter(|(at, layer)| kept.contains(&layer.parent) && !kept.contains(*at))
+                .map(|(at, _)| *at)
+                .collect();
+            if children.is_empty() {
+                break;
+            }
+            kept.extend(children);
+        }
+        layers.retain(|at, _| *at != root && kept.contains(at));
+        drop(layers);
+        self.hooks.post_commit(root);
+        Ok(())
+    }
+
+    /// The roots committed with `commit_virtual` and not flushed or dropped yet.
+    pub fn virtual_roots(&self) -> Vec<H256> {
+        self.layers.layers.lock().keys().copied().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::test_utils::{random_entries, Entries, FaultyDB, MemoryDB};
+    use crate::trie::ITrie;
+
+    // The entries block `seed` adds, under keys of their own.
+    fn entries(seed: u64) -> Entries {
+        random_entries(seed, 20)
+            .into_iter()
+            .map(|(key, value)| ([&seed.to_be_bytes()[..], &key].concat(), value))
+            .collect()
+    }
+
+    fn block<D: DB>(trie: &mut EthTrie<D>, seed: u64) -> H256 {
+        for (key, value) in entries(seed) {
+            trie.put(&key, &value).unwrap();
+        }
+        trie.commit_virtual().unwrap()
+    }
+
+    #[test]
+    fn virtual_commits_are_written_when_flushed() {
+        let db = Arc::new(MemoryDB::new());
+        let mut trie = EthTrie::new(db.clone());
+        block(&mut trie, 1);
+        let root = block(&mut trie, 2);
+        assert!(db.is_empty());
+
+        let reopened = trie.at_root(root);
+        for (key, value) in entries(1).into_iter().chain(entries(2)) {
+            assert_eq!(reopened.get(&key).unwrap(), Some(value));
+        }
+
+        trie.flush(root).unwrap();
+        assert!(trie.virtual_roots().is_empty());
+        let stored = EthTrie::new(db).at_root(root);
+        for (key, value) in entries(1).into_iter().chain(entries(2)) {
+            assert_eq!(stored.get(&key).unwrap(), Some(value));
+        }
+    }
+
+    #[test]
+    fn flush_drops_the_other_forks() {
+        let db = Arc::new(MemoryDB::new());
+        let mut trie = EthTrie::new(db.clone());
+        let base = block(&mut trie, 1);
+        let mut fork = trie.at_root(base);
+        let dropped = block(&mut fork, 2);
+        let kept = block(&mut trie, 3);
+        let child = block(&mut trie, 4);
+
+        trie.flush(kept).unwrap();
+        assert_eq!(trie.virtual_roots(), vec![child]);
+        assert!(db.get(kept.as_bytes()).unwrap().is_some());
+        assert!(db.get(dropped.as_bytes()).unwrap().is_none());
+    }
+
+    #[test]
+    fn failed_flush_keeps_the_layers() {
+        let db = Arc::new(FaultyDB::new());
+        let mut trie = EthTrie::new(db.clone());
+        let root = block(&mut trie, 1);
+        db.fail_writes(1);
+        assert!(trie.flush(root).is_err());
+        assert_eq!(trie.virtual_roots(), vec![root]);
+
+        trie.flush(root).unwrap();
+        let stored = EthTrie::new(db).at_root(root);
+        for (key, value) in entries(1) {
+            assert_eq!(stored.get(&key).unwrap(), Some(value));
+        }
+    }
+}
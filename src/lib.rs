@@ -1,10 +1,178 @@
+//! Merkle-Patricia tries backed by a key/value database.
+//!
+//! Without the default `std` feature only proof verification is available
+//! (`verify_proof`), along with the node codec and hashers it builds on, so proofs can
+//! be checked on embedded devices and in zkVM guests.
+#![cfg_attr(not(feature = "std"), no_std)]
+
+extern crate alloc;
+
 mod nibbles;
+#[cfg(feature = "std")]
 mod node;
 
+#[cfg(feature = "std")]
+mod audit;
+#[cfg(feature = "std")]
+mod binary;
+#[cfg(feature = "std")]
+mod build;
+#[cfg(feature = "std")]
+mod cache;
+#[cfg(feature = "std")]
+mod changeset;
+mod codec;
+#[cfg(feature = "std")]
+mod compact;
+#[cfg(feature = "std")]
+mod compare;
+#[cfg(feature = "std")]
+mod copy;
+#[cfg(feature = "std")]
 mod db;
+#[cfg(feature = "std")]
+mod dot;
+#[cfg(feature = "std")]
+mod entry;
 mod errors;
+#[cfg(feature = "std")]
+mod estimate;
+#[cfg(feature = "std")]
+mod fixed;
+#[cfg(feature = "flatfile")]
+mod flatfile;
+mod hasher;
+#[cfg(feature = "std")]
+mod history;
+#[cfg(feature = "std")]
+mod hooks;
+#[cfg(feature = "std")]
+mod journal;
+#[cfg(feature = "std")]
+mod json;
+#[cfg(feature = "std")]
+mod layers;
+#[cfg(feature = "std")]
+pub mod light;
+#[cfg(feature = "metrics")]
+pub mod metrics;
+#[cfg(feature = "std")]
+mod orphans;
+#[cfg(feature = "std")]
+mod page;
+#[cfg(feature = "std")]
+mod perf;
+#[cfg(feature = "std")]
+mod proof;
+#[cfg(feature = "serde")]
+mod serialize;
+#[cfg(feature = "server")]
+mod server;
+#[cfg(feature = "std")]
+mod recover;
+#[cfg(feature = "std")]
+mod replicated;
+#[cfg(feature = "std")]
+mod retry;
+#[cfg(feature = "std")]
+mod secure;
+#[cfg(feature = "std")]
+mod smt;
+#[cfg(feature = "std")]
+pub mod snap;
+#[cfg(feature = "std")]
+mod snapshot;
+#[cfg(feature = "std")]
+pub mod state;
+#[cfg(feature = "std")]
+mod subtrie;
+#[cfg(any(test, feature = "test-utils"))]
+pub mod test_utils;
+#[cfg(feature = "std")]
+mod traversal;
+#[cfg(feature = "std")]
 mod trie;
+#[cfg(feature = "std")]
+mod typed;
+#[cfg(feature = "std")]
+mod value;
+#[cfg(feature = "std")]
+mod versions;
+#[cfg(feature = "std")]
+mod watch;
+mod verify;
+#[cfg(feature = "std")]
+mod walk;
 
-pub use db::{SqliteDB, DB};
-pub use errors::{TrieError};
-pub use trie::{EthTrie, ITrie};
\ No newline at end of file
+#[cfg(feature = "std")]
+pub use audit::{AuditLog, AuditRecord};
+#[cfg(feature = "std")]
+pub use binary::BinaryTrie;
+#[cfg(feature = "std")]
+pub use changeset::ChangeSet;
+pub use codec::{ChildRef, DecodeLimits, DecodedNode, FieldCodec, NodeCodec, RlpCodec};
+#[cfg(feature = "std")]
+pub use compare::{Mismatch, MismatchKind};
+#[cfg(feature = "std")]
+pub use db::{CommitBatch, PageStats, SqliteDB, StorageStats, DB};
+#[cfg(feature = "std")]
+pub use entry::Entry;
+pub use errors::{TrieError, TrieOp, TrieResult};
+#[cfg(feature = "std")]
+pub use fixed::FixedKey;
+#[cfg(feature = "flatfile")]
+pub use flatfile::FlatFileDB;
+#[cfg(feature = "blake3")]
+pub use hasher::Blake3Hasher;
+#[cfg(feature = "poseidon")]
+pub use hasher::PoseidonHasher;
+#[cfg(feature = "sha3")]
+pub use hasher::Sha3Hasher;
+pub use hasher::{Hasher, KeccakHasher};
+#[cfg(feature = "std")]
+pub use journal::JournalDB;
+pub use nibbles::Nibbles;
+#[cfg(feature = "std")]
+pub use node::{
+    decode_node, decode_node_with, encode_node, encode_node_with, BranchNode, ExtensionNode,
+    HashNode, LeafNode, Node,
+};
+#[cfg(feature = "std")]
+pub use orphans::Orphans;
+#[cfg(feature = "std")]
+pub use page::{Page, PageCursor};
+#[cfg(feature = "std")]
+pub use perf::PerfCounters;
+#[cfg(feature = "std")]
+pub use proof::ProofIterator;
+#[cfg(feature = "server")]
+pub use server::TrieServer;
+#[cfg(feature = "std")]
+pub use recover::Recovery;
+#[cfg(feature = "std")]
+pub use replicated::ReplicatedDB;
+#[cfg(feature = "std")]
+pub use retry::{Failure, RetryDB, RetryError};
+#[cfg(feature = "std")]
+pub use secure::SecureTrie;
+#[cfg(feature = "std")]
+pub use smt::{SmtProof, SmtTrie};
+#[cfg(feature = "std")]
+pub use snapshot::TrieSnapshot;
+#[cfg(feature = "std")]
+pub use subtrie::Subtrie;
+#[cfg(feature = "std")]
+pub use traversal::{SkippedNode, TraversalMode};
+#[cfg(feature = "std")]
+pub use trie::{CommitOutcome, EthTrie, ITrie, PendingSizes, TraversedNode};
+#[cfg(feature = "json")]
+pub use typed::AsJson;
+#[cfg(feature = "std")]
+pub use typed::{AsRlp, TrieKey, TrieValue, TypedTrie};
+#[cfg(feature = "std")]
+pub use value::EmptyValues;
+pub use verify::{verify_proof, verify_proof_with};
+#[cfg(feature = "std")]
+pub use watch::WatchedChange;
+#[cfg(feature = "std")]
+pub use walk::{VisitedNode, WalkControl};
@@ -0,0 +1,65 @@
+//! Light-client verification: reading an account and its storage slots out of proofs,
+//! such as those of `eth_getProof`, given only the state root of a trusted block
+//! header. Nothing is read from a database.
+
+use ethereum_types::{H160, H256, U256};
+use keccak_hash::{keccak, KECCAK_NULL_RLP};
+
+use crate::errors::TrieResult;
+use crate::state::Account;
+use crate::verify::verify_proof;
+
+/// An account and storage slots read out of proofs against a state root.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProvenAccount {
+    pub address: H160,
+    /// The account, or `None` if the proof shows it doesn't exist.
+    pub account: Option<Account>,
+    /// The value of each slot asked for, in order, zero for slots that are not set.
+    pub storage: Vec<(H256, U256)>,
+}
+
+/// Verifies the proof of the account at `address` against `state_root`, returning the
+/// account, or `None` if the proof shows it is absent. A proof that doesn't lead from
+/// the root to the account's key fails with `TrieError::InvalidProof`.
+pub fn verify_account(
+    state_root: H256,
+    address: H160,
+    proof: &[Vec<u8>],
+) -> TrieResult<Option<Account>> {
+    verify_proof(state_root, keccak(address).as_bytes(), proof)?
+        .map(|account| Account::from_rlp(&account))
+        .transpose()
+}
+
+/// Verifies the proof of a storage `slot` against the `storage_root` of its account,
+/// returning the slot's value, zero if it is not set.
+pub fn verify_slot(storage_root: H256, slot: H256, proof: &[Vec<u8>]) -> TrieResult<U256> {
+    let value = verify_proof(storage_root, keccak(slot).as_bytes(), proof)?
+        .map(|value| rlp::decode(&value))
+        .transpose()?;
+    Ok(value.unwrap_or_default())
+}
+
+/// Verifies the proof of the account at `address` against `state_root`, then the proof
+/// of each of `slots` against the account's storage root, the whole path from a block
+/// header to storage values in one call. Slots of an absent account are checked
+/// against the empty storage root, so their proofs must be empty.
+pub fn verify_account_storage(
+    state_root: H256,
+    address: H160,
+    account_proof: &[Vec<u8>],
+    slots: &[(H256, Vec<Vec<u8>>)],
+) -> TrieResult<ProvenAccount> {
+    let account = verify_account(state_root, address, account_proof)?;
+    let storage_root = account.map_or(KECCAK_NULL_RLP, |account| account.storage_root);
+    let storage = slots
+        .iter()
+        .map(|(slot, proof)| Ok((*slot, verify_slot(storage_root, *slot, proof)?)))
+        .collect::<TrieResult<_>>()?;
+    Ok(ProvenAccount {
+        address,
+        account,
+        storage,
+    })
+}
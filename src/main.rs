@@ -1,81 +1,418 @@
-use rusqlite::{params, Connection, Result};
-mod nibbles;
-mod node;
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::process::ExitCode;
+use std::sync::Arc;
 
-mod db;
-mod errors;
-mod trie;
-pub use db::{SqliteDB, DB};
-pub use errors::{TrieError};
-pub use trie::{EthTrie, ITrie};
+use clap::{Arg, ArgAction, ArgMatches, Command};
+use ethereum_types::H256;
+#[cfg(feature = "server")]
+use mpt::TrieServer;
+use mpt::{verify_proof, EthTrie, ITrie, MismatchKind, PageCursor, SqliteDB, DB};
 
-use std::sync::Arc;
-use hex::FromHex;
-use rand::Rng;
-use std::fs;
+/// Metadata key under which the CLI keeps the root of the last commit.
+const HEAD_KEY: &[u8] = b"cli-head";
+
+/// Entries read from the trie at a time by `dump`.
+const DUMP_PAGE_SIZE: usize = 1024;
+
+/// `dump` reports progress on stderr every this many entries.
+const DUMP_PROGRESS_INTERVAL: usize = 100_000;
 
+type CliResult<T> = Result<T, Box<dyn Error>>;
 
-#[derive(Debug)]
-struct NodeDB {
-    key: Vec<u8>,
-    data: Option<Vec<u8>>,
+/// A line of a `dump --format jsonl` file, as read back by `import`.
+#[derive(serde::Deserialize)]
+struct JsonlEntry {
+    key: String,
+    value: String,
 }
 
-fn insert_full_branch() {
-    delete_file(String::from("test1.db"));
-    let memdb = Arc::new(SqliteDB::new(String::from("test1.db")));
-    let mut trie = EthTrie::new(memdb);
-
-    trie.put(b"test", b"test");
-    trie.put(b"test1", b"test");
-    trie.put(b"test2", b"test");
-    trie.put(b"test23", b"test");
-    trie.put(b"test33", b"test");
-    trie.put(b"test44", b"test");
-    trie.commit();
-
-    let v = trie.get(b"test").unwrap();
-    assert_eq!(Some(b"test".to_vec()), v);
+fn cli() -> Command {
+    let key = || Arg::new("key").required(true).help("Key, in hex");
+
+    let command = Command::new("mpt")
+        .about("Inspect and modify a Merkle-Patricia trie stored in SQLite")
+        .subcommand_required(true)
+        .arg(
+            Arg::new("db")
+                .long("db")
+                .global(true)
+                .default_value("trie.db")
+                .help("SQLite database holding the trie"),
+        )
+        .arg(
+            Arg::new("root")
+                .long("root")
+                .global(true)
+                .help("Root to open, in hex [default: root of the last commit]"),
+        )
+        .subcommand(
+            Command::new("put")
+                .about("Set a key and print the new root")
+                .arg(key())
+                .arg(Arg::new("value").required(true).help("Value, in hex")),
+        )
+        .subcommand(
+            Command::new("get")
+                .about("Print the value of a key")
+                .arg(key()),
+        )
+        .subcommand(
+            Command::new("del")
+                .about("Delete a key and print the new root")
+                .arg(key()),
+        )
+        .subcommand(Command::new("root").about("Print the root"))
+        .subcommand(
+            Command::new("proof")
+                .about("Print the proof of a key, one node per line")
+                .arg(key()),
+        )
+        .subcommand(
+            Command::new("verify")
+                .about(
+                    "Check a proof against --root and print the value it proves, \
+                     without opening the database",
+                )
+                .arg(
+                    Arg::new("key")
+                        .long("key")
+                        .required(true)
+                        .help("Key, in hex"),
+                )
+                .arg(
+                    Arg::new("proof")
+                        .long("proof")
+                        .required(true)
+                        .help("File with one proof node per line, in hex, as printed by `proof`; - for stdin"),
+                ),
+        )
+        .subcommand(
+            Command::new("dump")
+                .about("Write every key/value pair of the trie, in key order")
+                .arg(
+                    Arg::new("format")
+                        .long("format")
+                        .value_parser(["jsonl", "csv"])
+                        .default_value("jsonl")
+                        .help("One JSON object per entry, or CSV with a header"),
+                )
+                .arg(
+                    Arg::new("output")
+                        .long("output")
+                        .short('o')
+                        .help("File to write to [default: stdout]"),
+                ),
+        )
+        .subcommand(
+            Command::new("import")
+                .about("Insert the entries of a JSON lines file, commit and print the new root")
+                .arg(
+                    Arg::new("file")
+                        .required(true)
+                        .help("One {\"key\": \"0x..\", \"value\": \"0x..\"} object per line, as written by `dump`"),
+                ),
+        )
+        .subcommand(
+            Command::new("compare")
+                .about("List the nodes and blobs of the trie that another database lacks or stores differently")
+                .arg(
+                    Arg::new("other")
+                        .required(true)
+                        .help("SQLite database to compare with, such as a migrated copy"),
+                ),
+        )
+        .subcommand(
+            Command::new("copy")
+                .about("Copy the trie to another database, check the copy and make it its head")
+                .arg(
+                    Arg::new("target")
+                        .required(true)
+                        .help("SQLite database to copy to, created if missing"),
+                ),
+        )
+        .subcommand(
+            Command::new("compact").about(
+                "Drop the nodes the trie doesn't reach, keeping only its root, and shrink the file",
+            ),
+        )
+        .subcommand(
+            Command::new("orphans")
+                .about("Count the stored nodes the trie doesn't reach, keeping only its root")
+                .arg(
+                    Arg::new("delete")
+                        .long("delete")
+                        .action(ArgAction::SetTrue)
+                        .help("Delete them too, without shrinking the file"),
+                ),
+        )
+        .subcommand(
+            Command::new("import-geth")
+                .about("Rebuild the Ethereum state exported by `geth dump`, check its root and print it")
+                .arg(
+                    Arg::new("file")
+                        .required(true)
+                        .help("Output of `geth dump`, with or without --iterative"),
+                ),
+        );
+
+    #[cfg(feature = "server")]
+    let command = command.subcommand(
+        Command::new("serve")
+            .about("Serve the trie over JSON-RPC, see `TrieServer` for the methods")
+            .arg(
+                Arg::new("addr")
+                    .long("addr")
+                    .default_value("127.0.0.1:8545")
+                    .help("Address to listen on"),
+            ),
+    );
+
+    command
 }
 
-fn test_trie_remove() {
-    delete_file(String::from("test1.db"));
-    let memdb = Arc::new(SqliteDB::new(String::from("test1.db")));
-    let mut trie = EthTrie::new(memdb);
-    trie.put(b"test", b"test");
-    trie.commit();
-
-    trie.del(b"test");
-    trie.commit();
-    let found = trie.get(b"test");
-    assert!(found.is_err())
+fn parse_hex(s: &str) -> CliResult<Vec<u8>> {
+    let s = s.strip_prefix("0x").unwrap_or(s);
+    hex::decode(s).map_err(|e| format!("invalid hex {:?}: {}", s, e).into())
 }
 
-fn delete_file(path:String) -> std::io::Result<()> {
-    fs::remove_file(path)?;
+fn parse_root(s: &str) -> CliResult<H256> {
+    let bytes = parse_hex(s)?;
+    if bytes.len() != 32 {
+        return Err(format!("a root is 32 bytes, got {}", bytes.len()).into());
+    }
+    Ok(H256::from_slice(&bytes))
+}
+
+fn arg_hex(matches: &ArgMatches, name: &str) -> CliResult<Vec<u8>> {
+    parse_hex(matches.get_one::<String>(name).expect("required argument"))
+}
+
+fn head(db: &SqliteDB) -> CliResult<Option<H256>> {
+    match db.get_meta(HEAD_KEY)? {
+        Some(root) if root.len() != 32 => {
+            Err(format!("the stored head is {} bytes, not a root", root.len()).into())
+        }
+        root => Ok(root.map(|root| H256::from_slice(&root))),
+    }
+}
+
+fn set_head(db: &SqliteDB, root: H256) -> CliResult<()> {
+    db.insert_meta(HEAD_KEY, root.as_bytes().to_vec())?;
     Ok(())
 }
-fn test_small_trie_at_root() {
-    delete_file(String::from("test1.db"));
-    let memdb = Arc::new(SqliteDB::new(String::from("test1.db")));
-    let mut trie = EthTrie::new(memdb.clone());
-    trie.put(b"key", b"val");
-    let new_root_hash = trie.commit();
-
-    let empty_trie = EthTrie::new(memdb.clone());
-    // Can't find key in new trie at empty root
-    assert!(empty_trie.get(b"key").is_err());
-
-    let trie_view = empty_trie.at_root(new_root_hash);
-    assert_eq!(&trie_view.get(b"key").unwrap().unwrap(), b"val");
-
-    // Previous trie was not modified
-    assert!(empty_trie.get(b"key").is_err());
+
+/// Commits the trie, moving the head to the new root in the same transaction.
+fn commit_head(trie: &mut EthTrie<SqliteDB>) -> CliResult<H256> {
+    let outcome =
+        trie.commit_with_meta(|root| vec![(HEAD_KEY.to_vec(), root.as_bytes().to_vec())])?;
+    Ok(outcome.root)
+}
+
+fn dump<D: DB>(trie: &EthTrie<D>, format: &str, mut out: impl Write) -> CliResult<usize> {
+    if format == "csv" {
+        writeln!(out, "key,value")?;
+    }
+
+    let mut cursor: Option<PageCursor> = None;
+    let mut count = 0;
+    loop {
+        let page = trie.iter_page(cursor.as_ref(), DUMP_PAGE_SIZE)?;
+        for (key, value) in page.entries {
+            let (key, value) = (hex::encode(key), hex::encode(value));
+            match format {
+                "csv" => writeln!(out, "0x{},0x{}", key, value)?,
+                _ => writeln!(out, "{{\"key\":\"0x{}\",\"value\":\"0x{}\"}}", key, value)?,
+            }
+            count += 1;
+            if count % DUMP_PROGRESS_INTERVAL == 0 {
+                eprintln!("dumped {} entries", count);
+            }
+        }
+        match page.cursor {
+            Some(next) => cursor = Some(next),
+            None => break,
+        }
+    }
+
+    out.flush()?;
+    Ok(count)
+}
+
+/// Reads a JSON lines file into a map, so entries are inserted in key order and a key
+/// given twice keeps its last value.
+fn read_jsonl(path: &str) -> CliResult<BTreeMap<Vec<u8>, Vec<u8>>> {
+    let mut entries = BTreeMap::new();
+    for (number, line) in BufReader::new(File::open(path)?).lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let entry: JsonlEntry =
+            serde_json::from_str(&line).map_err(|e| format!("{}:{}: {}", path, number + 1, e))?;
+        entries.insert(parse_hex(&entry.key)?, parse_hex(&entry.value)?);
+    }
+    Ok(entries)
+}
+
+fn read_proof(path: &str) -> CliResult<Vec<Vec<u8>>> {
+    let reader: Box<dyn BufRead> = match path {
+        "-" => Box::new(io::stdin().lock()),
+        _ => Box::new(BufReader::new(File::open(path)?)),
+    };
+    let mut proof = Vec::new();
+    for line in reader.lines() {
+        let line = line?;
+        let line = line.trim();
+        if !line.is_empty() {
+            proof.push(parse_hex(line)?);
+        }
+    }
+    Ok(proof)
 }
 
-fn main() -> Result<()> {
-    test_trie_remove();
-    insert_full_branch();
-    test_small_trie_at_root();
+/// Proofs come from peers, so they are checked against the given root alone and the
+/// database is not opened.
+fn verify(root: Option<&String>, sub: &ArgMatches) -> CliResult<()> {
+    let root = parse_root(root.ok_or("verify needs the --root the proof was made against")?)?;
+    let proof = read_proof(sub.get_one::<String>("proof").expect("required argument"))?;
+    match verify_proof(root, &arg_hex(sub, "key")?, &proof)? {
+        Some(value) => println!("0x{}", hex::encode(value)),
+        None => println!("absent"),
+    }
     Ok(())
-}
\ No newline at end of file
+}
+
+fn run(matches: &ArgMatches) -> CliResult<()> {
+    if let Some(("verify", sub)) = matches.subcommand() {
+        return verify(matches.get_one::<String>("root"), sub);
+    }
+
+    let path = matches.get_one::<String>("db").expect("has a default");
+    let db = Arc::new(SqliteDB::new(path.clone()));
+    let root = match matches.get_one::<String>("root") {
+        Some(root) => Some(parse_root(root)?),
+        None => head(&db)?,
+    };
+    let mut trie = match root {
+        Some(root) => EthTrie::new(db.clone()).at_root(root),
+        None => EthTrie::new(db.clone()),
+    };
+
+    match matches.subcommand().expect("subcommand is required") {
+        ("put", sub) => {
+            trie.put(&arg_hex(sub, "key")?, &arg_hex(sub, "value")?)?;
+            let root = commit_head(&mut trie)?;
+            println!("{:?}", root);
+        }
+        ("get", sub) => match trie.get(&arg_hex(sub, "key")?)? {
+            Some(value) => println!("0x{}", hex::encode(value)),
+            None => return Err("key not found".into()),
+        },
+        ("del", sub) => {
+            trie.del(&arg_hex(sub, "key")?)?;
+            let root = commit_head(&mut trie)?;
+            println!("{:?}", root);
+        }
+        ("root", _) => println!("{:?}", trie.commit()?),
+        ("proof", sub) => {
+            for node in trie.proof(&arg_hex(sub, "key")?)? {
+                println!("0x{}", hex::encode(node));
+            }
+        }
+        ("dump", sub) => {
+            let format = sub.get_one::<String>("format").expect("has a default");
+            let count = match sub.get_one::<String>("output") {
+                Some(path) => dump(&trie, format, BufWriter::new(File::create(path)?))?,
+                None => dump(&trie, format, BufWriter::new(io::stdout().lock()))?,
+            };
+            eprintln!("dumped {} entries", count);
+        }
+        ("import", sub) => {
+            let entries = read_jsonl(sub.get_one::<String>("file").expect("required argument"))?;
+            for (key, value) in &entries {
+                trie.put(key, value)?;
+            }
+            let root = commit_head(&mut trie)?;
+            eprintln!("imported {} entries", entries.len());
+            println!("{:?}", root);
+        }
+        ("compare", sub) => {
+            let other = sub.get_one::<String>("other").expect("required argument");
+            let mismatches = trie.compare_with(&SqliteDB::new(other.clone()))?;
+            for mismatch in &mismatches {
+                let entry = if mismatch.blob { "blob" } else { "node" };
+                let kind = match mismatch.kind {
+                    MismatchKind::MissingInOther => "missing in other",
+                    MismatchKind::MissingHere => "missing here",
+                    MismatchKind::Differs => "differs",
+                };
+                println!("{} {:?} {}", entry, mismatch.hash, kind);
+            }
+            if !mismatches.is_empty() {
+                return Err(format!("{} mismatches", mismatches.len()).into());
+            }
+        }
+        ("copy", sub) => {
+            let target = sub.get_one::<String>("target").expect("required argument");
+            let target = Arc::new(SqliteDB::new(target.clone()));
+            let root = trie.commit()?;
+            trie.copy_to(target.clone(), root)?;
+            set_head(&target, root)?;
+            println!("{:?}", root);
+        }
+        ("compact", _) => {
+            let file_size = |trie: &EthTrie<SqliteDB>| -> CliResult<u64> {
+                let pages = trie.storage_stats()?.and_then(|stats| stats.pages);
+                Ok(pages.map_or(0, |pages| pages.page_size * pages.page_count))
+            };
+            let before = file_size(&trie)?;
+            let root = trie.commit()?;
+            trie.compact(&[root])?;
+            eprintln!("{} bytes, down from {}", file_size(&trie)?, before);
+        }
+        ("orphans", sub) => {
+            let root = trie.commit()?;
+            let orphans = if sub.get_flag("delete") {
+                trie.remove_orphans(&[root])?
+            } else {
+                trie.find_orphans(&[root])?
+            };
+            println!(
+                "{} orphaned nodes, {} bytes; {} reachable",
+                orphans.nodes.len(),
+                orphans.bytes,
+                orphans.reachable
+            );
+        }
+        ("import-geth", sub) => {
+            let file = File::open(sub.get_one::<String>("file").expect("required argument"))?;
+            let root = mpt::state::import_geth_dump(db.clone(), BufReader::new(file))?;
+            set_head(&db, root)?;
+            println!("{:?}", root);
+        }
+        #[cfg(feature = "server")]
+        ("serve", sub) => {
+            let server =
+                TrieServer::bind(sub.get_one::<String>("addr").expect("has a default"), trie)?;
+            if let Some(addr) = server.local_addr() {
+                eprintln!("listening on {}", addr);
+            }
+            server.run()?;
+        }
+        _ => unreachable!("unknown subcommands are rejected by clap"),
+    }
+    Ok(())
+}
+
+fn main() -> ExitCode {
+    match run(&cli().get_matches()) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("error: {}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
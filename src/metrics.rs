@@ -0,0 +1,109 @@
+//! Prometheus metrics shared by every trie in the process.
+//!
+//! - `mpt_node_reads_total`: nodes read from the database.
+//! - `mpt_node_cache_hits_total`: nodes found in the decoded node cache instead.
+//! - `mpt_commit_duration_seconds`: time spent in `commit`.
+//! - `mpt_commit_nodes_written`: nodes written to the database per commit.
+
+use std::io;
+use std::net::ToSocketAddrs;
+use std::sync::LazyLock;
+
+use prometheus::{
+    exponential_buckets, Encoder, Histogram, HistogramOpts, IntCounter, Registry, TextEncoder,
+};
+use tiny_http::{Header, Response};
+
+use crate::errors::{TrieError, TrieResult};
+
+pub(crate) struct Metrics {
+    registry: Registry,
+    pub node_reads: IntCounter,
+    pub cache_hits: IntCounter,
+    pub commit_duration: Histogram,
+    pub nodes_written: Histogram,
+}
+
+static METRICS: LazyLock<Metrics> = LazyLock::new(|| {
+    let node_reads = IntCounter::new("mpt_node_reads_total", "Trie nodes read from the database")
+        .expect("valid metric");
+    let cache_hits = IntCounter::new(
+        "mpt_node_cache_hits_total",
+        "Trie nodes found in the decoded node cache",
+    )
+    .expect("valid metric");
+    let commit_duration = Histogram::with_opts(HistogramOpts::new(
+        "mpt_commit_duration_seconds",
+        "Time spent committing a trie",
+    ))
+    .expect("valid metric");
+    let nodes_written = Histogram::with_opts(
+        HistogramOpts::new(
+            "mpt_commit_nodes_written",
+            "Trie nodes written to the database per commit",
+        )
+        .buckets(exponential_buckets(1.0, 4.0, 10).expect("valid buckets")),
+    )
+    .expect("valid metric");
+
+    let registry = Registry::new();
+    registry
+        .register(Box::new(node_reads.clone()))
+        .expect("metric registered once");
+    registry
+        .register(Box::new(cache_hits.clone()))
+        .expect("metric registered once");
+    registry
+        .register(Box::new(commit_duration.clone()))
+        .expect("metric registered once");
+    registry
+        .register(Box::new(nodes_written.clone()))
+        .expect("metric registered once");
+
+    Metrics {
+        registry,
+        node_reads,
+        cache_hits,
+        commit_duration,
+        nodes_written,
+    }
+});
+
+pub(crate) fn metrics() -> &'static Metrics {
+    &METRICS
+}
+
+/// The registry holding the trie metrics, for applications exporting them along
+/// with their own.
+pub fn registry() -> &'static Registry {
+    &metrics().registry
+}
+
+/// Renders the trie metrics in the Prometheus text format.
+pub fn gather() -> String {
+    let mut buffer = vec![];
+    TextEncoder::new()
+        .encode(&registry().gather(), &mut buffer)
+        .expect("text encoding doesn't fail");
+    String::from_utf8(buffer).expect("text encoding is UTF-8")
+}
+
+/// Serves `gather()` over HTTP on `addr` until the listener fails, for applications
+/// that don't expose metrics themselves.
+pub fn serve<A: ToSocketAddrs>(addr: A) -> TrieResult<()> {
+    let http = tiny_http::Server::http(addr).map_err(|e| TrieError::Io {
+        source: io::Error::other(e),
+    })?;
+    loop {
+        let request = http.recv()?;
+        // The client hanging up doesn't concern other clients.
+        let _ = request.respond(response());
+    }
+}
+
+/// The `gather()` output as an HTTP response.
+pub(crate) fn response() -> Response<io::Cursor<Vec<u8>>> {
+    let content_type = Header::from_bytes("Content-Type", TextEncoder::new().format_type())
+        .expect("static header is valid");
+    Response::from_string(gather()).with_header(content_type)
+}
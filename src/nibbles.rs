@@ -1,19 +1,67 @@
-use std::cmp::min;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::cmp::min;
 
+use smallvec::SmallVec;
+
+use crate::errors::{TrieError, TrieResult};
+
+// A 32-byte key and its terminator fit inline, so paths of typical keys (and the keys
+// of the nodes made from them) never touch the heap.
+const INLINE_NIBBLES: usize = 65;
+
+type NibbleVec = SmallVec<[u8; INLINE_NIBBLES]>;
+
+/// A path through the trie as a sequence of nibbles (half-bytes, 0 to 15). Paths to
+/// leaves end with the terminator 16, as in Ethereum's hex-prefix encoding.
+///
+/// Besides `TrieError::MissingTrieNode::traversed`, the part of the key walked before
+/// a missing node, it lets tools interpret proof nodes: `from_compact` and
+/// `encode_compact` convert the hex-prefix encoded keys and prefixes found in leaf and
+/// extension nodes, `from_raw` and `encode_raw` convert byte keys.
+///
+/// Deserialized paths are checked: every nibble is below 16 but a terminator, which
+/// can only come last.
 #[derive(Debug, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(try_from = "RawNibbles"))]
 pub struct Nibbles {
-    hex_data: Vec<u8>,
+    hex_data: NibbleVec,
+}
+
+// `Nibbles` as serialized, before it is checked.
+#[cfg(feature = "serde")]
+#[derive(serde::Deserialize)]
+struct RawNibbles {
+    hex_data: NibbleVec,
+}
+
+#[cfg(feature = "serde")]
+impl TryFrom<RawNibbles> for Nibbles {
+    type Error = TrieError;
+
+    fn try_from(raw: RawNibbles) -> TrieResult<Self> {
+        let hex = raw.hex_data.strip_suffix(&[16]).unwrap_or(&raw.hex_data);
+        if hex.iter().any(|&nibble| nibble >= 16) {
+            return Err(TrieError::InvalidData);
+        }
+        Ok(Nibbles {
+            hex_data: raw.hex_data,
+        })
+    }
 }
 
 impl Nibbles {
+    /// Wraps nibbles as they are, one per byte, including any terminator.
     pub fn from_hex(hex: &[u8]) -> Self {
         Nibbles {
-            hex_data: hex.to_vec(),
+            hex_data: NibbleVec::from_slice(hex),
         }
     }
 
+    /// Splits a byte key into nibbles, adding the terminator if `is_leaf`.
     pub fn from_raw(raw: &[u8], is_leaf: bool) -> Self {
-        let mut hex_data = vec![];
+        let mut hex_data = NibbleVec::with_capacity(raw.len() * 2 + 1);
         for item in raw.iter() {
             hex_data.push(item / 16);
             hex_data.push(item % 16);
@@ -24,23 +72,59 @@ impl Nibbles {
         Nibbles { hex_data }
     }
 
+    /// `from_raw` for the leaf path of a fixed-width key, such as a 20-byte address
+    /// or a 32-byte hash. The nibbles are written into a buffer sized for the key
+    /// instead of being pushed one at a time; keys of up to 32 bytes stay inline.
+    pub fn from_fixed<const N: usize>(key: &[u8; N]) -> Self {
+        if 2 * N + 1 > INLINE_NIBBLES {
+            return Self::from_raw(key, true);
+        }
+        let mut buf = [0; INLINE_NIBBLES];
+        for (i, byte) in key.iter().enumerate() {
+            buf[2 * i] = byte >> 4;
+            buf[2 * i + 1] = byte & 0x0f;
+        }
+        buf[2 * N] = 16;
+        Nibbles {
+            hex_data: NibbleVec::from_buf_and_len(buf, 2 * N + 1),
+        }
+    }
+
+    /// Decodes the hex-prefix (compact) encoding used in trie nodes, as found in the
+    /// first item of a leaf or extension node. The high nibble of the first byte is a
+    /// flag: bit 1 marks a leaf, whose path gets the terminator, and bit 0 an odd
+    /// length, in which case the low nibble is the first nibble of the path.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `compact` is empty or its flag nibble is not a valid prefix. Use
+    /// `try_from_compact` for data that has not been checked.
     pub fn from_compact(compact: &[u8]) -> Self {
-        let mut hex = vec![];
-        let flag = compact[0];
-
-        let mut is_leaf = false;
-        match flag >> 4 {
-            0x0 => {}
-            0x1 => hex.push(flag % 16),
-            0x2 => is_leaf = true,
+        Self::try_from_compact(compact).expect("invalid hex-prefix encoding")
+    }
+
+    /// Like `from_compact`, but returns `TrieError::InvalidData` for malformed input:
+    /// an empty slice, an unknown flag, or padding that is not zero.
+    pub fn try_from_compact(compact: &[u8]) -> TrieResult<Self> {
+        let (&flag, rest) = compact.split_first().ok_or(TrieError::InvalidData)?;
+        let mut hex = NibbleVec::with_capacity(rest.len() * 2 + 2);
+
+        let is_leaf = match flag >> 4 {
+            0x0 | 0x2 if flag & 0x0f != 0 => return Err(TrieError::InvalidData),
+            0x0 => false,
+            0x1 => {
+                hex.push(flag % 16);
+                false
+            }
+            0x2 => true,
             0x3 => {
-                is_leaf = true;
                 hex.push(flag % 16);
+                true
             }
-            _ => panic!("invalid data"),
-        }
+            _ => return Err(TrieError::InvalidData),
+        };
 
-        for item in &compact[1..] {
+        for item in rest {
             hex.push(item / 16);
             hex.push(item % 16);
         }
@@ -48,13 +132,18 @@ impl Nibbles {
             hex.push(16);
         }
 
-        Nibbles { hex_data: hex }
+        Ok(Nibbles { hex_data: hex })
     }
 
+    /// Returns true if the path ends with the leaf terminator.
     pub fn is_leaf(&self) -> bool {
-        self.hex_data[self.hex_data.len() - 1] == 16
+        self.hex_data.last() == Some(&16)
     }
 
+    /// Encodes the path with hex-prefix (compact) encoding, which packs two nibbles
+    /// per byte and records the terminator and odd length in the first byte. This is
+    /// how leaf keys and extension prefixes appear in encoded nodes, and the inverse
+    /// of `from_compact`.
     pub fn encode_compact(&self) -> Vec<u8> {
         let mut compact = vec![];
         let is_leaf = self.is_leaf();
@@ -85,6 +174,9 @@ impl Nibbles {
         compact
     }
 
+    /// Packs the nibbles back into a byte key, dropping the terminator, and tells
+    /// whether there was one. A trailing odd nibble is dropped too. This is the
+    /// inverse of `from_raw`, e.g. to turn the path walked to a leaf into its key.
     pub fn encode_raw(&self) -> (Vec<u8>, bool) {
         let mut raw = vec![];
         let is_leaf = self.is_leaf();
@@ -101,6 +193,7 @@ impl Nibbles {
         (raw, is_leaf)
     }
 
+    /// The number of nibbles, counting the terminator.
     pub fn len(&self) -> usize {
         self.hex_data.len()
     }
@@ -109,22 +202,17 @@ impl Nibbles {
         self.len() == 0
     }
 
+    /// Returns the nibble at `i`.
     pub fn at(&self, i: usize) -> usize {
         self.hex_data[i] as usize
     }
 
+    /// The number of leading nibbles shared with `other_partial`.
     pub fn common_prefix(&self, other_partial: &Nibbles) -> usize {
-        let s = min(self.len(), other_partial.len());
-        let mut i = 0usize;
-        while i < s {
-            if self.at(i) != other_partial.at(i) {
-                break;
-            }
-            i += 1;
-        }
-        i
+        common_prefix(&self.hex_data, &other_partial.hex_data)
     }
 
+    /// Returns the nibbles from `index` on.
     pub fn offset(&self, index: usize) -> Nibbles {
         self.slice(index, self.hex_data.len())
     }
@@ -133,13 +221,15 @@ impl Nibbles {
         Nibbles::from_hex(&self.hex_data[start..end])
     }
 
+    /// Returns the nibbles, one per byte.
     pub fn get_data(&self) -> &[u8] {
         &self.hex_data
     }
 
     pub fn join(&self, b: &Nibbles) -> Nibbles {
-        let hex_data = [self.get_data(), b.get_data()].concat();
-        Nibbles::from_hex(&hex_data)
+        let mut hex_data = self.hex_data.clone();
+        hex_data.extend_from_slice(b.get_data());
+        Nibbles { hex_data }
     }
 
     pub fn extend(&mut self, b: &Nibbles) {
@@ -158,3 +248,11 @@ impl Nibbles {
         self.hex_data.push(e)
     }
 }
+
+// The number of leading nibbles `a` and `b` share. Lookups compare the rest of the
+// key with node keys as slices of the key's nibbles, so walking down to a value
+// doesn't copy the key at each level.
+pub(crate) fn common_prefix(a: &[u8], b: &[u8]) -> usize {
+    let len = min(a.len(), b.len());
+    (0..len).find(|&i| a[i] != b[i]).unwrap_or(len)
+}
@@ -1,31 +1,50 @@
-use std::sync::{Arc, RwLock};
+use std::sync::{Arc, OnceLock};
 
 use ethereum_types::H256;
 
+use crate::codec::{ChildRef, DecodeLimits, DecodedNode, NodeCodec, RlpCodec};
+use crate::errors::{TrieError, TrieResult};
+use crate::hasher::{Hasher, KeccakHasher};
 use crate::nibbles::Nibbles;
 
+/// A trie node in memory. `Hash` stands for a child referenced by hash that hasn't
+/// been loaded; `decode_node` and `encode_node` convert nodes to and from the bytes
+/// stored in the database and included in proofs.
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Node {
     Empty,
     Leaf(Arc<LeafNode>),
-    Extension(Arc<RwLock<ExtensionNode>>),
-    Branch(Arc<RwLock<BranchNode>>),
+    Extension(Arc<ExtensionNode>),
+    Branch(Arc<BranchNode>),
     Hash(Arc<HashNode>),
 }
 
 impl Node {
     pub fn from_leaf(key: Nibbles, value: Vec<u8>) -> Self {
-        let leaf = Arc::new(LeafNode { key, value });
+        let leaf = Arc::new(LeafNode {
+            key,
+            value,
+            hash: OnceLock::new(),
+        });
         Node::Leaf(leaf)
     }
 
     pub fn from_branch(children: [Node; 16], value: Option<Vec<u8>>) -> Self {
-        let branch = Arc::new(RwLock::new(BranchNode { children, value }));
+        let branch = Arc::new(BranchNode {
+            children,
+            value,
+            hash: OnceLock::new(),
+        });
         Node::Branch(branch)
     }
 
     pub fn from_extension(prefix: Nibbles, node: Node) -> Self {
-        let ext = Arc::new(RwLock::new(ExtensionNode { prefix, node }));
+        let ext = Arc::new(ExtensionNode {
+            prefix,
+            node,
+            hash: OnceLock::new(),
+        });
         Node::Extension(ext)
     }
 
@@ -33,18 +52,122 @@ impl Node {
         let hash_node = Arc::new(HashNode { hash });
         Node::Hash(hash_node)
     }
+
+    /// Returns the hash the node is stored under if it hasn't been modified since it
+    /// was last hashed.
+    pub fn cached_hash(&self) -> Option<H256> {
+        match self {
+            Node::Leaf(leaf) => leaf.hash.get().copied(),
+            Node::Branch(branch) => branch.hash.get().copied(),
+            Node::Extension(ext) => ext.hash.get().copied(),
+            Node::Empty | Node::Hash(_) => None,
+        }
+    }
+
+    // Returns the node with every node not yet hashed rebuilt, so that hashing the
+    // copy on commit leaves the original dirty. Clean subtrees are shared as is.
+    pub(crate) fn copy_dirty(&self) -> Node {
+        if self.cached_hash().is_some() {
+            return self.clone();
+        }
+        match self {
+            Node::Empty | Node::Hash(_) => self.clone(),
+            Node::Leaf(leaf) => Node::from_leaf(leaf.key.clone(), leaf.value.clone()),
+            Node::Extension(ext) => Node::from_extension(ext.prefix.clone(), ext.node.copy_dirty()),
+            Node::Branch(branch) => Node::from_branch(
+                branch.children.each_ref().map(Node::copy_dirty),
+                branch.value.clone(),
+            ),
+        }
+    }
+
+    // Encodes the node with `C`, referencing each child as `child` returns it. A hash
+    // node stands for a node that isn't loaded, so it has no encoding of its own.
+    pub(crate) fn encode_with<C: NodeCodec>(
+        &self,
+        mut child: impl FnMut(&Node) -> EncodedNode,
+    ) -> Vec<u8> {
+        match self {
+            Node::Empty => C::encode_empty(),
+            Node::Leaf(leaf) => {
+                let key = leaf.key.get_data();
+                C::encode_leaf(key.strip_suffix(&[16]).unwrap_or(key), &leaf.value)
+            }
+            Node::Branch(branch) => {
+                let children = branch.children.each_ref().map(&mut child);
+                let children = std::array::from_fn(|i| children[i].as_child_ref());
+                C::encode_branch(&children, branch.value.as_deref())
+            }
+            Node::Extension(ext) => {
+                let child = child(&ext.node);
+                C::encode_extension(ext.prefix.get_data(), child.as_child_ref())
+            }
+            Node::Hash(_) => panic!("a hash node has no encoding"),
+        }
+    }
+
+    /// Records the hash the node is stored under, marking it clean.
+    pub fn set_hash(&self, hash: H256) {
+        let _ = match self {
+            Node::Leaf(leaf) => leaf.hash.set(hash),
+            Node::Branch(branch) => branch.hash.set(hash),
+            Node::Extension(ext) => ext.hash.set(hash),
+            Node::Empty | Node::Hash(_) => Ok(()),
+        };
+    }
 }
 
+// Nodes are immutable once built: changing one means building a new node along the
+// path, so other tries and caches holding the old one are unaffected.
+// Each node caches the hash it is stored under in `hash`. It is only set for nodes
+// whose encoding is at least the codec's inline threshold, and new nodes start
+// without one, so the next commit only encodes what changed.
+// The cached hash is not serialized, so deserialized nodes are written again on the
+// next commit and never rely on the receiving database already holding them.
+
 #[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(try_from = "RawLeafNode"))]
 pub struct LeafNode {
     pub key: Nibbles,
     pub value: Vec<u8>,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub hash: OnceLock<H256>,
+}
+
+// Deserialized leaf and extension nodes are checked before use: the trie indexes
+// branches with their nibbles and expects the terminator to end every leaf key and
+// no other path.
+#[cfg(feature = "serde")]
+#[derive(serde::Deserialize)]
+struct RawLeafNode {
+    key: Nibbles,
+    value: Vec<u8>,
+}
+
+#[cfg(feature = "serde")]
+impl TryFrom<RawLeafNode> for LeafNode {
+    type Error = TrieError;
+
+    fn try_from(raw: RawLeafNode) -> TrieResult<Self> {
+        if !raw.key.is_leaf() {
+            return Err(TrieError::InvalidData);
+        }
+        Ok(LeafNode {
+            key: raw.key,
+            value: raw.value,
+            hash: OnceLock::new(),
+        })
+    }
 }
 
 #[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct BranchNode {
     pub children: [Node; 16],
     pub value: Option<Vec<u8>>,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub hash: OnceLock<H256>,
 }
 
 impl BranchNode {
@@ -60,15 +183,55 @@ impl BranchNode {
             self.children[i] = n
         }
     }
+
+    /// Returns a new branch with the child at `i` replaced by `n`.
+    pub fn with_child(&self, i: usize, n: Node) -> Node {
+        let mut children = self.children.clone();
+        children[i] = n;
+        Node::from_branch(children, self.value.clone())
+    }
+
+    /// Returns a new branch with the same children holding `value`.
+    pub fn with_value(&self, value: Option<Vec<u8>>) -> Node {
+        Node::from_branch(self.children.clone(), value)
+    }
 }
 
 #[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(try_from = "RawExtensionNode"))]
 pub struct ExtensionNode {
     pub prefix: Nibbles,
     pub node: Node,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub hash: OnceLock<H256>,
+}
+
+#[cfg(feature = "serde")]
+#[derive(serde::Deserialize)]
+struct RawExtensionNode {
+    prefix: Nibbles,
+    node: Node,
+}
+
+#[cfg(feature = "serde")]
+impl TryFrom<RawExtensionNode> for ExtensionNode {
+    type Error = TrieError;
+
+    fn try_from(raw: RawExtensionNode) -> TrieResult<Self> {
+        if raw.prefix.is_empty() || raw.prefix.is_leaf() {
+            return Err(TrieError::InvalidData);
+        }
+        Ok(ExtensionNode {
+            prefix: raw.prefix,
+            node: raw.node,
+            hash: OnceLock::new(),
+        })
+    }
 }
 
 #[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct HashNode {
     pub hash: H256,
 }
@@ -93,3 +256,114 @@ pub fn empty_children() -> [Node; 16] {
         Node::Empty,
     ]
 }
+
+// How a node is referenced from its parent's encoding.
+pub(crate) enum EncodedNode {
+    Hash(H256),
+    Inline(Vec<u8>),
+}
+
+impl EncodedNode {
+    pub(crate) fn as_child_ref(&self) -> ChildRef<'_> {
+        match self {
+            EncodedNode::Hash(hash) => ChildRef::Hash(*hash),
+            EncodedNode::Inline(data) => ChildRef::Inline(data),
+        }
+    }
+}
+
+/// Decodes a node as stored in the database or included in a proof, with Ethereum's
+/// RLP encoding and the default `DecodeLimits`.
+pub fn decode_node(data: &[u8]) -> TrieResult<Node> {
+    decode_node_with::<RlpCodec>(data, DecodeLimits::default())
+}
+
+/// Decodes a node with the codec `C`. Children embedded in the node are decoded along
+/// with it, and those referenced by hash become `Node::Hash`. Data that isn't a node,
+/// such as a bare hash reference, or that exceeds `limits` fails with
+/// `TrieError::InvalidData`.
+pub fn decode_node_with<C: NodeCodec>(data: &[u8], limits: DecodeLimits) -> TrieResult<Node> {
+    if data.len() > limits.max_node_size {
+        return Err(TrieError::InvalidData);
+    }
+    match decode_at::<C>(data, limits.max_depth)? {
+        // A stored node is never a bare reference to another one.
+        Node::Hash(_) => Err(TrieError::InvalidData),
+        node => Ok(node),
+    }
+}
+
+// Children embedded in a node are decoded recursively, so the nesting depth is bounded.
+fn decode_at<C: NodeCodec>(data: &[u8], depth: usize) -> TrieResult<Node> {
+    match C::decode(data)? {
+        DecodedNode::Empty => Ok(Node::Empty),
+        DecodedNode::Leaf { mut key, value } => {
+            key.push(16);
+            Ok(Node::from_leaf(Nibbles::from_hex(&key), value))
+        }
+        DecodedNode::Extension { prefix, child } => {
+            let n = decode_child::<C>(child, depth)?;
+            Ok(Node::from_extension(Nibbles::from_hex(&prefix), n))
+        }
+        DecodedNode::Branch { children, value } => {
+            let mut nodes = empty_children();
+            for (node, child) in nodes.iter_mut().zip(children) {
+                *node = decode_child::<C>(child, depth)?;
+            }
+            Ok(Node::from_branch(nodes, value))
+        }
+        DecodedNode::Hash(hash) => Ok(Node::from_hash(hash)),
+    }
+}
+
+// Only nodes shorter than the inline threshold are embedded in their parent, anything
+// longer must be a hash reference.
+fn decode_child<C: NodeCodec>(data: &[u8], depth: usize) -> TrieResult<Node> {
+    if depth == 0 {
+        return Err(TrieError::InvalidData);
+    }
+    let node = decode_at::<C>(data, depth - 1)?;
+    match node {
+        Node::Hash(_) | Node::Empty => Ok(node),
+        _ if data.len() < C::INLINE_THRESHOLD => Ok(node),
+        _ => Err(TrieError::InvalidData),
+    }
+}
+
+/// Encodes a node with Ethereum's RLP encoding, hashing its children with keccak256,
+/// as `commit` would store it.
+///
+/// # Panics
+///
+/// If `node` is a `Node::Hash`, which stands for a node that isn't loaded.
+pub fn encode_node(node: &Node) -> Vec<u8> {
+    encode_node_with::<KeccakHasher, RlpCodec>(node)
+}
+
+/// Encodes a node with the codec `C`. Children are referenced by the hash they are
+/// stored under, or computed with `H` for those changed since, unless their encoding
+/// is short enough to be embedded. The encoding of a node decoded with
+/// `decode_node_with` is the data it was decoded from.
+///
+/// # Panics
+///
+/// If `node` is a `Node::Hash`, which stands for a node that isn't loaded.
+pub fn encode_node_with<H: Hasher, C: NodeCodec>(node: &Node) -> Vec<u8> {
+    node.encode_with::<C>(reference::<H, C>)
+}
+
+fn reference<H: Hasher, C: NodeCodec>(node: &Node) -> EncodedNode {
+    if let Some(hash) = node.cached_hash() {
+        return EncodedNode::Hash(hash);
+    }
+    let encoded = match node {
+        Node::Hash(hash_node) => return EncodedNode::Hash(hash_node.hash),
+        Node::Empty => return EncodedNode::Inline(C::encode_empty()),
+        node => encode_node_with::<H, C>(node),
+    };
+    if encoded.len() < C::INLINE_THRESHOLD {
+        EncodedNode::Inline(encoded)
+    } else {
+        EncodedNode::Hash(H::hash(&encoded))
+    }
+}
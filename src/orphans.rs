@@ -0,0 +1,97 @@
+use std::collections::HashSet;
+
+use ethereum_types::H256;
+
+use crate::codec::NodeCodec;
+use crate::db::DB;
+use crate::errors::TrieError;
+use crate::hasher::Hasher;
+use crate::trie::{EthTrie, TrieResult};
+
+/// The nodes stored in a database that none of the roots kept reaches, as found by
+/// `EthTrie::find_orphans`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Orphans {
+    /// The hashes of the orphaned nodes, in no particular order.
+    pub nodes: Vec<H256>,
+    /// The size of their data, in bytes.
+    pub bytes: u64,
+    /// How many stored nodes the roots reach.
+    pub reachable: usize,
+}
+
+impl<D, H, C> EthTrie<D, H, C>
+where
+    D: DB,
+    H: Hasher,
+    C: NodeCodec,
+{
+    /// Lists the nodes stored in this trie's database that none of `keep_roots`
+    /// reaches, to audit how much space commits made without pruning, roots dropped
+    /// from the version index or subtrees pruning missed take up. Nothing is changed;
+    /// `remove_orphans` deletes what this finds.
+    ///
+    /// Every node reachable from the roots is read first, so one missing fails with
+    /// `TrieError::MissingTrieNode`, since the nodes below it can't be told apart
+    /// from orphans. Value blobs aren't listed by backends, so orphaned blobs are not
+    /// reported. Fails with `TrieError::Unsupported` if the backend can't list the
+    /// nodes it holds.
+    pub fn find_orphans(&self, keep_roots: &[H256]) -> TrieResult<Orphans> {
+        let mut reachable = HashSet::new();
+        for &root in keep_roots {
+            self.mark_reachable(root, &mut reachable)?;
+        }
+
+        let mut orphans = Orphans {
+            reachable: reachable.len(),
+            ..Orphans::default()
+        };
+        let listed = self
+            .db
+            .iter_nodes(&mut |key, data| {
+                // Other keys can't be nodes, whatever the backend keeps under them.
+                let Ok(hash) = <[u8; 32]>::try_from(key).map(H256::from) else {
+                    return;
+                };
+                if !reachable.contains(&hash) {
+                    orphans.nodes.push(hash);
+                    orphans.bytes += data.len() as u64;
+                }
+            })
+            .map_err(TrieError::backend)?;
+        if !listed {
+            return Err(TrieError::Unsupported {
+                operation: "listing the stored nodes",
+            });
+        }
+        Ok(orphans)
+    }
+
+    /// Deletes the nodes `find_orphans` finds for `keep_roots` and returns them. Any
+    /// root not among `keep_roots`, this trie's included, may no longer be readable
+    /// afterwards, and nodes committed by another trie meanwhile, and not reachable
+    /// from `keep_roots`, are lost, so nothing else should commit meanwhile.
+    pub fn remove_orphans(&self, keep_roots: &[H256]) -> TrieResult<Orphans> {
+        let orphans = self.find_orphans(keep_roots)?;
+        let keys: Vec<Vec<u8>> = orphans
+            .nodes
+            .iter()
+            .map(|hash| hash.as_bytes().to_vec())
+            .collect();
+        self.db.remove_batch(&keys).map_err(TrieError::backend)?;
+        self.db.flush().map_err(TrieError::backend)?;
+        Ok(orphans)
+    }
+
+    // Adds the hashes of the stored nodes reachable from `root` to `reachable`,
+    // skipping the subtrees below nodes already in it.
+    fn mark_reachable(&self, root: H256, reachable: &mut HashSet<H256>) -> TrieResult<()> {
+        self.walk_stored(
+            root,
+            reachable,
+            |hash| self.stored_node(hash),
+            |_, _| Ok(()),
+        )?;
+        Ok(())
+    }
+}
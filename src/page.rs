@@ -0,0 +1,283 @@
+use std::cmp::Ordering;
+use std::fmt;
+use std::str::FromStr;
+
+use crate::codec::NodeCodec;
+use crate::db::DB;
+use crate::errors::TrieError;
+use crate::hasher::Hasher;
+use crate::node::Node;
+use crate::trie::{EthTrie, TrieResult};
+
+// The most entries `iter_page` allocates room for up front, whatever the limit.
+const MAX_PREALLOCATED: usize = 1024;
+
+/// Opaque position in the trie from which the next page resumes.
+/// It can be round-tripped through a string (`Display` / `FromStr`) so it can be
+/// handed to HTTP clients and sent back with the next request.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PageCursor(Vec<u8>);
+
+impl PageCursor {
+    // A cursor resuming right after `key`, whether or not it is in the trie.
+    pub(crate) fn after(key: &[u8]) -> Self {
+        PageCursor(key.to_vec())
+    }
+}
+
+impl fmt::Display for PageCursor {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", hex::encode(&self.0))
+    }
+}
+
+impl FromStr for PageCursor {
+    type Err = TrieError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        hex::decode(s)
+            .map(PageCursor)
+            .map_err(|_| TrieError::InvalidData)
+    }
+}
+
+/// A page of key/value pairs in key order.
+#[derive(Debug, Clone)]
+pub struct Page {
+    pub entries: Vec<(Vec<u8>, Vec<u8>)>,
+    /// Cursor to pass to the next `iter_page` call, or `None` if this was the last page.
+    pub cursor: Option<PageCursor>,
+}
+
+impl<D, H, C> EthTrie<D, H, C>
+where
+    D: DB,
+    H: Hasher,
+    C: NodeCodec,
+{
+    /// Returns up to `limit` entries whose keys come after `after` (or from the
+    /// start of the trie if `after` is `None`), together with a cursor for the next
+    /// page. Subtrees lying entirely before the cursor are skipped without being
+    /// loaded, so each call only pays for the page it returns.
+    pub fn iter_page(&self, after: Option<&PageCursor>, limit: usize) -> TrieResult<Page> {
+        let after = after.map(|cursor| to_nibbles(&cursor.0));
+        // One more entry than asked for tells whether there is a next page.
+        let wanted = limit.saturating_add(1);
+        let mut entries = Vec::with_capacity(wanted.min(MAX_PREALLOCATED));
+        if limit > 0 {
+            let mut prefix = vec![];
+            self.page_at(
+                &self.root,
+                &mut prefix,
+                after.as_deref(),
+                wanted,
+                &mut entries,
+            )?;
+        }
+
+        let cursor = if entries.len() > limit {
+            entries.truncate(limit);
+            entries.last().map(|(key, _)| PageCursor(key.clone()))
+        } else {
+            None
+        };
+        Ok(Page { entries, cursor })
+    }
+
+    /// Returns the entry with the smallest key, if the trie is not empty.
+    pub fn first_key_value(&self) -> TrieResult<Option<(Vec<u8>, Vec<u8>)>> {
+        Ok(self.iter_page(None, 1)?.entries.pop())
+    }
+
+    /// Returns the entry with the largest key, if the trie is not empty. Only the
+    /// rightmost path of the trie is loaded, unless a lenient traversal skips a
+    /// missing node on it and carries on with the subtree to its left.
+    pub fn last_key_value(&self) -> TrieResult<Option<(Vec<u8>, Vec<u8>)>> {
+        self.last_at(&self.root, &mut vec![])
+    }
+
+    /// Returns the first entry whose key comes strictly after `key`, whether or not
+    /// `key` itself is in the trie. Subtrees before `key` are skipped without being
+    /// loaded, as in `iter_page`.
+    pub fn next_key_after(&self, key: &[u8]) -> TrieResult<Option<(Vec<u8>, Vec<u8>)>> {
+        let after = to_nibbles(key);
+        let mut entries = Vec::with_capacity(1);
+        self.page_at(&self.root, &mut vec![], Some(&after), 1, &mut entries)?;
+        Ok(entries.pop())
+    }
+
+    // The entry with the largest key under `node`, whose key starts with `prefix`.
+    fn last_at(&self, node: &Node, prefix: &mut Vec<u8>) -> TrieResult<Option<(Vec<u8>, Vec<u8>)>> {
+        match node {
+            Node::Empty => Ok(None),
+            Node::Leaf(leaf) => {
+                prefix.extend_from_slice(leaf.key.get_data());
+                if prefix.last() == Some(&16) {
+                    prefix.pop();
+                }
+                let value = self.load_value(leaf.value.clone())?;
+                Ok(Some((from_nibbles(prefix), value)))
+            }
+            Node::Branch(branch) => {
+                for (i, child) in branch.children.iter().enumerate().rev() {
+                    if matches!(child, Node::Empty) {
+                        continue;
+                    }
+                    prefix.push(i as u8);
+                    if let Some(entry) = self.last_at(child, prefix)? {
+                        return Ok(Some(entry));
+                    }
+                    // Everything below the child was skipped.
+                    prefix.pop();
+                }
+                match &branch.value {
+                    Some(value) => {
+                        let value = self.load_value(value.clone())?;
+                        Ok(Some((from_nibbles(prefix), value)))
+                    }
+                    None => Ok(None),
+                }
+            }
+            Node::Extension(ext) => {
+                let len = prefix.len();
+                prefix.extend_from_slice(ext.prefix.get_data());
+                let entry = self.last_at(&ext.node, prefix)?;
+                if entry.is_none() {
+                    prefix.truncate(len);
+                }
+                Ok(entry)
+            }
+            Node::Hash(hash_node) => match self.load_or_skip(hash_node.hash, prefix)? {
+                Some(node) => self.last_at(&node, prefix),
+                None => Ok(None),
+            },
+        }
+    }
+
+    fn page_at(
+        &self,
+        node: &Node,
+        prefix: &mut Vec<u8>,
+        after: Option<&[u8]>,
+        limit: usize,
+        entries: &mut Vec<(Vec<u8>, Vec<u8>)>,
+    ) -> TrieResult<()> {
+        if entries.len() >= limit {
+            return Ok(());
+        }
+
+        match node {
+            Node::Empty => {}
+            Node::Leaf(leaf) => {
+                let len = prefix.len();
+                prefix.extend_from_slice(leaf.key.get_data());
+                if prefix.last() == Some(&16) {
+                    prefix.pop();
+                }
+                if after.is_none_or(|after| prefix.as_slice() > after) {
+                    entries.push((from_nibbles(prefix), self.load_value(leaf.value.clone())?));
+                }
+                prefix.truncate(len);
+            }
+            Node::Branch(branch) => {
+                // The branch value's key is a prefix of every key below it, so it is
+                // only emitted when we are not resuming inside this subtree.
+                if let (None, Some(value)) = (after, &branch.value) {
+                    entries.push((from_nibbles(prefix), self.load_value(value.clone())?));
+                }
+
+                for (i, child) in branch.children.iter().enumerate() {
+                    prefix.push(i as u8);
+                    if let Some(after) = seek(prefix, after) {
+                        self.page_at(child, prefix, after, limit, entries)?;
+                    }
+                    prefix.pop();
+                }
+            }
+            Node::Extension(ext) => {
+                let len = prefix.len();
+                prefix.extend_from_slice(ext.prefix.get_data());
+                if let Some(after) = seek(prefix, after) {
+                    self.page_at(&ext.node, prefix, after, limit, entries)?;
+                }
+                prefix.truncate(len);
+            }
+            Node::Hash(hash_node) => {
+                if let Some(node) = self.load_or_skip(hash_node.hash, prefix)? {
+                    self.page_at(&node, prefix, after, limit, entries)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+// Decides whether the subtree under `prefix` can hold keys after `after`.
+// Returns `None` to skip it, `Some(None)` if every key in it comes after the cursor
+// and `Some(Some(after))` if the cursor falls inside it.
+fn seek<'a>(prefix: &[u8], after: Option<&'a [u8]>) -> Option<Option<&'a [u8]>> {
+    match after {
+        None => Some(None),
+        Some(after) => {
+            let len = prefix.len().min(after.len());
+            match prefix[..len].cmp(&after[..len]) {
+                Ordering::Less => None,
+                Ordering::Greater => Some(None),
+                Ordering::Equal if prefix.len() > after.len() => Some(None),
+                Ordering::Equal => Some(Some(after)),
+            }
+        }
+    }
+}
+
+fn to_nibbles(key: &[u8]) -> Vec<u8> {
+    key.iter().flat_map(|b| [b / 16, b % 16]).collect()
+}
+
+pub(crate) fn from_nibbles(nibbles: &[u8]) -> Vec<u8> {
+    nibbles.chunks(2).map(|c| c[0] * 16 + c[1]).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::test_utils::{random_entries, MemoryDB};
+    use crate::trie::ITrie;
+
+    #[test]
+    fn pages_cover_the_trie_in_key_order() {
+        let entries = random_entries(1, 250);
+        let mut trie = EthTrie::new(Arc::new(MemoryDB::new()));
+        for (key, value) in &entries {
+            trie.put(key, value).unwrap();
+        }
+
+        let mut found = vec![];
+        let mut cursor = None;
+        loop {
+            let page = trie.iter_page(cursor.as_ref(), 100).unwrap();
+            assert!(page.entries.len() <= 100);
+            found.extend(page.entries);
+            cursor = page.cursor;
+            if cursor.is_none() {
+                break;
+            }
+        }
+        assert_eq!(found, entries.into_iter().collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn an_unbounded_limit_returns_everything() {
+        let entries = random_entries(2, 50);
+        let mut trie = EthTrie::new(Arc::new(MemoryDB::new()));
+        for (key, value) in &entries {
+            trie.put(key, value).unwrap();
+        }
+
+        let page = trie.iter_page(None, usize::MAX).unwrap();
+        assert_eq!(page.entries.len(), entries.len());
+        assert!(page.cursor.is_none());
+    }
+}
@@ -0,0 +1,103 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::codec::NodeCodec;
+use crate::db::DB;
+use crate::hasher::Hasher;
+use crate::trie::EthTrie;
+
+/// The work an `EthTrie` did since it was created or its counters were last reset,
+/// to measure the effect of tuning the node cache, value blobs and the like.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PerfCounters {
+    /// Nodes and value blobs read from the database.
+    pub db_reads: u64,
+    /// Nodes found in the decoded node cache instead of being read.
+    pub cache_hits: u64,
+    /// Nodes read from the database and decoded.
+    pub nodes_decoded: u64,
+    /// Invocations of the hasher, keccak256 by default, on nodes and value blobs.
+    pub hashes: u64,
+    /// Stored nodes whose encoding for a proof was found in the node cache instead of
+    /// being built again.
+    pub encodings_reused: u64,
+    /// Clean subtrees a commit referenced by their cached hash instead of encoding and
+    /// hashing them again.
+    pub subtrees_skipped: u64,
+}
+
+// Updated through `&self`, as reads only borrow the trie. Counts don't order any other
+// memory access, so relaxed atomics are enough.
+#[derive(Debug, Default)]
+pub(crate) struct Counters {
+    db_reads: AtomicU64,
+    cache_hits: AtomicU64,
+    nodes_decoded: AtomicU64,
+    hashes: AtomicU64,
+    encodings_reused: AtomicU64,
+    subtrees_skipped: AtomicU64,
+}
+
+impl Counters {
+    pub fn db_read(&self) {
+        self.db_reads.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn cache_hit(&self) {
+        self.cache_hits.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn node_decoded(&self) {
+        self.nodes_decoded.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn hashed(&self) {
+        self.hashes.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn encoding_reused(&self) {
+        self.encodings_reused.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn subtree_skipped(&self) {
+        self.subtrees_skipped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn get(&self) -> PerfCounters {
+        PerfCounters {
+            db_reads: self.db_reads.load(Ordering::Relaxed),
+            cache_hits: self.cache_hits.load(Ordering::Relaxed),
+            nodes_decoded: self.nodes_decoded.load(Ordering::Relaxed),
+            hashes: self.hashes.load(Ordering::Relaxed),
+            encodings_reused: self.encodings_reused.load(Ordering::Relaxed),
+            subtrees_skipped: self.subtrees_skipped.load(Ordering::Relaxed),
+        }
+    }
+
+    fn reset(&self) {
+        self.db_reads.store(0, Ordering::Relaxed);
+        self.cache_hits.store(0, Ordering::Relaxed);
+        self.nodes_decoded.store(0, Ordering::Relaxed);
+        self.hashes.store(0, Ordering::Relaxed);
+        self.encodings_reused.store(0, Ordering::Relaxed);
+        self.subtrees_skipped.store(0, Ordering::Relaxed);
+    }
+}
+
+impl<D, H, C> EthTrie<D, H, C>
+where
+    D: DB,
+    H: Hasher,
+    C: NodeCodec,
+{
+    /// Returns the work done by this trie since it was created or
+    /// `reset_perf_counters` was last called. Tries created with `at_root` count
+    /// their own work from zero.
+    pub fn perf_counters(&self) -> PerfCounters {
+        self.counters.get()
+    }
+
+    /// Sets every counter returned by `perf_counters` back to zero.
+    pub fn reset_perf_counters(&self) {
+        self.counters.reset()
+    }
+}
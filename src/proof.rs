@@ -0,0 +1,230 @@
+use ethereum_types::H256;
+
+use crate::codec::{NodeCodec, RlpCodec};
+use crate::db::DB;
+use crate::hasher::{Hasher, KeccakHasher};
+use crate::nibbles::Nibbles;
+use crate::node::Node;
+use crate::page::from_nibbles;
+use crate::trie::{EthTrie, TrieResult};
+
+type ProvedEntry = (Vec<u8>, Vec<u8>, Vec<Vec<u8>>);
+
+// A node still to be visited, with the key nibbles leading to it and the length of
+// the proof down to its parent.
+struct Frame {
+    node: Node,
+    nibbles: Vec<u8>,
+    proof_len: usize,
+}
+
+/// Iterator over `(key, value, proof)` triples in key order, created by
+/// `EthTrie::iter_with_proofs`. Each proof is the same list of encoded nodes that
+/// `ITrie::proof` returns for the key, but the nodes shared between consecutive keys
+/// are only loaded and encoded once.
+///
+/// A node or value that can't be loaded is yielded as an error, which ends the
+/// iteration. In `TraversalMode::Lenient` a missing node is skipped instead, along
+/// with the keys below it.
+pub struct ProofIterator<'a, D, H = KeccakHasher, C = RlpCodec>
+where
+    D: DB,
+    H: Hasher,
+    C: NodeCodec,
+{
+    trie: &'a EthTrie<D, H, C>,
+    stack: Vec<Frame>,
+    proof: Vec<Vec<u8>>,
+    // The nibbles every key yielded starts with.
+    prefix: Vec<u8>,
+}
+
+impl<D, H, C> EthTrie<D, H, C>
+where
+    D: DB,
+    H: Hasher,
+    C: NodeCodec,
+{
+    /// Walks the trie once, yielding every key and value together with its merkle
+    /// proof. Useful to pre-compute light-client responses or export a verifiable
+    /// dump without a separate `proof` call, and traversal, per key.
+    pub fn iter_with_proofs(&self) -> ProofIterator<'_, D, H, C> {
+        self.iter_prefix_with_proofs(&[])
+    }
+
+    /// Like `iter_with_proofs`, but only yields the keys starting with `prefix`, such
+    /// as all the storage slots of an account, for light-client servers answering
+    /// with every entry verifiable. Only the nodes on the way to those keys are read.
+    pub fn iter_prefix_with_proofs(&self, prefix: &[u8]) -> ProofIterator<'_, D, H, C> {
+        let stack = vec![Frame {
+            node: self.root.clone(),
+            nibbles: vec![],
+            proof_len: 0,
+        }];
+        ProofIterator {
+            trie: self,
+            stack,
+            proof: vec![],
+            prefix: Nibbles::from_raw(prefix, false).get_data().to_vec(),
+        }
+    }
+}
+
+impl<'a, D, H, C> ProofIterator<'a, D, H, C>
+where
+    D: DB,
+    H: Hasher,
+    C: NodeCodec,
+{
+    fn next_entry(&mut self) -> TrieResult<Option<ProvedEntry>> {
+        while let Some(Frame {
+            node,
+            mut nibbles,
+            proof_len,
+        }) = self.stack.pop()
+        {
+            // Skip the subtrees that can't hold a key starting with the prefix.
+            let common = nibbles.len().min(self.prefix.len());
+            if nibbles[..common] != self.prefix[..common] {
+                continue;
+            }
+            let node = match node {
+                Node::Empty => continue,
+                Node::Hash(hash_node) => {
+                    let node_hash = hash_node.hash;
+                    self.stack.push(Frame {
+                        node: Node::Hash(hash_node),
+                        nibbles,
+                        proof_len,
+                    });
+                    self.load_siblings()?;
+                    let frame = self.stack.last_mut().expect("pushed above");
+                    if matches!(frame.node, Node::Hash(_)) {
+                        match self.trie.load_or_skip(node_hash, &frame.nibbles)? {
+                            Some(node) => frame.node = node,
+                            None => {
+                                self.stack.pop();
+                            }
+                        }
+                    }
+                    continue;
+                }
+                node => node,
+            };
+
+            // The root is always part of the proof, other nodes only when they are
+            // referenced by hash rather than embedded in their parent.
+            self.proof.truncate(proof_len);
+            let encoded = self.trie.encode_current_node(&node);
+            if nibbles.is_empty() || encoded.len() >= C::INLINE_THRESHOLD {
+                self.proof.push(encoded);
+            }
+            let proof_len = self.proof.len();
+
+            match node {
+                Node::Leaf(leaf) => {
+                    nibbles.extend_from_slice(leaf.key.get_data());
+                    if nibbles.last() == Some(&16) {
+                        nibbles.pop();
+                    }
+                    if !nibbles.starts_with(&self.prefix) {
+                        continue;
+                    }
+                    let value = self.trie.load_value(leaf.value.clone())?;
+                    return Ok(Some((from_nibbles(&nibbles), value, self.proof.clone())));
+                }
+                Node::Branch(branch) => {
+                    // Within the prefix only the child it goes on with can hold a key;
+                    // past it every child is pushed, and loaded once popped.
+                    let next = self.prefix.get(nibbles.len()).map(|&n| n as usize);
+                    for (i, child) in branch.children.iter().enumerate().rev() {
+                        if next.is_some_and(|next| next != i) {
+                            continue;
+                        }
+                        let mut child_nibbles = nibbles.clone();
+                        child_nibbles.push(i as u8);
+                        self.stack.push(Frame {
+                            node: child.clone(),
+                            nibbles: child_nibbles,
+                            proof_len,
+                        });
+                    }
+                    if let Some(value) = branch
+                        .value
+                        .as_ref()
+                        .filter(|_| nibbles.len() >= self.prefix.len())
+                    {
+                        let value = self.trie.load_value(value.clone())?;
+                        return Ok(Some((from_nibbles(&nibbles), value, self.proof.clone())));
+                    }
+                }
+                Node::Extension(ext) => {
+                    nibbles.extend_from_slice(ext.prefix.get_data());
+                    self.stack.push(Frame {
+                        node: ext.node.clone(),
+                        nibbles,
+                        proof_len,
+                    });
+                }
+                Node::Empty | Node::Hash(_) => unreachable!(),
+            }
+        }
+        Ok(None)
+    }
+}
+
+impl<'a, D, H, C> ProofIterator<'a, D, H, C>
+where
+    D: DB,
+    H: Hasher,
+    C: NodeCodec,
+{
+    // Loads the nodes referenced by hash among the frames on top of the stack that are
+    // children of the same branch, with a single `multi_get`, once the first of them
+    // is popped. Those missing from the database stay hash references.
+    fn load_siblings(&mut self) -> TrieResult<()> {
+        let Some(top) = self.stack.last() else {
+            return Ok(());
+        };
+        let (len, proof_len) = (top.nibbles.len(), top.proof_len);
+        let siblings = self
+            .stack
+            .iter()
+            .rev()
+            .take_while(|frame| frame.nibbles.len() == len && frame.proof_len == proof_len)
+            .count();
+        let start = self.stack.len() - siblings;
+        let hashed: Vec<(usize, H256)> = self.stack[start..]
+            .iter()
+            .enumerate()
+            .filter_map(|(i, frame)| match &frame.node {
+                Node::Hash(hash_node) => Some((start + i, hash_node.hash)),
+                _ => None,
+            })
+            .collect();
+        let hashes: Vec<H256> = hashed.iter().map(|(_, hash)| *hash).collect();
+        for ((i, _), node) in hashed.into_iter().zip(self.trie.load_nodes(&hashes)?) {
+            if let Some(node) = node {
+                self.stack[i].node = node;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<'a, D, H, C> Iterator for ProofIterator<'a, D, H, C>
+where
+    D: DB,
+    H: Hasher,
+    C: NodeCodec,
+{
+    type Item = TrieResult<ProvedEntry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let entry = self.next_entry();
+        if entry.is_err() {
+            self.stack.clear();
+        }
+        entry.transpose()
+    }
+}
@@ -0,0 +1,178 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+
+use ethereum_types::H256;
+
+use crate::codec::NodeCodec;
+use crate::db::DB;
+use crate::errors::TrieError;
+use crate::hasher::Hasher;
+use crate::node::Node;
+use crate::page::from_nibbles;
+use crate::trie::{EthTrie, TrieResult};
+
+/// What `EthTrie::recover_from_leaves` salvaged from a damaged database.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Recovery {
+    /// The root of the rebuilt trie.
+    pub root: H256,
+    /// How many entries were salvaged and written to the rebuilt trie.
+    pub recovered: usize,
+    /// Nodes reachable from the root that are missing from the database, or stored
+    /// under a hash their data doesn't match. Every entry below them is lost.
+    pub lost_nodes: Vec<H256>,
+    /// Leaves found in the database that the walk from the root doesn't reach, being
+    /// below a lost node or left by earlier roots that weren't pruned. Their keys
+    /// can't be told from what is stored, so they are counted but not salvaged.
+    pub stranded_leaves: usize,
+    /// Entries whose value was stored apart and is missing from the database.
+    pub lost_values: usize,
+}
+
+// Accumulates what a recovery walk finds.
+struct Salvage {
+    nodes: HashMap<H256, Node>,
+    reached: HashSet<H256>,
+    entries: Vec<(Vec<u8>, Vec<u8>)>,
+    lost_nodes: Vec<H256>,
+    lost_values: usize,
+}
+
+impl<D, H, C> EthTrie<D, H, C>
+where
+    D: DB,
+    H: Hasher,
+    C: NodeCodec,
+{
+    /// Rebuilds the committed trie into `into` from what survives in this trie's
+    /// database, for when nodes were lost or corrupted on disk. Every stored node is
+    /// read and checked against its hash, then the trie is walked from its root
+    /// through the sound nodes, and the entries reached are written to a new trie
+    /// with `build_from_sorted`. Returns that trie, along with a `Recovery` telling
+    /// what was salvaged and what was lost.
+    ///
+    /// The sound nodes are held in memory during the walk. Pending changes are
+    /// ignored. Fails with `TrieError::Unsupported` if the backend can't list the
+    /// nodes it holds.
+    pub fn recover_from_leaves<D2: DB>(
+        &self,
+        into: Arc<D2>,
+    ) -> TrieResult<(EthTrie<D2, H, C>, Recovery)> {
+        let mut nodes = HashMap::new();
+        let listed = self
+            .db
+            .iter_nodes(&mut |key, data| {
+                let Ok(hash) = <[u8; 32]>::try_from(key).map(H256::from) else {
+                    return;
+                };
+                if H::hash(data) != hash {
+                    return;
+                }
+                if let Ok(node) = self.decode_node(data) {
+                    nodes.insert(hash, node);
+                }
+            })
+            .map_err(TrieError::backend)?;
+        if !listed {
+            return Err(TrieError::Unsupported {
+                operation: "listing the stored nodes",
+            });
+        }
+
+        let mut salvage = Salvage {
+            nodes,
+            reached: HashSet::new(),
+            entries: vec![],
+            lost_nodes: vec![],
+            lost_values: 0,
+        };
+        let root = if self.root_hash == H::hash(&C::encode_empty()) {
+            Node::Empty
+        } else {
+            Node::from_hash(self.root_hash)
+        };
+        self.salvage_at(&root, &mut vec![], &mut salvage)?;
+
+        let stranded_leaves = salvage
+            .nodes
+            .iter()
+            .filter(|(hash, _)| !salvage.reached.contains(hash))
+            .map(|(_, node)| count_leaves(node))
+            .sum();
+
+        // The walk meets keys in order, so they can be built from directly.
+        let mut trie = self.at_root_in(into, H::hash(&C::encode_empty()));
+        let recovered = salvage.entries.len();
+        let root = trie.build_from_sorted(salvage.entries)?;
+        let recovery = Recovery {
+            root,
+            recovered,
+            lost_nodes: salvage.lost_nodes,
+            stranded_leaves,
+            lost_values: salvage.lost_values,
+        };
+        Ok((trie, recovery))
+    }
+
+    // Collects the entries below `node`, found at `path`, that can be read back.
+    fn salvage_at(&self, node: &Node, path: &mut Vec<u8>, salvage: &mut Salvage) -> TrieResult<()> {
+        match node {
+            Node::Empty => {}
+            Node::Leaf(leaf) => {
+                let len = path.len();
+                // The leaf's key ends with the terminator.
+                let key = leaf.key.get_data();
+                path.extend_from_slice(&key[..key.len() - 1]);
+                self.salvage_value(path, leaf.value.clone(), salvage)?;
+                path.truncate(len);
+            }
+            Node::Branch(branch) => {
+                if let Some(value) = &branch.value {
+                    self.salvage_value(path, value.clone(), salvage)?;
+                }
+                for (i, child) in branch.children.iter().enumerate() {
+                    path.push(i as u8);
+                    self.salvage_at(child, path, salvage)?;
+                    path.pop();
+                }
+            }
+            Node::Extension(ext) => {
+                let len = path.len();
+                path.extend_from_slice(ext.prefix.get_data());
+                self.salvage_at(&ext.node, path, salvage)?;
+                path.truncate(len);
+            }
+            Node::Hash(hash_node) => {
+                let hash = hash_node.hash;
+                match salvage.nodes.get(&hash).cloned() {
+                    Some(node) => {
+                        salvage.reached.insert(hash);
+                        self.salvage_at(&node, path, salvage)?;
+                    }
+                    None => salvage.lost_nodes.push(hash),
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Adds the entry at `path` unless its value was stored apart and is gone.
+    fn salvage_value(&self, path: &[u8], stored: Vec<u8>, salvage: &mut Salvage) -> TrieResult<()> {
+        match self.load_value(stored) {
+            Ok(value) => salvage.entries.push((from_nibbles(path), value)),
+            Err(TrieError::NotFound { .. }) => salvage.lost_values += 1,
+            Err(error) => return Err(error),
+        }
+        Ok(())
+    }
+}
+
+// The leaves held by `node` itself and the nodes embedded in it.
+fn count_leaves(node: &Node) -> usize {
+    match node {
+        Node::Empty | Node::Hash(_) => 0,
+        Node::Leaf(_) => 1,
+        Node::Branch(branch) => branch.children.iter().map(count_leaves).sum(),
+        Node::Extension(ext) => count_leaves(&ext.node),
+    }
+}
@@ -0,0 +1,141 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::db::{CommitBatch, StorageStats, DB};
+
+/// A `DB` writing to a primary database and spreading reads across replicas of it,
+/// for fleets serving proofs from shared storage.
+///
+/// Nodes and blobs are stored under their hash, so a replica either returns the right
+/// data or none. Reads a replica can't answer, because it lags behind or fails, are
+/// retried on the primary, so what was just written through this `DB` can always be
+/// read back. Metadata is overwritten in place and is always read from the primary;
+/// other data that changes under the same key belongs there too.
+#[derive(Debug)]
+pub struct ReplicatedDB<D> {
+    primary: D,
+    replicas: Vec<D>,
+    // The replica the next read goes to, round-robin.
+    next: AtomicUsize,
+}
+
+impl<D: DB> ReplicatedDB<D> {
+    /// Without replicas every read goes to the primary.
+    pub fn new(primary: D, replicas: Vec<D>) -> Self {
+        ReplicatedDB {
+            primary,
+            replicas,
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn primary(&self) -> &D {
+        &self.primary
+    }
+
+    pub fn replicas(&self) -> &[D] {
+        &self.replicas
+    }
+
+    // Reads from the next replica, falling back to the primary.
+    fn read(
+        &self,
+        read: impl Fn(&D) -> Result<Option<Vec<u8>>, D::Error>,
+    ) -> Result<Option<Vec<u8>>, D::Error> {
+        if !self.replicas.is_empty() {
+            let i = self.next.fetch_add(1, Ordering::Relaxed) % self.replicas.len();
+            if let Ok(Some(value)) = read(&self.replicas[i]) {
+                return Ok(Some(value));
+            }
+        }
+        read(&self.primary)
+    }
+}
+
+impl<D: DB> DB for ReplicatedDB<D> {
+    type Error = D::Error;
+
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Self::Error> {
+        self.read(|db| db.get(key))
+    }
+
+    fn multi_get(&self, keys: &[Vec<u8>]) -> Result<Vec<Option<Vec<u8>>>, Self::Error> {
+        let mut values = if self.replicas.is_empty() {
+            vec![None; keys.len()]
+        } else {
+            let i = self.next.fetch_add(1, Ordering::Relaxed) % self.replicas.len();
+            self.replicas[i]
+                .multi_get(keys)
+                .unwrap_or_else(|_| vec![None; keys.len()])
+        };
+        let missing: Vec<usize> = (0..keys.len()).filter(|&i| values[i].is_none()).collect();
+        if !missing.is_empty() {
+            let keys: Vec<Vec<u8>> = missing.iter().map(|&i| keys[i].clone()).collect();
+            for (i, value) in missing.into_iter().zip(self.primary.multi_get(&keys)?) {
+                values[i] = value;
+            }
+        }
+        Ok(values)
+    }
+
+    fn contains(&self, key: &[u8]) -> Result<bool, Self::Error> {
+        if !self.replicas.is_empty() {
+            let i = self.next.fetch_add(1, Ordering::Relaxed) % self.replicas.len();
+            if let Ok(true) = self.replicas[i].contains(key) {
+                return Ok(true);
+            }
+        }
+        self.primary.contains(key)
+    }
+
+    fn insert(&self, key: &[u8], value: Vec<u8>) -> Result<(), Self::Error> {
+        self.primary.insert(key, value)
+    }
+
+    fn remove(&self, key: &[u8]) -> Result<(), Self::Error> {
+        self.primary.remove(key)
+    }
+
+    fn insert_batch(&self, keys: Vec<Vec<u8>>, values: Vec<Vec<u8>>) -> Result<(), Self::Error> {
+        self.primary.insert_batch(keys, values)
+    }
+
+    fn remove_batch(&self, keys: &[Vec<u8>]) -> Result<(), Self::Error> {
+        self.primary.remove_batch(keys)
+    }
+
+    fn commit_batch(&self, batch: CommitBatch) -> Result<(), Self::Error> {
+        self.primary.commit_batch(batch)
+    }
+
+    fn flush(&self) -> Result<(), Self::Error> {
+        self.primary.flush()
+    }
+
+    fn get_blob(&self, hash: &[u8]) -> Result<Option<Vec<u8>>, Self::Error> {
+        self.read(|db| db.get_blob(hash))
+    }
+
+    fn insert_blob(&self, hash: &[u8], value: Vec<u8>) -> Result<(), Self::Error> {
+        self.primary.insert_blob(hash, value)
+    }
+
+    fn get_meta(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Self::Error> {
+        self.primary.get_meta(key)
+    }
+
+    fn insert_meta(&self, key: &[u8], value: Vec<u8>) -> Result<(), Self::Error> {
+        self.primary.insert_meta(key, value)
+    }
+
+    fn remove_meta(&self, key: &[u8]) -> Result<(), Self::Error> {
+        self.primary.remove_meta(key)
+    }
+
+    fn stats(&self) -> Result<Option<StorageStats>, Self::Error> {
+        self.primary.stats()
+    }
+
+    fn iter_nodes(&self, f: &mut dyn FnMut(&[u8], &[u8])) -> Result<bool, Self::Error> {
+        self.primary.iter_nodes(f)
+    }
+}
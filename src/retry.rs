@@ -0,0 +1,276 @@
+use std::error::Error;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+
+use crate::db::{CommitBatch, StorageStats, DB, DEFAULT_RETRIES, DEFAULT_RETRY_BACKOFF};
+
+/// Whether a failed operation is worth retrying, as decided by a `RetryDB`'s
+/// classifier.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Failure {
+    /// The operation may succeed if tried again, as after a timeout or a dropped
+    /// connection.
+    Transient,
+    /// Trying again won't help.
+    Permanent,
+}
+
+/// The error of a `RetryDB`, telling failures that were retried from those that
+/// weren't.
+#[derive(Debug, thiserror::Error)]
+pub enum RetryError<E: Error + 'static> {
+    #[error("permanent database failure")]
+    Permanent(#[source] E),
+    /// Every attempt failed with a transient error; `source` is the last one.
+    #[error("database still failing after {attempts} attempts")]
+    Exhausted {
+        attempts: u32,
+        #[source]
+        source: E,
+    },
+}
+
+impl<E: Error + 'static> RetryError<E> {
+    /// The error returned by the wrapped database.
+    pub fn inner(&self) -> &E {
+        match self {
+            RetryError::Permanent(source) | RetryError::Exhausted { source, .. } => source,
+        }
+    }
+}
+
+type Classifier<E> = Arc<dyn Fn(&E) -> Failure + Send + Sync>;
+
+/// A `DB` retrying the operations of another that fail transiently, such as a
+/// database reached over the network. Each operation is retried up to a number of
+/// times, waiting longer before each retry, and can be spaced out to respect a rate
+/// limit. Which errors are transient is decided by a classifier; by default they
+/// all are.
+pub struct RetryDB<D: DB> {
+    inner: D,
+    retries: u32,
+    backoff: Duration,
+    classify: Classifier<D::Error>,
+    // The shortest time between two operations, and when the next one may start.
+    interval: Option<Duration>,
+    next_slot: Mutex<Instant>,
+}
+
+impl<D: DB> RetryDB<D> {
+    /// Retries failed operations 3 times, from 50 milliseconds, without a rate limit.
+    pub fn new(inner: D) -> Self {
+        RetryDB {
+            inner,
+            retries: DEFAULT_RETRIES,
+            backoff: DEFAULT_RETRY_BACKOFF,
+            classify: Arc::new(|_| Failure::Transient),
+            interval: None,
+            next_slot: Mutex::new(Instant::now()),
+        }
+    }
+
+    /// Sets how many times a transiently failing operation is retried, waiting
+    /// `backoff` before the first retry and twice as long before each next one.
+    pub fn with_retries(mut self, retries: u32, backoff: Duration) -> Self {
+        self.retries = retries;
+        self.backoff = backoff;
+        self
+    }
+
+    /// Sets which errors of the wrapped database are worth retrying. Permanent ones
+    /// are returned at once as `RetryError::Permanent`.
+    pub fn with_classifier(
+        mut self,
+        classify: impl Fn(&D::Error) -> Failure + Send + Sync + 'static,
+    ) -> Self {
+        self.classify = Arc::new(classify);
+        self
+    }
+
+    /// Limits the operations sent to the wrapped database, retries included, to
+    /// `per_second`, making callers wait for their turn. 0 removes the limit.
+    pub fn with_rate_limit(mut self, per_second: u32) -> Self {
+        self.interval = (per_second > 0).then(|| Duration::from_secs(1) / per_second);
+        self
+    }
+
+    pub fn inner(&self) -> &D {
+        &self.inner
+    }
+
+    fn retry<T>(
+        &self,
+        mut f: impl FnMut(&D) -> Result<T, D::Error>,
+    ) -> Result<T, RetryError<D::Error>> {
+        self.retry_attempts(|db, _| f(db))
+    }
+
+    // Retries an operation consuming `payload`. Each attempt that may still be
+    // retried gets a copy, and the last one the payload itself.
+    fn retry_owned<P: Clone, T>(
+        &self,
+        payload: P,
+        mut f: impl FnMut(&D, P) -> Result<T, D::Error>,
+    ) -> Result<T, RetryError<D::Error>> {
+        let mut payload = Some(payload);
+        self.retry_attempts(|db, attempt| {
+            let payload = if attempt > self.retries {
+                payload.take()
+            } else {
+                payload.clone()
+            };
+            f(db, payload.expect("no attempt follows the last one"))
+        })
+    }
+
+    // Runs `f` with the number of the attempt, from 1, until it succeeds, fails
+    // permanently or the retries run out.
+    fn retry_attempts<T>(
+        &self,
+        mut f: impl FnMut(&D, u32) -> Result<T, D::Error>,
+    ) -> Result<T, RetryError<D::Error>> {
+        let mut backoff = self.backoff;
+        let mut attempts = 0;
+        loop {
+            self.wait_turn();
+            attempts += 1;
+            match f(&self.inner, attempts) {
+                Ok(value) => return Ok(value),
+                Err(error) if (self.classify)(&error) == Failure::Permanent => {
+                    return Err(RetryError::Permanent(error))
+                }
+                Err(error) if attempts > self.retries => {
+                    return Err(RetryError::Exhausted {
+                        attempts,
+                        source: error,
+                    })
+                }
+                Err(_) => {
+                    thread::sleep(backoff);
+                    backoff = backoff.saturating_mul(2);
+                }
+            }
+        }
+    }
+
+    fn wait_turn(&self) {
+        let Some(interval) = self.interval else {
+            return;
+        };
+        let start = {
+            let mut next_slot = self.next_slot.lock();
+            let start = (*next_slot).max(Instant::now());
+            *next_slot = start + interval;
+            start
+        };
+        thread::sleep(start.saturating_duration_since(Instant::now()));
+    }
+}
+
+impl<D: DB> DB for RetryDB<D> {
+    type Error = RetryError<D::Error>;
+
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Self::Error> {
+        self.retry(|db| db.get(key))
+    }
+
+    fn multi_get(&self, keys: &[Vec<u8>]) -> Result<Vec<Option<Vec<u8>>>, Self::Error> {
+        self.retry(|db| db.multi_get(keys))
+    }
+
+    fn contains(&self, key: &[u8]) -> Result<bool, Self::Error> {
+        self.retry(|db| db.contains(key))
+    }
+
+    fn insert(&self, key: &[u8], value: Vec<u8>) -> Result<(), Self::Error> {
+        self.retry_owned(value, |db, value| db.insert(key, value))
+    }
+
+    fn remove(&self, key: &[u8]) -> Result<(), Self::Error> {
+        self.retry(|db| db.remove(key))
+    }
+
+    fn insert_batch(&self, keys: Vec<Vec<u8>>, values: Vec<Vec<u8>>) -> Result<(), Self::Error> {
+        self.retry_owned((keys, values), |db, (keys, values)| {
+            db.insert_batch(keys, values)
+        })
+    }
+
+    fn remove_batch(&self, keys: &[Vec<u8>]) -> Result<(), Self::Error> {
+        self.retry(|db| db.remove_batch(keys))
+    }
+
+    /// Retries the whole batch, which is only safe if the wrapped database writes
+    /// it in one transaction or, as the default `commit_batch` does, with writes
+    /// that can be repeated.
+    fn commit_batch(&self, batch: CommitBatch) -> Result<(), Self::Error> {
+        self.retry_owned(batch, |db, batch| db.commit_batch(batch))
+    }
+
+    fn flush(&self) -> Result<(), Self::Error> {
+        self.retry(|db| db.flush())
+    }
+
+    fn get_blob(&self, hash: &[u8]) -> Result<Option<Vec<u8>>, Self::Error> {
+        self.retry(|db| db.get_blob(hash))
+    }
+
+    fn insert_blob(&self, hash: &[u8], value: Vec<u8>) -> Result<(), Self::Error> {
+        self.retry_owned(value, |db, value| db.insert_blob(hash, value))
+    }
+
+    fn get_meta(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Self::Error> {
+        self.retry(|db| db.get_meta(key))
+    }
+
+    fn insert_meta(&self, key: &[u8], value: Vec<u8>) -> Result<(), Self::Error> {
+        self.retry_owned(value, |db, value| db.insert_meta(key, value))
+    }
+
+    fn remove_meta(&self, key: &[u8]) -> Result<(), Self::Error> {
+        self.retry(|db| db.remove_meta(key))
+    }
+
+    fn stats(&self) -> Result<Option<StorageStats>, Self::Error> {
+        self.retry(|db| db.stats())
+    }
+
+    /// A scan failing midway is retried from the start, so `f` may see some nodes
+    /// more than once.
+    fn iter_nodes(&self, f: &mut dyn FnMut(&[u8], &[u8])) -> Result<bool, Self::Error> {
+        self.retry(|db| db.iter_nodes(&mut *f))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::FaultyDB;
+
+    #[test]
+    fn retried_writes_store_the_whole_payload() {
+        let db = RetryDB::new(FaultyDB::new()).with_retries(2, Duration::ZERO);
+        db.inner().fail_writes(2);
+        db.insert_batch(
+            vec![b"a".to_vec(), b"b".to_vec()],
+            vec![b"1".to_vec(), b"2".to_vec()],
+        )
+        .unwrap();
+        db.inner().fail_writes(1);
+        db.insert_meta(b"meta", b"value".to_vec()).unwrap();
+
+        assert_eq!(db.get(b"a").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(db.get(b"b").unwrap(), Some(b"2".to_vec()));
+        assert_eq!(db.get_meta(b"meta").unwrap(), Some(b"value".to_vec()));
+
+        db.inner().fail_writes(3);
+        assert!(matches!(
+            db.insert(b"c", b"3".to_vec()),
+            Err(RetryError::Exhausted { attempts: 3, .. })
+        ));
+        assert_eq!(db.get(b"c").unwrap(), None);
+    }
+}
@@ -0,0 +1,177 @@
+use std::collections::HashMap;
+
+use ethereum_types::H256;
+
+use crate::codec::{NodeCodec, RlpCodec};
+use crate::db::DB;
+use crate::errors::{TrieError, TrieResult};
+use crate::hasher::{Hasher, KeccakHasher};
+use crate::trie::{EthTrie, ITrie};
+
+fn preimage_key(hash: &H256) -> Vec<u8> {
+    [b"preimage:".as_slice(), hash.as_bytes()].concat()
+}
+
+/// A trie whose keys are hashed with `H` before use, as Ethereum's state and storage
+/// tries are, so paths stay short and balanced whatever the keys. The raw keys can't
+/// be recovered from the trie, so with `with_preimages(true)` each key is also stored
+/// under its hash, and `iter` yields raw keys again.
+pub struct SecureTrie<D, H = KeccakHasher, C = RlpCodec>
+where
+    D: DB,
+    H: Hasher,
+    C: NodeCodec,
+{
+    trie: EthTrie<D, H, C>,
+    preimages: bool,
+    // The preimages of the keys put since the last commit, which writes them.
+    pending_preimages: HashMap<H256, Vec<u8>>,
+}
+
+impl<D, H, C> SecureTrie<D, H, C>
+where
+    D: DB,
+    H: Hasher,
+    C: NodeCodec,
+{
+    pub fn new(trie: EthTrie<D, H, C>) -> Self {
+        SecureTrie {
+            trie,
+            preimages: false,
+            pending_preimages: HashMap::new(),
+        }
+    }
+
+    /// Chooses whether `put` records the raw key under its hash in the database's
+    /// metadata, for `resolve_preimage` and `iter`. Preimages are written by `commit`,
+    /// in the same batch as the nodes, and are kept when keys are deleted.
+    pub fn with_preimages(mut self, preimages: bool) -> Self {
+        self.preimages = preimages;
+        self
+    }
+
+    /// The underlying trie, keyed by hashes, to reach what isn't wrapped here, such
+    /// as `at_root`.
+    pub fn inner(&self) -> &EthTrie<D, H, C> {
+        &self.trie
+    }
+
+    pub fn inner_mut(&mut self) -> &mut EthTrie<D, H, C> {
+        &mut self.trie
+    }
+
+    pub fn into_inner(self) -> EthTrie<D, H, C> {
+        self.trie
+    }
+
+    /// The key `key` is stored under in the underlying trie.
+    pub fn hash_key(key: &[u8]) -> H256 {
+        H::hash(key)
+    }
+
+    pub fn get(&self, key: &[u8]) -> TrieResult<Option<Vec<u8>>> {
+        self.trie.get_fixed(&Self::hash_key(key))
+    }
+
+    pub fn put(&mut self, key: &[u8], value: &[u8]) -> TrieResult<()> {
+        let hash = Self::hash_key(key);
+        if self.preimages {
+            self.pending_preimages.insert(hash, key.to_vec());
+        }
+        self.trie.put_fixed(&hash, value)
+    }
+
+    pub fn del(&mut self, key: &[u8]) -> TrieResult<()> {
+        self.trie.del_fixed(&Self::hash_key(key))
+    }
+
+    /// Commits the trie and the preimages of the keys put since the last commit. If
+    /// the commit fails, the preimages stay pending with the changes.
+    pub fn commit(&mut self) -> TrieResult<H256> {
+        let preimages = &self.pending_preimages;
+        let outcome = self.trie.commit_with_meta(|_| {
+            preimages
+                .iter()
+                .map(|(hash, key)| (preimage_key(hash), key.clone()))
+                .collect()
+        })?;
+        self.pending_preimages.clear();
+        Ok(outcome.root)
+    }
+
+    /// The proof of `key`, which verifiers check against `hash_key(key)`.
+    pub fn proof(&mut self, key: &[u8]) -> TrieResult<Vec<Vec<u8>>> {
+        self.trie.proof(Self::hash_key(key).as_bytes())
+    }
+
+    /// Returns the raw key stored under `hash`, if it was put with preimages on,
+    /// whether or not it is committed yet.
+    pub fn resolve_preimage(&self, hash: &H256) -> TrieResult<Option<Vec<u8>>> {
+        if let Some(key) = self.pending_preimages.get(hash) {
+            return Ok(Some(key.clone()));
+        }
+        self.trie.get_meta(&preimage_key(hash))
+    }
+
+    /// Iterates over the entries with their raw keys, in the order of the hashed keys.
+    /// A key without a preimage fails with `TrieError::NotFound` for its hash; the
+    /// hashed keys themselves are available from `inner().iter()`.
+    pub fn iter(&self) -> impl Iterator<Item = TrieResult<(Vec<u8>, Vec<u8>)>> + '_ {
+        self.trie.iter().map(|entry| {
+            let (hashed, value) = entry?;
+            // Keys put through `inner_mut` may not be hashes.
+            if hashed.len() != H256::len_bytes() {
+                return Err(TrieError::InvalidData);
+            }
+            let hash = H256::from_slice(&hashed);
+            let key = self
+                .resolve_preimage(&hash)?
+                .ok_or(TrieError::NotFound { hash })?;
+            Ok((key, value))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::test_utils::{FaultyDB, MemoryDB};
+
+    #[test]
+    fn preimages_are_written_by_the_commit() {
+        let db = Arc::new(MemoryDB::new());
+        let mut trie = SecureTrie::<_>::new(EthTrie::new(db.clone())).with_preimages(true);
+        trie.put(b"key", b"value").unwrap();
+        let hash = SecureTrie::<MemoryDB>::hash_key(b"key");
+        assert_eq!(db.get_meta(&preimage_key(&hash)).unwrap(), None);
+        assert_eq!(trie.resolve_preimage(&hash).unwrap(), Some(b"key".to_vec()));
+
+        trie.commit().unwrap();
+        assert_eq!(
+            db.get_meta(&preimage_key(&hash)).unwrap(),
+            Some(b"key".to_vec())
+        );
+        let entries: Vec<_> = trie.iter().collect::<TrieResult<_>>().unwrap();
+        assert_eq!(entries, vec![(b"key".to_vec(), b"value".to_vec())]);
+    }
+
+    #[test]
+    fn failed_commit_keeps_the_preimages_pending() {
+        let db = Arc::new(FaultyDB::new());
+        let mut trie = SecureTrie::<_>::new(EthTrie::new(db.clone())).with_preimages(true);
+        trie.put(b"key", b"value").unwrap();
+        let hash = SecureTrie::<FaultyDB>::hash_key(b"key");
+
+        db.fail_writes(1);
+        assert!(trie.commit().is_err());
+        assert_eq!(db.get_meta(&preimage_key(&hash)).unwrap(), None);
+
+        trie.commit().unwrap();
+        assert_eq!(
+            db.get_meta(&preimage_key(&hash)).unwrap(),
+            Some(b"key".to_vec())
+        );
+    }
+}
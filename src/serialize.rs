@@ -0,0 +1,66 @@
+use serde::ser::{Error, SerializeMap, SerializeStruct};
+use serde::{Serialize, Serializer};
+
+use crate::codec::NodeCodec;
+use crate::db::DB;
+use crate::hasher::Hasher;
+use crate::trie::EthTrie;
+
+// Entries are read from the trie a page at a time, as `export_json` does.
+const SERIALIZE_PAGE_SIZE: usize = 1024;
+
+/// Serializes the last committed state of the trie as its root and a map of its keys
+/// to their values, in key order, all hex encoded, for snapshot-style golden tests:
+///
+/// ```json
+/// {"root": "56e8...b421", "entries": {"6b6579": "76616c7565"}}
+/// ```
+///
+/// Changes not committed yet are left out, so the root always matches the entries.
+/// A node that can't be read fails the serialization with the trie error's message.
+impl<D, H, C> Serialize for EthTrie<D, H, C>
+where
+    D: DB,
+    H: Hasher,
+    C: NodeCodec,
+{
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let committed = self.at_root(self.root_hash);
+        let mut state = serializer.serialize_struct("EthTrie", 2)?;
+        state.serialize_field("root", &hex::encode(self.root_hash))?;
+        state.serialize_field("entries", &Entries(&committed))?;
+        state.end()
+    }
+}
+
+struct Entries<'a, D, H, C>(&'a EthTrie<D, H, C>)
+where
+    D: DB,
+    H: Hasher,
+    C: NodeCodec;
+
+impl<D, H, C> Serialize for Entries<'_, D, H, C>
+where
+    D: DB,
+    H: Hasher,
+    C: NodeCodec,
+{
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(None)?;
+        let mut cursor = None;
+        loop {
+            let page = self
+                .0
+                .iter_page(cursor.as_ref(), SERIALIZE_PAGE_SIZE)
+                .map_err(S::Error::custom)?;
+            for (key, value) in page.entries {
+                map.serialize_entry(&hex::encode(key), &hex::encode(value))?;
+            }
+            match page.cursor {
+                Some(next) => cursor = Some(next),
+                None => break,
+            }
+        }
+        map.end()
+    }
+}
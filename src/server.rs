@@ -0,0 +1,218 @@
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs};
+
+use serde_json::{json, Value};
+use tiny_http::{Header, Method, Response};
+
+use crate::codec::{NodeCodec, RlpCodec};
+use crate::db::DB;
+use crate::errors::TrieError;
+use crate::hasher::{Hasher, KeccakHasher};
+use crate::trie::{EthTrie, ITrie, TrieResult};
+
+/// Entries returned by `mpt_iteratePrefix` when no limit is given.
+const DEFAULT_PAGE_SIZE: u64 = 100;
+
+/// Upper bound on the limit a client may ask `mpt_iteratePrefix` for.
+const MAX_PAGE_SIZE: u64 = 1000;
+
+// JSON-RPC 2.0 error codes.
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+const TRIE_ERROR: i64 = -32000;
+
+/// A JSON-RPC 2.0 service over HTTP answering reads against a committed trie, so it
+/// can be used as a verifiable key/value store. Requests are `POST`ed to any path and
+/// served one at a time. Keys, values, roots and proof nodes are `0x` prefixed hex.
+/// With the `metrics` feature, `GET /metrics` returns `metrics::gather()`.
+///
+/// - `mpt_root()` returns the root hash.
+/// - `mpt_get(key)` returns the value, or `null` if the key is absent.
+/// - `mpt_getProof(key)` returns `{root, key, value, proof}`, checkable with
+///   `verify_proof`.
+/// - `mpt_iteratePrefix(prefix, after?, limit?)` returns `{entries, next}`: up to
+///   `limit` entries whose key starts with `prefix`, in key order and after the key
+///   `after`. `next` is the `after` to send for the following page, or `null` once the
+///   prefix is exhausted.
+pub struct TrieServer<D, H = KeccakHasher, C = RlpCodec>
+where
+    D: DB,
+    H: Hasher,
+    C: NodeCodec,
+{
+    trie: EthTrie<D, H, C>,
+    http: tiny_http::Server,
+}
+
+impl<D, H, C> TrieServer<D, H, C>
+where
+    D: DB,
+    H: Hasher,
+    C: NodeCodec,
+{
+    /// Listens on `addr`, committing pending changes first so that proofs are made
+    /// against the root that is served.
+    pub fn bind<A: ToSocketAddrs>(addr: A, mut trie: EthTrie<D, H, C>) -> TrieResult<Self> {
+        trie.commit()?;
+        let http = tiny_http::Server::http(addr).map_err(|e| TrieError::Io {
+            source: io::Error::other(e),
+        })?;
+        Ok(TrieServer { trie, http })
+    }
+
+    /// The address the server listens on, useful when bound to port 0.
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.http.server_addr().to_ip()
+    }
+
+    /// Serves requests until the listener fails. Errors answering a single client are
+    /// not fatal.
+    pub fn run(mut self) -> TrieResult<()> {
+        loop {
+            let mut request = self.http.recv()?;
+            #[cfg(feature = "metrics")]
+            if *request.method() == Method::Get && request.url() == "/metrics" {
+                let _ = request.respond(crate::metrics::response());
+                continue;
+            }
+            let response = if *request.method() != Method::Post {
+                Response::from_string("JSON-RPC requests must be POSTed\n").with_status_code(405)
+            } else {
+                let mut body = String::new();
+                let reply = match request.as_reader().read_to_string(&mut body) {
+                    Ok(_) => self.handle(&body),
+                    Err(_) => {
+                        error(Value::Null, PARSE_ERROR, "cannot read the body as UTF-8").to_string()
+                    }
+                };
+                let content_type = Header::from_bytes("Content-Type", "application/json")
+                    .expect("static header is valid");
+                Response::from_string(reply).with_header(content_type)
+            };
+            // The client hanging up doesn't concern other clients.
+            let _ = request.respond(response);
+        }
+    }
+
+    /// Answers a JSON-RPC request or batch, so the service can also be mounted in
+    /// another HTTP server.
+    pub fn handle(&mut self, body: &str) -> String {
+        let reply = match serde_json::from_str::<Value>(body) {
+            Ok(Value::Array(batch)) if !batch.is_empty() => {
+                Value::Array(batch.into_iter().map(|r| self.call(r)).collect())
+            }
+            Ok(request) => self.call(request),
+            Err(e) => error(Value::Null, PARSE_ERROR, &e.to_string()),
+        };
+        reply.to_string()
+    }
+
+    fn call(&mut self, request: Value) -> Value {
+        let id = request.get("id").cloned().unwrap_or(Value::Null);
+        let (Some(method), Some("2.0")) = (
+            request.get("method").and_then(Value::as_str),
+            request.get("jsonrpc").and_then(Value::as_str),
+        ) else {
+            return error(id, INVALID_REQUEST, "not a JSON-RPC 2.0 request");
+        };
+        let params = match request.get("params") {
+            None => vec![],
+            Some(Value::Array(params)) => params.clone(),
+            Some(_) => return error(id, INVALID_PARAMS, "params must be an array"),
+        };
+
+        let result = match method {
+            "mpt_root" => Ok(Ok(json!(format!("{:?}", self.trie.root_hash)))),
+            "mpt_get" => self.get(&params),
+            "mpt_getProof" => self.get_proof(&params),
+            "mpt_iteratePrefix" => self.iterate_prefix(&params),
+            _ => return error(id, METHOD_NOT_FOUND, "method not found"),
+        };
+        match result {
+            Ok(Ok(result)) => json!({"jsonrpc": "2.0", "id": id, "result": result}),
+            Ok(Err(message)) => error(id, INVALID_PARAMS, message),
+            Err(e) => error(id, TRIE_ERROR, &e.to_string()),
+        }
+    }
+
+    // Methods return `Ok(Err(_))` for invalid params and `Err(_)` when the trie fails.
+
+    fn get(&self, params: &[Value]) -> TrieResult<Result<Value, &'static str>> {
+        let Some(key) = hex_param(params, 0) else {
+            return Ok(Err("expected [key]"));
+        };
+        Ok(Ok(json!(self.trie.get(&key)?.map(to_hex))))
+    }
+
+    fn get_proof(&mut self, params: &[Value]) -> TrieResult<Result<Value, &'static str>> {
+        let Some(key) = hex_param(params, 0) else {
+            return Ok(Err("expected [key]"));
+        };
+        let (value, proof) = self.trie.get_with_proof(&key)?;
+        Ok(Ok(json!({
+            "root": format!("{:?}", self.trie.root_hash),
+            "key": to_hex(key),
+            "value": value.map(to_hex),
+            "proof": proof.into_iter().map(to_hex).collect::<Vec<_>>(),
+        })))
+    }
+
+    fn iterate_prefix(&self, params: &[Value]) -> TrieResult<Result<Value, &'static str>> {
+        const USAGE: &str = "expected [prefix, after?, limit?]";
+        let Some(prefix) = hex_param(params, 0) else {
+            return Ok(Err(USAGE));
+        };
+        let after = match params.get(1) {
+            None | Some(Value::Null) => None,
+            Some(_) => match hex_param(params, 1) {
+                Some(after) => Some(after),
+                None => return Ok(Err(USAGE)),
+            },
+        };
+        let limit = match params.get(2) {
+            None | Some(Value::Null) => DEFAULT_PAGE_SIZE,
+            Some(limit) => match limit.as_u64() {
+                Some(limit) if limit > 0 => limit.min(MAX_PAGE_SIZE),
+                _ => return Ok(Err(USAGE)),
+            },
+        };
+
+        let mut entries = vec![];
+        let mut next = match after {
+            Some(after) => self.trie.next_key_after(&after)?,
+            // The prefix itself sorts before every other key starting with it.
+            None => match self.trie.get(&prefix)? {
+                Some(value) => Some((prefix.clone(), value)),
+                None => self.trie.next_key_after(&prefix)?,
+            },
+        };
+        while let Some((key, value)) = next {
+            if !key.starts_with(&prefix) {
+                break;
+            }
+            if entries.len() as u64 == limit {
+                // Another entry follows, so the client is told where to resume.
+                let last = entries.last().map(|entry: &Value| entry["key"].clone());
+                return Ok(Ok(json!({"entries": entries, "next": last})));
+            }
+            next = self.trie.next_key_after(&key)?;
+            entries.push(json!({"key": to_hex(key), "value": to_hex(value)}));
+        }
+        Ok(Ok(json!({"entries": entries, "next": null})))
+    }
+}
+
+fn error(id: Value, code: i64, message: &str) -> Value {
+    json!({"jsonrpc": "2.0", "id": id, "error": {"code": code, "message": message}})
+}
+
+fn hex_param(params: &[Value], index: usize) -> Option<Vec<u8>> {
+    let s = params.get(index)?.as_str()?;
+    hex::decode(s.strip_prefix("0x").unwrap_or(s)).ok()
+}
+
+fn to_hex<T: AsRef<[u8]>>(bytes: T) -> String {
+    format!("0x{}", hex::encode(bytes))
+}
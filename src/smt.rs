@@ -0,0 +1,540 @@
+use std::marker::PhantomData;
+use std::sync::Arc;
+
+use ethereum_types::H256;
+use hashbrown::{HashMap, HashSet};
+
+use crate::db::{CommitBatch, DB};
+use crate::errors::TrieError;
+use crate::hasher::{Hasher, KeccakHasher};
+use crate::trie::TrieResult;
+
+const LEAF_NODE: u8 = 0;
+const INTERNAL_NODE: u8 = 1;
+
+/// Keys are 256 bits, so that is the depth of the tree.
+const DEPTH: usize = 256;
+
+enum SmtNode {
+    Empty,
+    Leaf { key: H256, value: Vec<u8> },
+    Internal { children: [H256; 2] },
+}
+
+/// A sparse Merkle tree over 256-bit keys, stored in the same `DB` backends as
+/// `EthTrie`.
+///
+/// Every key has a fixed position among 2^256 leaves, all empty by default, so the
+/// root only depends on the set of entries and absence of a key can be proven as
+/// cheaply as presence. Empty subtrees hash to zero and a subtree holding a single
+/// entry is represented by its leaf, so only about log2(n) levels are stored and
+/// proven rather than 256.
+///
+/// Changes are kept in memory until `commit`, which writes the new nodes and prunes
+/// the ones they replaced.
+#[derive(Debug)]
+pub struct SmtTrie<D, H = KeccakHasher>
+where
+    D: DB,
+    H: Hasher,
+{
+    root_hash: H256,
+
+    db: Arc<D>,
+
+    // The batch of pending new nodes to write
+    cache: HashMap<H256, Vec<u8>>,
+    passing_keys: HashSet<H256>,
+
+    hasher: PhantomData<H>,
+}
+
+/// A proof that a key holds a value, or holds nothing, in an `SmtTrie`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SmtProof {
+    /// The sibling hashes from the root down to where the key's path ends, which is
+    /// usually far less than 256 levels.
+    pub siblings: Vec<H256>,
+    /// The key and value hash of the leaf found there. For a missing key this is
+    /// another key sharing the path so far, or `None` if the path ends in an empty
+    /// subtree.
+    pub leaf: Option<(H256, H256)>,
+}
+
+impl<D> SmtTrie<D>
+where
+    D: DB,
+{
+    pub fn new(db: Arc<D>) -> Self {
+        Self::new_with_hasher(db)
+    }
+}
+
+impl<D, H> SmtTrie<D, H>
+where
+    D: DB,
+    H: Hasher,
+{
+    /// Creates an empty tree hashing its nodes with `H`.
+    pub fn new_with_hasher(db: Arc<D>) -> Self {
+        Self {
+            root_hash: H256::zero(),
+            db,
+            cache: HashMap::new(),
+            passing_keys: HashSet::new(),
+            hasher: PhantomData,
+        }
+    }
+
+    pub fn at_root(&self, root_hash: H256) -> Self {
+        Self {
+            root_hash,
+            db: self.db.clone(),
+            cache: HashMap::new(),
+            passing_keys: HashSet::new(),
+            hasher: PhantomData,
+        }
+    }
+
+    /// Returns the root hash, including changes that are not committed yet.
+    /// The empty tree has a zero root.
+    pub fn root_hash(&self) -> H256 {
+        self.root_hash
+    }
+
+    pub fn get(&self, key: &H256) -> TrieResult<Option<Vec<u8>>> {
+        let mut node_hash = self.root_hash;
+        for depth in 0..DEPTH {
+            match self.load(node_hash)? {
+                SmtNode::Empty => return Ok(None),
+                SmtNode::Leaf {
+                    key: leaf_key,
+                    value,
+                } => return Ok((leaf_key == *key).then_some(value)),
+                SmtNode::Internal { children } => node_hash = children[bit(key, depth)],
+            }
+        }
+        Err(TrieError::InvalidData)
+    }
+
+    /// Inserts value under key, replacing any previous value. Empty values are
+    /// allowed; use `remove` to clear a key.
+    pub fn insert(&mut self, key: H256, value: &[u8]) -> TrieResult<()> {
+        let mut data = vec![LEAF_NODE];
+        data.extend_from_slice(key.as_bytes());
+        data.extend_from_slice(value);
+        let leaf_hash = leaf_hash::<H>(&key, &H::hash(value));
+        self.cache.insert(leaf_hash, data);
+
+        self.root_hash = self.insert_at(self.root_hash, 0, &key, leaf_hash)?;
+        Ok(())
+    }
+
+    /// Removes any existing value for key.
+    pub fn remove(&mut self, key: &H256) -> TrieResult<()> {
+        if let Some(root_hash) = self.remove_at(self.root_hash, 0, key)? {
+            self.root_hash = root_hash;
+        }
+        Ok(())
+    }
+
+    /// Saves the new nodes in the db, prunes the ones they replaced and returns the
+    /// root hash. The nodes stay pending if the write fails, for the next commit.
+    pub fn commit(&mut self) -> TrieResult<H256> {
+        // Nodes replaced before the commit never need to be written.
+        let mut keys = vec![];
+        let mut values = vec![];
+        let mut pending = vec![self.root_hash];
+        while let Some(hash) = pending.pop() {
+            let Some(data) = self.cache.get(&hash) else {
+                continue;
+            };
+            if data[0] == INTERNAL_NODE {
+                pending.push(H256::from_slice(&data[1..33]));
+                pending.push(H256::from_slice(&data[33..65]));
+            }
+            keys.push(hash.as_bytes().to_vec());
+            values.push(data.clone());
+        }
+
+        let written: HashSet<H256> = keys.iter().map(|key| H256::from_slice(key)).collect();
+        let removed: Vec<Vec<u8>> = self
+            .passing_keys
+            .iter()
+            .filter(|hash| !written.contains(*hash))
+            .map(|hash| hash.as_bytes().to_vec())
+            .collect();
+
+        self.db
+            .commit_batch(CommitBatch {
+                root: self.root_hash,
+                keys,
+                values,
+                removed,
+                meta: vec![],
+                removed_meta: vec![],
+            })
+            .map_err(TrieError::backend)?;
+        self.cache.clear();
+        self.passing_keys.clear();
+
+        Ok(self.root_hash)
+    }
+
+    /// Builds a proof for key, whether or not it holds a value.
+    pub fn prove(&self, key: &H256) -> TrieResult<SmtProof> {
+        let mut siblings = vec![];
+        let mut node_hash = self.root_hash;
+        for depth in 0..DEPTH {
+            match self.load(node_hash)? {
+                SmtNode::Empty => {
+                    return Ok(SmtProof {
+                        siblings,
+                        leaf: None,
+                    })
+                }
+                SmtNode::Leaf {
+                    key: leaf_key,
+                    value,
+                } => {
+                    let leaf = Some((leaf_key, H::hash(&value)));
+                    return Ok(SmtProof { siblings, leaf });
+                }
+                SmtNode::Internal { children } => {
+                    let bit = bit(key, depth);
+                    siblings.push(children[1 - bit]);
+                    node_hash = children[bit];
+                }
+            }
+        }
+        Err(TrieError::InvalidData)
+    }
+
+    fn insert_at(
+        &mut self,
+        node_hash: H256,
+        depth: usize,
+        key: &H256,
+        leaf_hash: H256,
+    ) -> TrieResult<H256> {
+        match self.load(node_hash)? {
+            SmtNode::Empty => Ok(leaf_hash),
+            SmtNode::Leaf { key: leaf_key, .. } if leaf_key == *key => {
+                self.passing_keys.insert(node_hash);
+                Ok(leaf_hash)
+            }
+            SmtNode::Leaf { key: leaf_key, .. } => {
+                // Both leaves move down to where their keys diverge.
+                self.split(depth, (key, leaf_hash), (&leaf_key, node_hash))
+            }
+            SmtNode::Internal { mut children } => {
+                self.passing_keys.insert(node_hash);
+                let bit = bit(key, depth);
+                children[bit] = self.insert_at(children[bit], depth + 1, key, leaf_hash)?;
+                Ok(self.internal(children))
+            }
+        }
+    }
+
+    fn split(&mut self, depth: usize, a: (&H256, H256), b: (&H256, H256)) -> TrieResult<H256> {
+        if depth == DEPTH {
+            return Err(TrieError::InvalidData);
+        }
+        let (bit_a, bit_b) = (bit(a.0, depth), bit(b.0, depth));
+        let mut children = [H256::zero(); 2];
+        if bit_a == bit_b {
+            children[bit_a] = self.split(depth + 1, a, b)?;
+        } else {
+            children[bit_a] = a.1;
+            children[bit_b] = b.1;
+        }
+        Ok(self.internal(children))
+    }
+
+    // Returns `None` if the key is absent, so untouched nodes stay as they are.
+    fn remove_at(&mut self, node_hash: H256, depth: usize, key: &H256) -> TrieResult<Option<H256>> {
+        match self.load(node_hash)? {
+            SmtNode::Empty => Ok(None),
+            SmtNode::Leaf { key: leaf_key, .. } => {
+                if leaf_key != *key {
+                    return Ok(None);
+                }
+                self.passing_keys.insert(node_hash);
+                Ok(Some(H256::zero()))
+            }
+            SmtNode::Internal { mut children } => {
+                let bit = bit(key, depth);
+                let child = match self.remove_at(children[bit], depth + 1, key)? {
+                    Some(child) => child,
+                    None => return Ok(None),
+                };
+                self.passing_keys.insert(node_hash);
+                children[bit] = child;
+
+                // A subtree left with a single leaf is represented by that leaf.
+                let sibling = children[1 - bit];
+                if child.is_zero() && matches!(self.load(sibling)?, SmtNode::Leaf { .. }) {
+                    return Ok(Some(sibling));
+                }
+                if sibling.is_zero() && matches!(self.load(child)?, SmtNode::Leaf { .. }) {
+                    return Ok(Some(child));
+                }
+                Ok(Some(self.internal(children)))
+            }
+        }
+    }
+
+    fn internal(&mut self, children: [H256; 2]) -> H256 {
+        if children == [H256::zero(); 2] {
+            return H256::zero();
+        }
+        let hash = internal_hash::<H>(&children);
+        let mut data = vec![INTERNAL_NODE];
+        data.extend_from_slice(children[0].as_bytes());
+        data.extend_from_slice(children[1].as_bytes());
+        self.cache.insert(hash, data);
+        hash
+    }
+
+    fn load(&self, hash: H256) -> TrieResult<SmtNode> {
+        if hash.is_zero() {
+            return Ok(SmtNode::Empty);
+        }
+
+        let data = match self.cache.get(&hash) {
+            Some(data) => data.clone(),
+            None => self
+                .db
+                .get(hash.as_bytes())
+                .map_err(TrieError::backend)?
+                .ok_or(TrieError::MissingTrieNode {
+                    node_hash: hash,
+                    traversed: None,
+                    root_hash: Some(self.root_hash),
+                    err_key: None,
+                })?,
+        };
+
+        match data.split_first() {
+            Some((&LEAF_NODE, rest)) if rest.len() >= 32 => Ok(SmtNode::Leaf {
+                key: H256::from_slice(&rest[..32]),
+                value: rest[32..].to_vec(),
+            }),
+            Some((&INTERNAL_NODE, rest)) if rest.len() == 64 => Ok(SmtNode::Internal {
+                children: [H256::from_slice(&rest[..32]), H256::from_slice(&rest[32..])],
+            }),
+            _ => Err(TrieError::DecodeAtNode {
+                hash,
+                source: Box::new(TrieError::InvalidData),
+            }),
+        }
+    }
+}
+
+impl SmtProof {
+    /// Checks the proof against `root_hash`: that key holds `value`, or that it is
+    /// absent if `value` is `None`. Errors with `InvalidProof` otherwise.
+    pub fn verify<H: Hasher>(
+        &self,
+        root_hash: H256,
+        key: &H256,
+        value: Option<&[u8]>,
+    ) -> TrieResult<()> {
+        let depth = self.siblings.len();
+        if depth > DEPTH {
+            return Err(TrieError::InvalidProof);
+        }
+
+        let mut node_hash = match (value, self.leaf) {
+            (Some(value), Some((leaf_key, value_hash)))
+                if leaf_key == *key && value_hash == H::hash(value) =>
+            {
+                leaf_hash::<H>(key, &value_hash)
+            }
+            (None, None) => H256::zero(),
+            // Another leaf sitting on the key's path shows the key is absent.
+            (None, Some((leaf_key, value_hash)))
+                if leaf_key != *key && (0..depth).all(|i| bit(&leaf_key, i) == bit(key, i)) =>
+            {
+                leaf_hash::<H>(&leaf_key, &value_hash)
+            }
+            _ => return Err(TrieError::InvalidProof),
+        };
+
+        for (depth, sibling) in self.siblings.iter().enumerate().rev() {
+            let mut children = [*sibling; 2];
+            children[bit(key, depth)] = node_hash;
+            node_hash = if children == [H256::zero(); 2] {
+                H256::zero()
+            } else {
+                internal_hash::<H>(&children)
+            };
+        }
+
+        if node_hash == root_hash {
+            Ok(())
+        } else {
+            Err(TrieError::InvalidProof)
+        }
+    }
+
+    /// Serializes the proof, leaving out empty siblings: the number of siblings, a
+    /// bitmap of the non-empty ones, those siblings, then the leaf if any.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut data = (self.siblings.len() as u16).to_be_bytes().to_vec();
+        let mut bitmap = vec![0u8; self.siblings.len().div_ceil(8)];
+        for (i, sibling) in self.siblings.iter().enumerate() {
+            if !sibling.is_zero() {
+                bitmap[i / 8] |= 1 << (7 - i % 8);
+            }
+        }
+        data.extend_from_slice(&bitmap);
+        for sibling in self.siblings.iter().filter(|sibling| !sibling.is_zero()) {
+            data.extend_from_slice(sibling.as_bytes());
+        }
+        if let Some((key, value_hash)) = &self.leaf {
+            data.extend_from_slice(key.as_bytes());
+            data.extend_from_slice(value_hash.as_bytes());
+        }
+        data
+    }
+
+    pub fn from_bytes(data: &[u8]) -> TrieResult<Self> {
+        let (len, rest) = data.split_at_checked(2).ok_or(TrieError::InvalidProof)?;
+        let len = u16::from_be_bytes([len[0], len[1]]) as usize;
+        let (bitmap, mut rest) = rest
+            .split_at_checked(len.div_ceil(8))
+            .ok_or(TrieError::InvalidProof)?;
+
+        let mut siblings = Vec::with_capacity(len);
+        for i in 0..len {
+            if bitmap[i / 8] & (1 << (7 - i % 8)) == 0 {
+                siblings.push(H256::zero());
+            } else {
+                let (sibling, tail) = rest.split_at_checked(32).ok_or(TrieError::InvalidProof)?;
+                siblings.push(H256::from_slice(sibling));
+                rest = tail;
+            }
+        }
+
+        let leaf = match rest.len() {
+            0 => None,
+            64 => Some((H256::from_slice(&rest[..32]), H256::from_slice(&rest[32..]))),
+            _ => return Err(TrieError::InvalidProof),
+        };
+        Ok(SmtProof { siblings, leaf })
+    }
+}
+
+fn bit(key: &H256, depth: usize) -> usize {
+    ((key.as_bytes()[depth / 8] >> (7 - depth % 8)) & 1) as usize
+}
+
+fn leaf_hash<H: Hasher>(key: &H256, value_hash: &H256) -> H256 {
+    H::hash(&[&[LEAF_NODE], key.as_bytes(), value_hash.as_bytes()].concat())
+}
+
+fn internal_hash<H: Hasher>(children: &[H256; 2]) -> H256 {
+    H::hash(
+        &[
+            &[INTERNAL_NODE],
+            children[0].as_bytes(),
+            children[1].as_bytes(),
+        ]
+        .concat(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{key_stream, FaultyDB, MemoryDB};
+
+    // `count` entries with random 256-bit keys, distinct for different seeds.
+    fn keyed_entries(seed: u64, count: usize) -> Vec<(H256, Vec<u8>)> {
+        key_stream(seed, 32)
+            .take(count)
+            .enumerate()
+            .map(|(i, key)| (H256::from_slice(&key), i.to_be_bytes().to_vec()))
+            .collect()
+    }
+
+    fn filled<D: DB>(db: Arc<D>, entries: &[(H256, Vec<u8>)]) -> SmtTrie<D> {
+        let mut tree = SmtTrie::new(db);
+        for (key, value) in entries {
+            tree.insert(*key, value).unwrap();
+        }
+        tree
+    }
+
+    #[test]
+    fn proofs_show_what_keys_hold() {
+        let entries = keyed_entries(1, 200);
+        let mut tree = filled(Arc::new(MemoryDB::new()), &entries);
+        let root = tree.commit().unwrap();
+
+        for (key, value) in &entries {
+            let proof = SmtProof::from_bytes(&tree.prove(key).unwrap().to_bytes()).unwrap();
+            proof
+                .verify::<KeccakHasher>(root, key, Some(value))
+                .unwrap();
+            assert!(proof.verify::<KeccakHasher>(root, key, None).is_err());
+            assert!(proof
+                .verify::<KeccakHasher>(root, key, Some(b"other"))
+                .is_err());
+        }
+        for (key, _) in keyed_entries(2, 50) {
+            let proof = tree.prove(&key).unwrap();
+            proof.verify::<KeccakHasher>(root, &key, None).unwrap();
+            assert!(proof
+                .verify::<KeccakHasher>(root, &key, Some(b"value"))
+                .is_err());
+        }
+    }
+
+    #[test]
+    fn root_depends_only_on_the_entries() {
+        let entries = keyed_entries(3, 200);
+        let mut tree = filled(Arc::new(MemoryDB::new()), &entries);
+        let root = tree.commit().unwrap();
+
+        let mut reversed = entries.clone();
+        reversed.reverse();
+        assert_eq!(
+            filled(Arc::new(MemoryDB::new()), &reversed).root_hash(),
+            root
+        );
+
+        let extra = keyed_entries(4, 50);
+        for (key, value) in &extra {
+            tree.insert(*key, value).unwrap();
+        }
+        assert_ne!(tree.root_hash(), root);
+        for (key, _) in &extra {
+            tree.remove(key).unwrap();
+        }
+        assert_eq!(tree.commit().unwrap(), root);
+
+        for (key, _) in &entries {
+            tree.remove(key).unwrap();
+        }
+        assert_eq!(tree.commit().unwrap(), H256::zero());
+    }
+
+    #[test]
+    fn failed_commit_keeps_the_nodes_pending() {
+        let entries = keyed_entries(5, 200);
+        let db = Arc::new(FaultyDB::new());
+        let mut tree = filled(db.clone(), &entries);
+
+        db.fail_writes(1);
+        assert!(tree.commit().is_err());
+        assert!(db.inner().is_empty());
+        let root = tree.commit().unwrap();
+
+        let tree = tree.at_root(root);
+        for (key, value) in &entries {
+            assert_eq!(tree.get(key).unwrap().as_ref(), Some(value));
+        }
+    }
+}
@@ -0,0 +1,664 @@
+//! Serving state ranges the way the snap/1 protocol does, so a node syncing with snap
+//! sync can download the state from this crate: contiguous runs of accounts or
+//! storage slots in hash order, with the proofs of the range's boundaries.
+//!
+//! Ranges follow geth's server: they start at the origin, stop after the first key
+//! at or beyond the limit, and grow until the response exceeds its byte budget,
+//! which is capped at `SOFT_RESPONSE_LIMIT`.
+//!
+//! `SnapSync` is the client half, which rebuilds a state from such ranges and heals
+//! what they missed.
+
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+use ethereum_types::{H256, U256};
+use keccak_hash::{keccak, KECCAK_EMPTY, KECCAK_NULL_RLP};
+use rlp::{Rlp, RlpStream};
+
+use crate::db::DB;
+use crate::errors::{TrieError, TrieResult};
+use crate::node::{decode_node, Node};
+use crate::page::PageCursor;
+use crate::state::Account;
+use crate::trie::{EthTrie, ITrie};
+use crate::verify::verify_proof;
+
+/// The largest response served, whatever the byte budget asked for, as in geth.
+pub const SOFT_RESPONSE_LIMIT: u64 = 2 * 1024 * 1024;
+
+// Entries read from the trie at a time while filling a range.
+const SCAN_PAGE_SIZE: usize = 128;
+
+/// The body of an `AccountRange` message: accounts in hash order, each in the slim
+/// format, and the proofs of the first and last of them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AccountRange {
+    pub accounts: Vec<(H256, Vec<u8>)>,
+    pub proof: Vec<Vec<u8>>,
+}
+
+/// The body of a `StorageRanges` message: for each account served, its storage slots
+/// in hash order with their RLP encoded values. Only a range cut short, or starting
+/// after the first slot, comes with the proofs of its boundaries.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StorageRanges {
+    pub slots: Vec<Vec<(H256, Vec<u8>)>>,
+    pub proof: Vec<Vec<u8>>,
+}
+
+impl AccountRange {
+    /// Encodes the message answering the request `request_id`.
+    pub fn rlp(&self, request_id: u64) -> Vec<u8> {
+        let mut stream = RlpStream::new_list(3);
+        stream.append(&request_id);
+        append_entries(&mut stream, &self.accounts, true);
+        append_proof(&mut stream, &self.proof);
+        stream.out().to_vec()
+    }
+}
+
+impl StorageRanges {
+    /// Encodes the message answering the request `request_id`.
+    pub fn rlp(&self, request_id: u64) -> Vec<u8> {
+        let mut stream = RlpStream::new_list(3);
+        stream.append(&request_id);
+        stream.begin_list(self.slots.len());
+        for slots in &self.slots {
+            append_entries(&mut stream, slots, false);
+        }
+        append_proof(&mut stream, &self.proof);
+        stream.out().to_vec()
+    }
+}
+
+/// An account in the slim format of snap/1, where the storage root and code hash of
+/// an account without storage or code are left empty.
+pub fn slim_account(account: &Account) -> Vec<u8> {
+    let mut stream = RlpStream::new_list(4);
+    stream.append(&account.nonce);
+    stream.append(&account.balance);
+    if account.storage_root == KECCAK_NULL_RLP {
+        stream.append_empty_data();
+    } else {
+        stream.append(&account.storage_root);
+    }
+    if account.code_hash == KECCAK_EMPTY {
+        stream.append_empty_data();
+    } else {
+        stream.append(&account.code_hash);
+    }
+    stream.out().to_vec()
+}
+
+/// Decodes an account in the slim format of snap/1.
+pub fn account_from_slim(data: &[u8]) -> TrieResult<Account> {
+    let r = Rlp::new(data);
+    if r.item_count()? != 4 {
+        return Err(TrieError::InvalidData);
+    }
+    let hash_or = |i: usize, empty: H256| -> TrieResult<H256> {
+        let item = r.at(i)?;
+        match item.is_empty() {
+            true => Ok(empty),
+            false => Ok(item.as_val()?),
+        }
+    };
+    Ok(Account {
+        nonce: r.val_at::<U256>(0)?,
+        balance: r.val_at::<U256>(1)?,
+        storage_root: hash_or(2, KECCAK_NULL_RLP)?,
+        code_hash: hash_or(3, KECCAK_EMPTY)?,
+    })
+}
+
+/// Answers `GetAccountRange`: the accounts of the state at `root` from `origin` on,
+/// up to `limit` or the byte budget `bytes`, with the proofs of `origin` and of the
+/// last account served.
+pub fn account_range<D: DB>(
+    state: &EthTrie<D>,
+    root: H256,
+    origin: H256,
+    limit: H256,
+    bytes: u64,
+) -> TrieResult<AccountRange> {
+    let bytes = bytes.min(SOFT_RESPONSE_LIMIT);
+    let mut trie = state.at_root(root);
+    let mut accounts = vec![];
+    let mut size = 0;
+    scan(&trie, origin, |hash, value| {
+        let account = slim_account(&Account::from_rlp(&value)?);
+        size += (H256::len_bytes() + account.len()) as u64;
+        accounts.push((hash, account));
+        Ok(hash < limit && size <= bytes)
+    })?;
+
+    let last = accounts.last().map(|(hash, _)| *hash);
+    let proof = boundary_proof(&mut trie, origin, last)?;
+    Ok(AccountRange { accounts, proof })
+}
+
+/// Answers `GetStorageRanges`: the storage slots of each account in `accounts`, read
+/// from the state at `root`, until the byte budget `bytes` runs out. `origin` and
+/// `limit` bound the slots of the first account only, and the others are served
+/// whole. An account missing from the state is served without slots.
+pub fn storage_ranges<D: DB>(
+    state: &EthTrie<D>,
+    root: H256,
+    accounts: &[H256],
+    origin: H256,
+    limit: H256,
+    bytes: u64,
+) -> TrieResult<StorageRanges> {
+    let bytes = bytes.min(SOFT_RESPONSE_LIMIT);
+    let state = state.at_root(root);
+    let mut ranges = vec![];
+    let mut proof = vec![];
+    let mut size = 0;
+    for (i, account_hash) in accounts.iter().enumerate() {
+        if size >= bytes {
+            break;
+        }
+        let (origin, limit) = match i {
+            0 => (origin, limit),
+            _ => (H256::zero(), H256::repeat_byte(0xff)),
+        };
+        let storage_root = match state.get(account_hash.as_bytes())? {
+            Some(account) => Account::from_rlp(&account)?.storage_root,
+            None => KECCAK_NULL_RLP,
+        };
+
+        let mut storage = state.at_root(storage_root);
+        let mut slots = vec![];
+        let mut aborted = false;
+        scan(&storage, origin, |hash, value| {
+            if size >= bytes {
+                aborted = true;
+                return Ok(false);
+            }
+            size += (H256::len_bytes() + value.len()) as u64;
+            slots.push((hash, value));
+            Ok(hash < limit)
+        })?;
+        // An empty range would tell the requester the account has no storage.
+        if aborted && slots.is_empty() {
+            break;
+        }
+        // Only a range that doesn't start at the first slot, or that was cut short, is
+        // proven, and it ends the response.
+        let last = slots.last().map(|(hash, _)| *hash);
+        ranges.push(slots);
+        if !origin.is_zero() || (aborted && last.is_some()) {
+            proof = boundary_proof(&mut storage, origin, last)?;
+            break;
+        }
+    }
+    Ok(StorageRanges {
+        slots: ranges,
+        proof,
+    })
+}
+
+/// Fetches trie nodes by hash from peers while healing, as `GetTrieNodes` does in
+/// snap/1. A peer may not have every node asked for, and leaves those out as `None`.
+pub trait NodeFetcher {
+    /// Returns the node stored under each of `hashes`, in order.
+    fn fetch_nodes(&self, hashes: &[H256]) -> TrieResult<Vec<Option<Vec<u8>>>>;
+}
+
+impl<F> NodeFetcher for F
+where
+    F: Fn(&[H256]) -> TrieResult<Vec<Option<Vec<u8>>>>,
+{
+    fn fetch_nodes(&self, hashes: &[H256]) -> TrieResult<Vec<Option<Vec<u8>>>> {
+        self(hashes)
+    }
+}
+
+/// Rebuilds the state with root `target` in a database, from the account and storage
+/// ranges of snap/1 served by peers, and heals what the ranges missed.
+///
+/// Ranges are accepted as they arrive, their boundaries checked against `target` or
+/// the storage root of their account, and held in memory. `build` then writes them to
+/// the database with `build_from_sorted`. Ranges can't prove that nothing was left out
+/// between their boundaries, so the built tries may differ from the target's: `heal`
+/// walks the target down from its root and fetches every node missing from the
+/// database with a `NodeFetcher`, checking its hash. A node is only written once every
+/// node below it is, so a node found in the database roots a complete subtree, and
+/// everything built that matches the target is kept. Once `is_synced` is true, the
+/// state can be opened with `EthTrie::at_root(target)`.
+#[derive(Debug)]
+pub struct SnapSync<D: DB> {
+    db: Arc<D>,
+    target: H256,
+    // Accepted accounts, fully RLP encoded, and storage slots by account, until built.
+    accounts: BTreeMap<H256, Vec<u8>>,
+    storage: BTreeMap<H256, BTreeMap<H256, Vec<u8>>>,
+    // The storage roots of the accounts built, to heal along with the state.
+    storage_roots: Vec<H256>,
+}
+
+impl<D: DB> SnapSync<D> {
+    pub fn new(db: Arc<D>, target: H256) -> Self {
+        SnapSync {
+            db,
+            target,
+            accounts: BTreeMap::new(),
+            storage: BTreeMap::new(),
+            storage_roots: vec![],
+        }
+    }
+
+    /// Accepts the response to a `GetAccountRange` request starting at `origin`.
+    /// Fails with `TrieError::InvalidProof` unless its proof shows the range's first
+    /// and last accounts as they are in the target.
+    pub fn accept_accounts(&mut self, origin: H256, range: &AccountRange) -> TrieResult<()> {
+        let accounts = range
+            .accounts
+            .iter()
+            .map(|(hash, slim)| Ok((*hash, account_from_slim(slim)?.rlp())))
+            .collect::<TrieResult<Vec<_>>>()?;
+        check_range(self.target, origin, &accounts, Some(&range.proof))?;
+        self.accounts.extend(accounts);
+        Ok(())
+    }
+
+    /// Accepts the response to a `GetStorageRanges` request for `accounts`, whose
+    /// first range starts at `origin`. The range carrying the proof is checked against
+    /// the storage root of its account, which must have been accepted already.
+    pub fn accept_storage(
+        &mut self,
+        accounts: &[H256],
+        origin: H256,
+        ranges: &StorageRanges,
+    ) -> TrieResult<()> {
+        for (i, slots) in ranges.slots.iter().enumerate() {
+            let account = *accounts.get(i).ok_or(TrieError::InvalidData)?;
+            let origin = if i == 0 { origin } else { H256::zero() };
+            let proof =
+                (i + 1 == ranges.slots.len() && !ranges.proof.is_empty()).then_some(&ranges.proof);
+            let storage_root = match (proof, self.accounts.get(&account)) {
+                (Some(_), Some(data)) => Account::from_rlp(data)?.storage_root,
+                (Some(_), None) => return Err(TrieError::InvalidData),
+                (None, _) => KECCAK_NULL_RLP,
+            };
+            check_range(storage_root, origin, slots, proof)?;
+            self.storage
+                .entry(account)
+                .or_default()
+                .extend(slots.iter().cloned());
+        }
+        Ok(())
+    }
+
+    /// Writes the ranges accepted so far to the database, each storage trie and the
+    /// state trie built bottom-up, and returns the root of the state built. It is the
+    /// target if the ranges covered the whole state.
+    pub fn build(&mut self) -> TrieResult<H256> {
+        for slots in std::mem::take(&mut self.storage).values() {
+            EthTrie::new(self.db.clone()).build_from_sorted(slots)?;
+        }
+        let accounts = std::mem::take(&mut self.accounts);
+        for data in accounts.values() {
+            let storage_root = Account::from_rlp(data)?.storage_root;
+            if storage_root != KECCAK_NULL_RLP {
+                self.storage_roots.push(storage_root);
+            }
+        }
+        EthTrie::new(self.db.clone()).build_from_sorted(&accounts)
+    }
+
+    /// Fetches every node of the target, and of the storage tries of its accounts,
+    /// that is missing from the database, and returns how many were fetched. Fails
+    /// with `TrieError::MissingTrieNode` if the fetcher doesn't have a node, and with
+    /// `TrieError::InvalidData` if it returns one that doesn't match its hash; nodes
+    /// written before the failure stay, so healing again resumes from there.
+    pub fn heal<F: NodeFetcher>(&mut self, fetcher: &F) -> TrieResult<usize> {
+        let mut fetched = 0;
+        self.heal_nodes(fetcher, &[self.target], true, &mut fetched)?;
+        // The storage roots are kept until healed, for `is_synced` and the next heal.
+        self.heal_nodes(fetcher, &self.storage_roots, false, &mut fetched)?;
+        self.storage_roots.clear();
+        self.db.flush().map_err(TrieError::backend)?;
+        Ok(fetched)
+    }
+
+    /// Whether the database holds the whole target state, storage tries included.
+    pub fn is_synced(&self) -> TrieResult<bool> {
+        let roots = std::iter::once(&self.target).chain(&self.storage_roots);
+        for root in roots.filter(|root| **root != KECCAK_NULL_RLP) {
+            if self
+                .db
+                .get(root.as_bytes())
+                .map_err(TrieError::backend)?
+                .is_none()
+            {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    // Fetches those of `hashes` missing from the database, and everything missing
+    // below them, writing each node after its children. In the state trie, the
+    // storage trie of an account is a child of its leaf.
+    fn heal_nodes<F: NodeFetcher>(
+        &self,
+        fetcher: &F,
+        hashes: &[H256],
+        state: bool,
+        fetched: &mut usize,
+    ) -> TrieResult<()> {
+        let hashes: Vec<H256> = hashes
+            .iter()
+            .copied()
+            .filter(|hash| *hash != KECCAK_NULL_RLP)
+            .collect();
+        let keys: Vec<Vec<u8>> = hashes.iter().map(|hash| hash.as_bytes().to_vec()).collect();
+        let stored = self.db.multi_get(&keys).map_err(TrieError::backend)?;
+        let missing: Vec<H256> = hashes
+            .into_iter()
+            .zip(stored)
+            .filter_map(|(hash, stored)| stored.is_none().then_some(hash))
+            .collect();
+        if missing.is_empty() {
+            return Ok(());
+        }
+
+        let nodes = fetcher.fetch_nodes(&missing)?;
+        for (i, hash) in missing.into_iter().enumerate() {
+            let data = nodes
+                .get(i)
+                .cloned()
+                .flatten()
+                .ok_or(TrieError::MissingTrieNode {
+                    node_hash: hash,
+                    traversed: None,
+                    root_hash: Some(self.target),
+                    err_key: None,
+                })?;
+            if keccak(&data) != hash {
+                return Err(TrieError::InvalidData);
+            }
+            *fetched += 1;
+
+            let node = decode_node(&data)?;
+            let mut children = vec![];
+            let mut storage_roots = vec![];
+            match &node {
+                Node::Branch(branch) => {
+                    for child in &branch.children {
+                        if let Node::Hash(hash_node) = child {
+                            children.push(hash_node.hash);
+                        }
+                    }
+                }
+                Node::Extension(ext) => {
+                    if let Node::Hash(hash_node) = &ext.node {
+                        children.push(hash_node.hash);
+                    }
+                }
+                Node::Leaf(leaf) if state => {
+                    storage_roots.push(Account::from_rlp(&leaf.value)?.storage_root);
+                }
+                _ => {}
+            }
+            self.heal_nodes(fetcher, &children, state, fetched)?;
+            self.heal_nodes(fetcher, &storage_roots, false, fetched)?;
+            self.db
+                .insert(hash.as_bytes(), data)
+                .map_err(TrieError::backend)?;
+        }
+        Ok(())
+    }
+}
+
+// Checks that `entries` are in order from `origin` on and, given a proof, that it
+// shows the key at `origin` and the last entry as the range has them.
+fn check_range(
+    root: H256,
+    origin: H256,
+    entries: &[(H256, Vec<u8>)],
+    proof: Option<&Vec<Vec<u8>>>,
+) -> TrieResult<()> {
+    let ordered = entries.windows(2).all(|pair| pair[0].0 < pair[1].0);
+    if !ordered || entries.first().is_some_and(|(hash, _)| *hash < origin) {
+        return Err(TrieError::InvalidData);
+    }
+    let Some(proof) = proof else {
+        return Ok(());
+    };
+    let at_origin = entries
+        .first()
+        .filter(|(hash, _)| *hash == origin)
+        .map(|(_, value)| value);
+    if verify_proof(root, origin.as_bytes(), proof)?.as_ref() != at_origin {
+        return Err(TrieError::InvalidProof);
+    }
+    if let Some((hash, value)) = entries.last() {
+        if verify_proof(root, hash.as_bytes(), proof)?.as_ref() != Some(value) {
+            return Err(TrieError::InvalidProof);
+        }
+    }
+    Ok(())
+}
+
+// Passes the entries of `trie` from `origin` on, in key order, to `take` until it
+// returns false. Keys must be hashes.
+fn scan<D, F>(trie: &EthTrie<D>, origin: H256, mut take: F) -> TrieResult<()>
+where
+    D: DB,
+    F: FnMut(H256, Vec<u8>) -> TrieResult<bool>,
+{
+    if let Some(value) = trie.get(origin.as_bytes())? {
+        if !take(origin, value)? {
+            return Ok(());
+        }
+    }
+    let mut cursor = PageCursor::after(origin.as_bytes());
+    loop {
+        let page = trie.iter_page(Some(&cursor), SCAN_PAGE_SIZE)?;
+        for (key, value) in page.entries {
+            if key.len() != H256::len_bytes() {
+                return Err(TrieError::InvalidData);
+            }
+            if !take(H256::from_slice(&key), value)? {
+                return Ok(());
+            }
+        }
+        match page.cursor {
+            Some(next) => cursor = next,
+            None => return Ok(()),
+        }
+    }
+}
+
+// The nodes proving `origin` and `last`, each listed once.
+fn boundary_proof<D: DB>(
+    trie: &mut EthTrie<D>,
+    origin: H256,
+    last: Option<H256>,
+) -> TrieResult<Vec<Vec<u8>>> {
+    let mut proof = trie.proof(origin.as_bytes())?;
+    if let Some(last) = last {
+        for node in trie.proof(last.as_bytes())? {
+            if !proof.contains(&node) {
+                proof.push(node);
+            }
+        }
+    }
+    Ok(proof)
+}
+
+// Accounts are embedded as the RLP lists they are, slots as byte strings.
+fn append_entries(stream: &mut RlpStream, entries: &[(H256, Vec<u8>)], raw: bool) {
+    stream.begin_list(entries.len());
+    for (hash, value) in entries {
+        stream.begin_list(2);
+        stream.append(hash);
+        if raw {
+            stream.append_raw(value, 1);
+        } else {
+            stream.append(value);
+        }
+    }
+}
+
+fn append_proof(stream: &mut RlpStream, proof: &[Vec<u8>]) {
+    stream.begin_list(proof.len());
+    for node in proof {
+        stream.append(node);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::{put_account, put_storage};
+    use crate::test_utils::MemoryDB;
+
+    // A state of 40 accounts, every third with 30 storage slots, and its root.
+    fn source() -> (EthTrie<MemoryDB>, H256) {
+        let db = Arc::new(MemoryDB::new());
+        let mut state = EthTrie::new(db.clone());
+        for i in 0..40u64 {
+            let mut account = Account {
+                nonce: i.into(),
+                ..Account::default()
+            };
+            if i % 3 == 0 {
+                let mut storage = EthTrie::new(db.clone());
+                for slot in 0..30u64 {
+                    put_storage(
+                        &mut storage,
+                        H256::from_low_u64_be(slot),
+                        (i + slot + 1).into(),
+                    )
+                    .unwrap();
+                }
+                account.storage_root = storage.commit().unwrap();
+            }
+            put_account(&mut state, &i.to_be_bytes(), &account).unwrap();
+        }
+        let root = state.commit().unwrap();
+        (state, root)
+    }
+
+    // Accepts every account of the source in one range, and returns their hashes
+    // and storage roots.
+    fn accept_all_accounts(
+        sync: &mut SnapSync<MemoryDB>,
+        source: &EthTrie<MemoryDB>,
+        root: H256,
+    ) -> Vec<(H256, H256)> {
+        let max = H256::repeat_byte(0xff);
+        let range = account_range(source, root, H256::zero(), max, SOFT_RESPONSE_LIMIT).unwrap();
+        sync.accept_accounts(H256::zero(), &range).unwrap();
+        range
+            .accounts
+            .iter()
+            .map(|(hash, slim)| (*hash, account_from_slim(slim).unwrap().storage_root))
+            .collect()
+    }
+
+    fn fetcher(
+        source: &EthTrie<MemoryDB>,
+    ) -> impl Fn(&[H256]) -> TrieResult<Vec<Option<Vec<u8>>>> + '_ {
+        |hashes| {
+            hashes
+                .iter()
+                .map(|hash| source.db.get(hash.as_bytes()).map_err(TrieError::backend))
+                .collect()
+        }
+    }
+
+    #[test]
+    fn full_ranges_build_the_target() {
+        let (source, root) = source();
+        let mut sync = SnapSync::new(Arc::new(MemoryDB::new()), root);
+        let accounts = accept_all_accounts(&mut sync, &source, root);
+        let max = H256::repeat_byte(0xff);
+        for (hash, _) in accounts
+            .iter()
+            .filter(|(_, storage)| *storage != KECCAK_NULL_RLP)
+        {
+            let ranges = storage_ranges(
+                &source,
+                root,
+                &[*hash],
+                H256::zero(),
+                max,
+                SOFT_RESPONSE_LIMIT,
+            )
+            .unwrap();
+            sync.accept_storage(&[*hash], H256::zero(), &ranges)
+                .unwrap();
+        }
+
+        assert_eq!(sync.build().unwrap(), root);
+        assert_eq!(sync.heal(&fetcher(&source)).unwrap(), 0);
+        assert!(sync.is_synced().unwrap());
+    }
+
+    #[test]
+    fn range_with_a_tampered_account_is_rejected() {
+        let (source, root) = source();
+        let mut sync = SnapSync::new(Arc::new(MemoryDB::new()), root);
+        let max = H256::repeat_byte(0xff);
+        let mut range = account_range(&source, root, H256::zero(), max, 500).unwrap();
+        let last = range.accounts.last_mut().unwrap();
+        last.1 = slim_account(&Account::default());
+        assert!(matches!(
+            sync.accept_accounts(H256::zero(), &range),
+            Err(TrieError::InvalidProof)
+        ));
+    }
+
+    #[test]
+    fn heal_fetches_what_the_ranges_missed() {
+        let (source, root) = source();
+        let mut sync = SnapSync::new(Arc::new(MemoryDB::new()), root);
+        let max = H256::repeat_byte(0xff);
+        let range = account_range(&source, root, H256::zero(), max, 500).unwrap();
+        assert!(range.accounts.len() < 40);
+        sync.accept_accounts(H256::zero(), &range).unwrap();
+        assert_ne!(sync.build().unwrap(), root);
+        assert!(!sync.is_synced().unwrap());
+
+        assert!(sync.heal(&fetcher(&source)).unwrap() > 0);
+        assert!(sync.is_synced().unwrap());
+        let state = EthTrie::new(sync.db.clone()).at_root(root);
+        for i in 0..40u64 {
+            assert_eq!(
+                crate::state::get_account_rlp(&state, &i.to_be_bytes())
+                    .unwrap()
+                    .unwrap()
+                    .nonce,
+                i.into()
+            );
+        }
+    }
+
+    #[test]
+    fn heal_resumes_after_a_fetch_failure() {
+        let (source, root) = source();
+        let mut sync = SnapSync::new(Arc::new(MemoryDB::new()), root);
+        let accounts = accept_all_accounts(&mut sync, &source, root);
+        // Without storage ranges, only the storage tries are left to heal.
+        assert_eq!(sync.build().unwrap(), root);
+        assert!(!sync.is_synced().unwrap());
+
+        let unavailable = |hashes: &[H256]| Ok(vec![None; hashes.len()]);
+        assert!(matches!(
+            sync.heal(&unavailable),
+            Err(TrieError::MissingTrieNode { .. })
+        ));
+        assert!(!sync.is_synced().unwrap());
+
+        assert!(sync.heal(&fetcher(&source)).unwrap() > 0);
+        assert!(sync.is_synced().unwrap());
+        for (_, storage_root) in accounts.iter().filter(|(_, root)| *root != KECCAK_NULL_RLP) {
+            assert!(sync.db.get(storage_root.as_bytes()).unwrap().is_some());
+        }
+    }
+}
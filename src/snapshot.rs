@@ -0,0 +1,532 @@
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+use ethereum_types::H256;
+use tiny_keccak::{Hasher as _, Keccak};
+
+use crate::codec::{DecodedNode, NodeCodec};
+use crate::db::DB;
+use crate::errors::TrieError;
+use crate::hasher::Hasher;
+use crate::node::Node;
+use crate::trie::{EthTrie, ITrie, TrieResult};
+
+// Snapshot files, all numbers big-endian:
+//
+//   magic     b"MPTSNAP" followed by the format version
+//   hasher    the length of the hasher's `NAME` as a byte, then the name
+//   root      the root hash
+//   entries   a kind, NODE or BLOB, a u32 length and the bytes, repeated, then END
+//   checksum  the keccak256 of everything before it
+//
+// Entries carry no key: nodes and blobs are stored under their own hash.
+const MAGIC: &[u8; 7] = b"MPTSNAP";
+const VERSION: u8 = 1;
+const END: u8 = 0;
+const NODE: u8 = 1;
+const BLOB: u8 = 2;
+
+// Nodes are written to the database in batches of this many while importing.
+const IMPORT_BATCH_SIZE: usize = 1024;
+
+/// The in-memory state of an `EthTrie`, including changes that are not committed
+/// yet. With the `serde` feature it can be serialized, to persist a trie or hand it
+/// to another process without going through the database.
+///
+/// Subtrees that were never loaded are kept as references into the database, so
+/// the trie it is restored into must use a database holding them, and the same
+/// hasher, codec and value settings.
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct TrieSnapshot {
+    root: Node,
+    root_hash: H256,
+}
+
+impl TrieSnapshot {
+    /// The root hash as of the last commit.
+    pub fn root_hash(&self) -> H256 {
+        self.root_hash
+    }
+}
+
+impl<D, H, C> EthTrie<D, H, C>
+where
+    D: DB,
+    H: Hasher,
+    C: NodeCodec,
+{
+    /// Captures the trie's nodes currently in memory.
+    pub fn snapshot(&self) -> TrieSnapshot {
+        TrieSnapshot {
+            root: self.root.clone(),
+            root_hash: self.root_hash,
+        }
+    }
+
+    /// Creates a trie sharing this one's database and settings, holding the state
+    /// captured in `snapshot`. Every node in the snapshot is written again by the
+    /// next commit, but nodes the original trie replaced are not pruned. Whether the
+    /// database holds the subtrees the snapshot refers to is told by
+    /// `check_references`.
+    ///
+    /// Fails with `TrieError::InvalidData` if the nodes are not shaped as a trie
+    /// builds them: an extension must lead to a branch, and a branch hold at least
+    /// two children, or a child and a value. Deserialization has already checked the
+    /// paths of leaves and extensions.
+    pub fn restore(&self, snapshot: TrieSnapshot) -> TrieResult<Self> {
+        let mut nodes = vec![&snapshot.root];
+        while let Some(node) = nodes.pop() {
+            match node {
+                Node::Empty | Node::Leaf(_) | Node::Hash(_) => {}
+                Node::Extension(ext) => match ext.node {
+                    Node::Branch(_) | Node::Hash(_) => nodes.push(&ext.node),
+                    _ => return Err(TrieError::InvalidData),
+                },
+                Node::Branch(branch) => {
+                    let children = branch
+                        .children
+                        .iter()
+                        .filter(|child| !matches!(child, Node::Empty));
+                    if children.clone().count() + usize::from(branch.value.is_some()) < 2 {
+                        return Err(TrieError::InvalidData);
+                    }
+                    nodes.extend(children);
+                }
+            }
+        }
+
+        let mut trie = self.at_root(snapshot.root_hash);
+        trie.root = snapshot.root;
+        Ok(trie)
+    }
+
+    /// Checks that the database, or a layer of `commit_virtual` not flushed yet, holds
+    /// every subtree the trie refers to by hash but hasn't loaded, testing that each
+    /// is stored without reading it. Fails with
+    /// `TrieError::MissingTrieNode` for the first one missing. Only the references
+    /// held in memory are checked, not the subtrees below them.
+    pub fn check_references(&self) -> TrieResult<()> {
+        let mut nodes = vec![self.root.clone()];
+        while let Some(node) = nodes.pop() {
+            match node {
+                Node::Empty | Node::Leaf(_) => {}
+                Node::Branch(branch) => nodes.extend(branch.children.iter().cloned()),
+                Node::Extension(ext) => nodes.push(ext.node.clone()),
+                Node::Hash(hash_node) => {
+                    let node_hash = hash_node.hash;
+                    if self.layers.holds(node_hash) {
+                        continue;
+                    }
+                    self.counters.db_read();
+                    if !self
+                        .db
+                        .contains(node_hash.as_bytes())
+                        .map_err(TrieError::backend)?
+                    {
+                        return Err(TrieError::MissingTrieNode {
+                            node_hash,
+                            traversed: None,
+                            root_hash: Some(self.root_hash),
+                            err_key: None,
+                        });
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<D, H, C> EthTrie<D, H, C>
+where
+    D: DB,
+    H: Hasher,
+    C: NodeCodec,
+{
+    /// Writes every node reachable from the root, and the value blobs they refer to,
+    /// to a snapshot file at `path`, and returns the root hash. Pending changes are
+    /// committed first. See `write_snapshot`.
+    pub fn export_snapshot<P: AsRef<Path>>(&mut self, path: P) -> TrieResult<H256> {
+        self.write_snapshot(BufWriter::new(File::create(path)?))
+    }
+
+    /// Writes the trie as a snapshot to `writer`, in a versioned binary format that
+    /// records the root and the hasher, followed by the stored nodes and a checksum.
+    /// Unlike `TrieSnapshot`, it holds the whole trie, so it can be imported into any
+    /// database with `read_snapshot`. Each node is written once, however many times
+    /// it appears in the trie.
+    pub fn write_snapshot<W: Write>(&mut self, writer: W) -> TrieResult<H256> {
+        let root = self.commit()?;
+        let mut out = Checksummed::new(writer);
+        out.write(MAGIC)?;
+        out.write(&[VERSION])?;
+        out.write(&[H::NAME.len() as u8])?;
+        out.write(H::NAME.as_bytes())?;
+        out.write(root.as_bytes())?;
+
+        let blobs = self.walk_stored(
+            root,
+            &mut HashSet::new(),
+            |hash| self.stored_node(hash),
+            |_, node| out.write_entry(NODE, &node),
+        )?;
+        for hash in blobs {
+            self.counters.db_read();
+            let blob = self
+                .db
+                .get_blob(hash.as_bytes())
+                .map_err(TrieError::backend)?
+                .ok_or(TrieError::NotFound { hash })?;
+            out.write_entry(BLOB, &blob)?;
+        }
+
+        out.write(&[END])?;
+        let (mut writer, checksum) = out.finish();
+        writer.write_all(checksum.as_bytes())?;
+        writer.flush()?;
+        Ok(root)
+    }
+
+    /// Imports the snapshot file at `path` into this trie's database and returns the
+    /// trie at its root. See `read_snapshot`.
+    pub fn import_snapshot<P: AsRef<Path>>(&self, path: P) -> TrieResult<Self> {
+        self.read_snapshot(BufReader::new(File::open(path)?))
+    }
+
+    /// Reads a snapshot written by `write_snapshot`, stores its nodes and blobs in
+    /// this trie's database, and returns the trie at the snapshot's root, with this
+    /// trie's settings.
+    ///
+    /// Snapshots of another format version or made with another hasher fail with
+    /// `TrieError::InvalidData`. Entries are stored as they are read, under their own
+    /// hash, so a damaged file can't store anything under a wrong key, but it can leave
+    /// nodes behind before the checksum fails to match with `TrieError::Corrupted`.
+    pub fn read_snapshot<R: Read>(&self, reader: R) -> TrieResult<Self> {
+        let mut input = Checksummed::new(reader);
+        let mut magic = [0; 8];
+        input.read(&mut magic)?;
+        if magic[..7] != MAGIC[..] || magic[7] != VERSION {
+            return Err(TrieError::InvalidData);
+        }
+        let mut len = [0; 1];
+        input.read(&mut len)?;
+        let mut name = vec![0; len[0] as usize];
+        input.read(&mut name)?;
+        if name != H::NAME.as_bytes() {
+            return Err(TrieError::InvalidData);
+        }
+        let mut root = H256::zero();
+        input.read(root.as_bytes_mut())?;
+
+        let mut keys = vec![];
+        let mut values = vec![];
+        loop {
+            let mut kind = [0; 1];
+            input.read(&mut kind)?;
+            let data = match kind[0] {
+                END => break,
+                NODE | BLOB => input.read_entry()?,
+                _ => return Err(TrieError::InvalidData),
+            };
+            let hash = H::hash(&data);
+            if kind[0] == BLOB {
+                self.db
+                    .insert_blob(hash.as_bytes(), data)
+                    .map_err(TrieError::backend)?;
+                continue;
+            }
+            keys.push(hash.as_bytes().to_vec());
+            values.push(data);
+            if keys.len() == IMPORT_BATCH_SIZE {
+                self.db
+                    .insert_batch(std::mem::take(&mut keys), std::mem::take(&mut values))
+                    .map_err(TrieError::backend)?;
+            }
+        }
+        self.db
+            .insert_batch(keys, values)
+            .map_err(TrieError::backend)?;
+        self.db.flush().map_err(TrieError::backend)?;
+
+        let (mut reader, expected) = input.finish();
+        let mut checksum = H256::zero();
+        reader.read_exact(checksum.as_bytes_mut())?;
+        if checksum != expected {
+            return Err(TrieError::Corrupted {
+                hash: checksum,
+                reason: "snapshot checksum does not match its contents",
+            });
+        }
+        Ok(self.at_root(root))
+    }
+
+    // Collects the hashes of the stored nodes and value blobs `data` refers to,
+    // looking into embedded children.
+    pub(crate) fn find_refs(
+        &self,
+        data: &[u8],
+        nodes: &mut Vec<H256>,
+        blobs: &mut Vec<H256>,
+    ) -> TrieResult<()> {
+        match C::decode(data)? {
+            DecodedNode::Empty => {}
+            DecodedNode::Hash(hash) => nodes.push(hash),
+            DecodedNode::Leaf { value, .. } => blobs.extend(self.values.blob_hash(&value)),
+            DecodedNode::Extension { child, .. } => self.find_refs(child, nodes, blobs)?,
+            DecodedNode::Branch { children, value } => {
+                for child in children {
+                    self.find_refs(child, nodes, blobs)?;
+                }
+                if let Some(value) = value {
+                    blobs.extend(self.values.blob_hash(&value));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Walks the stored nodes reachable from `root` depth first, each once: nodes in
+    // `visited` are skipped along with what lies below them. `load` returns the data
+    // of a node, or `None` if it isn't stored, which fails with
+    // `TrieError::MissingTrieNode`. The walk follows the references in the data and
+    // then hands it to `visit`. Returns the value blobs referred to, each once.
+    pub(crate) fn walk_stored(
+        &self,
+        root: H256,
+        visited: &mut HashSet<H256>,
+        mut load: impl FnMut(H256) -> TrieResult<Option<Vec<u8>>>,
+        mut visit: impl FnMut(H256, Vec<u8>) -> TrieResult<()>,
+    ) -> TrieResult<Vec<H256>> {
+        let mut nodes = vec![];
+        if root != H::hash(&C::encode_empty()) {
+            nodes.push(root);
+        }
+        let mut blobs = vec![];
+        while let Some(hash) = nodes.pop() {
+            if !visited.insert(hash) {
+                continue;
+            }
+            let node = load(hash)?.ok_or(TrieError::MissingTrieNode {
+                node_hash: hash,
+                traversed: None,
+                root_hash: Some(root),
+                err_key: None,
+            })?;
+            self.find_refs(&node, &mut nodes, &mut blobs)?;
+            visit(hash, node)?;
+        }
+
+        let mut seen = HashSet::new();
+        blobs.retain(|hash| seen.insert(*hash));
+        Ok(blobs)
+    }
+
+    // Reads the stored node `hash` from the trie's database.
+    pub(crate) fn stored_node(&self, hash: H256) -> TrieResult<Option<Vec<u8>>> {
+        self.counters.db_read();
+        self.db.get(hash.as_bytes()).map_err(TrieError::backend)
+    }
+}
+
+// Reads or writes a snapshot while hashing everything that goes through it.
+struct Checksummed<T> {
+    inner: T,
+    keccak: Keccak,
+}
+
+impl<T> Checksummed<T> {
+    fn new(inner: T) -> Self {
+        Checksummed {
+            inner,
+            keccak: Keccak::v256(),
+        }
+    }
+
+    fn finish(self) -> (T, H256) {
+        let mut checksum = H256::zero();
+        self.keccak.finalize(checksum.as_bytes_mut());
+        (self.inner, checksum)
+    }
+}
+
+impl<W: Write> Checksummed<W> {
+    fn write(&mut self, bytes: &[u8]) -> TrieResult<()> {
+        self.keccak.update(bytes);
+        Ok(self.inner.write_all(bytes)?)
+    }
+
+    fn write_entry(&mut self, kind: u8, data: &[u8]) -> TrieResult<()> {
+        let len = u32::try_from(data.len()).map_err(|_| TrieError::InvalidData)?;
+        self.write(&[kind])?;
+        self.write(&len.to_be_bytes())?;
+        self.write(data)
+    }
+}
+
+impl<R: Read> Checksummed<R> {
+    fn read(&mut self, buf: &mut [u8]) -> TrieResult<()> {
+        self.inner.read_exact(buf)?;
+        self.keccak.update(buf);
+        Ok(())
+    }
+
+    fn read_entry(&mut self) -> TrieResult<Vec<u8>> {
+        let mut len = [0; 4];
+        self.read(&mut len)?;
+        let len = u32::from_be_bytes(len) as usize;
+        // Read through `take` so a damaged length can't allocate gigabytes up front.
+        let mut data = vec![];
+        (&mut self.inner).take(len as u64).read_to_end(&mut data)?;
+        if data.len() != len {
+            return Err(TrieError::InvalidData);
+        }
+        self.keccak.update(&data);
+        Ok(data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::nibbles::Nibbles;
+    use crate::node::empty_children;
+    use crate::test_utils::{random_entries, MemoryDB};
+
+    fn snapshot_of(root: Node) -> TrieSnapshot {
+        TrieSnapshot {
+            root,
+            root_hash: H256::zero(),
+        }
+    }
+
+    #[test]
+    fn restore_brings_back_uncommitted_changes() {
+        let entries = random_entries(1, 200);
+        let mut trie = EthTrie::new(Arc::new(MemoryDB::new()));
+        for (key, value) in &entries {
+            trie.put(key, value).unwrap();
+        }
+        trie.commit().unwrap();
+        for key in entries.keys().step_by(3) {
+            trie.del(key).unwrap();
+        }
+
+        let mut restored = trie.restore(trie.snapshot()).unwrap();
+        restored.check_references().unwrap();
+        assert_eq!(restored.commit().unwrap(), trie.commit().unwrap());
+    }
+
+    #[test]
+    fn references_to_virtual_layers_are_found() {
+        let mut trie = EthTrie::new(Arc::new(MemoryDB::new()));
+        for (key, value) in random_entries(2, 100) {
+            trie.put(&key, &value).unwrap();
+        }
+        let root = trie.commit_virtual().unwrap();
+        // Reopened at the virtual root, the trie refers to nodes of the layer.
+        let mut trie = trie.at_root(root);
+        trie.put(b"key", b"value").unwrap();
+        trie.check_references().unwrap();
+
+        let mut other = EthTrie::new(Arc::new(MemoryDB::new()));
+        other.put(b"key", b"value").unwrap();
+        let missing = other.restore(trie.snapshot()).unwrap();
+        assert!(matches!(
+            missing.check_references(),
+            Err(TrieError::MissingTrieNode { .. })
+        ));
+
+        trie.flush(root).unwrap();
+        trie.check_references().unwrap();
+    }
+
+    #[test]
+    fn restore_rejects_nodes_no_trie_builds() {
+        let trie = EthTrie::new(Arc::new(MemoryDB::new()));
+        let leaf = Node::from_leaf(Nibbles::from_hex(&[1, 16]), b"value".to_vec());
+        let extension = Node::from_extension(Nibbles::from_hex(&[2]), leaf.clone());
+        assert!(matches!(
+            trie.restore(snapshot_of(extension)),
+            Err(TrieError::InvalidData)
+        ));
+
+        let mut children = empty_children();
+        children[3] = leaf;
+        let branch = Node::from_branch(children, None);
+        assert!(matches!(
+            trie.restore(snapshot_of(branch)),
+            Err(TrieError::InvalidData)
+        ));
+    }
+
+    #[test]
+    fn snapshot_files_hold_the_whole_trie() {
+        let entries = random_entries(2, 300);
+        let mut trie = EthTrie::new(Arc::new(MemoryDB::new())).with_large_values(16);
+        for (key, value) in &entries {
+            trie.put(key, value).unwrap();
+        }
+        let mut file = vec![];
+        let root = trie.write_snapshot(&mut file).unwrap();
+
+        let target = Arc::new(MemoryDB::new());
+        let empty = EthTrie::new(target.clone()).with_large_values(16);
+        let imported = empty.read_snapshot(&file[..]).unwrap();
+        assert_eq!(imported.root_hash, root);
+        assert!(trie.compare_with(&*target).unwrap().is_empty());
+        for (key, value) in &entries {
+            assert_eq!(imported.get(key).unwrap().as_ref(), Some(value));
+        }
+    }
+
+    #[test]
+    fn damaged_snapshot_files_are_rejected() {
+        let mut trie = EthTrie::new(Arc::new(MemoryDB::new()));
+        for (key, value) in &random_entries(3, 100) {
+            trie.put(key, value).unwrap();
+        }
+        let mut file = vec![];
+        trie.write_snapshot(&mut file).unwrap();
+
+        let empty = EthTrie::new(Arc::new(MemoryDB::new()));
+        for at in [file.len() / 2, file.len() - 1] {
+            let mut damaged = file.clone();
+            damaged[at] ^= 1;
+            assert!(matches!(
+                empty.read_snapshot(&damaged[..]),
+                Err(TrieError::Corrupted { .. })
+            ));
+        }
+        let mut damaged = file.clone();
+        damaged[7] += 1;
+        assert!(matches!(
+            empty.read_snapshot(&damaged[..]),
+            Err(TrieError::InvalidData)
+        ));
+        assert!(empty.read_snapshot(&file[..file.len() - 1]).is_err());
+    }
+
+    #[cfg(all(feature = "serde", feature = "json"))]
+    #[test]
+    fn deserialization_rejects_malformed_paths() {
+        let leaf = |key: &str| format!(r#"{{"Leaf":{{"key":{{"hex_data":{key}}},"value":[1]}}}}"#);
+        assert!(serde_json::from_str::<Node>(&leaf("[1,15,16]")).is_ok());
+        // A nibble out of range, a terminator before the end, and none at all.
+        for key in ["[1,17,16]", "[1,16,2,16]", "[1,2]"] {
+            assert!(serde_json::from_str::<Node>(&leaf(key)).is_err());
+        }
+
+        let extension = |prefix: &str| {
+            format!(r#"{{"Extension":{{"prefix":{{"hex_data":{prefix}}},"node":"Empty"}}}}"#)
+        };
+        assert!(serde_json::from_str::<Node>(&extension("[4]")).is_ok());
+        for prefix in ["[]", "[4,16]"] {
+            assert!(serde_json::from_str::<Node>(&extension(prefix)).is_err());
+        }
+    }
+}
@@ -0,0 +1,492 @@
+//! Ethereum state: accounts stored in a secure trie, keyed by the keccak256 of their
+//! address, each with its own storage trie keyed by the keccak256 of the slot.
+
+#[cfg(feature = "alloy")]
+mod alloy;
+
+#[cfg(feature = "json")]
+use std::io::{BufRead, Read};
+#[cfg(feature = "json")]
+use std::sync::Arc;
+
+use ethereum_types::{H160, H256, U256};
+use keccak_hash::{keccak, KECCAK_EMPTY, KECCAK_NULL_RLP};
+use rlp::{Rlp, RlpStream};
+
+use crate::db::DB;
+use crate::errors::{TrieError, TrieResult};
+use crate::light::{verify_account, verify_slot};
+use crate::trie::EthTrie;
+#[cfg(feature = "json")]
+use crate::trie::ITrie;
+
+/// An account as stored in the state trie.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Account {
+    pub nonce: U256,
+    pub balance: U256,
+    /// The root of the account's storage trie.
+    pub storage_root: H256,
+    /// The keccak256 of the account's code.
+    pub code_hash: H256,
+}
+
+impl Default for Account {
+    /// An account without code or storage.
+    fn default() -> Self {
+        Account {
+            nonce: U256::zero(),
+            balance: U256::zero(),
+            storage_root: KECCAK_NULL_RLP,
+            code_hash: KECCAK_EMPTY,
+        }
+    }
+}
+
+impl Account {
+    /// The account's encoding, the value stored in the state trie.
+    pub fn rlp(&self) -> Vec<u8> {
+        let mut stream = RlpStream::new_list(4);
+        stream.append(&self.nonce);
+        stream.append(&self.balance);
+        stream.append(&self.storage_root);
+        stream.append(&self.code_hash);
+        stream.out().to_vec()
+    }
+
+    pub fn from_rlp(data: &[u8]) -> TrieResult<Self> {
+        let r = Rlp::new(data);
+        if r.item_count()? != 4 {
+            return Err(TrieError::InvalidData);
+        }
+        Ok(Account {
+            nonce: r.val_at(0)?,
+            balance: r.val_at(1)?,
+            storage_root: r.val_at(2)?,
+            code_hash: r.val_at(3)?,
+        })
+    }
+}
+
+/// Stores `value` in `slot` of a storage trie the way Ethereum does: under the
+/// keccak256 of the slot, RLP encoded. Zero values are not stored, so putting one
+/// clears the slot.
+pub fn put_storage<D: DB>(storage: &mut EthTrie<D>, slot: H256, value: U256) -> TrieResult<()> {
+    let key = keccak(slot);
+    if value.is_zero() {
+        storage.del_fixed(&key)
+    } else {
+        storage.put_fixed(&key, &rlp::encode(&value))
+    }
+}
+
+/// Stores `account` under `address` in a state trie, keyed by the keccak256 of the
+/// address.
+pub fn put_account<D: DB>(
+    state: &mut EthTrie<D>,
+    address: &[u8],
+    account: &Account,
+) -> TrieResult<()> {
+    set_account_rlp(state, address, account)
+}
+
+/// Reads the account at `address` in a state trie, decoding its RLP in the leaf that
+/// holds it instead of from a copy of the value. This is the most common read of the
+/// state, and makes one allocation fewer than `get` followed by `Account::from_rlp`.
+pub fn get_account_rlp<D: DB>(state: &EthTrie<D>, address: &[u8]) -> TrieResult<Option<Account>> {
+    state.read_fixed(&keccak(address), Account::from_rlp)
+}
+
+/// Stores `account` under `address` in a state trie, encoding its RLP straight into
+/// the value the trie keeps.
+pub fn set_account_rlp<D: DB>(
+    state: &mut EthTrie<D>,
+    address: &[u8],
+    account: &Account,
+) -> TrieResult<()> {
+    state.put_fixed_owned(&keccak(address), account.rlp())
+}
+
+/// The proof of an account and of some of its storage slots, as returned by
+/// `eth_getProof` (EIP-1186). With the `alloy` feature it converts to and from alloy's
+/// `EIP1186AccountProofResponse`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AccountProof {
+    pub address: H160,
+    pub account: Account,
+    /// The nodes on the path to the account in the state trie.
+    pub proof: Vec<Vec<u8>>,
+    pub storage: Vec<StorageProof>,
+}
+
+/// The proof of a storage slot, against the storage root of its account.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StorageProof {
+    pub slot: H256,
+    /// The value of the slot, zero if it is not set.
+    pub value: U256,
+    /// The nodes on the path to the slot in the storage trie.
+    pub proof: Vec<Vec<u8>>,
+}
+
+impl AccountProof {
+    /// Checks the account against `state_root` and every storage slot against the
+    /// account's storage root, returning `TrieError::InvalidProof` if any of them
+    /// isn't what the proofs show.
+    ///
+    /// An account missing from the state must have a zero nonce and balance, and all
+    /// its slots zero values; its hashes aren't checked, as nodes report them either
+    /// as those of an empty account or as zero.
+    pub fn verify(&self, state_root: H256) -> TrieResult<()> {
+        let storage_root = match verify_account(state_root, self.address, &self.proof)? {
+            Some(account) if account == self.account => account.storage_root,
+            None if self.account.nonce.is_zero() && self.account.balance.is_zero() => {
+                KECCAK_NULL_RLP
+            }
+            _ => return Err(TrieError::InvalidProof),
+        };
+
+        for slot in &self.storage {
+            if verify_slot(storage_root, slot.slot, &slot.proof)? != slot.value {
+                return Err(TrieError::InvalidProof);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Proves the account at `address` in `state`, and the given storage `slots` of it,
+/// the way `eth_getProof` does. The storage trie is read from the same database as
+/// the state. A missing account is proven absent and reported as an empty one.
+pub fn prove_account<D: DB>(
+    state: &mut EthTrie<D>,
+    address: H160,
+    slots: &[H256],
+) -> TrieResult<AccountProof> {
+    let key = keccak(address);
+    let (account, proof) = state.get_with_proof(key.as_bytes())?;
+    let account = account
+        .map(|account| Account::from_rlp(&account))
+        .transpose()?
+        .unwrap_or_default();
+
+    let mut storage_trie = state.at_root(account.storage_root);
+    let storage = slots
+        .iter()
+        .map(|&slot| {
+            let key = keccak(slot);
+            let (value, proof) = storage_trie.get_with_proof(key.as_bytes())?;
+            let value = value
+                .map(|value| rlp::decode(&value))
+                .transpose()?
+                .unwrap_or_default();
+            Ok(StorageProof { slot, value, proof })
+        })
+        .collect::<TrieResult<_>>()?;
+
+    Ok(AccountProof {
+        address,
+        account,
+        proof,
+        storage,
+    })
+}
+
+/// Proves a single storage `slot` of the account at `address`: the account's proof in
+/// `state`, and the slot's proof in its storage trie, in `storage[0]`. This is what
+/// `eth_getProof` returns when asked for one slot.
+pub fn prove_storage<D: DB>(
+    state: &mut EthTrie<D>,
+    address: H160,
+    slot: H256,
+) -> TrieResult<AccountProof> {
+    prove_account(state, address, &[slot])
+}
+
+/// Builds the state of a geth-style genesis in `db` and returns its root. `genesis` is
+/// either a whole genesis file or its `alloc` section, which maps addresses to
+/// accounts:
+///
+/// ```json
+/// {"0x71562b71999873db5b286df957af199ec94617f7": {
+///     "balance": "0xffffffffffffffff",
+///     "nonce": "0x1",
+///     "code": "0x6001",
+///     "storage": {"0x01": "0x02"}
+/// }}
+/// ```
+///
+/// Every field but `balance` is optional; numbers are hex or decimal. Storage tries
+/// are written to `db` as well, and code is hashed but not stored.
+#[cfg(feature = "json")]
+pub fn load_genesis<D: DB>(db: Arc<D>, genesis: &str) -> TrieResult<H256> {
+    use serde_json::Value;
+
+    let genesis: Value = serde_json::from_str(genesis).map_err(|_| TrieError::InvalidData)?;
+    let alloc = genesis
+        .get("alloc")
+        .unwrap_or(&genesis)
+        .as_object()
+        .ok_or(TrieError::InvalidData)?;
+
+    let mut state = EthTrie::new(db.clone());
+    for (address, fields) in alloc {
+        let address = hex_bytes(address)?;
+        if address.len() != 20 {
+            return Err(TrieError::InvalidData);
+        }
+
+        let mut account = Account {
+            balance: number(fields.get("balance").ok_or(TrieError::InvalidData)?)?,
+            ..Account::default()
+        };
+        if let Some(nonce) = fields.get("nonce") {
+            account.nonce = number(nonce)?;
+        }
+        if let Some(code) = fields.get("code") {
+            account.code_hash = keccak(hex_bytes(code.as_str().ok_or(TrieError::InvalidData)?)?);
+        }
+        if let Some(storage) = fields.get("storage") {
+            let mut trie = EthTrie::new(db.clone());
+            for (slot, value) in storage.as_object().ok_or(TrieError::InvalidData)? {
+                put_storage(&mut trie, word(slot)?, number(value)?)?;
+            }
+            account.storage_root = trie.commit()?;
+        }
+        put_account(&mut state, &address, &account)?;
+    }
+    state.commit()
+}
+
+// The changes an import holds in memory, per trie, before flushing them.
+#[cfg(feature = "json")]
+const IMPORT_MEMORY_BUDGET: usize = 64 << 20;
+
+/// Rebuilds in `db` the state exported by geth's `dump` command and returns its root,
+/// after checking it against the root recorded in the dump. Both outputs are read:
+/// the default JSON object, and the JSON lines written with `--iterative`, whose first
+/// line holds the root.
+///
+/// Every account's storage is rebuilt from the dump and checked against the account's
+/// root, so dumps made with `--nostorage`, or missing storage preimages, are rejected
+/// with `TrieError::RootMismatch`. Code is checked against its hash and stored with
+/// `DB::insert_blob` under it. Accounts are keyed by the hash of their `address`, or by
+/// their `key` when geth didn't know the address.
+///
+/// The tries are flushed to `db` as their changes grow past 64 MiB, but the default
+/// JSON object is parsed whole, so dump large states with `--iterative`, which is read
+/// one account at a time. Only `geth dump` output is read: reading geth's or reth's
+/// databases directly is not supported; export them with `geth dump` first.
+#[cfg(feature = "json")]
+pub fn import_geth_dump<D: DB, R: BufRead>(db: Arc<D>, mut reader: R) -> TrieResult<H256> {
+    use serde_json::Value;
+
+    let mut state = EthTrie::new(db.clone()).with_memory_budget(IMPORT_MEMORY_BUDGET);
+    let mut first = String::new();
+    reader.read_line(&mut first)?;
+    let expected = match serde_json::from_str::<Value>(&first) {
+        // `--iterative`: the root, then one account per line.
+        Ok(header) if header.get("accounts").is_none() => {
+            let root = header.get("root").ok_or(TrieError::InvalidData)?;
+            for line in reader.lines() {
+                let line = line?;
+                if line.trim().is_empty() {
+                    continue;
+                }
+                let account: Value =
+                    serde_json::from_str(&line).map_err(|_| TrieError::InvalidData)?;
+                import_geth_account(&db, &mut state, None, &account)?;
+            }
+            root.clone()
+        }
+        // A single object, pretty printed unless it fits on the first line.
+        parsed => {
+            let dump = match parsed {
+                Ok(dump) => dump,
+                Err(_) => serde_json::from_reader(first.as_bytes().chain(reader))
+                    .map_err(|_| TrieError::InvalidData)?,
+            };
+            let accounts = dump["accounts"].as_object().ok_or(TrieError::InvalidData)?;
+            for (name, account) in accounts {
+                import_geth_account(&db, &mut state, Some(name), account)?;
+            }
+            dump["root"].clone()
+        }
+    };
+
+    let expected = word(expected.as_str().ok_or(TrieError::InvalidData)?)?;
+    let found = state.commit()?;
+    if found != expected {
+        return Err(TrieError::RootMismatch { expected, found });
+    }
+    Ok(found)
+}
+
+// `name` is the key of the account in a non-iterative dump: its address, or
+// `pre(<key>)` when geth didn't know it.
+#[cfg(feature = "json")]
+fn import_geth_account<D: DB>(
+    db: &Arc<D>,
+    state: &mut EthTrie<D>,
+    name: Option<&str>,
+    account: &serde_json::Value,
+) -> TrieResult<()> {
+    let field = |name: &str| account.get(name).and_then(|v| v.as_str());
+    let address = field("address").or(name.filter(|name| !name.starts_with("pre(")));
+    let key = match (address, field("key")) {
+        (Some(address), _) => keccak(hex_bytes(address)?),
+        (None, Some(key)) => word(key)?,
+        (None, None) => return Err(TrieError::InvalidData),
+    };
+
+    let mut storage = EthTrie::new(db.clone()).with_memory_budget(IMPORT_MEMORY_BUDGET);
+    if let Some(slots) = account.get("storage") {
+        for (slot, value) in slots.as_object().ok_or(TrieError::InvalidData)? {
+            // Values are hex, without the `0x` prefix.
+            let value = value.as_str().ok_or(TrieError::InvalidData)?;
+            let value = U256::from_big_endian(word(value)?.as_bytes());
+            put_storage(&mut storage, word(slot)?, value)?;
+        }
+    }
+    let storage_root = storage.commit()?;
+    let expected = field("root")
+        .map(word)
+        .transpose()?
+        .unwrap_or(KECCAK_NULL_RLP);
+    if storage_root != expected {
+        return Err(TrieError::RootMismatch {
+            expected,
+            found: storage_root,
+        });
+    }
+
+    let code_hash = field("codeHash")
+        .map(word)
+        .transpose()?
+        .unwrap_or(KECCAK_EMPTY);
+    if let Some(code) = field("code") {
+        let code = hex_bytes(code)?;
+        if keccak(&code) != code_hash {
+            return Err(TrieError::Corrupted {
+                hash: code_hash,
+                reason: "code does not match its hash",
+            });
+        }
+        db.insert_blob(code_hash.as_bytes(), code)
+            .map_err(TrieError::backend)?;
+    }
+
+    let account = Account {
+        nonce: account
+            .get("nonce")
+            .map(number)
+            .transpose()?
+            .unwrap_or_default(),
+        balance: account
+            .get("balance")
+            .map(number)
+            .transpose()?
+            .unwrap_or_default(),
+        storage_root,
+        code_hash,
+    };
+    state.put_fixed(&key, &account.rlp())
+}
+
+#[cfg(feature = "json")]
+fn hex_bytes(s: &str) -> TrieResult<Vec<u8>> {
+    let s = s.strip_prefix("0x").unwrap_or(s);
+    // geth accepts odd lengths, such as "0x0".
+    let padded;
+    let s = if s.len() % 2 == 1 {
+        padded = format!("0{}", s);
+        &padded
+    } else {
+        s
+    };
+    hex::decode(s).map_err(|_| TrieError::InvalidData)
+}
+
+// A 32-byte big-endian word, left padded.
+#[cfg(feature = "json")]
+fn word(s: &str) -> TrieResult<H256> {
+    let bytes = hex_bytes(s)?;
+    if bytes.len() > 32 {
+        return Err(TrieError::InvalidData);
+    }
+    let mut word = H256::zero();
+    word.as_bytes_mut()[32 - bytes.len()..].copy_from_slice(&bytes);
+    Ok(word)
+}
+
+#[cfg(feature = "json")]
+fn number(value: &serde_json::Value) -> TrieResult<U256> {
+    match value {
+        serde_json::Value::Number(n) => n.as_u64().map(U256::from).ok_or(TrieError::InvalidData),
+        serde_json::Value::String(s) if s.starts_with("0x") => {
+            Ok(U256::from_big_endian(word(s)?.as_bytes()))
+        }
+        serde_json::Value::String(s) => U256::from_dec_str(s).map_err(|_| TrieError::InvalidData),
+        _ => Err(TrieError::InvalidData),
+    }
+}
+
+#[cfg(all(test, feature = "json"))]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::test_utils::MemoryDB;
+
+    const ADDRESS: &str = "0x0000000000000000000000000000000000000001";
+
+    // The root of a state holding one account with a storage slot, and the account's
+    // storage root.
+    fn roots() -> (H256, H256) {
+        let db = Arc::new(MemoryDB::new());
+        let mut storage = EthTrie::new(db.clone());
+        put_storage(&mut storage, H256::from_low_u64_be(1), U256::from(2)).unwrap();
+        let storage_root = storage.commit().unwrap();
+        let genesis = format!(
+            r#"{{"{ADDRESS}": {{"balance": "16", "nonce": 1, "storage": {{"0x01": "0x02"}}}}}}"#
+        );
+        (load_genesis(db, &genesis).unwrap(), storage_root)
+    }
+
+    fn account(storage_root: H256) -> String {
+        format!(
+            r#""balance": "16", "nonce": 1, "root": "{storage_root:?}", "storage": {{"0x01": "02"}}"#
+        )
+    }
+
+    #[test]
+    fn both_dump_formats_are_imported() {
+        let (root, storage_root) = roots();
+        let account = account(storage_root);
+
+        let dump = format!(
+            "{{\n  \"root\": \"{root:?}\",\n  \"accounts\": {{\n    \"{ADDRESS}\": {{{account}}}\n  }}\n}}\n"
+        );
+        let db = Arc::new(MemoryDB::new());
+        assert_eq!(import_geth_dump(db, Cursor::new(dump)).unwrap(), root);
+
+        let dump =
+            format!("{{\"root\": \"{root:?}\"}}\n{{\"address\": \"{ADDRESS}\", {account}}}\n");
+        let db = Arc::new(MemoryDB::new());
+        assert_eq!(import_geth_dump(db, Cursor::new(dump)).unwrap(), root);
+    }
+
+    #[test]
+    fn dumps_of_another_root_are_rejected() {
+        let (_, storage_root) = roots();
+        let dump = format!(
+            "{{\"root\": \"{:?}\"}}\n{{\"address\": \"{ADDRESS}\", {}}}\n",
+            H256::zero(),
+            account(storage_root)
+        );
+        assert!(matches!(
+            import_geth_dump(Arc::new(MemoryDB::new()), Cursor::new(dump)),
+            Err(TrieError::RootMismatch { .. })
+        ));
+    }
+}
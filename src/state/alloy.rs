@@ -0,0 +1,89 @@
+//! Conversions between proofs and alloy's `eth_getProof` types, so proofs fetched from
+//! a node can be verified here and proofs made here served as a node would.
+
+use alloy_primitives::{Address, Bytes, B256};
+use alloy_rpc_types_eth::{EIP1186AccountProofResponse, EIP1186StorageProof};
+use ethereum_types::{H160, H256, U256};
+
+use super::{Account, AccountProof, StorageProof};
+use crate::errors::TrieError;
+
+impl From<EIP1186AccountProofResponse> for AccountProof {
+    fn from(response: EIP1186AccountProofResponse) -> Self {
+        AccountProof {
+            address: H160(response.address.into_array()),
+            account: Account {
+                nonce: response.nonce.into(),
+                balance: from_alloy(response.balance),
+                storage_root: H256(response.storage_hash.0),
+                code_hash: H256(response.code_hash.0),
+            },
+            proof: nodes(response.account_proof),
+            storage: response
+                .storage_proof
+                .into_iter()
+                .map(StorageProof::from)
+                .collect(),
+        }
+    }
+}
+
+impl From<EIP1186StorageProof> for StorageProof {
+    fn from(proof: EIP1186StorageProof) -> Self {
+        StorageProof {
+            slot: H256(proof.key.as_b256().0),
+            value: from_alloy(proof.value),
+            proof: nodes(proof.proof),
+        }
+    }
+}
+
+/// Fails with `TrieError::InvalidData` if the nonce doesn't fit in a `u64`, which
+/// Ethereum doesn't allow (EIP-2681) and the response can't represent.
+impl TryFrom<AccountProof> for EIP1186AccountProofResponse {
+    type Error = TrieError;
+
+    fn try_from(proof: AccountProof) -> Result<Self, Self::Error> {
+        let account = proof.account;
+        if account.nonce > U256::from(u64::MAX) {
+            return Err(TrieError::InvalidData);
+        }
+        Ok(EIP1186AccountProofResponse {
+            address: Address::from(proof.address.0),
+            balance: to_alloy(account.balance),
+            code_hash: B256::from(account.code_hash.0),
+            nonce: account.nonce.low_u64(),
+            storage_hash: B256::from(account.storage_root.0),
+            account_proof: proof.proof.into_iter().map(Bytes::from).collect(),
+            storage_proof: proof
+                .storage
+                .into_iter()
+                .map(EIP1186StorageProof::from)
+                .collect(),
+        })
+    }
+}
+
+impl From<StorageProof> for EIP1186StorageProof {
+    fn from(proof: StorageProof) -> Self {
+        EIP1186StorageProof {
+            key: B256::from(proof.slot.0).into(),
+            value: to_alloy(proof.value),
+            proof: proof.proof.into_iter().map(Bytes::from).collect(),
+        }
+    }
+}
+
+fn nodes(proof: Vec<Bytes>) -> Vec<Vec<u8>> {
+    proof.into_iter().map(Vec::from).collect()
+}
+
+fn from_alloy(value: alloy_primitives::U256) -> U256 {
+    U256::from_big_endian(&value.to_be_bytes::<32>())
+}
+
+fn to_alloy(value: U256) -> alloy_primitives::U256 {
+    let mut bytes = [0; 32];
+    value.to_big_endian(&mut bytes);
+    alloy_primitives::U256::from_be_bytes(bytes)
+}
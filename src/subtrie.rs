@@ -0,0 +1,155 @@
+use std::collections::HashSet;
+
+use ethereum_types::H256;
+
+use crate::codec::{DecodedNode, NodeCodec};
+use crate::db::DB;
+use crate::errors::TrieError;
+use crate::hasher::Hasher;
+use crate::nibbles::Nibbles;
+use crate::trie::{EthTrie, ITrie, TrieResult};
+
+/// The subtree of a trie holding every key that starts with a prefix, as exported
+/// by `EthTrie::export_subtrie`. Its nodes are the ones stored in the trie, so it
+/// is a trie of its own, with root `root`, whose keys are those of the original
+/// trie with `path` cut off. `EthTrie::import_subtrie` stores it in any database.
+/// With the `serde` feature it can be serialized to ship it between processes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Subtrie {
+    /// The hash of the subtree's top node, or of the empty trie if no key starts
+    /// with the prefix.
+    pub root: H256,
+    /// The nibbles leading from the trie's root to the top node. They are the
+    /// prefix, or its start when the prefix ends inside the key of the top node.
+    pub path: Nibbles,
+    /// The encoded nodes of the subtree, each stored under its own hash, top node
+    /// included even when its parent embeds it.
+    pub nodes: Vec<Vec<u8>>,
+    /// The value blobs the nodes refer to.
+    pub blobs: Vec<Vec<u8>>,
+}
+
+impl<D, H, C> EthTrie<D, H, C>
+where
+    D: DB,
+    H: Hasher,
+    C: NodeCodec,
+{
+    /// Collects the nodes holding every key that starts with `prefix`, and the
+    /// value blobs they refer to, so a namespace of the trie can be shipped on its
+    /// own. Pending changes are committed first.
+    ///
+    /// The subtree's top node is the first one whose path covers the whole prefix:
+    /// the node found by following `prefix` down the trie, or the leaf or extension
+    /// whose key the prefix ends in.
+    pub fn export_subtrie(&mut self, prefix: &[u8]) -> TrieResult<Subtrie> {
+        let root = self.commit()?;
+        let prefix = Nibbles::from_raw(prefix, false);
+        let prefix = prefix.get_data();
+        let mut subtrie = Subtrie {
+            root: H::hash(&C::encode_empty()),
+            path: Nibbles::from_hex(&[]),
+            nodes: vec![],
+            blobs: vec![],
+        };
+        if root == subtrie.root {
+            return Ok(subtrie);
+        }
+
+        let mut top = self.load_stored(root, root)?;
+        let mut walked = 0;
+        loop {
+            if let DecodedNode::Hash(hash) = C::decode(&top)? {
+                top = self.load_stored(hash, root)?;
+                continue;
+            }
+            let partial = &prefix[walked..];
+            if partial.is_empty() {
+                break;
+            }
+            let (child, skip) = match C::decode(&top)? {
+                DecodedNode::Empty | DecodedNode::Hash(_) => return Ok(subtrie),
+                DecodedNode::Leaf { key, .. } if key.starts_with(partial) => break,
+                DecodedNode::Leaf { .. } => return Ok(subtrie),
+                DecodedNode::Extension { prefix, .. } if prefix.starts_with(partial) => break,
+                DecodedNode::Extension { prefix, child } if partial.starts_with(&prefix) => {
+                    (child.to_vec(), prefix.len())
+                }
+                DecodedNode::Extension { .. } => return Ok(subtrie),
+                DecodedNode::Branch { children, .. } => (children[partial[0] as usize].to_vec(), 1),
+            };
+            top = child;
+            walked += skip;
+        }
+        if let DecodedNode::Empty = C::decode(&top)? {
+            return Ok(subtrie);
+        }
+
+        subtrie.root = H::hash(&top);
+        subtrie.path = Nibbles::from_hex(&prefix[..walked]);
+        let mut hashes = vec![];
+        let mut blobs = vec![];
+        self.find_refs(&top, &mut hashes, &mut blobs)?;
+        subtrie.nodes.push(top);
+        let mut visited = HashSet::from([subtrie.root]);
+        while let Some(hash) = hashes.pop() {
+            if !visited.insert(hash) {
+                continue;
+            }
+            let node = self.load_stored(hash, root)?;
+            self.find_refs(&node, &mut hashes, &mut blobs)?;
+            subtrie.nodes.push(node);
+        }
+
+        let mut visited = HashSet::new();
+        for hash in blobs {
+            if !visited.insert(hash) {
+                continue;
+            }
+            self.counters.db_read();
+            let blob = self
+                .db
+                .get_blob(hash.as_bytes())
+                .map_err(TrieError::backend)?
+                .ok_or(TrieError::NotFound { hash })?;
+            subtrie.blobs.push(blob);
+        }
+        Ok(subtrie)
+    }
+
+    /// Stores the nodes and blobs of `subtrie` in this trie's database, and returns
+    /// the trie at the subtree's root, with this trie's settings. Everything is
+    /// stored under its own hash, so nothing can end up under a wrong key.
+    pub fn import_subtrie(&self, subtrie: &Subtrie) -> TrieResult<Self> {
+        for blob in &subtrie.blobs {
+            self.db
+                .insert_blob(H::hash(blob).as_bytes(), blob.clone())
+                .map_err(TrieError::backend)?;
+        }
+        let keys = subtrie
+            .nodes
+            .iter()
+            .map(|node| H::hash(node).as_bytes().to_vec())
+            .collect();
+        self.db
+            .insert_batch(keys, subtrie.nodes.clone())
+            .map_err(TrieError::backend)?;
+        self.db.flush().map_err(TrieError::backend)?;
+        Ok(self.at_root(subtrie.root))
+    }
+
+    // The stored node `hash`, reached from `root`.
+    fn load_stored(&self, hash: H256, root: H256) -> TrieResult<Vec<u8>> {
+        self.counters.db_read();
+        self.db
+            .get(hash.as_bytes())
+            .map_err(TrieError::backend)?
+            .ok_or(TrieError::MissingTrieNode {
+                node_hash: hash,
+                traversed: None,
+                root_hash: Some(root),
+                err_key: None,
+            })
+    }
+}
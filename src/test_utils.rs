@@ -0,0 +1,339 @@
+//! Helpers for property testing code built on `EthTrie`: deterministic key streams and
+//! random entries, an in-memory `DB`, and a reference implementation of the root
+//! computation to check tries against.
+//!
+//! Everything is seeded, so a failing case can be replayed from its seed.
+
+#[cfg(any(test, feature = "test-utils"))]
+pub mod ethereum;
+
+use std::collections::{BTreeMap, HashMap};
+use std::convert::Infallible;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use ethereum_types::H256;
+use parking_lot::Mutex;
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::{Rng, SeedableRng};
+
+use crate::codec::{ChildRef, NodeCodec, RlpCodec};
+use crate::db::{CommitBatch, StorageStats, BLOB_KEY_PREFIX, DB, META_KEY_PREFIX};
+use crate::errors::{TrieError, TrieResult};
+use crate::hasher::{Hasher, KeccakHasher};
+use crate::trie::{EthTrie, ITrie};
+
+/// Key/value pairs in key order, as held by a trie.
+pub type Entries = BTreeMap<Vec<u8>, Vec<u8>>;
+
+/// A `DB` keeping everything in a map, so tests don't touch the file system.
+#[derive(Debug, Default)]
+pub struct MemoryDB {
+    entries: Mutex<HashMap<Vec<u8>, Vec<u8>>>,
+}
+
+impl MemoryDB {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The number of nodes and blobs stored.
+    pub fn len(&self) -> usize {
+        self.entries.lock().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.lock().is_empty()
+    }
+}
+
+impl DB for MemoryDB {
+    type Error = Infallible;
+
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Self::Error> {
+        Ok(self.entries.lock().get(key).cloned())
+    }
+
+    fn multi_get(&self, keys: &[Vec<u8>]) -> Result<Vec<Option<Vec<u8>>>, Self::Error> {
+        let entries = self.entries.lock();
+        Ok(keys.iter().map(|key| entries.get(key).cloned()).collect())
+    }
+
+    fn contains(&self, key: &[u8]) -> Result<bool, Self::Error> {
+        Ok(self.entries.lock().contains_key(key))
+    }
+
+    fn insert(&self, key: &[u8], value: Vec<u8>) -> Result<(), Self::Error> {
+        self.entries.lock().insert(key.to_vec(), value);
+        Ok(())
+    }
+
+    fn remove(&self, key: &[u8]) -> Result<(), Self::Error> {
+        self.entries.lock().remove(key);
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    fn stats(&self) -> Result<Option<StorageStats>, Self::Error> {
+        let entries = self.entries.lock();
+        let is_node =
+            |key: &[u8]| !key.starts_with(BLOB_KEY_PREFIX) && !key.starts_with(META_KEY_PREFIX);
+        Ok(Some(StorageStats {
+            nodes: entries.keys().filter(|key| is_node(key)).count() as u64,
+            bytes: entries
+                .iter()
+                .map(|(key, value)| (key.len() + value.len()) as u64)
+                .sum(),
+            pages: None,
+        }))
+    }
+
+    fn iter_nodes(&self, f: &mut dyn FnMut(&[u8], &[u8])) -> Result<bool, Self::Error> {
+        let entries = self.entries.lock();
+        for (key, value) in entries.iter() {
+            if !key.starts_with(BLOB_KEY_PREFIX) && !key.starts_with(META_KEY_PREFIX) {
+                f(key, value);
+            }
+        }
+        Ok(true)
+    }
+}
+
+/// A `MemoryDB` whose writes can be made to fail, to test how code built on a trie
+/// recovers from backend errors. A failing write changes nothing.
+#[derive(Debug, Default)]
+pub struct FaultyDB {
+    inner: MemoryDB,
+    failures: AtomicUsize,
+}
+
+/// The error of a write `FaultyDB` was told to fail.
+#[derive(Debug, thiserror::Error)]
+#[error("injected write failure")]
+pub struct InjectedFailure;
+
+impl FaultyDB {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Makes the next `count` writes fail. A batch, or a whole `commit_batch`, is a
+    /// single write.
+    pub fn fail_writes(&self, count: usize) {
+        self.failures.store(count, Ordering::SeqCst);
+    }
+
+    /// The database underneath, holding what was written.
+    pub fn inner(&self) -> &MemoryDB {
+        &self.inner
+    }
+
+    fn write<T>(&self, f: impl FnOnce() -> Result<T, Infallible>) -> Result<T, InjectedFailure> {
+        let fail = self
+            .failures
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1))
+            .is_ok();
+        if fail {
+            return Err(InjectedFailure);
+        }
+        f().map_err(|e| match e {})
+    }
+}
+
+impl DB for FaultyDB {
+    type Error = InjectedFailure;
+
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Self::Error> {
+        self.inner.get(key).map_err(|e| match e {})
+    }
+
+    fn multi_get(&self, keys: &[Vec<u8>]) -> Result<Vec<Option<Vec<u8>>>, Self::Error> {
+        self.inner.multi_get(keys).map_err(|e| match e {})
+    }
+
+    fn insert(&self, key: &[u8], value: Vec<u8>) -> Result<(), Self::Error> {
+        self.write(|| self.inner.insert(key, value))
+    }
+
+    fn remove(&self, key: &[u8]) -> Result<(), Self::Error> {
+        self.write(|| self.inner.remove(key))
+    }
+
+    fn insert_batch(&self, keys: Vec<Vec<u8>>, values: Vec<Vec<u8>>) -> Result<(), Self::Error> {
+        self.write(|| self.inner.insert_batch(keys, values))
+    }
+
+    fn remove_batch(&self, keys: &[Vec<u8>]) -> Result<(), Self::Error> {
+        self.write(|| self.inner.remove_batch(keys))
+    }
+
+    fn commit_batch(&self, batch: CommitBatch) -> Result<(), Self::Error> {
+        self.write(|| self.inner.commit_batch(batch))
+    }
+
+    fn flush(&self) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    fn get_blob(&self, hash: &[u8]) -> Result<Option<Vec<u8>>, Self::Error> {
+        self.inner.get_blob(hash).map_err(|e| match e {})
+    }
+
+    fn insert_blob(&self, hash: &[u8], value: Vec<u8>) -> Result<(), Self::Error> {
+        self.write(|| self.inner.insert_blob(hash, value))
+    }
+
+    fn get_meta(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Self::Error> {
+        self.inner.get_meta(key).map_err(|e| match e {})
+    }
+
+    fn insert_meta(&self, key: &[u8], value: Vec<u8>) -> Result<(), Self::Error> {
+        self.write(|| self.inner.insert_meta(key, value))
+    }
+
+    fn remove_meta(&self, key: &[u8]) -> Result<(), Self::Error> {
+        self.write(|| self.inner.remove_meta(key))
+    }
+
+    fn stats(&self) -> Result<Option<StorageStats>, Self::Error> {
+        self.inner.stats().map_err(|e| match e {})
+    }
+
+    fn iter_nodes(&self, f: &mut dyn FnMut(&[u8], &[u8])) -> Result<bool, Self::Error> {
+        self.inner.iter_nodes(f).map_err(|e| match e {})
+    }
+}
+
+/// An endless stream of pseudo-random keys of `key_len` bytes, the same for a given
+/// seed. With 20 or 32 bytes it resembles Ethereum's hashed state keys.
+pub fn key_stream(seed: u64, key_len: usize) -> impl Iterator<Item = Vec<u8>> {
+    let mut rng = StdRng::seed_from_u64(seed);
+    std::iter::repeat_with(move || {
+        let mut key = vec![0; key_len];
+        rng.fill(&mut key[..]);
+        key
+    })
+}
+
+/// `count` entries derived from `seed`. Keys are 1 to 6 bytes below 16, so many of
+/// them share prefixes or are prefixes of one another, which exercises extension
+/// nodes and branch values. Values are distinct and non-empty, some short enough to
+/// be embedded in their parent and some not.
+pub fn random_entries(seed: u64, count: usize) -> Entries {
+    let mut rng = StdRng::seed_from_u64(seed);
+    let mut entries = BTreeMap::new();
+    while entries.len() < count {
+        let key_len = rng.gen_range(1..=6);
+        let key: Vec<u8> = (0..key_len).map(|_| rng.gen_range(0..16)).collect();
+        let mut value = (entries.len() as u32).to_be_bytes().to_vec();
+        let padding = rng.gen_range(0..40);
+        value.extend((0..padding).map(|_| rng.gen::<u8>()));
+        entries.entry(key).or_insert(value);
+    }
+    entries
+}
+
+/// Builds a trie holding `random_entries(seed, count)` in `db`, inserting them in a
+/// shuffled order, and commits it. The entries are returned along with it.
+pub fn random_trie<D: DB>(
+    db: Arc<D>,
+    seed: u64,
+    count: usize,
+) -> TrieResult<(EthTrie<D>, Entries)> {
+    let entries = random_entries(seed, count);
+    let mut order: Vec<_> = entries.iter().collect();
+    order.shuffle(&mut StdRng::seed_from_u64(seed));
+
+    let mut trie = EthTrie::new(db);
+    for (key, value) in order {
+        trie.put(key, value)?;
+    }
+    trie.commit()?;
+    Ok((trie, entries))
+}
+
+/// The root of a keccak/RLP trie holding `entries`, computed from scratch without a
+/// database or any of `EthTrie`'s node handling. Entries with empty values are
+/// skipped, as `EthTrie` removes keys put with an empty value.
+pub fn reference_root(entries: &Entries) -> H256 {
+    reference_root_with::<KeccakHasher, RlpCodec>(entries)
+}
+
+/// `reference_root` for tries using another hasher or codec.
+pub fn reference_root_with<H: Hasher, C: NodeCodec>(entries: &Entries) -> H256 {
+    let entries: Vec<(Vec<u8>, &[u8])> = entries
+        .iter()
+        .filter(|(_, value)| !value.is_empty())
+        .map(|(key, value)| (to_nibbles(key), value.as_slice()))
+        .collect();
+    // The root is stored under its hash whatever its size.
+    H::hash(&encode::<H, C>(&entries, 0))
+}
+
+/// Commits `trie` and checks its root against `reference_root_with` for the same
+/// hasher and codec, returning `TrieError::RootMismatch` if they differ.
+pub fn check_root<D, H, C>(trie: &mut EthTrie<D, H, C>, entries: &Entries) -> TrieResult<H256>
+where
+    D: DB,
+    H: Hasher,
+    C: NodeCodec,
+{
+    let found = trie.commit()?;
+    let expected = reference_root_with::<H, C>(entries);
+    if found != expected {
+        return Err(TrieError::RootMismatch { expected, found });
+    }
+    Ok(found)
+}
+
+fn to_nibbles(key: &[u8]) -> Vec<u8> {
+    key.iter().flat_map(|b| [b >> 4, b & 0x0f]).collect()
+}
+
+// Encodes the node holding `entries`, sorted by key, whose first `depth` nibbles are
+// consumed by its ancestors.
+fn encode<H: Hasher, C: NodeCodec>(entries: &[(Vec<u8>, &[u8])], depth: usize) -> Vec<u8> {
+    match entries {
+        [] => C::encode_empty(),
+        [(key, value)] => C::encode_leaf(&key[depth..], value),
+        _ => {
+            // Sorted keys share the prefix of the first and last ones.
+            let (first, last) = (
+                &entries[0].0[depth..],
+                &entries[entries.len() - 1].0[depth..],
+            );
+            let shared = first.iter().zip(last).take_while(|(a, b)| a == b).count();
+            if shared > 0 {
+                let child = encode::<H, C>(entries, depth + shared);
+                return C::encode_extension(&first[..shared], child_ref::<H, C>(&child));
+            }
+
+            // Only the first key can end here, every other one being longer.
+            let (value, rest) = match entries.split_first() {
+                Some(((key, value), rest)) if key.len() == depth => (Some(*value), rest),
+                _ => (None, entries),
+            };
+            let children: Vec<Vec<u8>> = (0..16u8)
+                .map(|nibble| {
+                    let start = rest.partition_point(|(key, _)| key[depth] < nibble);
+                    let end = rest.partition_point(|(key, _)| key[depth] <= nibble);
+                    encode::<H, C>(&rest[start..end], depth + 1)
+                })
+                .collect();
+            let refs = std::array::from_fn(|i| child_ref::<H, C>(&children[i]));
+            C::encode_branch(&refs, value)
+        }
+    }
+}
+
+fn child_ref<H: Hasher, C: NodeCodec>(encoded: &[u8]) -> ChildRef<'_> {
+    if encoded.len() < C::INLINE_THRESHOLD || *encoded == *C::encode_empty() {
+        ChildRef::Inline(encoded)
+    } else {
+        ChildRef::Hash(H::hash(encoded))
+    }
+}
@@ -0,0 +1,179 @@
+//! Runs the trie fixtures of [ethereum/tests](https://github.com/ethereum/tests)
+//! (`TrieTests/*.json`) against `EthTrie`.
+//!
+//! Each test inserts, and for `null` values deletes, its entries in order, then
+//! checks the root against the fixture and the proof of every key it mentions with
+//! `verify_proof_with`. Keys and values starting with `0x` are hex, anything else
+//! stands for its own bytes. In `secureTrie` fixtures keys are hashed before use.
+//!
+//! The `_with` variants run fixtures in the same format under another hasher or
+//! codec, whose expected roots have to be computed for that configuration.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+
+use ethereum_types::H256;
+use serde_json::Value;
+
+use super::MemoryDB;
+use crate::codec::{NodeCodec, RlpCodec};
+use crate::errors::{TrieError, TrieResult};
+use crate::hasher::{Hasher, KeccakHasher};
+use crate::trie::{EthTrie, ITrie};
+use crate::verify::verify_proof_with;
+
+/// The outcome of running a fixture file.
+#[derive(Debug, Default)]
+pub struct TrieTestReport {
+    /// Names of the tests that passed.
+    pub passed: Vec<String>,
+    /// Names of the tests that failed, with the reason.
+    pub failed: Vec<(String, TrieError)>,
+}
+
+impl TrieTestReport {
+    pub fn is_success(&self) -> bool {
+        self.failed.is_empty()
+    }
+}
+
+/// Runs a fixture file with keccak256 and RLP, the configuration the upstream
+/// fixtures are made for. Keys are hashed if the file name contains `secureTrie`
+/// or `securetrie`, as in upstream.
+///
+/// Errors are returned for files that can't be read or parsed; failing tests are
+/// reported in the `TrieTestReport`.
+pub fn run_trie_tests<P: AsRef<Path>>(path: P) -> TrieResult<TrieTestReport> {
+    run_trie_tests_with::<KeccakHasher, RlpCodec, P>(path)
+}
+
+/// `run_trie_tests` for another hasher or codec.
+pub fn run_trie_tests_with<H, C, P>(path: P) -> TrieResult<TrieTestReport>
+where
+    H: Hasher,
+    C: NodeCodec,
+    P: AsRef<Path>,
+{
+    let path = path.as_ref();
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let secure = name.contains("secureTrie") || name.contains("securetrie");
+    run_trie_tests_json::<H, C>(&fs::read_to_string(path)?, secure)
+}
+
+/// Runs fixtures already read into memory, `secure` telling whether keys are hashed.
+pub fn run_trie_tests_json<H: Hasher, C: NodeCodec>(
+    json: &str,
+    secure: bool,
+) -> TrieResult<TrieTestReport> {
+    let tests: BTreeMap<String, Value> =
+        serde_json::from_str(json).map_err(|_| TrieError::InvalidData)?;
+
+    let mut report = TrieTestReport::default();
+    for (name, test) in tests {
+        match run_test::<H, C>(&test, secure) {
+            Ok(()) => report.passed.push(name),
+            Err(e) => report.failed.push((name, e)),
+        }
+    }
+    Ok(report)
+}
+
+fn run_test<H: Hasher, C: NodeCodec>(test: &Value, secure: bool) -> TrieResult<()> {
+    // `trietest` lists operations in order, `trieanyorder` maps keys to values.
+    let operations: Vec<(Vec<u8>, Option<Vec<u8>>)> = match &test["in"] {
+        Value::Array(operations) => operations
+            .iter()
+            .map(|op| match op.as_array().map(Vec::as_slice) {
+                Some([key, value]) => Ok((bytes(key)?, optional_bytes(value)?)),
+                _ => Err(TrieError::InvalidData),
+            })
+            .collect::<TrieResult<_>>()?,
+        Value::Object(entries) => entries
+            .iter()
+            .map(|(key, value)| Ok((str_bytes(key)?, optional_bytes(value)?)))
+            .collect::<TrieResult<_>>()?,
+        _ => return Err(TrieError::InvalidData),
+    };
+    let expected = test["root"]
+        .as_str()
+        .map(str_bytes)
+        .transpose()?
+        .filter(|root| root.len() == 32)
+        .map(|root| H256::from_slice(&root))
+        .ok_or(TrieError::InvalidData)?;
+
+    let mut trie = EthTrie::<_, H, C>::new_with_hasher(Arc::new(MemoryDB::new()));
+    let mut state = BTreeMap::new();
+    for (key, value) in operations {
+        let key = if secure {
+            H::hash(&key).as_bytes().to_vec()
+        } else {
+            key
+        };
+        match &value {
+            Some(value) => trie.put(&key, value)?,
+            None => trie.del(&key)?,
+        }
+        // Putting an empty value removes the key.
+        state.insert(key, value.filter(|v| !v.is_empty()));
+    }
+
+    let found = trie.commit()?;
+    if found != expected {
+        return Err(TrieError::RootMismatch { expected, found });
+    }
+    for (key, value) in state {
+        let proof = trie.proof(&key)?;
+        if verify_proof_with::<H, C>(found, &key, &proof)? != value {
+            return Err(TrieError::InvalidProof);
+        }
+    }
+    Ok(())
+}
+
+fn str_bytes(s: &str) -> TrieResult<Vec<u8>> {
+    match s.strip_prefix("0x") {
+        Some(hex) => hex::decode(hex).map_err(|_| TrieError::InvalidData),
+        None => Ok(s.as_bytes().to_vec()),
+    }
+}
+
+fn bytes(value: &Value) -> TrieResult<Vec<u8>> {
+    str_bytes(value.as_str().ok_or(TrieError::InvalidData)?)
+}
+
+// `null` values delete the key.
+fn optional_bytes(value: &Value) -> TrieResult<Option<Vec<u8>>> {
+    match value {
+        Value::Null => Ok(None),
+        value => bytes(value).map(Some),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+
+    fn fixture(name: &str) -> PathBuf {
+        [env!("CARGO_MANIFEST_DIR"), "tests/fixtures/TrieTests", name]
+            .iter()
+            .collect()
+    }
+
+    #[test]
+    fn upstream_fixtures_pass() {
+        for name in [
+            "trietest.json",
+            "trieanyorder.json",
+            "trieanyorder_secureTrie.json",
+        ] {
+            let report = run_trie_tests(fixture(name)).unwrap();
+            assert!(!report.passed.is_empty(), "{} ran no test", name);
+            assert!(report.is_success(), "{}: {:?}", name, report.failed);
+        }
+    }
+}
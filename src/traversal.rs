@@ -0,0 +1,87 @@
+use std::sync::Arc;
+
+use ethereum_types::H256;
+use parking_lot::Mutex;
+
+use crate::codec::NodeCodec;
+use crate::db::DB;
+use crate::errors::TrieError;
+use crate::hasher::Hasher;
+use crate::nibbles::Nibbles;
+use crate::node::Node;
+use crate::trie::{EthTrie, TrieResult};
+
+/// How iteration, proof iteration, pages, diffs, `get_many` and `last_key_value` treat
+/// a node missing from the database.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum TraversalMode {
+    /// Fails with `TrieError::MissingTrieNode`, so no gap goes unnoticed, as
+    /// validators need.
+    #[default]
+    Strict,
+    /// Leaves out the subtree below a missing node and records it, for best-effort
+    /// analytics over a trie known to be incomplete. `take_skipped_nodes` returns
+    /// what was left out.
+    Lenient,
+}
+
+/// A node missing from the database that a lenient traversal left out, along with
+/// everything below it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SkippedNode {
+    pub hash: H256,
+    /// The nibbles of the path from the root to the node.
+    pub path: Nibbles,
+}
+
+// The nodes skipped by lenient traversals, recorded through `&self` and shared with
+// the tries opened with `at_root`, which exports and diffs walk.
+pub(crate) type SkippedNodes = Arc<Mutex<Vec<SkippedNode>>>;
+
+impl<D, H, C> EthTrie<D, H, C>
+where
+    D: DB,
+    H: Hasher,
+    C: NodeCodec,
+{
+    /// Chooses whether `iter`, `iter_with_proofs`, `iter_page`, changesets and other
+    /// diffs fail on a node missing from the database, as they do by default, or skip
+    /// it and carry on.
+    pub fn with_traversal_mode(mut self, mode: TraversalMode) -> Self {
+        self.traversal = mode;
+        self
+    }
+
+    /// Returns the nodes lenient traversals have skipped since the last call, in the
+    /// order they were met, and clears the report.
+    pub fn take_skipped_nodes(&self) -> Vec<SkippedNode> {
+        std::mem::take(&mut *self.skipped.lock())
+    }
+
+    // Loads the node `hash` met at `path` by a traversal. A missing node fails in
+    // strict mode, and is recorded and returned as `None` in lenient mode.
+    pub(crate) fn load_or_skip(&self, hash: H256, path: &[u8]) -> TrieResult<Option<Node>> {
+        if let Some(node) = self.recover_from_db(hash)? {
+            return Ok(Some(node));
+        }
+        self.skip_missing(hash, Nibbles::from_hex(path))?;
+        Ok(None)
+    }
+
+    // Handles the node `hash` found missing at `path`, for traversals loading nodes
+    // themselves: fails in strict mode, and records the node in lenient mode.
+    pub(crate) fn skip_missing(&self, hash: H256, path: Nibbles) -> TrieResult<()> {
+        match self.traversal {
+            TraversalMode::Strict => Err(TrieError::MissingTrieNode {
+                node_hash: hash,
+                traversed: Some(Box::new(path)),
+                root_hash: Some(self.root_hash),
+                err_key: None,
+            }),
+            TraversalMode::Lenient => {
+                self.skipped.lock().push(SkippedNode { hash, path });
+                Ok(())
+            }
+        }
+    }
+}
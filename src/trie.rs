@@ -1,38 +1,56 @@
-use std::sync::{Arc, RwLock};
+use std::collections::BTreeSet;
+use std::marker::PhantomData;
+use std::num::NonZeroUsize;
+use std::sync::{Arc, OnceLock};
 
 use ethereum_types::H256;
 use hashbrown::{HashMap, HashSet};
-use keccak_hash::{keccak, KECCAK_EMPTY, KECCAK_NULL_RLP};
-use log::warn;
-use rlp::{Prototype, Rlp, RlpStream};
-
-use crate::db::{SqliteDB, DB};
-use crate::errors::TrieError;
-use crate::nibbles::Nibbles;
-use crate::node::{empty_children, BranchNode, Node};
-
-pub type TrieResult<T> = Result<T, TrieError>;
-const HASHED_LENGTH: usize = 32;
-
-use std::fs;
-fn delete_file(path:String) -> std::io::Result<()> {
-    fs::remove_file(path)?;
-    Ok(())
+#[cfg(feature = "tracing")]
+use tracing::field::Empty;
+
+use crate::audit::audit_meta;
+use crate::cache::{NodeCache, DEFAULT_NODE_CACHE_CAPACITY};
+use crate::codec::{DecodeLimits, NodeCodec, RlpCodec};
+use crate::db::{CommitBatch, Meta, StorageStats, DB};
+use crate::errors::{with_context, TrieError, TrieOp};
+pub use crate::errors::TrieResult;
+use crate::hasher::{Hasher, KeccakHasher};
+use crate::hooks::CommitHooks;
+use crate::layers::Layers;
+use crate::nibbles::{common_prefix, Nibbles};
+use crate::node::{decode_node_with, empty_children, BranchNode, EncodedNode, Node};
+use crate::page::from_nibbles;
+use crate::perf::Counters;
+use crate::traversal::{SkippedNodes, TraversalMode};
+use crate::value::ValueEncoding;
+use crate::watch::WatchedChange;
+
+// Fills in a field declared `Empty` on the current span when the `tracing` feature is
+// enabled, and compiles to nothing otherwise.
+macro_rules! record {
+    ($field:literal, $value:expr) => {
+        #[cfg(feature = "tracing")]
+        tracing::Span::current().record($field, $value);
+        // Keeps the variables the value is computed from used, without computing it.
+        #[cfg(not(feature = "tracing"))]
+        let _ = || $value;
+    };
 }
 
 pub trait ITrie<D: DB> {
-    /// Returns the value for key stored in the trie.
+    /// Returns the value for key stored in the trie, or `None` if there is none.
     fn get(&self, key: &[u8]) -> TrieResult<Option<Vec<u8>>>;
 
-    /// Inserts value into trie and modifies it if it exists
-    fn put(&mut self, key: &[u8], value: &[u8]) -> ();
+    /// Inserts value into trie and modifies it if it exists.
+    /// An empty value removes the key unless the trie stores empty values.
+    fn put(&mut self, key: &[u8], value: &[u8]) -> TrieResult<()>;
 
     /// Removes any existing value for key from the trie.
     fn del(&mut self, key: &[u8]) -> TrieResult<()>;
 
     /// Saves all the nodes in the db, clears the cache data, recalculates the root.
     /// Returns the root hash of the trie.
-    fn commit(&mut self) -> H256;
+    fn commit(&mut self) -> TrieResult<H256>;
 
     /// Prove constructs a merkle proof for key. The result contains all encoded nodes
     /// on the path to the value at key. The value itself is also included in the last
@@ -41,29 +59,114 @@ pub trait ITrie<D: DB> {
     /// If the trie does not contain a value for key, the returned proof contains all
     /// nodes of the longest existing prefix of the key (at least the root node), ending
     /// with the node that proves the absence of the key.
-    // TODO refactor encode_raw() so that it doesn't need a &mut self
-    fn proof(&mut self, key: &[u8]) -> TrieResult<Vec<Vec<u8>>>;    
+    fn proof(&mut self, key: &[u8]) -> TrieResult<Vec<Vec<u8>>>;
 }
 
 #[derive(Debug)]
-pub struct EthTrie<D>
+pub struct EthTrie<D, H = KeccakHasher, C = RlpCodec>
 where
     D: DB,
+    H: Hasher,
+    C: NodeCodec,
 {
-    root: Node,
-    root_hash: H256,
+    pub(crate) root: Node,
+    pub(crate) root_hash: H256,
 
-    db: Arc<D>,
+    pub(crate) db: Arc<D>,
 
     // The batch of pending new nodes to write
     cache: HashMap<Vec<u8>, Vec<u8>>,
     passing_keys: HashSet<Vec<u8>>,
     gen_keys: HashSet<Vec<u8>>,
+
+    // Decoded nodes, shared by every trie created from this one with `at_root`.
+    node_cache: Option<Arc<NodeCache>>,
+
+    pub(crate) values: ValueEncoding,
+    limits: DecodeLimits,
+    // Whether commit removes the nodes it replaced.
+    prune: bool,
+    // How far below the latest version `commit_version` keeps versions indexed.
+    pub(crate) version_retention: Option<u64>,
+    // Whether `commit_version` records the versions at which each key changed.
+    pub(crate) history: bool,
+    // Whether `commit` appends the keys it changes to the audit log.
+    pub(crate) audit: bool,
+    // The keys whose changes `commit` reports, and those the last commit changed.
+    pub(crate) watched: BTreeSet<Vec<u8>>,
+    pub(crate) watched_changes: Vec<WatchedChange>,
+    pub(crate) hooks: CommitHooks,
+    // Changes not committed yet are flushed once `dirty_bytes` exceeds this.
+    pub(crate) memory_budget: Option<usize>,
+    // Nodes replaced past this many pending removals are left in the database.
+    removal_limit: Option<usize>,
+    dirty_bytes: usize,
+    // The layers of virtual commits, shared like the node cache.
+    pub(crate) layers: Arc<Layers>,
+    // Shared with closures borrowing the trie's other fields, never between tries.
+    pub(crate) counters: Arc<Counters>,
+    // Whether traversals skip missing nodes, and the ones they skipped.
+    pub(crate) traversal: TraversalMode,
+    pub(crate) skipped: SkippedNodes,
+
+    hasher: PhantomData<H>,
+    codec: PhantomData<C>,
+}
+
+/// What a commit wrote, as returned by `EthTrie::commit_outcome`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CommitOutcome {
+    /// The root committed.
+    pub root: H256,
+    /// The nodes written.
+    pub nodes_written: usize,
+    /// The nodes pruned.
+    pub nodes_deleted: usize,
+    /// The size of the nodes written.
+    pub bytes_written: usize,
+}
+
+/// The changes of a trie not committed yet, as returned by `EthTrie::pending_sizes`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PendingSizes {
+    /// The encoded nodes waiting to be written, left by a commit that failed.
+    pub nodes: usize,
+    /// The stored nodes replaced, which the next commit prunes.
+    pub removals: usize,
+    /// The keys and values written, with an allowance for the nodes they change, as
+    /// counted against the memory budget; zero without one.
+    pub dirty_bytes: usize,
+}
+
+// A rough allowance for the nodes a write changes on the way to its key, most of
+// which it shares with nearby writes, counted against the memory budget.
+const WRITE_OVERHEAD: usize = 128;
+
+// A value, if the key has one, with the proof of the key.
+type ValueWithProof = (Option<Vec<u8>>, Vec<Vec<u8>>);
+
+/// A node loaded from the database while looking up a key.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraversedNode {
+    /// The hash the node is stored under.
+    pub hash: H256,
+    /// The number of key nibbles consumed before reaching the node.
+    pub offset: usize,
+}
+
+// What a `change` closure does with the value it is given.
+pub(crate) enum Change {
+    Keep,
+    Put(Vec<u8>),
+    Remove,
 }
 
-enum EncodedNode {
-    Hash(H256),
-    Inline(Vec<u8>),
+// The result of applying an `update` closure below a node.
+enum Update {
+    Unchanged,
+    Written(Node),
+    // A value was removed, so the returned node may need to be degenerated.
+    Removed(Node),
 }
 
 #[derive(Clone, Debug)]
@@ -103,22 +206,100 @@ impl From<Node> for TraceNode {
     }
 }
 
-pub struct TrieIterator<'a, D>
+pub struct TrieIterator<'a, D, H = KeccakHasher, C = RlpCodec>
 where
     D: DB,
+    H: Hasher,
+    C: NodeCodec,
 {
-    trie: &'a EthTrie<D>,
-    nibble: Nibbles,
-    nodes: Vec<TraceNode>,
+    trie: &'a EthTrie<D, H, C>,
+    walk: Walk,
 }
 
-impl<'a, D> Iterator for TrieIterator<'a, D>
+/// Iterator over the keys and values of a trie it owns, created by `into_iter`.
+pub struct IntoIter<D, H = KeccakHasher, C = RlpCodec>
 where
     D: DB,
+    H: Hasher,
+    C: NodeCodec,
 {
-    type Item = (Vec<u8>, Vec<u8>);
+    trie: EthTrie<D, H, C>,
+    walk: Walk,
+}
 
-    fn next(&mut self) -> Option<Self::Item> {
+// The state of a walk over the keys of a trie, from both ends.
+struct Walk {
+    nibble: Nibbles,
+    nodes: Vec<TraceNode>,
+    // The walk from the back, and the last keys yielded from either end, where the
+    // two walks stop once they meet.
+    back: Vec<BackFrame>,
+    front_key: Option<Vec<u8>>,
+    back_key: Option<Vec<u8>>,
+}
+
+// A node still to be visited from the back, or a branch value left for after the
+// branch's children, with the key nibbles leading to it.
+enum BackFrame {
+    Node(Node, Vec<u8>),
+    Value(Vec<u8>, Vec<u8>),
+}
+
+impl Walk {
+    fn new(root: &Node) -> Self {
+        Walk {
+            nibble: Nibbles::from_raw(&[], false),
+            nodes: vec![root.clone().into()],
+            back: vec![BackFrame::Node(root.clone(), vec![])],
+            front_key: None,
+            back_key: None,
+        }
+    }
+
+    // The walk ends after the first error, so a failure is never mistaken for the end
+    // of the trie and nothing after it is yielded.
+    fn next<D: DB, H: Hasher, C: NodeCodec>(
+        &mut self,
+        trie: &EthTrie<D, H, C>,
+    ) -> Option<TrieResult<(Vec<u8>, Vec<u8>)>> {
+        let (key, value) = match self.next_front(trie).transpose()? {
+            Ok(entry) => entry,
+            Err(e) => {
+                self.finish();
+                return Some(Err(e));
+            }
+        };
+        if self.back_key.as_ref().is_some_and(|back| key >= *back) {
+            self.finish();
+            return None;
+        }
+        self.front_key = Some(key.clone());
+        Some(Ok((key, value)))
+    }
+
+    fn next_back<D: DB, H: Hasher, C: NodeCodec>(
+        &mut self,
+        trie: &EthTrie<D, H, C>,
+    ) -> Option<TrieResult<(Vec<u8>, Vec<u8>)>> {
+        let (key, value) = match self.next_back_entry(trie).transpose()? {
+            Ok(entry) => entry,
+            Err(e) => {
+                self.finish();
+                return Some(Err(e));
+            }
+        };
+        if self.front_key.as_ref().is_some_and(|front| key <= *front) {
+            self.finish();
+            return None;
+        }
+        self.back_key = Some(key.clone());
+        Some(Ok((key, value)))
+    }
+
+    fn next_front<D: DB, H: Hasher, C: NodeCodec>(
+        &mut self,
+        trie: &EthTrie<D, H, C>,
+    ) -> TrieResult<Option<(Vec<u8>, Vec<u8>)>> {
         loop {
             let mut now = self.nodes.last().cloned();
             if let Some(ref mut now) = now {
@@ -134,8 +315,7 @@ where
 
                             Node::Extension(ref ext) => {
                                 let cur_len = self.nibble.len();
-                                self.nibble
-                                    .truncate(cur_len - ext.read().unwrap().prefix.len());
+                                self.nibble.truncate(cur_len - ext.prefix.len());
                             }
 
                             Node::Branch(_) => {
@@ -147,38 +327,39 @@ where
                     }
 
                     (TraceStatus::Doing, Node::Extension(ref ext)) => {
-                        self.nibble.extend(&ext.read().unwrap().prefix);
-                        self.nodes.push((ext.read().unwrap().node.clone()).into());
+                        self.nibble.extend(&ext.prefix);
+                        self.nodes.push((ext.node.clone()).into());
                     }
 
                     (TraceStatus::Doing, Node::Leaf(ref leaf)) => {
                         self.nibble.extend(&leaf.key);
-                        return Some((self.nibble.encode_raw().0, leaf.value.clone()));
+                        let value = trie.load_value(leaf.value.clone())?;
+                        return Ok(Some((self.nibble.encode_raw().0, value)));
                     }
 
                     (TraceStatus::Doing, Node::Branch(ref branch)) => {
-                        let value_option = branch.read().unwrap().value.clone();
+                        // The children are visited next, so they are read together.
+                        let children = trie.load_children(&branch.children)?;
+                        let loaded = Node::from_branch(children, branch.value.clone());
+                        if let Some(hash) = now.node.cached_hash() {
+                            loaded.set_hash(hash);
+                        }
+                        self.nodes.last_mut().unwrap().node = loaded;
+
+                        let value_option = branch.value.clone();
                         if let Some(value) = value_option {
-                            return Some((self.nibble.encode_raw().0, value));
+                            let value = trie.load_value(value)?;
+                            return Ok(Some((self.nibble.encode_raw().0, value)));
                         } else {
                             continue;
                         }
                     }
 
                     (TraceStatus::Doing, Node::Hash(ref hash_node)) => {
-                        let node_hash = hash_node.hash;
-                        if let Ok(n) = self.trie.recover_from_db(node_hash) {
-                            self.nodes.pop();
-                            match n {
-                                Some(node) => self.nodes.push(node.into()),
-                                None => {
-                                    warn!("Trie node with hash {:?} is missing from the database. Skipping...", &node_hash);
-                                    continue;
-                                }
-                            }
-                        } else {
-                            //error!();
-                            return None;
+                        let node = trie.load_or_skip(hash_node.hash, self.nibble.get_data())?;
+                        self.nodes.pop();
+                        if let Some(node) = node {
+                            self.nodes.push(node.into());
                         }
                     }
 
@@ -190,7 +371,7 @@ where
                             self.nibble.push(i);
                         }
                         self.nodes
-                            .push((branch.read().unwrap().children[i as usize].clone()).into());
+                            .push((branch.children[i as usize].clone()).into());
                     }
 
                     (_, Node::Empty) => {
@@ -199,119 +380,793 @@ where
                     _ => {}
                 }
             } else {
-                return None;
+                return Ok(None);
             }
         }
     }
+
+    // The next entry walking from the last key, mirroring `next_front`: a branch's
+    // children come last to first, and its value after them.
+    fn next_back_entry<D: DB, H: Hasher, C: NodeCodec>(
+        &mut self,
+        trie: &EthTrie<D, H, C>,
+    ) -> TrieResult<Option<(Vec<u8>, Vec<u8>)>> {
+        while let Some(frame) = self.back.pop() {
+            let (node, mut nibbles) = match frame {
+                BackFrame::Value(value, nibbles) => {
+                    let value = trie.load_value(value)?;
+                    return Ok(Some((from_nibbles(&nibbles), value)));
+                }
+                BackFrame::Node(node, nibbles) => (node, nibbles),
+            };
+            match node {
+                Node::Empty => {}
+                Node::Leaf(leaf) => {
+                    nibbles.extend_from_slice(leaf.key.get_data());
+                    if nibbles.last() == Some(&16) {
+                        nibbles.pop();
+                    }
+                    let value = trie.load_value(leaf.value.clone())?;
+                    return Ok(Some((from_nibbles(&nibbles), value)));
+                }
+                Node::Extension(ext) => {
+                    nibbles.extend_from_slice(ext.prefix.get_data());
+                    self.back.push(BackFrame::Node(ext.node.clone(), nibbles));
+                }
+                Node::Branch(branch) => {
+                    if let Some(value) = &branch.value {
+                        self.back.push(BackFrame::Value(value.clone(), nibbles.clone()));
+                    }
+                    let children = trie.load_children(&branch.children)?;
+                    for (i, child) in children.into_iter().enumerate() {
+                        let mut child_nibbles = nibbles.clone();
+                        child_nibbles.push(i as u8);
+                        self.back.push(BackFrame::Node(child, child_nibbles));
+                    }
+                }
+                Node::Hash(hash_node) => {
+                    if let Some(node) = trie.load_or_skip(hash_node.hash, &nibbles)? {
+                        self.back.push(BackFrame::Node(node, nibbles));
+                    }
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    fn finish(&mut self) {
+        self.nodes.clear();
+        self.back.clear();
+    }
+}
+
+impl<'a, D, H, C> Iterator for TrieIterator<'a, D, H, C>
+where
+    D: DB,
+    H: Hasher,
+    C: NodeCodec,
+{
+    type Item = TrieResult<(Vec<u8>, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.walk.next(self.trie)
+    }
+}
+
+/// Walks the trie from its last key, as `.rev()` does, and meets the walk from the
+/// front when both are used, so every key is yielded once.
+impl<'a, D, H, C> DoubleEndedIterator for TrieIterator<'a, D, H, C>
+where
+    D: DB,
+    H: Hasher,
+    C: NodeCodec,
+{
+    fn next_back(&mut self) -> Option<Self::Item> {
+        self.walk.next_back(self.trie)
+    }
+}
+
+impl<D, H, C> Iterator for IntoIter<D, H, C>
+where
+    D: DB,
+    H: Hasher,
+    C: NodeCodec,
+{
+    type Item = TrieResult<(Vec<u8>, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.walk.next(&self.trie)
+    }
+}
+
+impl<D, H, C> DoubleEndedIterator for IntoIter<D, H, C>
+where
+    D: DB,
+    H: Hasher,
+    C: NodeCodec,
+{
+    fn next_back(&mut self) -> Option<Self::Item> {
+        self.walk.next_back(&self.trie)
+    }
+}
+
+impl<'a, D, H, C> IntoIterator for &'a EthTrie<D, H, C>
+where
+    D: DB,
+    H: Hasher,
+    C: NodeCodec,
+{
+    type Item = TrieResult<(Vec<u8>, Vec<u8>)>;
+    type IntoIter = TrieIterator<'a, D, H, C>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+/// Consumes the trie handle, iterating over the trie as it stands, uncommitted
+/// changes included.
+impl<D, H, C> IntoIterator for EthTrie<D, H, C>
+where
+    D: DB,
+    H: Hasher,
+    C: NodeCodec,
+{
+    type Item = TrieResult<(Vec<u8>, Vec<u8>)>;
+    type IntoIter = IntoIter<D, H, C>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        let walk = Walk::new(&self.root);
+        IntoIter { trie: self, walk }
+    }
 }
 
 impl<D> EthTrie<D>
 where
     D: DB,
 {
-    pub fn iter(&self) -> TrieIterator<D> {
-        let nodes: Vec<TraceNode> = vec![(self.root.clone()).into()];
+    pub fn new(db: Arc<D>) -> Self {
+        Self::new_with_hasher(db)
+    }
+}
+
+impl<D, H, C> EthTrie<D, H, C>
+where
+    D: DB,
+    H: Hasher,
+    C: NodeCodec,
+{
+    /// Iterates over the keys and values in key order, uncommitted changes included.
+    /// A node or value that can't be loaded is yielded as an error, which ends the
+    /// iteration, so a corrupted or pruned trie is never mistaken for a shorter one.
+    pub fn iter(&self) -> TrieIterator<'_, D, H, C> {
         TrieIterator {
             trie: self,
-            nibble: Nibbles::from_raw(&[], false),
-            nodes,
+            walk: Walk::new(&self.root),
         }
     }
-    pub fn new(db: Arc<D>) -> Self {
+    /// Creates an empty trie hashing its nodes with `H` instead of keccak256, and
+    /// encoding them with `C`, e.g. `EthTrie::<_, Sha3Hasher>::new_with_hasher(db)`.
+    pub fn new_with_hasher(db: Arc<D>) -> Self {
         Self {
             root: Node::Empty,
-            root_hash: KECCAK_NULL_RLP.as_fixed_bytes().into(),
+            root_hash: H::hash(&C::encode_empty()),
 
             cache: HashMap::new(),
             passing_keys: HashSet::new(),
             gen_keys: HashSet::new(),
 
+            node_cache: NonZeroUsize::new(DEFAULT_NODE_CACHE_CAPACITY)
+                .map(|capacity| Arc::new(NodeCache::new(capacity))),
+            values: ValueEncoding::default(),
+            limits: DecodeLimits::default(),
+            prune: true,
+            version_retention: None,
+            history: false,
+            audit: false,
+            watched: BTreeSet::new(),
+            watched_changes: vec![],
+            hooks: CommitHooks::default(),
+            memory_budget: None,
+            removal_limit: None,
+            dirty_bytes: 0,
+            layers: Arc::default(),
+            counters: Arc::default(),
+            traversal: TraversalMode::default(),
+            skipped: SkippedNodes::default(),
+            hasher: PhantomData,
+            codec: PhantomData,
+
             db,
         }
     }
 
+    /// Replaces the cache of decoded nodes with one holding up to `capacity` nodes.
+    /// A capacity of 0 disables caching, so every node is read from the database.
+    pub fn with_node_cache_capacity(mut self, capacity: usize) -> Self {
+        self.node_cache =
+            NonZeroUsize::new(capacity).map(|capacity| Arc::new(NodeCache::new(capacity)));
+        self
+    }
+
+    /// Sets the limits applied when decoding nodes read from the database.
+    pub fn with_decode_limits(mut self, limits: DecodeLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Chooses whether `commit` removes the nodes replaced since the last commit, as
+    /// it does by default. Without pruning every committed root stays readable with
+    /// `at_root`, which `export_changeset` relies on, at the cost of a database that
+    /// only grows.
+    pub fn with_pruning(mut self, prune: bool) -> Self {
+        self.prune = prune;
+        self
+    }
+
+    /// Commits like `commit`, and returns what the commit wrote along with the root,
+    /// for callers metering state growth per block. Nodes written by earlier partial
+    /// flushes, or with the virtual root the trie builds on, aren't counted.
+    pub fn commit_outcome(&mut self) -> TrieResult<CommitOutcome> {
+        self.commit_with_meta(|_| vec![])
+    }
+
+    /// Commits like `commit_outcome`, writing the metadata `meta` returns for the new
+    /// root in the same batch as the nodes, so it is stored if and only if they are.
+    /// Each pair replaces any value stored under its key, as `DB::insert_meta` does.
+    pub fn commit_with_meta(
+        &mut self,
+        meta: impl FnOnce(H256) -> Vec<(Vec<u8>, Vec<u8>)>,
+    ) -> TrieResult<CommitOutcome> {
+        self.commit_changing_meta(vec![], meta)
+    }
+
+    // Commits like `commit_with_meta`, also removing the metadata under
+    // `removed_meta` in the same batch.
+    pub(crate) fn commit_changing_meta(
+        &mut self,
+        removed_meta: Vec<Vec<u8>>,
+        meta: impl FnOnce(H256) -> Meta,
+    ) -> TrieResult<CommitOutcome> {
+        let watched = self.watched_values()?;
+        let audit = if self.audit {
+            Some((self.audit_len()?, self.audit_changes()?))
+        } else {
+            None
+        };
+        // The audit records are written with the nodes, so a commit is never left
+        // without them.
+        let outcome = self.write_commit(removed_meta, |root| {
+            let mut meta = meta(root);
+            if let Some((len, changes)) = audit {
+                meta.extend(audit_meta(len, root, changes));
+            }
+            meta
+        })?;
+        self.record_watched(watched)?;
+        Ok(outcome)
+    }
+
+    /// Looks up several keys at once, returning their values in the order of `keys`.
+    /// The keys walk down the trie together, so the nodes on prefixes they share are
+    /// visited once, and the stored nodes reached at each depth are read with a single
+    /// `multi_get`: much faster than a `get` per key for many keys, such as every
+    /// account a block touches. A node missing from the database is handled as the
+    /// traversal mode says, the keys below it returned as absent in lenient mode.
+    pub fn get_many(&self, keys: &[&[u8]]) -> TrieResult<Vec<Option<Vec<u8>>>> {
+        let paths: Vec<Nibbles> = keys.iter().map(|key| Nibbles::from_raw(key, true)).collect();
+        let mut values = vec![None; keys.len()];
+        // The nodes reached at the current depth, each with the offset into the paths
+        // and the keys walking through it.
+        let mut frontier = vec![(self.root.clone(), 0, (0..keys.len()).collect::<Vec<_>>())];
+        while !frontier.is_empty() {
+            let hashes: Vec<H256> = frontier
+                .iter()
+                .filter_map(|(node, _, _)| match node {
+                    Node::Hash(hash_node) => Some(hash_node.hash),
+                    _ => None,
+                })
+                .collect();
+            let mut loaded = self.load_nodes(&hashes)?.into_iter();
+            let mut next = vec![];
+            for (node, path_index, group) in frontier {
+                let node = match node {
+                    Node::Hash(hash_node) => match loaded.next().flatten() {
+                        Some(node) => node,
+                        None => {
+                            let path = paths[group[0]].slice(0, path_index);
+                            let skipped = self.skip_missing(hash_node.hash, path);
+                            with_context(skipped, TrieOp::Get, keys[group[0]])?;
+                            // The keys below a skipped node are left as absent.
+                            continue;
+                        }
+                    },
+                    node => node,
+                };
+                match node {
+                    Node::Empty | Node::Hash(_) => {}
+                    Node::Leaf(leaf) => {
+                        for i in group {
+                            if leaf.key.get_data() == &paths[i].get_data()[path_index..] {
+                                values[i] = Some(leaf.value.clone());
+                            }
+                        }
+                    }
+                    Node::Branch(branch) => {
+                        let mut children: [Vec<usize>; 16] = Default::default();
+                        for i in group {
+                            match paths[i].get_data().get(path_index) {
+                                None | Some(16) => values[i] = branch.value.clone(),
+                                Some(&nibble) => children[nibble as usize].push(i),
+                            }
+                        }
+                        for (index, group) in children.into_iter().enumerate() {
+                            if !group.is_empty() {
+                                next.push((branch.children[index].clone(), path_index + 1, group));
+                            }
+                        }
+                    }
+                    Node::Extension(extension) => {
+                        let prefix = extension.prefix.get_data();
+                        let group: Vec<usize> = group
+                            .into_iter()
+                            .filter(|&i| paths[i].get_data()[path_index..].starts_with(prefix))
+                            .collect();
+                        if !group.is_empty() {
+                            next.push((extension.node.clone(), path_index + prefix.len(), group));
+                        }
+                    }
+                }
+            }
+            frontier = next;
+        }
+        values
+            .into_iter()
+            .map(|value| value.map(|v| self.load_value(v)).transpose())
+            .collect()
+    }
+
+    /// Caps the memory taken by changes not committed yet at about `bytes`, for
+    /// imports too large to hold until a single commit. Once the keys and values
+    /// written since the last commit, with an allowance for the nodes each write
+    /// changes, exceed it, the next write runs `flush_partial` first. If the flush
+    /// fails, that write fails without being applied, and the changes before it stay
+    /// pending for a later flush or commit.
+    pub fn with_memory_budget(mut self, bytes: usize) -> Self {
+        self.memory_budget = Some(bytes);
+        self
+    }
+
+    /// Caps the stored nodes remembered for pruning by the next commit at `limit`.
+    /// The ones replaced once it is reached are left in the database instead, where
+    /// `compact` can reclaim them, so a long session without commits doesn't grow the
+    /// set without bound. Nodes written since the last commit are bounded by
+    /// `with_memory_budget`.
+    pub fn with_pending_removal_limit(mut self, limit: usize) -> Self {
+        self.removal_limit = Some(limit);
+        self
+    }
+
+    /// The sizes of the changes not committed yet.
+    pub fn pending_sizes(&self) -> PendingSizes {
+        PendingSizes {
+            nodes: self.cache.len(),
+            removals: self.passing_keys.len(),
+            dirty_bytes: self.dirty_bytes,
+        }
+    }
+
+    /// Returns an independent copy of the trie, uncommitted changes included, sharing
+    /// its database, node cache and settings. Nodes changed since the last commit are
+    /// copied rather than shared, since committing marks them as stored: a copy sharing
+    /// them would skip writing them, and lose them if the other trie's commit never
+    /// reaches the database. Either trie can then be changed and committed on its own.
+    pub fn try_clone(&self) -> Self {
+        let mut trie = self.at_root(self.root_hash);
+        trie.root = self.root.copy_dirty();
+        trie.cache = self.cache.clone();
+        trie.passing_keys = self.passing_keys.clone();
+        trie.gen_keys = self.gen_keys.clone();
+        trie.watched_changes = self.watched_changes.clone();
+        trie.dirty_bytes = self.dirty_bytes;
+        trie
+    }
+
+    /// Opens the trie with root `root_hash` in the same database, sharing the node
+    /// cache and settings. The empty root is never stored, so it isn't looked up.
     pub fn at_root(&self, root_hash: H256) -> Self {
+        let root = if root_hash == H::hash(&C::encode_empty()) {
+            Node::Empty
+        } else {
+            Node::from_hash(root_hash)
+        };
         Self {
-            root: Node::from_hash(root_hash),
+            root,
             root_hash,
 
             cache: HashMap::new(),
             passing_keys: HashSet::new(),
             gen_keys: HashSet::new(),
 
+            node_cache: self.node_cache.clone(),
+            values: self.values,
+            limits: self.limits,
+            prune: self.prune,
+            version_retention: self.version_retention,
+            history: self.history,
+            audit: self.audit,
+            watched: self.watched.clone(),
+            watched_changes: vec![],
+            hooks: self.hooks.clone(),
+            memory_budget: self.memory_budget,
+            removal_limit: self.removal_limit,
+            dirty_bytes: 0,
+            layers: self.layers.clone(),
+            counters: Arc::default(),
+            traversal: self.traversal,
+            skipped: self.skipped.clone(),
+            hasher: PhantomData,
+            codec: PhantomData,
+
             db: self.db.clone(),
         }
     }
-}
 
-impl<D> ITrie<D> for EthTrie<D>
-where
-    D: DB,
-{
-    /// Returns the value for key stored in the trie.
-    fn get(&self, key: &[u8]) -> TrieResult<Option<Vec<u8>>> {
+    // Opens the trie with root `root_hash` in another database, with this trie's
+    // settings but a node cache of its own.
+    pub(crate) fn at_root_in<D2: DB>(&self, db: Arc<D2>, root_hash: H256) -> EthTrie<D2, H, C> {
+        let mut trie = EthTrie::new_with_hasher(db);
+        trie.values = self.values;
+        trie.limits = self.limits;
+        trie.prune = self.prune;
+        trie.version_retention = self.version_retention;
+        trie.history = self.history;
+        trie.audit = self.audit;
+        trie.watched = self.watched.clone();
+        trie.hooks = self.hooks.clone();
+        trie.memory_budget = self.memory_budget;
+        trie.removal_limit = self.removal_limit;
+        trie.traversal = self.traversal;
+        trie.at_root(root_hash)
+    }
+
+    /// Returns the value for key along with the hashed nodes loaded on the way to it,
+    /// in traversal order. Nodes already in memory or embedded in their parent are
+    /// not listed.
+    ///
+    /// On failure the nodes traversed so far are dropped, but `MissingTrieNode` still
+    /// records the nibbles walked before the missing node.
+    pub fn get_with_path(&self, key: &[u8]) -> TrieResult<(Option<Vec<u8>>, Vec<TraversedNode>)> {
         let path = &Nibbles::from_raw(key, true);
-        let result: Result<Option<Vec<u8>>, TrieError> = self.get_at(&self.root, path, 0);
-        
-        if let Err(TrieError::MissingTrieNode {
-            node_hash,
-            traversed,
-            root_hash,
-            err_key: _,
-        }) = result
-        {
-            Err(TrieError::MissingTrieNode {
-                node_hash,
-                traversed,
-                root_hash,
-                err_key: Some(key.to_vec()),
+        let mut visited = vec![];
+        let result = self.get_at(&self.root, path, 0, Some(&mut visited));
+        let value = with_context(result, TrieOp::Get, key)?;
+        let value = value.map(|v| self.load_value(v)).transpose();
+        Ok((with_context(value, TrieOp::Get, key)?, visited))
+    }
+
+    /// Returns the value for key along with its proof, as `get` and `proof` would,
+    /// from a single walk down the trie.
+    pub fn get_with_proof(&mut self, key: &[u8]) -> TrieResult<ValueWithProof> {
+        let (value, proof) = self.prove(key)?;
+        Ok((value.map(|v| self.load_value(v)).transpose()?, proof))
+    }
+
+    /// Reads the value for key, passes it to `f` and stores whatever `f` returns,
+    /// all in a single walk down the trie. Returning `None` removes the key, as does
+    /// an empty value unless the trie stores empty values.
+    pub fn update<F>(&mut self, key: &[u8], f: F) -> TrieResult<()>
+    where
+        F: FnOnce(Option<Vec<u8>>) -> Option<Vec<u8>>,
+    {
+        self.change(key, TrieOp::Update, |old| {
+            Ok(match f(old) {
+                Some(value) => Change::Put(value),
+                None => Change::Remove,
             })
+        })
+    }
+
+    // The single walk behind `update` and `Entry`: `f` gets the value of key and
+    // decides what becomes of it. An empty value removes the key as `put` would.
+    pub(crate) fn change<F>(&mut self, key: &[u8], op: TrieOp, f: F) -> TrieResult<()>
+    where
+        F: FnOnce(Option<Vec<u8>>) -> TrieResult<Change>,
+    {
+        with_context(self.make_room(), op, key)?;
+        let path = &Nibbles::from_raw(key, true);
+        let db = self.db.clone();
+        let values = self.values;
+        let counters = self.counters.clone();
+        // Counted towards the memory budget as `put` and `del` count them.
+        let mut bytes = key.len();
+        let result = self.update_at(self.root.clone(), path, 0, |stored| {
+            let old = stored
+                .map(|v| values.load::<_, H>(&*db, v, &counters))
+                .transpose()?;
+            match f(old)? {
+                Change::Put(value) if !values.removes(&value) => {
+                    let value = values.store::<_, H>(&*db, value, &counters)?;
+                    bytes += value.len();
+                    Ok(Change::Put(value))
+                }
+                Change::Keep => Ok(Change::Keep),
+                _ => Ok(Change::Remove),
+            }
+        });
+
+        match with_context(result, op, key)? {
+            Update::Unchanged => Ok(()),
+            Update::Written(n) | Update::Removed(n) => {
+                self.root = n;
+                self.note_write(bytes);
+                Ok(())
+            }
+        }
+    }
+
+    /// Stores `new_value` under `key` only if the key currently holds `expected_old`,
+    /// or no value when it is `None`, and fails with `TrieError::ValueMismatch`
+    /// otherwise, leaving the trie unchanged. This gives optimistic concurrency to
+    /// components sharing one trie handle, each retrying from the value it finds.
+    pub fn put_cas(
+        &mut self,
+        key: &[u8],
+        expected_old: Option<&[u8]>,
+        new_value: &[u8],
+    ) -> TrieResult<()> {
+        let path = Nibbles::from_raw(key, true);
+        let found = self.get_path(key, &path)?;
+        if found.as_deref() != expected_old {
+            return Err(TrieError::ValueMismatch {
+                key: key.to_vec(),
+                found,
+            });
+        }
+        self.put_path(key, &path, new_value)
+    }
+
+    /// Removes every key starting with `prefix` by detaching the subtree that holds
+    /// them, instead of deleting the keys one by one. The detached nodes are pruned
+    /// from the db on the next commit, which means loading them once to find them.
+    pub fn delete_prefix(&mut self, prefix: &[u8]) -> TrieResult<()> {
+        let path = &Nibbles::from_raw(prefix, false);
+        let result = self.delete_prefix_at(self.root.clone(), path, 0);
+
+        match with_context(result, TrieOp::DeletePrefix, prefix)? {
+            Update::Unchanged => Ok(()),
+            Update::Written(n) | Update::Removed(n) => {
+                self.root = n;
+                Ok(())
+            }
+        }
+    }
+
+    /// Returns how much the trie's database holds, nodes of every root included, or
+    /// `None` if its backend doesn't keep track.
+    pub fn storage_stats(&self) -> TrieResult<Option<StorageStats>> {
+        self.db.stats().map_err(TrieError::backend)
+    }
+
+    /// Writes the nodes changed since the last commit to the database and drops them
+    /// from memory, keeping a reference to the root of the trie as it stands, which
+    /// is not committed: the trie keeps reporting the last committed root, and only
+    /// `commit` runs the commit hooks. Nodes replaced since the last commit, including
+    /// ones written by earlier partial flushes, are still pruned by the next commit,
+    /// so the last committed root stays readable until then.
+    pub fn flush_partial(&mut self) -> TrieResult<()> {
+        let batch = self.commit_batch();
+        if !batch.keys.is_empty() {
+            // On failure the nodes stay pending, for the next flush or commit to write.
+            self.db
+                .insert_batch(batch.keys, batch.values)
+                .map_err(TrieError::backend)?;
+        }
+        self.cache.clear();
+        self.root = if batch.root == H::hash(&C::encode_empty()) {
+            Node::Empty
         } else {
-            result
+            Node::from_hash(batch.root)
+        };
+        // The nodes written are replaced like any other from now on, and the ones to
+        // prune wait for the commit, unless they are written again.
+        self.gen_keys.clear();
+        self.passing_keys = batch.removed.into_iter().collect();
+        self.dirty_bytes = 0;
+        Ok(())
+    }
+
+    // Drops the changes made since the last commit, when there were none pending at
+    // `root`, bringing the trie back to it without writing anything.
+    pub(crate) fn discard_changes(&mut self, root: Node) {
+        self.root = root;
+        self.cache.clear();
+        self.passing_keys.clear();
+        self.gen_keys.clear();
+        self.dirty_bytes = 0;
+    }
+
+    // Remembers the stored node `hash`, just replaced, for the next commit to prune,
+    // unless the pending removals are at their limit.
+    fn pass(&mut self, hash: H256) {
+        if self
+            .removal_limit
+            .is_none_or(|limit| self.passing_keys.len() < limit)
+        {
+            self.passing_keys.insert(hash.as_bytes().to_vec());
         }
     }
 
-    /// Inserts value into trie and modifies it if it exists
-    fn put(&mut self, key: &[u8], value: &[u8]) -> () {
-        if value.is_empty() {
-            self.del(key);
-            return ();
+    // Flushes the changes so far if they exceed the memory budget. Writes call it
+    // before changing anything, so a failed flush fails the write without applying it.
+    fn make_room(&mut self) -> TrieResult<()> {
+        if self
+            .memory_budget
+            .is_some_and(|budget| self.dirty_bytes > budget)
+        {
+            self.flush_partial()?;
+        }
+        Ok(())
+    }
+
+    // Accounts for a write of `bytes` of key and value, to be flushed by the next
+    // write if it takes the changes over the memory budget.
+    fn note_write(&mut self, bytes: usize) {
+        if self.memory_budget.is_some() {
+            self.dirty_bytes += bytes + WRITE_OVERHEAD;
+        }
+    }
+
+    // The stored value of `key` and its proof, from a single walk.
+    fn prove(&self, key: &[u8]) -> TrieResult<ValueWithProof> {
+        let key_path = &Nibbles::from_raw(key, true);
+        let mut path = vec![];
+        let result = self.get_path_at(self.root.clone(), key_path, 0, &mut path);
+        let value = with_context(result, TrieOp::Proof, key)?;
+
+        let mut proof = Vec::with_capacity(path.len());
+        for (i, n) in path.iter().enumerate() {
+            // Embedded nodes are already part of their parent's encoding.
+            let encoded = self.encode_current_node(n);
+            if i == 0 || encoded.len() >= C::INLINE_THRESHOLD {
+                proof.push(encoded);
+            }
+        }
+        Ok((value, proof))
+    }
+
+    pub(crate) fn load_value(&self, stored: Vec<u8>) -> TrieResult<Vec<u8>> {
+        self.values.load::<_, H>(&*self.db, stored, &self.counters)
+    }
+
+    // `get`, `put` and `del` for a key already split into its leaf `path`.
+    pub(crate) fn get_path(&self, key: &[u8], path: &Nibbles) -> TrieResult<Option<Vec<u8>>> {
+        let result = self
+            .get_at(&self.root, path, 0, None)
+            .and_then(|value| value.map(|v| self.load_value(v)).transpose());
+        with_context(result, TrieOp::Get, key)
+    }
+
+    // `get_path` passing the value to `read` where it is stored, without copying it
+    // unless it is a blob.
+    pub(crate) fn read_path<R, F>(
+        &self,
+        key: &[u8],
+        path: &Nibbles,
+        read: F,
+    ) -> TrieResult<Option<R>>
+    where
+        F: FnOnce(&[u8]) -> TrieResult<R>,
+    {
+        let result = self.read_at(&self.root, path, 0, None, |stored| {
+            match self.values.inline(stored)? {
+                Some(value) => read(value),
+                None => read(&self.load_value(stored.to_vec())?),
+            }
+        });
+        with_context(result, TrieOp::Get, key)
+    }
+
+    pub(crate) fn put_path(&mut self, key: &[u8], path: &Nibbles, value: &[u8]) -> TrieResult<()> {
+        self.put_path_owned(key, path, value.to_vec())
+    }
+
+    // `put_path` taking ownership of the value, which is stored without a copy.
+    pub(crate) fn put_path_owned(
+        &mut self,
+        key: &[u8],
+        path: &Nibbles,
+        value: Vec<u8>,
+    ) -> TrieResult<()> {
+        if self.values.removes(&value) {
+            return self.del_path(key, path);
         }
+        with_context(self.make_room(), TrieOp::Put, key)?;
+        let value = self.values.store::<_, H>(&*self.db, value, &self.counters);
+        let value = with_context(value, TrieOp::Put, key)?;
         let root = self.root.clone();
-        let path = &Nibbles::from_raw(key, true);
-        let result = self.insert_at(root, path, 0, value.to_vec());
-        self.root = result.unwrap();       
+        let bytes = key.len() + value.len();
+        self.root = with_context(self.insert_at(root, path, 0, value), TrieOp::Put, key)?;
+        self.note_write(bytes);
+        Ok(())
     }
 
-    /// Removes any existing value for key from the trie.
-    fn del(&mut self, key: &[u8]) -> TrieResult<()> {
-        let path = &Nibbles::from_raw(key, true);
+    /// The hashes of the stored nodes that deleting `key` would replace, sorted: the
+    /// nodes on the path to the key, and any sibling merged into its parent, which the
+    /// next commit rewrites and, with pruning on, removes. The trie isn't changed, and
+    /// the list is empty if the key has no value.
+    pub fn would_delete(&self, key: &[u8]) -> TrieResult<Vec<H256>> {
+        let mut trie = self.at_root(self.root_hash);
+        trie.removal_limit = None;
+        let path = Nibbles::from_raw(key, true);
+        let (_, deleted) = with_context(trie.delete_at(&self.root, &path, 0), TrieOp::Delete, key)?;
+        if !deleted {
+            return Ok(vec![]);
+        }
+        let mut hashes: Vec<H256> = trie
+            .passing_keys
+            .iter()
+            .map(|key| H256::from_slice(key))
+            .collect();
+        hashes.sort();
+        Ok(hashes)
+    }
+
+    pub(crate) fn del_path(&mut self, key: &[u8], path: &Nibbles) -> TrieResult<()> {
+        with_context(self.make_room(), TrieOp::Delete, key)?;
         let result = self.delete_at(&self.root.clone(), path, 0);
+        let (n, _) = with_context(result, TrieOp::Delete, key)?;
+        self.root = n;
+        self.note_write(key.len());
+        Ok(())
+    }
+}
 
-        if let Err(TrieError::MissingTrieNode {
-            node_hash,
-            traversed,
-            root_hash,
-            err_key: _,
-        }) = result
-        {
-            Err(TrieError::MissingTrieNode {
-                node_hash,
-                traversed,
-                root_hash,
-                err_key: Some(key.to_vec()),
-            })
-        } else {
-            let (n, removed) = result.unwrap();
-            self.root = n;
-            Ok(())
+impl<D, H, C> ITrie<D> for EthTrie<D, H, C>
+where
+    D: DB,
+    H: Hasher,
+    C: NodeCodec,
+{
+    /// Returns the value for key stored in the trie, or `None` if there is none.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(
+            level = "debug",
+            skip_all,
+            fields(key_len = key.len(), value_len = Empty)
+        )
+    )]
+    fn get(&self, key: &[u8]) -> TrieResult<Option<Vec<u8>>> {
+        let value = self.get_path(key, &Nibbles::from_raw(key, true))?;
+        if let Some(value) = &value {
+            record!("value_len", value.len());
         }
+        Ok(value)
+    }
+
+    /// Inserts value into trie and modifies it if it exists
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(
+            level = "debug",
+            skip_all,
+            fields(key_len = key.len(), value_len = value.len())
+        )
+    )]
+    fn put(&mut self, key: &[u8], value: &[u8]) -> TrieResult<()> {
+        self.put_path(key, &Nibbles::from_raw(key, true), value)
+    }
+
+    /// Removes any existing value for key from the trie.
+    fn del(&mut self, key: &[u8]) -> TrieResult<()> {
+        self.del_path(key, &Nibbles::from_raw(key, true))
     }
 
     /// Saves all the nodes in the db, clears the cache data, recalculates the root.
     /// Returns the root hash of the trie.
-    fn commit(&mut self) -> H256 {
-        self.commit()
+    fn commit(&mut self) -> TrieResult<H256> {
+        self.commit_outcome().map(|outcome| outcome.root)
     }
 
     /// Prove constructs a merkle proof for key. The result contains all encoded nodes
@@ -321,111 +1176,93 @@ where
     /// If the trie does not contain a value for key, the returned proof contains all
     /// nodes of the longest existing prefix of the key (at least the root node), ending
     /// with the node that proves the absence of the key.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(
+            level = "debug",
+            skip_all,
+            fields(key_len = key.len(), nodes = Empty, bytes = Empty)
+        )
+    )]
     fn proof(&mut self, key: &[u8]) -> TrieResult<Vec<Vec<u8>>> {
-        let key_path = &Nibbles::from_raw(key, true);
-        let result = self.get_path_at(&self.root, key_path, 0);
-
-        if let Err(TrieError::MissingTrieNode {
-            node_hash,
-            traversed,
-            root_hash,
-            err_key: _,
-        }) = result
-        {
-            Err(TrieError::MissingTrieNode {
-                node_hash,
-                traversed,
-                root_hash,
-                err_key: Some(key.to_vec()),
-            })
-        } else {
-            let mut path = result?;
-            match self.root {
-                Node::Empty => {}
-                _ => path.push(self.root.clone()),
-            }
-            Ok(path
-                .into_iter()
-                .rev()
-                .map(|n| self.encode_raw(&n))
-                .collect())
-        }
+        let (_, proof) = self.prove(key)?;
+        record!("nodes", proof.len());
+        record!("bytes", proof.iter().map(Vec::len).sum::<usize>());
+        Ok(proof)
     }
 }
 
-impl<D> EthTrie<D>
+impl<D, H, C> EthTrie<D, H, C>
 where
     D: DB,
+    H: Hasher,
+    C: NodeCodec,
 {
     fn get_at(
         &self,
         source_node: &Node,
         path: &Nibbles,
         path_index: usize,
+        visited: Option<&mut Vec<TraversedNode>>,
     ) -> TrieResult<Option<Vec<u8>>> {
-        let partial = &path.offset(path_index);
-        //println!("{:?} AAAA {:?}", partial, source_node);
+        self.read_at(source_node, path, path_index, visited, |value| Ok(value.to_vec()))
+    }
+
+    // Finds the value at `path` and passes it to `read` as it is stored in its node.
+    fn read_at<R, F>(
+        &self,
+        source_node: &Node,
+        path: &Nibbles,
+        path_index: usize,
+        mut visited: Option<&mut Vec<TraversedNode>>,
+        read: F,
+    ) -> TrieResult<Option<R>>
+    where
+        F: FnOnce(&[u8]) -> TrieResult<R>,
+    {
+        // The rest of the path, as a view into the key's nibbles.
+        let partial = &path.get_data()[path_index..];
         match source_node {
-            Node::Empty => {
-                Err(TrieError::MissingTrieNode {
-                    node_hash: KECCAK_EMPTY,
-                    traversed: Some(path.slice(0, path_index)),
-                    root_hash: Some(self.root_hash),
-                    err_key: None,
-                })
-                //Ok(None)
-            }, //Ok(None),
+            Node::Empty => Ok(None),
             Node::Leaf(leaf) => {
-                if &leaf.key == partial {
-                    Ok(Some(leaf.value.clone()))
+                if leaf.key.get_data() == partial {
+                    read(&leaf.value).map(Some)
                 } else {
-                    Err(TrieError::MissingTrieNode {
-                        node_hash: KECCAK_EMPTY,
-                        traversed: Some(path.slice(0, path_index)),
-                        root_hash: Some(self.root_hash),
-                        err_key: None,
-                    })
-                    //Ok(None)
+                    Ok(None)
                 }
             }
-            Node::Branch(branch) => {
-                let borrow_branch = branch.read().unwrap();
-
-                if partial.is_empty() || partial.at(0) == 16 {
-                    Ok(borrow_branch.value.clone())
-                } else {
-                    let index = partial.at(0);
-                    self.get_at(&borrow_branch.children[index], path, path_index + 1)
+            Node::Branch(branch) => match partial.first() {
+                None | Some(16) => branch.value.as_deref().map(read).transpose(),
+                Some(&index) => {
+                    let child = &branch.children[index as usize];
+                    self.read_at(child, path, path_index + 1, visited, read)
                 }
-            }
+            },
             Node::Extension(extension) => {
-                let extension = extension.read().unwrap();
-
-                let prefix = &extension.prefix;
-                let match_len = partial.common_prefix(prefix);
-                if match_len == prefix.len() {
-                    self.get_at(&extension.node, path, path_index + match_len)
+                let prefix = extension.prefix.get_data();
+                if partial.starts_with(prefix) {
+                    self.read_at(&extension.node, path, path_index + prefix.len(), visited, read)
                 } else {
-                    Err(TrieError::MissingTrieNode {
-                        node_hash: KECCAK_EMPTY,
-                        traversed: Some(path.slice(0, path_index)),
-                        root_hash: Some(self.root_hash),
-                        err_key: None,
-                    })
-                    //Ok(None)
+                    Ok(None)
                 }
             }
             Node::Hash(hash_node) => {
                 let node_hash = hash_node.hash;
+                if let Some(visited) = visited.as_deref_mut() {
+                    visited.push(TraversedNode {
+                        hash: node_hash,
+                        offset: path_index,
+                    });
+                }
                 let node =
                     self.recover_from_db(node_hash)?
                         .ok_or_else(|| TrieError::MissingTrieNode {
                             node_hash,
-                            traversed: Some(path.slice(0, path_index)),
+                            traversed: Some(Box::new(path.slice(0, path_index))),
                             root_hash: Some(self.root_hash),
                             err_key: None,
                         })?;
-                self.get_at(&node, path, path_index)
+                self.read_at(&node, path, path_index, visited, read)
             }
         }
     }
@@ -437,12 +1274,13 @@ where
         path_index: usize,
         value: Vec<u8>,
     ) -> TrieResult<Node> {
-        let partial = path.offset(path_index);
+        let partial = &path.get_data()[path_index..];
+        self.mark_replaced(&n);
         match n {
-            Node::Empty => Ok(Node::from_leaf(partial, value)),
+            Node::Empty => Ok(Node::from_leaf(Nibbles::from_hex(partial), value)),
             Node::Leaf(leaf) => {
-                let old_partial = &leaf.key;
-                let match_index = partial.common_prefix(old_partial);
+                let old_partial = leaf.key.get_data();
+                let match_index = common_prefix(partial, old_partial);
                 if match_index == old_partial.len() {
                     return Ok(Node::from_leaf(leaf.key.clone(), value));
                 }
@@ -450,48 +1288,47 @@ where
                 let mut branch = BranchNode {
                     children: empty_children(),
                     value: None,
+                    hash: OnceLock::new(),
                 };
 
-                let n = Node::from_leaf(old_partial.offset(match_index + 1), leaf.value.clone());
-                branch.insert(old_partial.at(match_index), n);
+                let n = Node::from_leaf(
+                    Nibbles::from_hex(&old_partial[match_index + 1..]),
+                    leaf.value.clone(),
+                );
+                branch.insert(old_partial[match_index] as usize, n);
 
-                let n = Node::from_leaf(partial.offset(match_index + 1), value);
-                branch.insert(partial.at(match_index), n);
+                let n = Node::from_leaf(Nibbles::from_hex(&partial[match_index + 1..]), value);
+                branch.insert(partial[match_index] as usize, n);
 
                 if match_index == 0 {
-                    return Ok(Node::Branch(Arc::new(RwLock::new(branch))));
+                    return Ok(Node::Branch(Arc::new(branch)));
                 }
 
                 // if include a common prefix
                 Ok(Node::from_extension(
-                    partial.slice(0, match_index),
-                    Node::Branch(Arc::new(RwLock::new(branch))),
+                    Nibbles::from_hex(&partial[..match_index]),
+                    Node::Branch(Arc::new(branch)),
                 ))
             }
             Node::Branch(branch) => {
-                let mut borrow_branch = branch.write().unwrap();
-
-                if partial.at(0) == 0x10 {
-                    borrow_branch.value = Some(value);
-                    return Ok(Node::Branch(branch.clone()));
+                let index = partial[0] as usize;
+                if index == 0x10 {
+                    return Ok(branch.with_value(Some(value)));
                 }
 
-                let child = borrow_branch.children[partial.at(0)].clone();
+                let child = branch.children[index].clone();
                 let new_child = self.insert_at(child, path, path_index + 1, value)?;
-                borrow_branch.children[partial.at(0)] = new_child;
-                Ok(Node::Branch(branch.clone()))
+                Ok(branch.with_child(index, new_child))
             }
             Node::Extension(ext) => {
-                let mut borrow_ext = ext.write().unwrap();
-
-                let prefix = &borrow_ext.prefix;
-                let sub_node = borrow_ext.node.clone();
-                let match_index = partial.common_prefix(prefix);
-
+                let prefix = &ext.prefix;
+                let sub_node = ext.node.clone();
+                let match_index = common_prefix(partial, prefix.get_data());
                 if match_index == 0 {
                     let mut branch = BranchNode {
                         children: empty_children(),
                         value: None,
+                        hash: OnceLock::new(),
                     };
                     branch.insert(
                         prefix.at(0),
@@ -501,7 +1338,7 @@ where
                             Node::from_extension(prefix.offset(1), sub_node)
                         },
                     );
-                    let node = Node::Branch(Arc::new(RwLock::new(branch)));
+                    let node = Node::Branch(Arc::new(branch));
 
                     return self.insert_at(node, path, path_index, value);
                 }
@@ -514,18 +1351,16 @@ where
 
                 let new_ext = Node::from_extension(prefix.offset(match_index), sub_node);
                 let new_node = self.insert_at(new_ext, path, path_index + match_index, value)?;
-                borrow_ext.prefix = prefix.slice(0, match_index);
-                borrow_ext.node = new_node;
-                Ok(Node::Extension(ext.clone()))
+                Ok(Node::from_extension(prefix.slice(0, match_index), new_node))
             }
             Node::Hash(hash_node) => {
                 let node_hash = hash_node.hash;
-                self.passing_keys.insert(node_hash.as_bytes().to_vec());
+                self.pass(node_hash);
                 let node =
                     self.recover_from_db(node_hash)?
                         .ok_or_else(|| TrieError::MissingTrieNode {
                             node_hash,
-                            traversed: Some(path.slice(0, path_index)),
+                            traversed: Some(Box::new(path.slice(0, path_index))),
                             root_hash: Some(self.root_hash),
                             err_key: None,
                         })?;
@@ -534,67 +1369,305 @@ where
         }
     }
 
+    fn update_at<F>(
+        &mut self,
+        n: Node,
+        path: &Nibbles,
+        path_index: usize,
+        f: F,
+    ) -> TrieResult<Update>
+    where
+        F: FnOnce(Option<Vec<u8>>) -> TrieResult<Change>,
+    {
+        let partial = &path.get_data()[path_index..];
+        let old_hash = n.cached_hash();
+        let update = match n {
+            Node::Empty => match f(None)? {
+                Change::Put(value) => {
+                    Update::Written(Node::from_leaf(Nibbles::from_hex(partial), value))
+                }
+                Change::Keep | Change::Remove => Update::Unchanged,
+            },
+            Node::Leaf(ref leaf) => {
+                if leaf.key.get_data() == partial {
+                    match f(Some(leaf.value.clone()))? {
+                        Change::Put(value) => {
+                            Update::Written(Node::from_leaf(leaf.key.clone(), value))
+                        }
+                        Change::Keep => Update::Unchanged,
+                        Change::Remove => Update::Removed(Node::Empty),
+                    }
+                } else {
+                    match f(None)? {
+                        Change::Put(value) => {
+                            Update::Written(self.insert_at(n, path, path_index, value)?)
+                        }
+                        Change::Keep | Change::Remove => Update::Unchanged,
+                    }
+                }
+            }
+            Node::Branch(ref branch) => {
+                if partial[0] == 0x10 {
+                    match f(branch.value.clone())? {
+                        Change::Put(value) => Update::Written(branch.with_value(Some(value))),
+                        Change::Remove if branch.value.is_some() => {
+                            Update::Removed(branch.with_value(None))
+                        }
+                        Change::Keep | Change::Remove => Update::Unchanged,
+                    }
+                } else {
+                    let index = partial[0] as usize;
+                    let child = branch.children[index].clone();
+                    match self.update_at(child, path, path_index + 1, f)? {
+                        Update::Unchanged => Update::Unchanged,
+                        Update::Written(new_child) => {
+                            Update::Written(branch.with_child(index, new_child))
+                        }
+                        Update::Removed(new_child) => {
+                            Update::Removed(branch.with_child(index, new_child))
+                        }
+                    }
+                }
+            }
+            Node::Extension(ref ext) => {
+                let prefix = &ext.prefix;
+                let match_len = common_prefix(partial, prefix.get_data());
+
+                if match_len == prefix.len() {
+                    let sub_node = ext.node.clone();
+                    match self.update_at(sub_node, path, path_index + match_len, f)? {
+                        Update::Unchanged => Update::Unchanged,
+                        Update::Written(new_node) => {
+                            Update::Written(Node::from_extension(prefix.clone(), new_node))
+                        }
+                        Update::Removed(new_node) => {
+                            Update::Removed(Node::from_extension(prefix.clone(), new_node))
+                        }
+                    }
+                } else {
+                    match f(None)? {
+                        Change::Put(value) => {
+                            Update::Written(self.insert_at(n, path, path_index, value)?)
+                        }
+                        Change::Keep | Change::Remove => Update::Unchanged,
+                    }
+                }
+            }
+            Node::Hash(hash_node) => {
+                let node_hash = hash_node.hash;
+                let node =
+                    self.recover_from_db(node_hash)?
+                        .ok_or_else(|| TrieError::MissingTrieNode {
+                            node_hash,
+                            traversed: Some(Box::new(path.slice(0, path_index))),
+                            root_hash: Some(self.root_hash),
+                            err_key: None,
+                        })?;
+                let update = self.update_at(node, path, path_index, f)?;
+                if !matches!(update, Update::Unchanged) {
+                    self.pass(node_hash);
+                }
+                return Ok(update);
+            }
+        };
+
+        if let (Some(hash), false) = (old_hash, matches!(update, Update::Unchanged)) {
+            self.pass(hash);
+        }
+        match update {
+            Update::Removed(n) => Ok(Update::Removed(
+                self.degenerate(n, &path.get_data()[..path_index])?,
+            )),
+            update => Ok(update),
+        }
+    }
+
+    fn delete_prefix_at(
+        &mut self,
+        n: Node,
+        path: &Nibbles,
+        path_index: usize,
+    ) -> TrieResult<Update> {
+        let partial = &path.get_data()[path_index..];
+        if partial.is_empty() {
+            // Every key below this node starts with the prefix.
+            if let Node::Empty = n {
+                return Ok(Update::Unchanged);
+            }
+            self.forget_subtree(&n, &mut path.get_data()[..path_index].to_vec())?;
+            return Ok(Update::Removed(Node::Empty));
+        }
+
+        let update = match n {
+            Node::Empty => Update::Unchanged,
+            Node::Leaf(ref leaf) => {
+                if leaf.key.get_data().starts_with(partial) {
+                    self.mark_replaced(&n);
+                    Update::Removed(Node::Empty)
+                } else {
+                    Update::Unchanged
+                }
+            }
+            Node::Branch(ref branch) => {
+                let index = partial[0] as usize;
+                let child = branch.children[index].clone();
+                match self.delete_prefix_at(child, path, path_index + 1)? {
+                    Update::Unchanged => Update::Unchanged,
+                    Update::Written(new_child) | Update::Removed(new_child) => {
+                        self.mark_replaced(&n);
+                        Update::Removed(branch.with_child(index, new_child))
+                    }
+                }
+            }
+            Node::Extension(ref ext) => {
+                let prefix = &ext.prefix;
+                let match_len = common_prefix(partial, prefix.get_data());
+
+                if match_len == partial.len() {
+                    self.forget_subtree(&n, &mut path.get_data()[..path_index].to_vec())?;
+                    Update::Removed(Node::Empty)
+                } else if match_len == prefix.len() {
+                    let sub_node = ext.node.clone();
+                    match self.delete_prefix_at(sub_node, path, path_index + match_len)? {
+                        Update::Unchanged => Update::Unchanged,
+                        Update::Written(Node::Empty) | Update::Removed(Node::Empty) => {
+                            self.mark_replaced(&n);
+                            Update::Removed(Node::Empty)
+                        }
+                        Update::Written(new_node) | Update::Removed(new_node) => {
+                            self.mark_replaced(&n);
+                            Update::Removed(Node::from_extension(prefix.clone(), new_node))
+                        }
+                    }
+                } else {
+                    Update::Unchanged
+                }
+            }
+            Node::Hash(hash_node) => {
+                let node_hash = hash_node.hash;
+                let node =
+                    self.recover_from_db(node_hash)?
+                        .ok_or_else(|| TrieError::MissingTrieNode {
+                            node_hash,
+                            traversed: Some(Box::new(path.slice(0, path_index))),
+                            root_hash: Some(self.root_hash),
+                            err_key: None,
+                        })?;
+                let update = self.delete_prefix_at(node, path, path_index)?;
+                if !matches!(update, Update::Unchanged) {
+                    self.pass(node_hash);
+                }
+                return Ok(update);
+            }
+        };
+
+        match update {
+            Update::Removed(n) => Ok(Update::Removed(
+                self.degenerate(n, &path.get_data()[..path_index])?,
+            )),
+            update => Ok(update),
+        }
+    }
+
+    // Records every stored node of a detached subtree, found at the nibbles `walked`,
+    // so that commit prunes it.
+    fn forget_subtree(&mut self, n: &Node, walked: &mut Vec<u8>) -> TrieResult<()> {
+        self.mark_replaced(n);
+        match n {
+            Node::Empty | Node::Leaf(_) => Ok(()),
+            Node::Branch(branch) => {
+                for (i, child) in branch.children.iter().enumerate() {
+                    walked.push(i as u8);
+                    self.forget_subtree(child, walked)?;
+                    walked.pop();
+                }
+                Ok(())
+            }
+            Node::Extension(ext) => {
+                let len = walked.len();
+                walked.extend_from_slice(ext.prefix.get_data());
+                self.forget_subtree(&ext.node, walked)?;
+                walked.truncate(len);
+                Ok(())
+            }
+            Node::Hash(hash_node) => {
+                let node_hash = hash_node.hash;
+                self.pass(node_hash);
+                let node = self
+                    .recover_from_db(node_hash)?
+                    .ok_or_else(|| TrieError::MissingTrieNode {
+                        node_hash,
+                        traversed: Some(Box::new(Nibbles::from_hex(walked))),
+                        root_hash: Some(self.root_hash),
+                        err_key: None,
+                    })?;
+                self.forget_subtree(&node, walked)
+            }
+        }
+    }
+
     fn delete_at(
         &mut self,
         old_node: &Node,
         path: &Nibbles,
         path_index: usize,
     ) -> TrieResult<(Node, bool)> {
-        let partial = &path.offset(path_index);
+        let partial = &path.get_data()[path_index..];
         let (new_node, deleted) = match old_node {
             Node::Empty => Ok((Node::Empty, false)),
             Node::Leaf(leaf) => {
-                if &leaf.key == partial {
+                if leaf.key.get_data() == partial {
+                    self.mark_replaced(old_node);
                     return Ok((Node::Empty, true));
                 }
                 Ok((Node::Leaf(leaf.clone()), false))
             }
             Node::Branch(branch) => {
-                let mut borrow_branch = branch.write().unwrap();
-
-                if partial.at(0) == 0x10 {
-                    borrow_branch.value = None;
-                    return Ok((Node::Branch(branch.clone()), true));
-                }
-
-                let index = partial.at(0);
-                let child = &borrow_branch.children[index];
+                // Removing the value may leave the branch with a single child, so it
+                // goes through `degenerate` below like any other deletion.
+                if partial[0] == 0x10 {
+                    match branch.value {
+                        Some(_) => Ok((branch.with_value(None), true)),
+                        None => Ok((Node::Branch(branch.clone()), false)),
+                    }
+                } else {
+                    let index = partial[0] as usize;
+                    let child = &branch.children[index];
 
-                let (new_child, deleted) = self.delete_at(child, path, path_index + 1)?;
-                if deleted {
-                    borrow_branch.children[index] = new_child;
+                    let (new_child, deleted) = self.delete_at(child, path, path_index + 1)?;
+                    if deleted {
+                        Ok((branch.with_child(index, new_child), deleted))
+                    } else {
+                        Ok((Node::Branch(branch.clone()), deleted))
+                    }
                 }
-
-                Ok((Node::Branch(branch.clone()), deleted))
             }
             Node::Extension(ext) => {
-                let mut borrow_ext = ext.write().unwrap();
-
-                let prefix = &borrow_ext.prefix;
-                let match_len = partial.common_prefix(prefix);
+                let prefix = &ext.prefix;
+                let match_len = common_prefix(partial, prefix.get_data());
 
                 if match_len == prefix.len() {
                     let (new_node, deleted) =
-                        self.delete_at(&borrow_ext.node, path, path_index + match_len)?;
+                        self.delete_at(&ext.node, path, path_index + match_len)?;
 
                     if deleted {
-                        borrow_ext.node = new_node;
+                        Ok((Node::from_extension(prefix.clone(), new_node), deleted))
+                    } else {
+                        Ok((Node::Extension(ext.clone()), deleted))
                     }
-
-                    Ok((Node::Extension(ext.clone()), deleted))
                 } else {
                     Ok((Node::Extension(ext.clone()), false))
                 }
             }
             Node::Hash(hash_node) => {
                 let hash = hash_node.hash;
-                self.passing_keys.insert(hash.as_bytes().to_vec());
+                self.pass(hash);
 
                 let node =
                     self.recover_from_db(hash)?
                         .ok_or_else(|| TrieError::MissingTrieNode {
                             node_hash: hash,
-                            traversed: Some(path.slice(0, path_index)),
+                            traversed: Some(Box::new(path.slice(0, path_index))),
                             root_hash: Some(self.root_hash),
                             err_key: None,
                         })?;
@@ -603,22 +1676,30 @@ where
         }?;
 
         if deleted {
-            Ok((self.degenerate(new_node)?, deleted))
+            self.mark_replaced(old_node);
+            Ok((self.degenerate(new_node, &path.get_data()[..path_index])?, deleted))
         } else {
             Ok((new_node, deleted))
         }
     }
 
+    // Records that a node loaded from the db is being replaced, so that commit prunes it.
+    // Nodes behind a `Node::Hash` are recorded when they are loaded instead.
+    fn mark_replaced(&mut self, n: &Node) {
+        if let Some(hash) = n.cached_hash() {
+            self.pass(hash);
+        }
+    }
+
     // This refactors the trie after a node deletion, as necessary.
     // For example, if a deletion removes a child of a branch node, leaving only one child left, it
     // needs to be modified into an extension and maybe combined with its parent and/or child node.
-    fn degenerate(&mut self, n: Node) -> TrieResult<Node> {
+    // `walked` holds the nibbles of the path to `n`.
+    fn degenerate(&mut self, n: Node, walked: &[u8]) -> TrieResult<Node> {
         match n {
             Node::Branch(branch) => {
-                let borrow_branch = branch.read().unwrap();
-
                 let mut used_indexs = vec![];
-                for (index, node) in borrow_branch.children.iter().enumerate() {
+                for (index, node) in branch.children.iter().enumerate() {
                     match node {
                         Node::Empty => continue,
                         _ => used_indexs.push(index),
@@ -626,53 +1707,52 @@ where
                 }
 
                 // if only a value node, transmute to leaf.
-                if used_indexs.is_empty() && borrow_branch.value.is_some() {
+                if let (true, Some(value)) = (used_indexs.is_empty(), &branch.value) {
                     let key = Nibbles::from_raw(&[], true);
-                    let value = borrow_branch.value.clone().unwrap();
-                    Ok(Node::from_leaf(key, value))
+                    Ok(Node::from_leaf(key, value.clone()))
                 // if only one node. make an extension.
-                } else if used_indexs.len() == 1 && borrow_branch.value.is_none() {
+                } else if used_indexs.len() == 1 && branch.value.is_none() {
                     let used_index = used_indexs[0];
-                    let n = borrow_branch.children[used_index].clone();
+                    let n = branch.children[used_index].clone();
 
                     let new_node = Node::from_extension(Nibbles::from_hex(&[used_index as u8]), n);
-                    self.degenerate(new_node)
+                    self.degenerate(new_node, walked)
                 } else {
                     Ok(Node::Branch(branch.clone()))
                 }
             }
             Node::Extension(ext) => {
-                let borrow_ext = ext.read().unwrap();
-
-                let prefix = &borrow_ext.prefix;
-                match borrow_ext.node.clone() {
+                let prefix = &ext.prefix;
+                match ext.node.clone() {
                     Node::Extension(sub_ext) => {
-                        let borrow_sub_ext = sub_ext.read().unwrap();
-
-                        let new_prefix = prefix.join(&borrow_sub_ext.prefix);
-                        let new_n = Node::from_extension(new_prefix, borrow_sub_ext.node.clone());
-                        self.degenerate(new_n)
+                        self.mark_replaced(&ext.node);
+                        let new_prefix = prefix.join(&sub_ext.prefix);
+                        let new_n = Node::from_extension(new_prefix, sub_ext.node.clone());
+                        self.degenerate(new_n, walked)
                     }
                     Node::Leaf(leaf) => {
+                        self.mark_replaced(&ext.node);
                         let new_prefix = prefix.join(&leaf.key);
                         Ok(Node::from_leaf(new_prefix, leaf.value.clone()))
                     }
                     // try again after recovering node from the db.
                     Node::Hash(hash_node) => {
                         let node_hash = hash_node.hash;
-                        self.passing_keys.insert(node_hash.as_bytes().to_vec());
+                        self.pass(node_hash);
 
                         let new_node =
                             self.recover_from_db(node_hash)?
-                                .ok_or(TrieError::MissingTrieNode {
+                                .ok_or_else(|| TrieError::MissingTrieNode {
                                     node_hash,
-                                    traversed: None,
+                                    traversed: Some(Box::new(Nibbles::from_hex(
+                                        &[walked, prefix.get_data()].concat(),
+                                    ))),
                                     root_hash: Some(self.root_hash),
                                     err_key: None,
                                 })?;
 
-                        let n = Node::from_extension(borrow_ext.prefix.clone(), new_node);
-                        self.degenerate(n)
+                        let n = Node::from_extension(ext.prefix.clone(), new_node);
+                        self.degenerate(n, walked)
                     }
                     _ => Ok(Node::Extension(ext.clone())),
                 }
@@ -681,65 +1761,125 @@ where
         }
     }
 
-    // Get nodes path along the key, only the nodes whose encode length is greater than
-    // hash length are added.
-    // For embedded nodes whose data are already contained in their parent node, we don't need to
-    // add them in the path.
-    // In the code below, we only add the nodes get by `get_node_from_hash`, because they contains
-    // all data stored in db, including nodes whose encoded data is less than hash length.
+    // Collects the nodes on the way to `path` in `nodes`, from the root down with hash
+    // nodes resolved, and returns the value stored at the end of it. Nodes loaded from
+    // the db and nodes still in memory (e.g. kept after a commit) are both listed; the
+    // caller drops the embedded ones when encoding the proof.
     fn get_path_at(
         &self,
-        source_node: &Node,
+        source_node: Node,
         path: &Nibbles,
         path_index: usize,
-    ) -> TrieResult<Vec<Node>> {
-        let partial = &path.offset(path_index);
+        nodes: &mut Vec<Node>,
+    ) -> TrieResult<Option<Vec<u8>>> {
+        let partial = &path.get_data()[path_index..];
         match source_node {
-            Node::Empty | Node::Leaf(_) => Ok(vec![]),
-            Node::Branch(branch) => {
-                let borrow_branch = branch.read().unwrap();
-
-                if partial.is_empty() || partial.at(0) == 16 {
-                    Ok(vec![])
-                } else {
-                    let node = &borrow_branch.children[partial.at(0)];
-                    self.get_path_at(node, path, path_index + 1)
+            Node::Empty => Ok(None),
+            Node::Leaf(ref leaf) => {
+                let value = (leaf.key.get_data() == partial).then(|| leaf.value.clone());
+                nodes.push(source_node);
+                Ok(value)
+            }
+            Node::Branch(ref branch) => {
+                let next = match partial.first() {
+                    None | Some(16) => None,
+                    Some(&index) => Some(branch.children[index as usize].clone()),
+                };
+                let value = branch.value.clone();
+                nodes.push(source_node);
+                match next {
+                    Some(node) => self.get_path_at(node, path, path_index + 1, nodes),
+                    None => Ok(value),
                 }
             }
-            Node::Extension(ext) => {
-                let borrow_ext = ext.read().unwrap();
-
-                let prefix = &borrow_ext.prefix;
-                let match_len = partial.common_prefix(prefix);
-
-                if match_len == prefix.len() {
-                    self.get_path_at(&borrow_ext.node, path, path_index + match_len)
-                } else {
-                    Ok(vec![])
+            Node::Extension(ref ext) => {
+                let prefix = ext.prefix.get_data();
+                let match_len = common_prefix(partial, prefix);
+                let next = (match_len == prefix.len()).then(|| ext.node.clone());
+                nodes.push(source_node);
+                match next {
+                    Some(node) => self.get_path_at(node, path, path_index + match_len, nodes),
+                    None => Ok(None),
                 }
             }
             Node::Hash(hash_node) => {
                 let node_hash = hash_node.hash;
                 let n = self
                     .recover_from_db(node_hash)?
-                    .ok_or(TrieError::MissingTrieNode {
+                    .ok_or_else(|| TrieError::MissingTrieNode {
                         node_hash,
-                        traversed: None,
+                        traversed: Some(Box::new(path.slice(0, path_index))),
                         root_hash: Some(self.root_hash),
                         err_key: None,
                     })?;
-                let mut rest = self.get_path_at(&n, path, path_index)?;
-                rest.push(n);
-                Ok(rest)
+                self.get_path_at(n, path, path_index, nodes)
             }
         }
     }
 
-    fn commit(&mut self) -> H256 {
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(
+            name = "commit",
+            level = "debug",
+            skip_all,
+            fields(
+                nodes_written = Empty,
+                bytes_written = Empty,
+                nodes_removed = Empty,
+            )
+        )
+    )]
+    fn write_commit(
+        &mut self,
+        removed_meta: Vec<Vec<u8>>,
+        meta: impl FnOnce(H256) -> Meta,
+    ) -> TrieResult<CommitOutcome> {
+        #[cfg(feature = "metrics")]
+        let _timer = crate::metrics::metrics().commit_duration.start_timer();
+        if self.layers.contains(self.root_hash) {
+            // The nodes this commit builds on must reach the database first.
+            self.flush(self.root_hash)?;
+        }
+        let mut batch = self.commit_batch();
+        let root_hash = batch.root;
+        batch.meta.extend(meta(root_hash));
+        batch.removed_meta = removed_meta;
+        self.hooks.pre_commit(&mut batch)?;
+        let outcome = CommitOutcome {
+            root: root_hash,
+            nodes_written: batch.keys.len(),
+            nodes_deleted: batch.removed.len(),
+            bytes_written: batch.values.iter().map(Vec::len).sum(),
+        };
+        {
+            #[cfg(feature = "tracing")]
+            let _span = tracing::debug_span!(
+                "db.commit_batch",
+                nodes = batch.keys.len(),
+                removed = batch.removed.len()
+            )
+            .entered();
+            self.db
+                .commit_batch(batch)
+                .map_err(TrieError::backend)?;
+        }
+
+        self.finish_commit(root_hash);
+        self.hooks.post_commit(root_hash);
+        Ok(outcome)
+    }
+
+    // Encodes the nodes changed since the last commit into the batch writing them,
+    // along with the removal of the nodes they replaced. The nodes now cache their
+    // hashes, so they stay pending until the batch is stored: a failed write leaves
+    // them for the next commit to write.
+    pub(crate) fn commit_batch(&mut self) -> CommitBatch {
         let root_hash = match self.write_node(&self.root.clone()) {
             EncodedNode::Hash(hash) => hash,
             EncodedNode::Inline(encoded) => {
-                let hash: H256 = keccak(&encoded).as_fixed_bytes().into();
+                self.counters.hashed();
+                let hash = H::hash(&encoded);
                 self.cache.insert(hash.as_bytes().to_vec(), encoded);
                 hash
             }
@@ -747,147 +1887,269 @@ where
 
         let mut keys = Vec::with_capacity(self.cache.len());
         let mut values = Vec::with_capacity(self.cache.len());
-        for (k, v) in self.cache.drain() {
-            keys.push(k.to_vec());
-            values.push(v);
+        for (k, v) in &self.cache {
+            keys.push(k.clone());
+            values.push(v.clone());
         }
 
-        self.db.insert_batch(keys, values);
+        #[cfg(feature = "metrics")]
+        crate::metrics::metrics()
+            .nodes_written
+            .observe(keys.len() as f64);
+        record!("nodes_written", keys.len());
+        record!("bytes_written", values.iter().map(Vec::len).sum::<usize>());
 
         let removed_keys: Vec<Vec<u8>> = self
             .passing_keys
             .iter()
-            .filter(|h| !self.gen_keys.contains(&h.to_vec()))
+            .filter(|h| self.prune && !self.gen_keys.contains(&h.to_vec()))
             .map(|h| h.to_vec())
             .collect();
 
-        self.db.remove_batch(&removed_keys);
+        record!("nodes_removed", removed_keys.len());
+        CommitBatch {
+            root: root_hash,
+            keys,
+            values,
+            removed: removed_keys,
+            meta: vec![],
+            removed_meta: vec![],
+        }
+    }
 
+    /// Returns the root of the trie as it stands, the one `commit` would return, by
+    /// hashing the nodes changed since the last commit without committing them. The
+    /// proofs of a trie with uncommitted changes verify against it, so a service can
+    /// prove values it hasn't persisted yet.
+    pub fn current_root(&self) -> H256 {
+        match self.encode_current(&self.root) {
+            EncodedNode::Hash(hash) => hash,
+            EncodedNode::Inline(encoded) => {
+                self.counters.hashed();
+                H::hash(&encoded)
+            }
+        }
+    }
+
+    // How `node` is referenced from its parent as it stands, hashing changed nodes
+    // without marking them as stored, unlike `write_node`.
+    fn encode_current(&self, node: &Node) -> EncodedNode {
+        if let Some(hash) = node.cached_hash() {
+            return EncodedNode::Hash(hash);
+        }
+        let encoded = match node {
+            Node::Hash(hash_node) => return EncodedNode::Hash(hash_node.hash),
+            Node::Empty => return EncodedNode::Inline(C::encode_empty()),
+            node => self.encode_current_node(node),
+        };
+        if encoded.len() < C::INLINE_THRESHOLD {
+            EncodedNode::Inline(encoded)
+        } else {
+            self.counters.hashed();
+            EncodedNode::Hash(H::hash(&encoded))
+        }
+    }
+
+    // The encoding of `node`, with its children referenced as `encode_current` does.
+    // Stored nodes are encoded once, then found in the node cache by hash.
+    pub(crate) fn encode_current_node(&self, node: &Node) -> Vec<u8> {
+        let cache = node.cached_hash().zip(self.node_cache.as_ref());
+        if let Some(encoded) = cache.and_then(|(hash, cache)| cache.encoding(&hash)) {
+            self.counters.encoding_reused();
+            return encoded;
+        }
+        let encoded = node.encode_with::<C>(|n| self.encode_current(n));
+        if let Some((hash, cache)) = cache {
+            cache.insert_encoding(hash, &encoded);
+        }
+        encoded
+    }
+
+    // Moves the trie to `root_hash` once the batch from `commit_batch` is stored.
+    // Every hashed node reachable from the root now caches its hash, so the root is kept
+    // as is instead of being decoded again; unloaded subtrees stay hash references.
+    pub(crate) fn finish_commit(&mut self, root_hash: H256) {
         self.root_hash = root_hash;
+        self.cache.clear();
         self.gen_keys.clear();
         self.passing_keys.clear();
-        self.root = self.recover_from_db(root_hash).unwrap().unwrap();
-        root_hash
+        self.dirty_bytes = 0;
     }
 
     fn write_node(&mut self, to_encode: &Node) -> EncodedNode {
         // Returns the hash value directly to avoid double counting.
-        if let Node::Hash(hash_node) = to_encode {
-            return EncodedNode::Hash(hash_node.hash);
+        match to_encode {
+            Node::Hash(hash_node) => return EncodedNode::Hash(hash_node.hash),
+            Node::Empty => return EncodedNode::Inline(C::encode_empty()),
+            _ => {}
+        }
+
+        // Clean nodes are already stored under their hash, so only dirty subtrees get
+        // encoded again. The key is still kept alive in case it was passed through.
+        if let Some(hash) = to_encode.cached_hash() {
+            self.counters.subtree_skipped();
+            self.gen_keys.insert(hash.as_bytes().to_vec());
+            return EncodedNode::Hash(hash);
         }
 
         let data = self.encode_raw(to_encode);
-        // Nodes smaller than 32 bytes are stored inside their parent,
-        // Nodes equal to 32 bytes are returned directly
-        if data.len() < HASHED_LENGTH {
+        // Nodes smaller than the codec's threshold are stored inside their parent,
+        // others are referenced by hash
+        if data.len() < C::INLINE_THRESHOLD {
             EncodedNode::Inline(data)
         } else {
-            let hash: H256 = keccak(&data).as_fixed_bytes().into();
+            self.counters.hashed();
+            let hash = H::hash(&data);
             self.cache.insert(hash.as_bytes().to_vec(), data);
 
             self.gen_keys.insert(hash.as_bytes().to_vec());
+            to_encode.set_hash(hash);
             EncodedNode::Hash(hash)
         }
     }
 
-    fn encode_raw(&mut self, node: &Node) -> Vec<u8> {
-        match node {
-            Node::Empty => rlp::NULL_RLP.to_vec(),
-            Node::Leaf(leaf) => {
-                let mut stream = RlpStream::new_list(2);
-                stream.append(&leaf.key.encode_compact());
-                stream.append(&leaf.value);
-                stream.out().to_vec()
-            }
-            Node::Branch(branch) => {
-                let borrow_branch = branch.read().unwrap();
-
-                let mut stream = RlpStream::new_list(17);
-                for i in 0..16 {
-                    let n = &borrow_branch.children[i];
-                    match self.write_node(n) {
-                        EncodedNode::Hash(hash) => stream.append(&hash.as_bytes()),
-                        EncodedNode::Inline(data) => stream.append_raw(&data, 1),
-                    };
-                }
+    pub(crate) fn encode_raw(&mut self, node: &Node) -> Vec<u8> {
+        node.encode_with::<C>(|n| self.write_node(n))
+    }
 
-                match &borrow_branch.value {
-                    Some(v) => stream.append(v),
-                    None => stream.append_empty_data(),
-                };
-                stream.out().to_vec()
+    // Decodes a node read from the database, within the trie's decode limits.
+    pub(crate) fn decode_node(&self, data: &[u8]) -> TrieResult<Node> {
+        decode_node_with::<C>(data, self.limits)
+    }
+
+    pub(crate) fn recover_from_db(&self, key: H256) -> TrieResult<Option<Node>> {
+        if let Some(node) = self.cached_node(key) {
+            return Ok(Some(node));
+        }
+        if let Some(value) = self.layers.get(key) {
+            return self.decode_stored(key, &value).map(Some);
+        }
+
+        #[cfg(feature = "metrics")]
+        crate::metrics::metrics().node_reads.inc();
+        self.counters.db_read();
+        let stored = {
+            #[cfg(feature = "tracing")]
+            let _span = tracing::trace_span!("db.get", hash = %key, bytes = Empty).entered();
+            let stored = self.db.get(key.as_bytes()).map_err(TrieError::backend)?;
+            if let Some(value) = &stored {
+                record!("bytes", value.len());
             }
-            Node::Extension(ext) => {
-                let borrow_ext = ext.read().unwrap();
+            stored
+        };
+        stored.map(|value| self.decode_stored(key, &value)).transpose()
+    }
 
-                let mut stream = RlpStream::new_list(2);
-                stream.append(&borrow_ext.prefix.encode_compact());
-                match self.write_node(&borrow_ext.node) {
-                    EncodedNode::Hash(hash) => stream.append(&hash.as_bytes()),
-                    EncodedNode::Inline(data) => stream.append_raw(&data, 1),
-                };
-                stream.out().to_vec()
+    /// Returns `children` with every child referenced by hash loaded, reading those
+    /// not in the node cache with a single `multi_get` instead of one read each as
+    /// they are visited. Children missing from the database stay hash references.
+    pub(crate) fn load_children(&self, children: &[Node; 16]) -> TrieResult<[Node; 16]> {
+        let hashed: Vec<(usize, H256)> = children
+            .iter()
+            .enumerate()
+            .filter_map(|(i, child)| match child {
+                Node::Hash(hash_node) => Some((i, hash_node.hash)),
+                _ => None,
+            })
+            .collect();
+        let hashes: Vec<H256> = hashed.iter().map(|(_, hash)| *hash).collect();
+        let mut loaded = children.clone();
+        for ((i, _), node) in hashed.into_iter().zip(self.load_nodes(&hashes)?) {
+            if let Some(node) = node {
+                loaded[i] = node;
             }
-            Node::Hash(_hash) => unreachable!(),
         }
+        Ok(loaded)
     }
 
-    fn decode_node(data: &[u8]) -> TrieResult<Node> {
-        let r = Rlp::new(data);
-
-        match r.prototype()? {
-            Prototype::Data(0) => Ok(Node::Empty),
-            Prototype::List(2) => {
-                let key = r.at(0)?.data()?;
-                let key = Nibbles::from_compact(key);
-
-                if key.is_leaf() {
-                    Ok(Node::from_leaf(key, r.at(1)?.data()?.to_vec()))
-                } else {
-                    let n = Self::decode_node(r.at(1)?.as_raw())?;
-
-                    Ok(Node::from_extension(key, n))
-                }
+    // The nodes stored under `hashes`, reading those not in the node cache or a layer
+    // with a single `multi_get`; `None` for the ones missing from the database.
+    pub(crate) fn load_nodes(&self, hashes: &[H256]) -> TrieResult<Vec<Option<Node>>> {
+        let mut loaded = vec![None; hashes.len()];
+        let mut missing = vec![];
+        for (i, &hash) in hashes.iter().enumerate() {
+            match self.cached_node(hash) {
+                Some(node) => loaded[i] = Some(node),
+                None => match self.layers.get(hash) {
+                    Some(value) => loaded[i] = Some(self.decode_stored(hash, &value)?),
+                    None => missing.push((i, hash)),
+                },
             }
-            Prototype::List(17) => {
-                let mut nodes = empty_children();
-                #[allow(clippy::needless_range_loop)]
-                for i in 0..nodes.len() {
-                    let rlp_data = r.at(i)?;
-                    let n = Self::decode_node(rlp_data.as_raw())?;
-                    nodes[i] = n;
-                }
-
-                // The last element is a value node.
-                let value_rlp = r.at(16)?;
-                let value = if value_rlp.is_empty() {
-                    None
-                } else {
-                    Some(value_rlp.data()?.to_vec())
-                };
+        }
+        if missing.is_empty() {
+            return Ok(loaded);
+        }
 
-                Ok(Node::from_branch(nodes, value))
-            }
-            _ => {
-                if r.is_data() && r.size() == HASHED_LENGTH {
-                    let hash = H256::from_slice(r.data()?);
-                    Ok(Node::from_hash(hash))
-                } else {
-                    Err(TrieError::InvalidData)
-                }
+        #[cfg(feature = "metrics")]
+        crate::metrics::metrics()
+            .node_reads
+            .inc_by(missing.len() as u64);
+        for _ in &missing {
+            self.counters.db_read();
+        }
+        let keys: Vec<Vec<u8>> = missing.iter().map(|(_, hash)| hash.as_bytes().to_vec()).collect();
+        let stored = {
+            #[cfg(feature = "tracing")]
+            let _span =
+                tracing::trace_span!("db.multi_get", nodes = keys.len(), bytes = Empty).entered();
+            let stored = self.db.multi_get(&keys).map_err(TrieError::backend)?;
+            record!("bytes", stored.iter().flatten().map(Vec::len).sum::<usize>());
+            stored
+        };
+        for ((i, hash), value) in missing.into_iter().zip(stored) {
+            if let Some(value) = value {
+                loaded[i] = Some(self.decode_stored(hash, &value)?);
             }
         }
+        Ok(loaded)
     }
 
-    fn recover_from_db(&self, key: H256) -> TrieResult<Option<Node>> {
-        let node = match self
-            .db
-            .get(key.as_bytes())
-            .map_err(|e| TrieError::SqliteDB(e.to_string()))?
-        {
-            Some(value) => Some(Self::decode_node(&value)?),
-            None => None,
-        };
+    fn cached_node(&self, key: H256) -> Option<Node> {
+        let node = self.node_cache.as_ref().and_then(|cache| cache.get(&key))?;
+        #[cfg(feature = "metrics")]
+        crate::metrics::metrics().cache_hits.inc();
+        self.counters.cache_hit();
+        Some(node)
+    }
+
+    // Decodes the node stored under `key` and adds it to the node cache.
+    fn decode_stored(&self, key: H256, value: &[u8]) -> TrieResult<Node> {
+        let node = self
+            .decode_node(value)
+            .map_err(|e| TrieError::DecodeAtNode {
+                hash: key,
+                source: Box::new(e),
+            })?;
+        self.counters.node_decoded();
+        if value.len() >= C::INLINE_THRESHOLD {
+            node.set_hash(key);
+        }
+        if let Some(cache) = &self.node_cache {
+            cache.insert(key, &node);
+        }
         Ok(node)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{random_entries, reference_root, FaultyDB};
+
+    #[test]
+    fn write_failing_to_flush_is_not_applied() {
+        let entries = random_entries(1, 100);
+        let db = Arc::new(FaultyDB::new());
+        let mut trie = EthTrie::new(db.clone()).with_memory_budget(1);
+
+        for (i, (key, value)) in entries.iter().enumerate() {
+            if i % 10 == 5 {
+                db.fail_writes(1);
+                assert!(trie.put(key, value).is_err());
+                assert_eq!(trie.get(key).unwrap(), None);
+            }
+            trie.put(key, value).unwrap();
+        }
+        assert_eq!(trie.commit().unwrap(), reference_root(&entries));
+    }
+}
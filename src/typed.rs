@@ -0,0 +1,209 @@
+use std::marker::PhantomData;
+
+use ethereum_types::{H160, H256};
+
+use crate::codec::{NodeCodec, RlpCodec};
+use crate::db::DB;
+use crate::errors::{TrieError, TrieResult};
+use crate::hasher::{Hasher, KeccakHasher};
+use crate::trie::{EthTrie, ITrie};
+
+/// A type usable as the key of a `TypedTrie`. Iteration follows the order of the
+/// encoded keys, so encodings that sort like the keys keep iteration in key order.
+pub trait TrieKey: Sized {
+    fn encode_key(&self) -> Vec<u8>;
+
+    fn decode_key(bytes: &[u8]) -> TrieResult<Self>;
+}
+
+/// A type usable as the value of a `TypedTrie`.
+pub trait TrieValue: Sized {
+    fn encode_value(&self) -> Vec<u8>;
+
+    fn decode_value(bytes: &[u8]) -> TrieResult<Self>;
+}
+
+// Types with an obvious byte representation are stored as is, as keys or values.
+macro_rules! raw_bytes {
+    ($($ty:ty => |$bytes:ident| $decode:expr),* $(,)?) => {
+        $(
+            impl TrieKey for $ty {
+                fn encode_key(&self) -> Vec<u8> {
+                    AsRef::<[u8]>::as_ref(self).to_vec()
+                }
+
+                fn decode_key($bytes: &[u8]) -> TrieResult<Self> {
+                    $decode
+                }
+            }
+
+            impl TrieValue for $ty {
+                fn encode_value(&self) -> Vec<u8> {
+                    AsRef::<[u8]>::as_ref(self).to_vec()
+                }
+
+                fn decode_value($bytes: &[u8]) -> TrieResult<Self> {
+                    $decode
+                }
+            }
+        )*
+    };
+}
+
+raw_bytes! {
+    Vec<u8> => |bytes| Ok(bytes.to_vec()),
+    String => |bytes| String::from_utf8(bytes.to_vec()).map_err(|_| TrieError::InvalidData),
+    H256 => |bytes| fixed(bytes).map(H256),
+    H160 => |bytes| fixed(bytes).map(H160),
+}
+
+impl<const N: usize> TrieKey for [u8; N] {
+    fn encode_key(&self) -> Vec<u8> {
+        self.to_vec()
+    }
+
+    fn decode_key(bytes: &[u8]) -> TrieResult<Self> {
+        fixed(bytes)
+    }
+}
+
+impl<const N: usize> TrieValue for [u8; N] {
+    fn encode_value(&self) -> Vec<u8> {
+        self.to_vec()
+    }
+
+    fn decode_value(bytes: &[u8]) -> TrieResult<Self> {
+        fixed(bytes)
+    }
+}
+
+/// Big-endian, so keys iterate in numeric order.
+impl TrieKey for u64 {
+    fn encode_key(&self) -> Vec<u8> {
+        self.to_be_bytes().to_vec()
+    }
+
+    fn decode_key(bytes: &[u8]) -> TrieResult<Self> {
+        fixed(bytes).map(u64::from_be_bytes)
+    }
+}
+
+fn fixed<const N: usize>(bytes: &[u8]) -> TrieResult<[u8; N]> {
+    bytes.try_into().map_err(|_| TrieError::InvalidData)
+}
+
+/// Stores a key or value with its RLP encoding.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct AsRlp<T>(pub T);
+
+impl<T: rlp::Encodable + rlp::Decodable> TrieKey for AsRlp<T> {
+    fn encode_key(&self) -> Vec<u8> {
+        rlp::encode(&self.0).to_vec()
+    }
+
+    fn decode_key(bytes: &[u8]) -> TrieResult<Self> {
+        Ok(AsRlp(rlp::decode(bytes)?))
+    }
+}
+
+impl<T: rlp::Encodable + rlp::Decodable> TrieValue for AsRlp<T> {
+    fn encode_value(&self) -> Vec<u8> {
+        rlp::encode(&self.0).to_vec()
+    }
+
+    fn decode_value(bytes: &[u8]) -> TrieResult<Self> {
+        Ok(AsRlp(rlp::decode(bytes)?))
+    }
+}
+
+/// Stores a value serialized with serde as JSON.
+#[cfg(feature = "json")]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct AsJson<T>(pub T);
+
+#[cfg(feature = "json")]
+impl<T: serde::Serialize + serde::de::DeserializeOwned> TrieValue for AsJson<T> {
+    fn encode_value(&self) -> Vec<u8> {
+        serde_json::to_vec(&self.0).expect("serializing to memory doesn't fail")
+    }
+
+    fn decode_value(bytes: &[u8]) -> TrieResult<Self> {
+        serde_json::from_slice(bytes)
+            .map(AsJson)
+            .map_err(|_| TrieError::InvalidData)
+    }
+}
+
+/// An `EthTrie` whose keys and values are `K` and `V` instead of byte vectors, going
+/// through `TrieKey` and `TrieValue` on the way in and out. Bytes that don't decode
+/// as `K` or `V` are reported as `TrieError::InvalidData`, or the decoder's error.
+pub struct TypedTrie<K, V, D, H = KeccakHasher, C = RlpCodec>
+where
+    D: DB,
+    H: Hasher,
+    C: NodeCodec,
+{
+    trie: EthTrie<D, H, C>,
+    types: PhantomData<fn() -> (K, V)>,
+}
+
+impl<K, V, D, H, C> TypedTrie<K, V, D, H, C>
+where
+    K: TrieKey,
+    V: TrieValue,
+    D: DB,
+    H: Hasher,
+    C: NodeCodec,
+{
+    pub fn new(trie: EthTrie<D, H, C>) -> Self {
+        TypedTrie {
+            trie,
+            types: PhantomData,
+        }
+    }
+
+    /// The underlying trie, to reach what isn't typed, such as `at_root`.
+    pub fn inner(&self) -> &EthTrie<D, H, C> {
+        &self.trie
+    }
+
+    pub fn inner_mut(&mut self) -> &mut EthTrie<D, H, C> {
+        &mut self.trie
+    }
+
+    pub fn into_inner(self) -> EthTrie<D, H, C> {
+        self.trie
+    }
+
+    pub fn get(&self, key: &K) -> TrieResult<Option<V>> {
+        self.trie
+            .get(&key.encode_key())?
+            .map(|value| V::decode_value(&value))
+            .transpose()
+    }
+
+    pub fn put(&mut self, key: &K, value: &V) -> TrieResult<()> {
+        self.trie.put(&key.encode_key(), &value.encode_value())
+    }
+
+    pub fn del(&mut self, key: &K) -> TrieResult<()> {
+        self.trie.del(&key.encode_key())
+    }
+
+    pub fn commit(&mut self) -> TrieResult<H256> {
+        self.trie.commit()
+    }
+
+    /// The proof of `key`. Verifiers need the encoded key, not `key` itself.
+    pub fn proof(&mut self, key: &K) -> TrieResult<Vec<Vec<u8>>> {
+        self.trie.proof(&key.encode_key())
+    }
+
+    /// Iterates over the entries in the order of their encoded keys.
+    pub fn iter(&self) -> impl Iterator<Item = TrieResult<(K, V)>> + '_ {
+        self.trie.iter().map(|entry| {
+            let (key, value) = entry?;
+            Ok((K::decode_key(&key)?, V::decode_value(&value)?))
+        })
+    }
+}
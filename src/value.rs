@@ -0,0 +1,150 @@
+use ethereum_types::H256;
+
+use crate::codec::NodeCodec;
+use crate::db::DB;
+use crate::errors::TrieError;
+use crate::hasher::Hasher;
+use crate::perf::Counters;
+use crate::trie::{EthTrie, TrieResult};
+
+// When values are tagged every stored value starts with one of these, so a small value
+// can never be mistaken for a reference to a blob and empty values stay non-empty.
+const INLINE_VALUE: u8 = 0;
+const BLOB_VALUE: u8 = 1;
+
+/// What putting an empty value does.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum EmptyValues {
+    /// Remove the key, as Ethereum tries do.
+    #[default]
+    Delete,
+    /// Store the empty value, so `get` returns `Some(vec![])`.
+    Store,
+}
+
+/// How values are encoded in the trie's leaves.
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct ValueEncoding {
+    // Values longer than this are stored as blobs, see `with_large_values`.
+    pub large_value_threshold: Option<usize>,
+    pub empty_values: EmptyValues,
+}
+
+impl<D, H, C> EthTrie<D, H, C>
+where
+    D: DB,
+    H: Hasher,
+    C: NodeCodec,
+{
+    /// Stores values longer than `threshold` bytes as blobs next to the trie (see
+    /// `DB::insert_blob`), keeping only their hash in the leaf, so nodes stay small.
+    /// Values are reassembled transparently by `get` and iteration.
+    ///
+    /// This changes how every value is encoded in the trie, so the same setting must
+    /// be used whenever the database is opened, and roots will differ from a plain
+    /// Ethereum trie. Blobs are written as soon as they are put and are not pruned.
+    pub fn with_large_values(mut self, threshold: usize) -> Self {
+        self.values.large_value_threshold = Some(threshold);
+        self
+    }
+
+    /// Chooses what `put`, `update` and entries do with empty values.
+    ///
+    /// Ethereum's node encoding can't tell an empty value from a missing one, so
+    /// `EmptyValues::Store` tags every value in the trie like `with_large_values`
+    /// does: it must be used consistently for a database, roots differ from a plain
+    /// Ethereum trie and values in proofs carry the tag.
+    pub fn with_empty_values(mut self, empty_values: EmptyValues) -> Self {
+        self.values.empty_values = empty_values;
+        self
+    }
+}
+
+impl ValueEncoding {
+    fn tagged(&self) -> bool {
+        self.large_value_threshold.is_some() || self.empty_values == EmptyValues::Store
+    }
+
+    // The hash of the blob a stored value refers to, if it is one.
+    pub fn blob_hash(&self, stored: &[u8]) -> Option<H256> {
+        match stored.split_first() {
+            Some((&BLOB_VALUE, hash)) if self.tagged() && hash.len() == 32 => {
+                Some(H256::from_slice(hash))
+            }
+            _ => None,
+        }
+    }
+
+    // The value `stored` holds in place, or `None` if it refers to a blob.
+    pub fn inline<'a>(&self, stored: &'a [u8]) -> TrieResult<Option<&'a [u8]>> {
+        if !self.tagged() {
+            return Ok(Some(stored));
+        }
+        match stored.split_first() {
+            Some((&INLINE_VALUE, value)) => Ok(Some(value)),
+            Some((&BLOB_VALUE, hash)) if hash.len() == 32 => Ok(None),
+            _ => Err(TrieError::InvalidData),
+        }
+    }
+
+    // Whether writing `value` removes the key instead.
+    pub fn removes(&self, value: &[u8]) -> bool {
+        value.is_empty() && self.empty_values == EmptyValues::Delete
+    }
+
+    // Turns a value into what gets stored in the trie, moving it to a blob if needed.
+    pub fn store<D: DB, H: Hasher>(
+        &self,
+        db: &D,
+        value: Vec<u8>,
+        counters: &Counters,
+    ) -> TrieResult<Vec<u8>> {
+        if !self.tagged() {
+            return Ok(value);
+        }
+
+        match self.large_value_threshold {
+            Some(threshold) if value.len() > threshold => {
+                counters.hashed();
+                let hash = H::hash(&value);
+                db.insert_blob(hash.as_bytes(), value)
+                    .map_err(TrieError::backend)?;
+                Ok([&[BLOB_VALUE], hash.as_bytes()].concat())
+            }
+            _ => Ok([&[INLINE_VALUE], &value[..]].concat()),
+        }
+    }
+
+    // Turns what is stored in the trie back into the original value.
+    pub fn load<D: DB, H: Hasher>(
+        &self,
+        db: &D,
+        stored: Vec<u8>,
+        counters: &Counters,
+    ) -> TrieResult<Vec<u8>> {
+        if !self.tagged() {
+            return Ok(stored);
+        }
+
+        match stored.split_first() {
+            Some((&INLINE_VALUE, value)) => Ok(value.to_vec()),
+            Some((&BLOB_VALUE, hash)) if hash.len() == 32 => {
+                let hash = H256::from_slice(hash);
+                counters.db_read();
+                let value = db
+                    .get_blob(hash.as_bytes())
+                    .map_err(TrieError::backend)?
+                    .ok_or(TrieError::NotFound { hash })?;
+                counters.hashed();
+                if H::hash(&value) != hash {
+                    return Err(TrieError::Corrupted {
+                        hash,
+                        reason: "value blob does not match its hash",
+                    });
+                }
+                Ok(value)
+            }
+            _ => Err(TrieError::InvalidData),
+        }
+    }
+}
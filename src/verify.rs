@@ -0,0 +1,74 @@
+use alloc::vec::Vec;
+
+use ethereum_types::H256;
+
+use crate::codec::{DecodedNode, NodeCodec, RlpCodec};
+use crate::errors::{TrieError, TrieResult};
+use crate::hasher::{Hasher, KeccakHasher};
+use crate::nibbles::Nibbles;
+
+/// Verifies a proof made by `EthTrie::proof` against `root_hash`, returning the value
+/// stored for `key`, or `None` if the proof shows the key is absent.
+///
+/// Only nodes are decoded here, nothing is read from a database, so this is available
+/// without the `std` feature. Values that `EthTrie` stores as blobs are returned as
+/// stored, since the blob itself is not part of the proof.
+pub fn verify_proof(root_hash: H256, key: &[u8], proof: &[Vec<u8>]) -> TrieResult<Option<Vec<u8>>> {
+    verify_proof_with::<KeccakHasher, RlpCodec>(root_hash, key, proof)
+}
+
+/// `verify_proof` for tries using another hasher or codec.
+pub fn verify_proof_with<H: Hasher, C: NodeCodec>(
+    root_hash: H256,
+    key: &[u8],
+    proof: &[Vec<u8>],
+) -> TrieResult<Option<Vec<u8>>> {
+    let nodes: Vec<(H256, &[u8])> = proof.iter().map(|n| (H::hash(n), n.as_slice())).collect();
+    let find = |hash: H256| {
+        nodes
+            .iter()
+            .find(|(h, _)| *h == hash)
+            .map(|(_, n)| *n)
+            .ok_or(TrieError::InvalidProof)
+    };
+
+    // Proofs against the empty trie hold no nodes.
+    if root_hash == H::hash(&C::encode_empty()) {
+        return Ok(None);
+    }
+
+    let path = Nibbles::from_raw(key, false);
+    let mut partial = path.get_data();
+    // The root is stored under its hash whatever its size.
+    let mut data = find(root_hash)?;
+    // Embedded children are strictly shorter than their parent, and a node can't refer
+    // to one of its ancestors by hash, so the walk ends without a depth limit.
+    loop {
+        let child = match C::decode(data)? {
+            DecodedNode::Empty => return Ok(None),
+            DecodedNode::Leaf { key, value } => {
+                return Ok((key == partial).then_some(value));
+            }
+            DecodedNode::Extension { prefix, child } => {
+                if !partial.starts_with(&prefix) {
+                    return Ok(None);
+                }
+                partial = &partial[prefix.len()..];
+                child
+            }
+            DecodedNode::Branch { children, value } => match partial.split_first() {
+                None => return Ok(value),
+                Some((&nibble, rest)) => {
+                    partial = rest;
+                    children[nibble as usize]
+                }
+            },
+            DecodedNode::Hash(_) => return Err(TrieError::InvalidProof),
+        };
+
+        data = match C::decode(child)? {
+            DecodedNode::Hash(hash) => find(hash)?,
+            _ => child,
+        };
+    }
+}
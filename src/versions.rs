@@ -0,0 +1,219 @@
+use ethereum_types::H256;
+
+use crate::codec::NodeCodec;
+use crate::db::DB;
+use crate::errors::TrieError;
+use crate::hasher::Hasher;
+use crate::trie::{EthTrie, TrieResult};
+
+// The oldest and latest indexed versions, big-endian.
+const VERSIONS_KEY: &[u8] = b"versions";
+
+// Each version is stored as its root followed by the next indexed version, once
+// there is one, so pruning walks the versions it drops without scanning for gaps.
+fn version_key(version: u64) -> Vec<u8> {
+    [b"version:".as_slice(), &version.to_be_bytes()].concat()
+}
+
+impl<D, H, C> EthTrie<D, H, C>
+where
+    D: DB,
+    H: Hasher,
+    C: NodeCodec,
+{
+    /// Keeps only the versions at most `versions` below the latest one in the index
+    /// written by `commit_version`, so `with_version_retention(128)` keeps the roots
+    /// of the last 129 blocks. Older versions are dropped from the index as new ones
+    /// are committed. Only the index entries are removed: whether the nodes of a root
+    /// stay readable depends on `with_pruning`.
+    pub fn with_version_retention(mut self, versions: u64) -> Self {
+        self.version_retention = Some(versions);
+        self
+    }
+
+    /// Commits like `commit` and records the root under `version`, such as a block
+    /// height, in a metadata index kept in the trie's database. `version` must be
+    /// greater than every version recorded before, otherwise this fails with
+    /// `TrieError::InvalidData` without committing.
+    ///
+    /// Commits prune the nodes they replace, so the trie must be created
+    /// `with_pruning(false)` for the roots of earlier versions to stay readable.
+    pub fn commit_version(&mut self, version: u64) -> TrieResult<H256> {
+        let range = self.version_range()?;
+        if range.is_some_and(|(_, latest)| version <= latest) {
+            return Err(TrieError::InvalidData);
+        }
+
+        // The changes are found before `commit` prunes the previous version's nodes.
+        let mut meta = if self.history {
+            let base = match range {
+                Some((_, latest)) => root_of(&self.version_entry(latest)?)?,
+                None => H::hash(&C::encode_empty()),
+            };
+            self.history_meta(version, self.changes_since(base)?)?
+        } else {
+            vec![]
+        };
+
+        // The index is written with the nodes, so it never lists a root that isn't
+        // stored.
+        let (oldest, expired) = match range {
+            Some((oldest, latest)) => {
+                let (oldest, expired) = self.expired_versions(oldest, latest, version)?;
+                // An expired latest version is removed rather than linked.
+                if latest >= oldest {
+                    let mut entry = self.version_entry(latest)?;
+                    entry.extend_from_slice(&version.to_be_bytes());
+                    meta.push((version_key(latest), entry));
+                }
+                (oldest, expired)
+            }
+            None => (version, vec![]),
+        };
+        let range = [oldest.to_be_bytes(), version.to_be_bytes()].concat();
+        meta.push((VERSIONS_KEY.to_vec(), range));
+
+        // The expired versions and their history leave with the same commit, so none
+        // are left behind.
+        let mut removed = vec![];
+        for expired in expired {
+            removed.push(version_key(expired));
+            removed.extend(self.history_removals(expired)?);
+        }
+        let outcome = self.commit_changing_meta(removed, |root| {
+            meta.push((version_key(version), root.as_bytes().to_vec()));
+            meta
+        })?;
+        Ok(outcome.root)
+    }
+
+    /// Returns the root committed as `version` with `commit_version`, or `None` if no
+    /// root was recorded under it or it has been dropped from the index.
+    pub fn root_at(&self, version: u64) -> TrieResult<Option<H256>> {
+        let entry = self.get_meta(&version_key(version))?;
+        entry.map(|entry| root_of(&entry)).transpose()
+    }
+
+    /// Returns the latest version recorded with `commit_version` and its root, for
+    /// instance to reopen the trie where it was left with `at_root`.
+    pub fn latest_version(&self) -> TrieResult<Option<(u64, H256)>> {
+        match self.version_range()? {
+            Some((_, latest)) => Ok(Some((latest, root_of(&self.version_entry(latest)?)?))),
+            None => Ok(None),
+        }
+    }
+
+    // The versions below the retention window ending at `version`, from `oldest` on,
+    // which `latest`, recorded before it, now links to, and the oldest version kept.
+    fn expired_versions(
+        &self,
+        mut oldest: u64,
+        latest: u64,
+        version: u64,
+    ) -> TrieResult<(u64, Vec<u64>)> {
+        let Some(retention) = self.version_retention else {
+            return Ok((oldest, vec![]));
+        };
+        let cutoff = version.saturating_sub(retention);
+        let mut expired = vec![];
+        while oldest < cutoff {
+            expired.push(oldest);
+            oldest = if oldest == latest {
+                version
+            } else {
+                // Every version below the latest one links to the next.
+                let entry = self.version_entry(oldest)?;
+                be_u64(entry.get(32..).unwrap_or_default())?
+            };
+        }
+        Ok((oldest, expired))
+    }
+
+    fn version_range(&self) -> TrieResult<Option<(u64, u64)>> {
+        let range = self.get_meta(VERSIONS_KEY)?;
+        range
+            .map(|range| match range.len() {
+                16 => Ok((be_u64(&range[..8])?, be_u64(&range[8..])?)),
+                _ => Err(TrieError::InvalidData),
+            })
+            .transpose()
+    }
+
+    fn version_entry(&self, version: u64) -> TrieResult<Vec<u8>> {
+        self.get_meta(&version_key(version))?
+            .ok_or(TrieError::InvalidData)
+    }
+
+    pub(crate) fn get_meta(&self, key: &[u8]) -> TrieResult<Option<Vec<u8>>> {
+        self.db.get_meta(key).map_err(TrieError::backend)
+    }
+}
+
+fn root_of(entry: &[u8]) -> TrieResult<H256> {
+    entry
+        .get(..32)
+        .map(H256::from_slice)
+        .ok_or(TrieError::InvalidData)
+}
+
+pub(crate) fn be_u64(bytes: &[u8]) -> TrieResult<u64> {
+    bytes
+        .try_into()
+        .map(u64::from_be_bytes)
+        .map_err(|_| TrieError::InvalidData)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::journal::JournalDB;
+    use crate::test_utils::{FaultyDB, MemoryDB};
+    use crate::trie::ITrie;
+
+    #[test]
+    fn expired_versions_leave_with_the_commit() {
+        let db = Arc::new(FaultyDB::new());
+        let mut trie = EthTrie::new(db.clone())
+            .with_pruning(false)
+            .with_history(true)
+            .with_version_retention(1);
+        for version in 1..=2 {
+            trie.put(b"key", &[version as u8]).unwrap();
+            trie.commit_version(version).unwrap();
+        }
+
+        // A failed commit removes nothing.
+        trie.put(b"key", &[3]).unwrap();
+        db.fail_writes(1);
+        assert!(trie.commit_version(3).is_err());
+        assert!(trie.root_at(1).unwrap().is_some());
+        assert_eq!(trie.history(b"key").unwrap().len(), 2);
+
+        let root = trie.commit_version(3).unwrap();
+        assert_eq!(trie.root_at(1).unwrap(), None);
+        assert_eq!(trie.latest_version().unwrap(), Some((3, root)));
+        assert_eq!(
+            trie.history(b"key").unwrap(),
+            vec![(2, Some(vec![2])), (3, Some(vec![3]))]
+        );
+    }
+
+    #[test]
+    fn journaled_removals_reach_the_database_below() {
+        let db = Arc::new(JournalDB::new(MemoryDB::new(), 0));
+        let mut trie = EthTrie::new(db.clone())
+            .with_pruning(false)
+            .with_version_retention(0);
+        for version in 1..=2 {
+            let parent = trie.root_hash;
+            trie.put(b"key", &[version as u8]).unwrap();
+            let root = trie.commit_version(version).unwrap();
+            db.seal(parent, root).unwrap();
+        }
+        assert_eq!(trie.root_at(1).unwrap(), None);
+        assert_eq!(db.backing().get_meta(&version_key(1)).unwrap(), None);
+        assert!(db.backing().get_meta(&version_key(2)).unwrap().is_some());
+    }
+}
@@ -0,0 +1,105 @@
+use ethereum_types::H256;
+
+use crate::codec::NodeCodec;
+use crate::db::DB;
+use crate::errors::TrieError;
+use crate::hasher::Hasher;
+use crate::node::Node;
+use crate::trie::{EthTrie, TrieResult};
+
+/// What `walk` does once a node has been visited.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WalkControl {
+    /// Goes on into the node's children.
+    Continue,
+    /// Leaves out the node's children and everything below them, without loading them.
+    SkipSubtree,
+    /// Ends the walk.
+    Stop,
+}
+
+/// A node met by `walk`.
+#[derive(Debug)]
+pub struct VisitedNode<'a> {
+    /// The nibbles of the path from the root to the node, without the node's own key
+    /// or prefix.
+    pub path: &'a [u8],
+    /// The hash the node is stored under, or `None` for nodes embedded in their parent
+    /// and nodes changed since the last commit.
+    pub hash: Option<H256>,
+    pub node: &'a Node,
+}
+
+impl<D, H, C> EthTrie<D, H, C>
+where
+    D: DB,
+    H: Hasher,
+    C: NodeCodec,
+{
+    /// Visits the nodes of the trie depth first, in key order, passing each one to
+    /// `visitor` with its path and hash. The visitor decides whether the walk goes
+    /// into the node's children, skips them or stops, so analytics, selective exports
+    /// and pruning heuristics only load the parts of the trie they need.
+    pub fn walk<F>(&self, mut visitor: F) -> TrieResult<()>
+    where
+        F: FnMut(&VisitedNode<'_>) -> WalkControl,
+    {
+        self.walk_from(&self.root, &mut vec![], &mut visitor)?;
+        Ok(())
+    }
+
+    // Walks the subtree of `node`, returning false once the visitor has stopped.
+    fn walk_from<F>(&self, node: &Node, path: &mut Vec<u8>, visitor: &mut F) -> TrieResult<bool>
+    where
+        F: FnMut(&VisitedNode<'_>) -> WalkControl,
+    {
+        if let Node::Hash(hash_node) = node {
+            let hash = hash_node.hash;
+            let node = self
+                .recover_from_db(hash)?
+                .ok_or(TrieError::MissingTrieNode {
+                    node_hash: hash,
+                    traversed: None,
+                    root_hash: Some(self.root_hash),
+                    err_key: None,
+                })?;
+            return self.walk_from(&node, path, visitor);
+        }
+        if let Node::Empty = node {
+            return Ok(true);
+        }
+
+        let visited = VisitedNode {
+            path,
+            hash: node.cached_hash(),
+            node,
+        };
+        match visitor(&visited) {
+            WalkControl::Continue => {}
+            WalkControl::SkipSubtree => return Ok(true),
+            WalkControl::Stop => return Ok(false),
+        }
+
+        match node {
+            Node::Branch(branch) => {
+                for (i, child) in branch.children.iter().enumerate() {
+                    path.push(i as u8);
+                    let go_on = self.walk_from(child, path, visitor)?;
+                    path.pop();
+                    if !go_on {
+                        return Ok(false);
+                    }
+                }
+                Ok(true)
+            }
+            Node::Extension(ext) => {
+                let len = path.len();
+                path.extend_from_slice(ext.prefix.get_data());
+                let go_on = self.walk_from(&ext.node, path, visitor)?;
+                path.truncate(len);
+                Ok(go_on)
+            }
+            _ => Ok(true),
+        }
+    }
+}
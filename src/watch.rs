@@ -0,0 +1,68 @@
+use crate::codec::NodeCodec;
+use crate::db::DB;
+use crate::hasher::Hasher;
+use crate::trie::{EthTrie, TrieResult};
+
+/// A watched key changed by a commit, as returned by `EthTrie::watched_changes`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct WatchedChange {
+    pub key: Vec<u8>,
+    /// The value after the commit, or `None` if the key was deleted.
+    pub value: Option<Vec<u8>>,
+}
+
+impl<D, H, C> EthTrie<D, H, C>
+where
+    D: DB,
+    H: Hasher,
+    C: NodeCodec,
+{
+    /// Adds `key` to the keys whose changes each commit reports in
+    /// `watched_changes`, so indexers can follow a few keys without diffing whole
+    /// roots. The watch list is kept by the tries created with `at_root`.
+    pub fn watch(&mut self, key: &[u8]) {
+        self.watched.insert(key.to_vec());
+    }
+
+    /// Removes `key` from the watch list.
+    pub fn unwatch(&mut self, key: &[u8]) {
+        self.watched.remove(key);
+    }
+
+    /// The watched keys whose value the last commit changed, in key order, with
+    /// their new values.
+    pub fn watched_changes(&self) -> &[WatchedChange] {
+        &self.watched_changes
+    }
+
+    // The values of the watched keys at the last committed root, read before
+    // `commit` prunes its nodes.
+    pub(crate) fn watched_values(&self) -> TrieResult<Vec<Option<Vec<u8>>>> {
+        if self.watched.is_empty() {
+            return Ok(vec![]);
+        }
+        let keys: Vec<&[u8]> = self.watched.iter().map(Vec::as_slice).collect();
+        self.at_root(self.root_hash).get_many(&keys)
+    }
+
+    // Records the watched keys whose value differs from `old`, once committed.
+    pub(crate) fn record_watched(&mut self, old: Vec<Option<Vec<u8>>>) -> TrieResult<()> {
+        self.watched_changes.clear();
+        if self.watched.is_empty() {
+            return Ok(());
+        }
+        let keys: Vec<&[u8]> = self.watched.iter().map(Vec::as_slice).collect();
+        let new = self.get_many(&keys)?;
+        self.watched_changes = keys
+            .into_iter()
+            .zip(old.into_iter().zip(new))
+            .filter(|(_, (old, new))| old != new)
+            .map(|(key, (_, value))| WatchedChange {
+                key: key.to_vec(),
+                value,
+            })
+            .collect();
+        Ok(())
+    }
+}